@@ -0,0 +1,39 @@
+#![no_main]
+use arbitrary::Arbitrary;
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use punch::mux::{FrameCodec, MAX_FRAME_PAYLOAD};
+use tokio_util::codec::{Decoder, Encoder};
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    // the stream arrives in pieces of these sizes
+    chunks: Vec<u16>,
+    data: Vec<u8>,
+}
+
+fuzz_target!(|input: Input| {
+    let mut codec = FrameCodec;
+    let mut src = BytesMut::new();
+    let mut rest = &input.data[..];
+    let mut chunks = input.chunks.iter().map(|&n| n as usize + 1).chain(std::iter::repeat(usize::MAX));
+    while !rest.is_empty() {
+        let n = chunks.next().unwrap().min(rest.len());
+        src.extend_from_slice(&rest[..n]);
+        rest = &rest[n..];
+        loop {
+            match codec.decode(&mut src) {
+                Ok(Some(frame)) => {
+                    // whatever decodes has to encode to something that decodes the same
+                    let mut again = BytesMut::new();
+                    codec.encode(frame.clone(), &mut again).unwrap();
+                    assert_eq!(codec.decode(&mut again).unwrap(), Some(frame));
+                }
+                Ok(None) => break,
+                Err(_) => return,
+            }
+            // a bogus length must not make us buffer more than one frame
+            assert!(src.capacity() <= 2 * (input.data.len() + 9 + MAX_FRAME_PAYLOAD));
+        }
+    }
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use punch::hybrid::Message;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = Message::decode(data) {
+        assert_eq!(Message::decode(&msg.encode()).unwrap(), msg);
+    }
+});
@@ -0,0 +1,119 @@
+#![no_main]
+//! Feeds sequences of client messages to the hybrid server state machine and
+//! checks it against a simple model of who is registered and who waits.
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use punch::{
+    hybrid::{Answer, Message},
+    server::{HybridState, TcpAction},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+};
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Register { from: u8, id: u8 },
+    Unregister { from: u8, id: u8 },
+    PunchRequest { from: u8, id_b: u8 },
+    PunchReply { from: u8, a: u8 },
+    GiveUp { a: u8 },
+    RawUdp { from: u8, data: Vec<u8> },
+    RawTcp { from: u8, data: Vec<u8> },
+}
+
+fn addr(n: u8) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, n], 1000 + n as u16))
+}
+
+// only the addr that registered an id may take it back
+fn unregister(registered: &mut HashMap<String, SocketAddr>, id: &str, from: SocketAddr) {
+    if registered.get(id) == Some(&from) {
+        registered.remove(id);
+    }
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut state = HybridState::<SocketAddr>::default();
+    let mut registered = HashMap::<String, SocketAddr>::new();
+    let mut waiting = HashSet::<SocketAddr>::new();
+
+    for op in ops {
+        match op {
+            Op::Register { from, id } => {
+                let rsp = state.on_udp(addr(from), 0, Message::register_request(id.to_string()));
+                assert_eq!(rsp, Some(Message::register_response(0)));
+                registered.insert(id.to_string(), addr(from));
+            }
+            Op::Unregister { from, id } => {
+                let rsp = state.on_udp(addr(from), 0, Message::unregister_request(id.to_string()));
+                assert_eq!(rsp, None);
+                unregister(&mut registered, &id.to_string(), addr(from));
+            }
+            Op::PunchRequest { from, id_b } => {
+                let a = addr(from);
+                match state.on_tcp(a, Message::punchA2S(id_b.to_string()), a) {
+                    TcpAction::TellB(b, 0, Message::punchS2B(told)) => {
+                        assert_eq!(Some(&b), registered.get(&id_b.to_string()));
+                        assert_eq!(told, a);
+                        waiting.insert(a);
+                    }
+                    TcpAction::Reply(Message::punchS2A(None)) => {
+                        assert!(!registered.contains_key(&id_b.to_string()))
+                    }
+                    action => panic!("unexpected {:?}", action),
+                }
+            }
+            Op::PunchReply { from, a } => {
+                match state.on_tcp(
+                    addr(from),
+                    Message::punchB2S(Answer::A(addr(a))),
+                    addr(from),
+                ) {
+                    TcpAction::AnswerA(waiter, b) => {
+                        assert_eq!(waiter, addr(a));
+                        assert_eq!(b, addr(from));
+                        assert!(waiting.remove(&addr(a)));
+                    }
+                    TcpAction::Ignore => assert!(!waiting.contains(&addr(a))),
+                    action => panic!("unexpected {:?}", action),
+                }
+            }
+            Op::GiveUp { a } => {
+                state.forget_a(addr(a));
+                waiting.remove(&addr(a));
+            }
+            Op::RawUdp { from, data } => {
+                if let Ok(msg) = Message::decode(&data) {
+                    match &msg {
+                        Message::register_request(id) => {
+                            registered.insert(id.clone(), addr(from));
+                        }
+                        Message::unregister_request(id) => {
+                            unregister(&mut registered, id, addr(from))
+                        }
+                        _ => {}
+                    }
+                    state.on_udp(addr(from), 0, msg);
+                }
+            }
+            Op::RawTcp { from, data } => {
+                if let Ok(msg) = Message::decode(&data) {
+                    match state.on_tcp(addr(from), msg, addr(from)) {
+                        TcpAction::TellB(..) => {
+                            waiting.insert(addr(from));
+                        }
+                        TcpAction::AnswerA(waiter, _) => {
+                            waiting.remove(&waiter);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        assert_eq!(state.registered(), registered.len());
+        assert_eq!(state.waiting(), waiting.len());
+    }
+});
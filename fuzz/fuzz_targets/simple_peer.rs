@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use punch::simple::Peer;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(peer) = Peer::decode(data) {
+        assert_eq!(Peer::decode(&peer.encode()).unwrap(), peer);
+    }
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use punch::simple::Register;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(register) = Register::decode(data) {
+        assert_eq!(Register::decode(&register.encode()).unwrap(), register);
+    }
+});
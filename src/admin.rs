@@ -0,0 +1,121 @@
+//! Admin api of the rendezvous servers, json over http. Every request needs
+//! `Authorization: Bearer <token>`.
+//!
+//! - `GET /registrations`: every live registration
+//! - `GET /registrations/<id>`: the registrations of one id, 404 if none
+//! - `DELETE /registrations/<id>`: kick, the id is forgotten until it registers again
+//! - `GET /sessions`: punches in flight
+//! - `GET /trails`: what the server did in recent punches, oldest first
+//! - `GET /trails/<id>`: the recent punches of one id
+//! - `GET /bans`
+//! - `PUT /bans/<id>`: kick and refuse the id from now on
+//! - `DELETE /bans/<id>`: lift a ban, 404 if there was none
+
+use crate::{
+    http::{self, percent_decode, Request, Response},
+    server::Control,
+};
+use anyhow::{bail, Result};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// Serves the api for `control`, for clients that present `token`.
+pub async fn serve(listener: TcpListener, control: Arc<Control>, token: String) -> Result<()> {
+    if token.is_empty() {
+        bail!("the admin api needs a token");
+    }
+    let token = Arc::new(token);
+    http::serve(listener, move |request: Request| {
+        let control = control.clone();
+        let token = token.clone();
+        async move {
+            if !authorized(&request, &token) {
+                tracing::warn!(
+                    "admin {} {} without a valid token",
+                    request.method,
+                    request.path
+                );
+                return error(401, "missing or wrong token");
+            }
+            handle(&control, &request)
+        }
+    })
+    .await
+}
+
+fn authorized(request: &Request, token: &str) -> bool {
+    let given = match request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        Some(given) => given.trim().as_bytes(),
+        None => return false,
+    };
+    // same time for every wrong token of the right length
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn handle(control: &Control, request: &Request) -> Response {
+    let segments: Vec<_> = request.path.trim_matches('/').split('/').collect();
+    let id = match segments.get(1).map(|segment| percent_decode(segment)) {
+        Some(Some(id)) if !id.is_empty() => Some(id),
+        Some(_) => return error(400, "bad id"),
+        None => None,
+    };
+    if segments.len() > 2 {
+        return error(404, "no such resource");
+    }
+    let method = request.method.as_str();
+    match (segments[0], id) {
+        ("registrations", None) => match method {
+            "GET" => ok(&control.registrations(None)),
+            _ => error(405, "only GET"),
+        },
+        ("registrations", Some(id)) => match method {
+            "GET" => match control.registrations(Some(&id)) {
+                found if found.is_empty() => error(404, "not registered"),
+                found => ok(&found),
+            },
+            "DELETE" => ok(&json!({ "kicked": control.kick(&id) })),
+            _ => error(405, "only GET and DELETE"),
+        },
+        ("sessions", None) => match method {
+            "GET" => ok(&control.sessions()),
+            _ => error(405, "only GET"),
+        },
+        ("trails", id) => match method {
+            "GET" => ok(&control.trails().list(id.as_deref())),
+            _ => error(405, "only GET"),
+        },
+        ("bans", None) => match method {
+            "GET" => ok(&control.banned()),
+            _ => error(405, "only GET"),
+        },
+        ("bans", Some(id)) => match method {
+            "PUT" => ok(&json!({ "kicked": control.ban(&id) })),
+            "DELETE" if control.unban(&id) => ok(&json!({ "unbanned": id })),
+            "DELETE" => error(404, "not banned"),
+            _ => error(405, "only PUT and DELETE"),
+        },
+        _ => error(404, "no such resource"),
+    }
+}
+
+fn ok<T: Serialize>(body: &T) -> Response {
+    Response::new(200, "application/json", serde_json::to_vec(body).unwrap())
+}
+
+fn error(status: u16, message: &str) -> Response {
+    Response::new(
+        status,
+        "application/json",
+        serde_json::to_vec(&json!({ "error": message })).unwrap(),
+    )
+}
@@ -0,0 +1,212 @@
+use anyhow::Result;
+use clap::{Args, Parser, Subcommand};
+use punch::{
+    chat,
+    config::{ClientArgs, Config, ServerArgs},
+    puncher::Strategy,
+    server,
+    session::Session,
+    shutdown,
+    transfer::{self, ReceiveArgs, SendArgs},
+    trigger::TriggerArgs,
+    tunnel::{self, ForwardArgs},
+};
+
+/// Punch a hole to a peer through a rendezvous server, or run the server
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a rendezvous server
+    Server(ServerCommand),
+    /// Punch to the peer and chat with it
+    Connect(ConnectCommand),
+    /// Wait for the peer to punch to us and chat with it
+    Listen(ClientArgs),
+    /// Forward tcp ports or serve SOCKS5 over the punched connection
+    Forward(ForwardCommand),
+    /// Send a file, resuming after the connection drops
+    Send(SendCommand),
+    /// Receive a file, resuming after the connection drops
+    Receive(ReceiveCommand),
+    /// Ask the server how our addrs are mapped and print the json report
+    NatCheck(ClientArgs),
+}
+
+#[derive(Args, Debug)]
+struct ServerCommand {
+    /// Which clients the server is for
+    #[clap(long, value_enum, default_value = "hybrid")]
+    strategy: Strategy,
+
+    #[clap(flatten)]
+    server: ServerArgs,
+}
+
+#[derive(Args, Debug)]
+struct ConnectCommand {
+    #[clap(flatten)]
+    client: ClientArgs,
+
+    #[clap(flatten)]
+    trigger: TriggerArgs,
+}
+
+#[derive(Args, Debug)]
+struct ForwardCommand {
+    #[clap(flatten)]
+    client: ClientArgs,
+
+    /// Wait for the peer instead of connecting
+    #[clap(long)]
+    listen: bool,
+
+    #[clap(flatten)]
+    trigger: TriggerArgs,
+
+    #[clap(flatten)]
+    forward: ForwardArgs,
+}
+
+#[derive(Args, Debug)]
+struct SendCommand {
+    #[clap(flatten)]
+    client: ClientArgs,
+
+    /// Wait for the peer instead of connecting
+    #[clap(long)]
+    listen: bool,
+
+    #[clap(flatten)]
+    trigger: TriggerArgs,
+
+    #[clap(flatten)]
+    send: SendArgs,
+}
+
+#[derive(Args, Debug)]
+struct ReceiveCommand {
+    #[clap(flatten)]
+    client: ClientArgs,
+
+    /// Wait for the peer instead of connecting
+    #[clap(long)]
+    listen: bool,
+
+    #[clap(flatten)]
+    trigger: TriggerArgs,
+
+    #[clap(flatten)]
+    receive: ReceiveArgs,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::Server(command) => serve(command).await,
+        Command::Connect(command) => {
+            chat_with(command.client.load()?, false, &command.trigger).await
+        }
+        Command::Listen(client) => chat_with(client.load()?, true, &TriggerArgs::default()).await,
+        Command::Forward(command) => {
+            let config = command.client.load()?;
+            config.init_logger()?;
+            let puncher = config.puncher(command.listen).await?;
+            let leave = puncher.leave();
+            shutdown::client(leave, async {
+                let session = Session::connect(puncher.trigger(command.trigger.trigger()?)).await?;
+                let initiator = session.initiator();
+                tunnel::forward(session, initiator, &command.forward).await
+            })
+            .await
+        }
+        Command::Send(command) => {
+            let config = command.client.load()?;
+            config.init_logger()?;
+            let mut puncher = config
+                .puncher(command.listen)
+                .await?
+                .trigger(command.trigger.trigger()?);
+            let leave = puncher.leave();
+            shutdown::client(leave, transfer::send_file(&mut puncher, &command.send.path)).await
+        }
+        Command::Receive(command) => {
+            let config = command.client.load()?;
+            config.init_logger()?;
+            let mut puncher = config
+                .puncher(command.listen)
+                .await?
+                .trigger(command.trigger.trigger()?);
+            let leave = puncher.leave();
+            shutdown::client(leave, async {
+                let path = transfer::receive_file(&mut puncher, &command.receive.dir).await?;
+                tracing::info!("saved {:?}", path);
+                Ok(())
+            })
+            .await
+        }
+        Command::NatCheck(client) => {
+            let config = client.load()?;
+            config.init_logger()?;
+            // no peer is needed to ask the server
+            let mut puncher = config.puncher(true).await?;
+            let report = puncher.nat_check().await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+    }
+}
+
+async fn serve(command: ServerCommand) -> Result<()> {
+    let config = command.server.load()?;
+    config.init_logger()?;
+    let options = config.options()?;
+    options.shutdown.on_signal();
+    config.serve_metrics(&options)?;
+    config.serve_admin(&options)?;
+    match command.strategy {
+        Strategy::Udp => server::udp_with(config.udp_sockets()?, options).await,
+        Strategy::Tcp => server::tcp_with(config.tcp_listeners()?, options).await,
+        Strategy::Hybrid => {
+            server::hybrid_with(config.tcp_listeners()?, config.udp_sockets()?, options).await
+        }
+    }
+}
+
+async fn chat_with(config: Config, listen: bool, trigger: &TriggerArgs) -> Result<()> {
+    config.init_logger()?;
+    let puncher = config.puncher(listen).await?.trigger(trigger.trigger()?);
+    let leave = puncher.leave();
+    shutdown::client(leave, async {
+        let session = Session::connect(puncher).await?;
+        let id = config.client.id.clone().unwrap_or_default();
+        chat::run(
+            session,
+            &mut chat::stdin_lines(),
+            &id,
+            config.client.peer_id.clone(),
+        )
+        .await
+    })
+    .await
+}
+
+/*
+punch server --bind 0.0.0.0:12345
+
+client B:
+punch listen --server "101.34.84.73:12345" --id B
+
+client A, punching once B is up or when asked to:
+punch connect --server "101.34.84.73:12345" --id A --peer-id B --punch-after 5s
+punch connect --server "101.34.84.73:12345" --id A --peer-id B --punch-socket /run/punch.sock
+echo punch | nc -U /run/punch.sock
+
+which server is it, and how are we mapped:
+punch nat-check --server "101.34.84.73:12345" --id A
+*/
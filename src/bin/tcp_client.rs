@@ -1,29 +1,33 @@
 use anyhow::Result;
-use clap::Parser;
-use log;
-use punch::{new_tcp_socket, simple::*};
-use std::net::SocketAddr;
-use tokio::{
-    self,
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    time::{interval, sleep, timeout, Duration},
+use clap::{Parser, Subcommand};
+use punch::{
+    chat,
+    logging::{self, LogFormat},
+    puncher::{Puncher, Strategy},
+    rendezvous::ServerList,
+    session::Session,
+    shutdown,
+    transfer::{self, ReceiveArgs, SendArgs},
+    tunnel::{self, ForwardArgs},
 };
+use std::path::PathBuf;
 
 /*
 用tcp 去注册/心跳
     1. 一直复用端口会出现连接失败的情况
     2. 不复用端口, 会出现两个A,B一前一后,第二个连上服务器的获取的是过时的地址,
        因为第一个再连的时候地址变了, 之后监听的端口也变了, 所以做listener的不能一直连
+       => listener一直用第一次注册的端口, 连接的一方只认listener的地址, listener只认对方的ip
 
 */
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Server addr
-    #[clap(short, long)]
-    server: String,
+    /// Server host:port, give it again to fail over to another server; the ipv4
+    /// and ipv6 addrs of one server can be joined with a comma
+    #[clap(short, long, required = true)]
+    server: Vec<String>,
 
     /// ID
     #[clap(short, long)]
@@ -36,136 +40,73 @@ struct Args {
     /// As listener
     #[clap(long)]
     listener: bool,
+
+    /// Write logs as text or as json lines, RUST_LOG picks what is logged
+    #[clap(long, value_enum, default_value = "text")]
+    log_format: LogFormat,
+
+    /// Write a json report of the punch attempts to this file
+    #[clap(long)]
+    report: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Forward tcp ports or serve SOCKS5 over the punched connection instead of chatting
+    Forward(ForwardArgs),
+    /// Send a file, resuming after the connection drops
+    Send(SendArgs),
+    /// Receive a file, resuming after the connection drops
+    Receive(ReceiveArgs),
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    std::env::set_var("RUST_LOG", "info");
-    env_logger::init();
     let args = Args::parse();
-    let mut buf = vec![0u8; 1024];
-
-    // step 1: connect server && register && get peer addr and local addr
-
-    let server_addr: SocketAddr = args.server.parse().expect("bad server addr");
-    #[allow(unused_assignments)]
-    let mut peer_addr: Option<SocketAddr> = None;
-    #[allow(unused_assignments)]
-    let mut local_addr: Option<SocketAddr> = None;
-
-    loop {
-        sleep(Duration::from_secs(1)).await;
-
-        // connect server
-        #[allow(unused_assignments)]
-        let mut stream: Option<TcpStream> = None;
-        match timeout(Duration::from_secs(3), TcpStream::connect(server_addr)).await {
-            Ok(Ok(s)) => {
-                stream = Some(s);
-                log::info!("connect to {} ok!", server_addr);
-            }
-            _ => {
-                log::warn!("connect to {} time out", server_addr);
-                continue;
-            }
-        }
-        let mut stream = stream.unwrap();
-
-        // register
-        let msg = Register {
-            id: args.id.clone(),
-            peer_id: args.peer_id.clone(),
-        };
-        match stream.write_all(&msg.encode()).await {
-            Ok(_) => log::info!("send register ok"),
-            Err(e) => {
-                log::error!("send register falied. {:?}", e);
-                continue;
-            }
-        }
-
-        // get peer addr and local addr
-        match timeout(Duration::from_secs(10), stream.read(&mut buf)).await {
-            Ok(Ok(size)) => {
-                if let Ok(peer) = Peer::decode(&buf[..size]) {
-                    if let Some(addr) = peer.peer_addr {
-                        log::info!("get peer addr {:?}", addr);
-                        peer_addr = Some(addr);
-                        local_addr = Some(stream.local_addr().unwrap());
-                        break;
-                    } else {
-                        log::info!("peer is not registered yet");
-                    }
-                }
-            }
-            _ => {
-                log::warn!("wait register response timeout");
-                continue;
-            }
-        }
+    logging::init(None, args.log_format)?;
+
+    // step 1-2: connect server && register && get peer addr, then get stream
+    let mut puncher = Puncher::new(
+        Strategy::Tcp,
+        ServerList::new(&args.server)?,
+        args.id.clone(),
+        Some(args.peer_id.clone()),
+    )
+    .tcp_listener(args.listener);
+    if let Some(path) = &args.report {
+        puncher = puncher.report(path);
     }
-    let peer_addr = peer_addr.unwrap();
-    let local_addr = local_addr.unwrap();
-
-    // step 2 get stream
-    #[allow(unused_assignments)]
-    let mut stream2: Option<TcpStream> = None; //AB对话的stream
-    if args.listener {
-        //监听之前的端口
-        let listener = TcpListener::bind(local_addr).await?;
-        log::info!("A listening at {:?}", local_addr);
-        match listener.accept().await {
-            Ok((stream, addr)) => {
-                log::info!("accept client from {:?}", addr);
-                if addr == peer_addr {
-                    stream2 = Some(stream);
-                } else {
-                    log::error!("expect {:?}, but accept {:?}", peer_addr, addr)
-                }
-            }
-            Err(e) => log::error!("Failed accept: {:?}", e),
-        }
-    } else {
-        loop {
-            //用之前的端口去连接
-            let socket = new_tcp_socket(local_addr, true)?;
-            match timeout(Duration::from_secs(3), socket.connect(peer_addr.clone())).await {
-                Ok(Ok(stream)) => {
-                    log::info!("connect to peer {:?} success.", peer_addr);
-                    stream2 = Some(stream);
-                    break;
-                }
-                _ => {
-                    log::warn!("Failed to connect to peer {:?}", peer_addr);
-                }
-            }
+    let leave = puncher.leave();
+    shutdown::client(leave, run(&args, puncher)).await
+}
+
+async fn run(args: &Args, mut puncher: Puncher) -> Result<()> {
+    match &args.command {
+        Some(Command::Send(send)) => return transfer::send_file(&mut puncher, &send.path).await,
+        Some(Command::Receive(receive)) => {
+            let path = transfer::receive_file(&mut puncher, &receive.dir).await?;
+            tracing::info!("saved {:?}", path);
+            return Ok(());
         }
+        _ => {}
     }
-    let mut stream = stream2.unwrap();
-
-    // step 4 send & recv message
-
-    let mut timer = interval(Duration::from_secs(1));
-    loop {
-        tokio::select! {
-            _ = timer.tick() => {
-                let msg = format!("hello {} I'm {}", args.peer_id, args.id);
-                match stream.write_all(msg.as_bytes()).await {
-                    Ok(_) => log::info!("send ok"),
-                    Err(e) => log::warn!("send failed:{:?}", e),
-                }
-            }
-            n = stream.read(&mut buf) => {
-                match n {
-                    Ok(n) => {
-                        let msg = String::from_utf8_lossy(&buf[..n]);
-                        log::info!("recv {} from {}", msg, args.peer_id);
-                    }
-                    Err(e) => log::warn!("recv failed:{:?}", e),
-                }
-            }
-        }
+    let session = Session::connect(puncher).await?;
+    if let Some(Command::Forward(forward)) = &args.command {
+        let initiator = session.initiator();
+        return tunnel::forward(session, initiator, forward).await;
     }
+
+    // step 4 chat with the peer
+    chat::run(
+        session,
+        &mut chat::stdin_lines(),
+        &args.id,
+        Some(args.peer_id.clone()),
+    )
+    .await
 }
 
 /*
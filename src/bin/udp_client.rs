@@ -1,20 +1,24 @@
 use anyhow::Result;
-use clap::Parser;
-use log;
-use punch::simple::*;
-use std::net::SocketAddr;
-use tokio::{
-    self,
-    net::UdpSocket,
-    time::{interval, sleep, timeout, Duration},
+use clap::{Parser, Subcommand};
+use punch::{
+    chat,
+    logging::{self, LogFormat},
+    puncher::{Puncher, Strategy},
+    rendezvous::ServerList,
+    session::Session,
+    shutdown,
+    transfer::{self, ReceiveArgs, SendArgs},
+    tunnel::{self, ForwardArgs},
 };
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Server addr
-    #[clap(short, long)]
-    server: String,
+    /// Server host:port, give it again to fail over to another server; the ipv4
+    /// and ipv6 addrs of one server can be joined with a comma
+    #[clap(short, long, required = true)]
+    server: Vec<String>,
 
     /// id
     #[clap(short, long)]
@@ -23,104 +27,72 @@ struct Args {
     /// peer id
     #[clap(short, long)]
     peer_id: String,
+
+    /// Write logs as text or as json lines, RUST_LOG picks what is logged
+    #[clap(long, value_enum, default_value = "text")]
+    log_format: LogFormat,
+
+    /// Write a json report of the punch attempts to this file
+    #[clap(long)]
+    report: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Forward tcp ports or serve SOCKS5 over the punched connection instead of chatting
+    Forward(ForwardArgs),
+    /// Send a file, resuming after the connection drops
+    Send(SendArgs),
+    /// Receive a file, resuming after the connection drops
+    Receive(ReceiveArgs),
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    std::env::set_var("RUST_LOG", "info");
-    env_logger::init();
     let args = Args::parse();
-    let mut buf = vec![0u8; 1024];
-
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-
-    // step 1: connect server
-    loop {
-        let addr: SocketAddr = args.server.parse().expect("bad server addr");
-        match timeout(Duration::from_secs(3), socket.connect(addr)).await {
-            Ok(_) => {
-                log::info!("connect to {} ok!", addr);
-                break;
-            }
-            Err(e) => {
-                log::warn!("connect to {} time out :{:?}", addr, e);
-            }
-        }
+    logging::init(None, args.log_format)?;
+
+    // step 1-3: connect server, register and get peer addr, connect to peer
+    let mut puncher = Puncher::new(
+        Strategy::Udp,
+        ServerList::new(&args.server)?,
+        args.id.clone(),
+        Some(args.peer_id.clone()),
+    );
+    if let Some(path) = &args.report {
+        puncher = puncher.report(path);
     }
+    let leave = puncher.leave();
+    shutdown::client(leave, run(&args, puncher)).await
+}
 
-    // step 2: register and get peer addr
-    #[allow(unused_assignments)]
-    let mut peer_addr: Option<SocketAddr> = None;
-    loop {
-        sleep(Duration::from_secs(1)).await;
-        let msg = Register {
-            id: args.id.clone(),
-            peer_id: args.peer_id.clone(),
-        };
-        match socket.send(&msg.encode()).await {
-            Ok(_) => log::info!("send register ok"),
-            Err(e) => {
-                log::error!("send register falied. {:?}", e);
-                continue;
-            }
-        }
-
-        match timeout(Duration::from_secs(10), socket.recv(&mut buf)).await {
-            Ok(Ok(size)) => {
-                if let Ok(peer) = Peer::decode(&buf[..size]) {
-                    if let Some(addr) = peer.peer_addr {
-                        log::info!("get peer addr {:?}", addr);
-                        peer_addr = Some(addr);
-                        break;
-                    } else {
-                        log::info!("peer is not registered yet");
-                    }
-                }
-            }
-            _ => {
-                log::warn!("wait register response timeout");
-                continue;
-            }
+async fn run(args: &Args, mut puncher: Puncher) -> Result<()> {
+    match &args.command {
+        Some(Command::Send(send)) => return transfer::send_file(&mut puncher, &send.path).await,
+        Some(Command::Receive(receive)) => {
+            let path = transfer::receive_file(&mut puncher, &receive.dir).await?;
+            tracing::info!("saved {:?}", path);
+            return Ok(());
         }
+        _ => {}
     }
-
-    // step 3 connect to peer
-    let peer_addr = peer_addr.unwrap();
-    loop {
-        match timeout(Duration::from_secs(3), socket.connect(&peer_addr)).await {
-            Ok(_) => {
-                log::info!("connect to peer {:?} success.", peer_addr);
-                break;
-            }
-            Err(e) => {
-                log::warn!("Failed to connect to peer {:?} {:?}", peer_addr, e);
-            }
-        }
+    let session = Session::connect(puncher).await?;
+    if let Some(Command::Forward(forward)) = &args.command {
+        let initiator = session.initiator();
+        return tunnel::forward(session, initiator, forward).await;
     }
 
-    // step 4 send & recv message
-
-    let mut timer = interval(Duration::from_secs(1));
-    loop {
-        tokio::select! {
-            _ = timer.tick() => {
-                let msg = format!("hello {} I'm {}", args.peer_id, args.id);
-                match socket.send(msg.as_bytes()).await {
-                    Ok(_) => log::info!("send ok"),
-                    Err(e) => log::warn!("send failed:{:?}", e),
-                }
-            }
-            n = socket.recv(&mut buf) => {
-                match n {
-                    Ok(n) => {
-                        let msg = String::from_utf8_lossy(&buf[..n]);
-                        log::info!("recv {} from {}", msg, args.peer_id);
-                    }
-                    Err(e) => log::warn!("recv failed:{:?}", e),
-                }
-            }
-        }
-    }
+    // step 4 chat with the peer
+    chat::run(
+        session,
+        &mut chat::stdin_lines(),
+        &args.id,
+        Some(args.peer_id.clone()),
+    )
+    .await
 }
 
 /*
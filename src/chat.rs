@@ -0,0 +1,196 @@
+use crate::{
+    hybrid::Message,
+    session::{Session, SessionInfo},
+};
+use anyhow::{bail, Result};
+use futures::{future::BoxFuture, FutureExt, SinkExt, StreamExt};
+use std::time::SystemTime;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::mpsc,
+    time::Instant,
+};
+use tokio_util::codec::{Framed, LinesCodec};
+
+const MAX_LINE: usize = 64 * 1024;
+
+/// Reads stdin lines in the background. Create it once and pass it to every
+/// [`run`], so nothing typed between two connections gets lost.
+pub fn stdin_lines() -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if tx.send(line).await.is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// What a chat runs over: a [`Session`], or any stream that can tell about
+/// itself the way a session does.
+pub trait Link: AsyncRead + AsyncWrite + Unpin {
+    fn info(&self) -> SessionInfo;
+    /// Closes our direction once the chat is over.
+    fn close(self) -> BoxFuture<'static, Result<()>>;
+}
+
+impl Link for Session {
+    fn info(&self) -> SessionInfo {
+        Session::info(self)
+    }
+
+    fn close(self) -> BoxFuture<'static, Result<()>> {
+        Session::close(self).boxed()
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    sent: u64,
+    sent_bytes: u64,
+    received: u64,
+    received_bytes: u64,
+}
+
+/// Interactive chat: stdin lines go to the peer as `messageAB`, messages from
+/// the peer are printed with its id and a timestamp.
+///
+/// Short connection losses are bridged by the session. Returns `Ok` on
+/// `/quit` or when the peer quits, an error when the session is gone.
+pub async fn run(
+    session: Session,
+    lines: &mut mpsc::Receiver<String>,
+    id: &str,
+    peer_id: Option<String>,
+) -> Result<()> {
+    run_with(session, lines, &mut tokio::io::stdout(), id, peer_id).await
+}
+
+/// [`run`] over any [`Link`], printing to `out` instead of stdout.
+pub async fn run_with<L, W>(
+    session: L,
+    lines: &mut mpsc::Receiver<String>,
+    out: &mut W,
+    id: &str,
+    mut peer_id: Option<String>,
+) -> Result<()>
+where
+    L: Link,
+    W: AsyncWrite + Unpin,
+{
+    let info = session.info();
+    let peer_addr = info.peer_addr.map(|addr| addr.to_string()).unwrap_or_default();
+    tracing::info!("begin chat.local:{:?} peer:{:?}", info.local_addr, info.peer_addr);
+    let mut chat = Framed::new(session, LinesCodec::new_with_max_length(MAX_LINE));
+    let mut stats = Stats::default();
+    let started = Instant::now();
+    let mut stdin_open = true;
+
+    send(&mut chat, &Message::helloAB(id.to_owned())).await?;
+    print(
+        out,
+        format!(
+            "connected to {}, commands: /quit /stats /peer",
+            peer_name(&peer_id, &peer_addr)
+        ),
+    )
+    .await?;
+
+    loop {
+        tokio::select! {
+            line = chat.next() => {
+                let line = match line {
+                    Some(Ok(line)) => line,
+                    Some(Err(e)) => bail!("recv failed: {:?}", e),
+                    None => {
+                        print(out, format!("{} left", peer_name(&peer_id, &peer_addr))).await?;
+                        if let Err(e) = chat.into_inner().close().await {
+                            tracing::debug!("close after peer left: {:?}", e);
+                        }
+                        return Ok(());
+                    }
+                };
+                match Message::decode(line.as_bytes()) {
+                    Ok(Message::helloAB(id)) => {
+                        tracing::info!("peer is {}", id);
+                        peer_id = Some(id);
+                    }
+                    Ok(Message::messageAB(text)) => {
+                        stats.received += 1;
+                        stats.received_bytes += text.len() as u64;
+                        print(out, format!(
+                            "[{}] {}: {}",
+                            humantime::format_rfc3339_seconds(SystemTime::now()),
+                            peer_name(&peer_id, &peer_addr),
+                            text
+                        )).await?;
+                    }
+                    Ok(msg) => tracing::warn!("unexpected chat message {:?}", msg),
+                    Err(e) => tracing::warn!("chat msg decode failed: {:?}", e),
+                }
+            }
+            line = lines.recv(), if stdin_open => {
+                let line = match line {
+                    Some(line) => line,
+                    None => {
+                        // keep printing what the peer sends
+                        stdin_open = false;
+                        continue;
+                    }
+                };
+                match line.trim() {
+                    "" => {}
+                    "/quit" => return chat.into_inner().close().await,
+                    "/stats" => print(out, format!(
+                        "up {:?}, sent {} messages ({} bytes), received {} messages ({} bytes)",
+                        started.elapsed(),
+                        stats.sent,
+                        stats.sent_bytes,
+                        stats.received,
+                        stats.received_bytes
+                    )).await?,
+                    "/peer" => {
+                        let info = chat.get_ref().info();
+                        print(out, format!(
+                            "peer {} at {:?}, local {:?}, session {:x}, reconnected {} times",
+                            peer_id.as_deref().unwrap_or("(unknown id)"),
+                            info.peer_addr,
+                            info.local_addr,
+                            info.id,
+                            info.reconnects
+                        )).await?
+                    }
+                    cmd if cmd.starts_with('/') => {
+                        print(out, format!("unknown command {}, try /quit /stats /peer", cmd)).await?
+                    }
+                    _ => {
+                        stats.sent += 1;
+                        stats.sent_bytes += line.len() as u64;
+                        send(&mut chat, &Message::messageAB(line)).await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn send<L: Link>(chat: &mut Framed<L, LinesCodec>, msg: &Message) -> Result<()> {
+    chat.send(String::from_utf8(msg.encode())?).await?;
+    Ok(())
+}
+
+async fn print<W: AsyncWrite + Unpin>(out: &mut W, line: String) -> Result<()> {
+    out.write_all(format!("{}\n", line).as_bytes()).await?;
+    out.flush().await?;
+    Ok(())
+}
+
+fn peer_name(peer_id: &Option<String>, peer_addr: &str) -> String {
+    match peer_id {
+        Some(id) => id.clone(),
+        None => peer_addr.to_owned(),
+    }
+}
@@ -0,0 +1,623 @@
+//! Rendezvous server and client settings, from an optional toml file and the
+//! command line.
+//!
+//! ```toml
+//! bind = ["0.0.0.0:12345"]        # tcp and udp, ipv6 addrs take ipv6 only
+//! tcp = ["[::]:12346"]            # tcp only
+//! udp = []                        # udp only
+//! websocket = ["0.0.0.0:8080"]    # hybrid only: the same messages over WebSocket
+//! log_level = "info,punch=debug"   # wins over RUST_LOG
+//! log_format = "json"             # or "text"
+//! registration_ttl = "2m"
+//! drain_timeout = "10s"           # on SIGINT/SIGTERM, how long punches under way may take
+//! store = "/var/lib/punch/registry.log"  # keeps registrations and bans over restarts
+//! metrics = "127.0.0.1:9100"     # prometheus endpoint, off when not set
+//!
+//! [limits]
+//! max_registrations = 10000
+//! max_connections = 1024
+//! max_websockets = 1024           # hybrid: open WebSocket connections
+//!
+//! [rate_limit]                    # per second, 0 turns a limit off
+//! ip_rate = 50                    # requests of one source ip
+//! ip_burst = 100
+//! id_rate = 5                     # registrations of one id
+//! id_burst = 20
+//! punch_rate = 2                  # punch requests for one peer
+//! punch_burst = 20
+//! ban_after = 100                 # refused requests before the source ip is banned
+//! ban_for = "10m"
+//! no_amplification = true         # udp replies never larger than the request
+//!
+//! [auth]
+//! allow_ids = ["A", "B"]
+//!
+//! [cluster]                       # hybrid: other servers asked for ids not registered here
+//! nodes = ["10.0.0.2:12345", "10.0.0.3:12345"]
+//!
+//! [admin]                         # json api, off when listen is not set
+//! listen = "127.0.0.1:9101"
+//! token = "secret"                # or PUNCH_ADMIN_TOKEN in the environment
+//!
+//! [client]                        # for `punch connect`, `listen` and the others
+//! servers = ["punch.example.com:12345"]
+//! id = "A"
+//! peer_id = "B"
+//! strategy = "auto"               # udp, tcp, hybrid or auto: ask the server
+//! report = "punch.json"
+//! ```
+//!
+//! There is no relay: peers that cannot punch to each other do not connect,
+//! so there are no settings for one either.
+
+use crate::{
+    admin,
+    limit::{Limiter, RateLimit},
+    logging::{self, LogFormat},
+    metrics::{self, Metrics},
+    net::{self, Datagram, Network},
+    puncher::{Puncher, Strategy},
+    rendezvous::ServerList,
+    server::{Control, Options},
+    shutdown::Shutdown,
+    store::LogStore,
+};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Deserializer};
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::net::TcpListener;
+
+/// Listen addrs when neither the file nor the command line names one.
+pub const DEFAULT_ADDRS: [&str; 2] = ["0.0.0.0:12345", "[::]:12345"];
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addrs listened on with both tcp and udp
+    pub bind: Vec<SocketAddr>,
+    /// Addrs listened on with tcp only
+    pub tcp: Vec<SocketAddr>,
+    /// Addrs listened on with udp only
+    pub udp: Vec<SocketAddr>,
+    /// Addrs the hybrid server takes WebSocket connections on
+    pub websocket: Vec<SocketAddr>,
+    /// Log filter, wins over RUST_LOG when set
+    pub log_level: Option<String>,
+    pub log_format: LogFormat,
+    /// Serve Prometheus metrics over http on this addr
+    pub metrics: Option<SocketAddr>,
+    /// Registrations not refreshed for this long are forgotten
+    #[serde(deserialize_with = "duration")]
+    pub registration_ttl: Duration,
+    /// How long a stopping server waits for the punches under way
+    #[serde(deserialize_with = "duration")]
+    pub drain_timeout: Duration,
+    /// Append-only log the registry and the bans are kept in, memory only when not set
+    pub store: Option<PathBuf>,
+    pub limits: Limits,
+    pub rate_limit: RateLimit,
+    pub auth: Auth,
+    pub admin: Admin,
+    pub cluster: Cluster,
+    pub client: Client,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// New ids are refused once this many are registered
+    pub max_registrations: usize,
+    /// Tcp connections handled at once
+    pub max_connections: usize,
+    /// WebSocket connections open at once
+    pub max_websockets: usize,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    /// Only these ids may register, empty allows everyone
+    pub allow_ids: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Admin {
+    /// Serve the admin api over http on this addr
+    pub listen: Option<SocketAddr>,
+    /// Bearer token the api asks for
+    pub token: Option<String>,
+}
+
+/// Where the admin token comes from when neither the file nor the command line has it.
+pub const ADMIN_TOKEN_ENV: &str = "PUNCH_ADMIN_TOKEN";
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Client {
+    /// Rendezvous servers as `host:port`, the next one is used when one fails
+    pub servers: Vec<String>,
+    pub id: Option<String>,
+    /// Connect to this peer, listening needs it too except with hybrid
+    pub peer_id: Option<String>,
+    pub strategy: StrategyChoice,
+    /// Write the json report of the punch attempts here
+    pub report: Option<PathBuf>,
+}
+
+/// The punch method of a client, or `auto` to use whatever the server runs.
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StrategyChoice {
+    #[default]
+    Auto,
+    Udp,
+    Tcp,
+    Hybrid,
+}
+
+impl StrategyChoice {
+    /// None for `auto`.
+    pub fn strategy(self) -> Option<Strategy> {
+        match self {
+            StrategyChoice::Auto => None,
+            StrategyChoice::Udp => Some(Strategy::Udp),
+            StrategyChoice::Tcp => Some(Strategy::Tcp),
+            StrategyChoice::Hybrid => Some(Strategy::Hybrid),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cluster {
+    /// Tcp addrs of the other hybrid servers, they must list this one too
+    pub nodes: Vec<SocketAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let options = Options::default();
+        Self {
+            bind: Vec::new(),
+            tcp: Vec::new(),
+            udp: Vec::new(),
+            websocket: Vec::new(),
+            log_level: None,
+            log_format: LogFormat::Text,
+            metrics: None,
+            registration_ttl: options.registration_ttl,
+            drain_timeout: options.drain_timeout,
+            store: None,
+            limits: Limits {
+                max_registrations: options.max_registrations,
+                max_connections: options.max_connections,
+                max_websockets: options.max_websockets,
+            },
+            rate_limit: RateLimit::default(),
+            auth: Auth::default(),
+            admin: Admin::default(),
+            cluster: Cluster::default(),
+            client: Client::default(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Config::default().limits
+    }
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Duration, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    humantime::parse_duration(&text).map_err(serde::de::Error::custom)
+}
+
+impl Config {
+    pub fn from_toml(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("read {:?}", path))?;
+        Self::from_toml(&text).with_context(|| format!("parse {:?}", path))
+    }
+
+    pub fn tcp_addrs(&self) -> Vec<SocketAddr> {
+        self.bind.iter().chain(&self.tcp).copied().collect()
+    }
+
+    pub fn udp_addrs(&self) -> Vec<SocketAddr> {
+        self.bind.iter().chain(&self.udp).copied().collect()
+    }
+
+    /// The rules for [`crate::server`], fails on settings the servers cannot
+    /// honour. Opens the store, so call it once.
+    pub fn options(&self) -> Result<Options> {
+        if self.limits.max_connections == 0 {
+            bail!("max_connections must be at least 1");
+        }
+        if self.limits.max_websockets == 0 {
+            bail!("max_websockets must be at least 1");
+        }
+        let control = match &self.store {
+            Some(path) => {
+                Control::with_store(Arc::new(LogStore::open(path, self.registration_ttl)?))?
+            }
+            None => Control::default(),
+        };
+        let metrics = Arc::new(Metrics::default());
+        Ok(Options {
+            limiter: Arc::new(Limiter::new(self.rate_limit.clone(), metrics.clone())),
+            metrics,
+            control: Arc::new(control),
+            registration_ttl: self.registration_ttl,
+            max_registrations: self.limits.max_registrations,
+            max_connections: self.limits.max_connections,
+            max_websockets: self.limits.max_websockets,
+            allow_ids: self.auth.allow_ids.clone(),
+            cluster: self.cluster.nodes.clone(),
+            shutdown: Shutdown::default(),
+            drain_timeout: self.drain_timeout,
+        })
+    }
+
+    /// Starts logging at `log_level`, or at RUST_LOG, or at `info`.
+    pub fn init_logger(&self) -> Result<()> {
+        logging::init(self.log_level.as_deref(), self.log_format)
+    }
+
+    /// A puncher for the `[client]` settings, asking the server which strategy
+    /// it runs for `auto`. With `listen` it waits for the peer: hybrid B, or
+    /// the tcp side that listens on its registered port.
+    pub async fn puncher(&self, listen: bool) -> Result<Puncher> {
+        let client = &self.client;
+        let id = match &client.id {
+            Some(id) => id.clone(),
+            None => bail!("no id, give --id or set it in [client]"),
+        };
+        let mut servers = ServerList::new(&client.servers)?;
+        let strategy = match client.strategy.strategy() {
+            Some(strategy) => strategy,
+            None => Strategy::detect(&mut servers, &Network::Os, &id).await?,
+        };
+        // a hybrid side with a peer id connects, udp and tcp check it when punching
+        let peer_id = match (strategy, listen, &client.peer_id) {
+            (Strategy::Hybrid, true, _) => None,
+            (Strategy::Hybrid, false, None) => {
+                bail!("no peer id, give --peer-id or set it in [client]")
+            }
+            _ => client.peer_id.clone(),
+        };
+        let mut puncher = Puncher::new(strategy, servers, id, peer_id)
+            .tcp_listener(listen && strategy == Strategy::Tcp);
+        if let Some(path) = &client.report {
+            puncher = puncher.report(path);
+        }
+        Ok(puncher)
+    }
+
+    pub fn tcp_listeners(&self) -> Result<Vec<TcpListener>> {
+        let mut listeners = Vec::new();
+        for addr in self.tcp_addrs() {
+            match net::bind_tcp(addr) {
+                Ok(listener) => listeners.push(listener),
+                Err(e) if no_ipv6(addr, &e) => tracing::warn!("skip tcp {}: {}", addr, e),
+                Err(e) => return Err(e).with_context(|| format!("listen on tcp {}", addr)),
+            }
+        }
+        if listeners.is_empty() {
+            bail!("no tcp addr to listen on");
+        }
+        Ok(listeners)
+    }
+
+    /// Listeners for the WebSocket clients, none when not configured.
+    pub fn websocket_listeners(&self) -> Result<Vec<TcpListener>> {
+        self.websocket
+            .iter()
+            .map(|&addr| {
+                net::bind_tcp(addr).with_context(|| format!("listen on websocket {}", addr))
+            })
+            .collect()
+    }
+
+    /// Starts the metrics endpoint if one is configured.
+    pub fn serve_metrics(&self, options: &Options) -> Result<()> {
+        if let Some(addr) = self.metrics {
+            let listener =
+                net::bind_tcp(addr).with_context(|| format!("listen on metrics {}", addr))?;
+            let metrics = options.metrics.clone();
+            tokio::spawn(async move {
+                if let Err(e) = metrics::serve(listener, metrics).await {
+                    tracing::error!("metrics endpoint stopped: {:?}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Starts the admin api if it is configured, fails without a token.
+    pub fn serve_admin(&self, options: &Options) -> Result<()> {
+        let addr = match self.admin.listen {
+            Some(addr) => addr,
+            None => return Ok(()),
+        };
+        let token = match self.admin.token.clone() {
+            Some(token) => token,
+            None => std::env::var(ADMIN_TOKEN_ENV).unwrap_or_default(),
+        };
+        if token.is_empty() {
+            bail!(
+                "the admin api needs a token, set one or {}",
+                ADMIN_TOKEN_ENV
+            );
+        }
+        let listener = net::bind_tcp(addr).with_context(|| format!("listen on admin {}", addr))?;
+        let control = options.control.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(listener, control, token).await {
+                tracing::error!("admin api stopped: {:?}", e);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn udp_sockets(&self) -> Result<Vec<Datagram>> {
+        let mut sockets = Vec::new();
+        for addr in self.udp_addrs() {
+            match net::bind_udp(addr) {
+                Ok(socket) => sockets.push(Datagram::Os(socket)),
+                Err(e) if no_ipv6(addr, &e) => tracing::warn!("skip udp {}: {}", addr, e),
+                Err(e) => return Err(e).with_context(|| format!("bind udp {}", addr)),
+            }
+        }
+        if sockets.is_empty() {
+            bail!("no udp addr to listen on");
+        }
+        Ok(sockets)
+    }
+}
+
+// `[::]` on a host without ipv6, the server still runs on ipv4
+fn no_ipv6(addr: SocketAddr, e: &io::Error) -> bool {
+    addr.is_ipv6()
+        && addr.ip().is_unspecified()
+        && !matches!(
+            e.kind(),
+            io::ErrorKind::AddrInUse | io::ErrorKind::PermissionDenied
+        )
+}
+
+/// Command line of the server binaries, every flag overrides the config file.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ServerArgs {
+    /// Toml config file
+    #[clap(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Listen addr for both tcp and udp, may be repeated [default: 0.0.0.0:12345 and [::]:12345]
+    #[clap(short, long)]
+    pub bind: Vec<SocketAddr>,
+
+    /// Listen addr for tcp only, may be repeated
+    #[clap(long)]
+    pub tcp: Vec<SocketAddr>,
+
+    /// Listen addr for udp only, may be repeated
+    #[clap(long)]
+    pub udp: Vec<SocketAddr>,
+
+    /// Listen addr for WebSocket clients of the hybrid server, may be repeated
+    #[clap(long)]
+    pub websocket: Vec<SocketAddr>,
+
+    /// Log filter like `info` or `warn,punch=debug`
+    #[clap(long)]
+    pub log_level: Option<String>,
+
+    /// Write logs as text or as json lines
+    #[clap(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Serve Prometheus metrics on http://<addr>/metrics
+    #[clap(long)]
+    pub metrics: Option<SocketAddr>,
+
+    /// Serve the admin api on this addr
+    #[clap(long)]
+    pub admin: Option<SocketAddr>,
+
+    /// Token for the admin api, visible to other local users, prefer the file or PUNCH_ADMIN_TOKEN
+    #[clap(long)]
+    pub admin_token: Option<String>,
+
+    /// Forget registrations not refreshed for this long, like `90s` or `5m`
+    #[clap(long, value_parser = humantime::parse_duration)]
+    pub registration_ttl: Option<Duration>,
+
+    /// On SIGINT or SIGTERM, wait this long for the punches under way, like `10s`
+    #[clap(long, value_parser = humantime::parse_duration)]
+    pub drain_timeout: Option<Duration>,
+
+    /// Keep registrations and bans in this file over restarts
+    #[clap(long)]
+    pub store: Option<PathBuf>,
+
+    /// Refuse new ids once this many are registered
+    #[clap(long)]
+    pub max_registrations: Option<usize>,
+
+    /// Tcp connections handled at once
+    #[clap(long)]
+    pub max_connections: Option<usize>,
+
+    /// Hybrid: WebSocket connections open at once
+    #[clap(long)]
+    pub max_websockets: Option<usize>,
+
+    /// Turn off the rate limits and temporary bans, udp replies stay no larger than requests
+    #[clap(long)]
+    pub no_rate_limit: bool,
+
+    /// Only let this id register, may be repeated
+    #[clap(long)]
+    pub allow_id: Vec<String>,
+
+    /// Tcp addr of another hybrid server of the cluster, may be repeated
+    #[clap(long)]
+    pub cluster_node: Vec<SocketAddr>,
+}
+
+impl ServerArgs {
+    /// Reads the config file if one was given and applies the flags on top.
+    pub fn load(&self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        self.apply(&mut config);
+        Ok(config)
+    }
+
+    pub fn apply(&self, config: &mut Config) {
+        // any listen flag replaces all addrs of the file
+        if !self.bind.is_empty() || !self.tcp.is_empty() || !self.udp.is_empty() {
+            config.bind = self.bind.clone();
+            config.tcp = self.tcp.clone();
+            config.udp = self.udp.clone();
+        }
+        if !self.websocket.is_empty() {
+            config.websocket = self.websocket.clone();
+        }
+        if config.bind.is_empty() && config.tcp.is_empty() && config.udp.is_empty() {
+            config.bind = DEFAULT_ADDRS.iter().map(|a| a.parse().unwrap()).collect();
+        }
+        if let Some(level) = &self.log_level {
+            config.log_level = Some(level.clone());
+        }
+        if let Some(format) = self.log_format {
+            config.log_format = format;
+        }
+        if let Some(addr) = self.metrics {
+            config.metrics = Some(addr);
+        }
+        if let Some(addr) = self.admin {
+            config.admin.listen = Some(addr);
+        }
+        if let Some(token) = &self.admin_token {
+            config.admin.token = Some(token.clone());
+        }
+        if let Some(ttl) = self.registration_ttl {
+            config.registration_ttl = ttl;
+        }
+        if let Some(drain) = self.drain_timeout {
+            config.drain_timeout = drain;
+        }
+        if let Some(path) = &self.store {
+            config.store = Some(path.clone());
+        }
+        if let Some(max) = self.max_registrations {
+            config.limits.max_registrations = max;
+        }
+        if let Some(max) = self.max_connections {
+            config.limits.max_connections = max;
+        }
+        if let Some(max) = self.max_websockets {
+            config.limits.max_websockets = max;
+        }
+        if self.no_rate_limit {
+            config.rate_limit = RateLimit {
+                no_amplification: config.rate_limit.no_amplification,
+                ..RateLimit::off()
+            };
+        }
+        if !self.allow_id.is_empty() {
+            config.auth.allow_ids = self.allow_id.clone();
+        }
+        if !self.cluster_node.is_empty() {
+            config.cluster.nodes = self.cluster_node.clone();
+        }
+    }
+}
+
+/// Command line of the clients, every flag overrides the `[client]` section.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ClientArgs {
+    /// Toml config file
+    #[clap(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Server host:port, give it again to fail over to another server; the ipv4
+    /// and ipv6 addrs of one server can be joined with a comma
+    #[clap(short, long)]
+    pub server: Vec<String>,
+
+    /// Our id at the server
+    #[clap(short, long)]
+    pub id: Option<String>,
+
+    /// Id of the peer to punch to
+    #[clap(short, long)]
+    pub peer_id: Option<String>,
+
+    /// How to punch, auto asks the server [default: auto]
+    #[clap(long, value_enum)]
+    pub strategy: Option<StrategyChoice>,
+
+    /// Write a json report of the punch attempts to this file
+    #[clap(long)]
+    pub report: Option<PathBuf>,
+
+    /// Log filter like `info` or `warn,punch=debug`
+    #[clap(long)]
+    pub log_level: Option<String>,
+
+    /// Write logs as text or as json lines
+    #[clap(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+}
+
+impl ClientArgs {
+    /// Reads the config file if one was given and applies the flags on top.
+    pub fn load(&self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        self.apply(&mut config);
+        Ok(config)
+    }
+
+    pub fn apply(&self, config: &mut Config) {
+        let client = &mut config.client;
+        if !self.server.is_empty() {
+            client.servers = self.server.clone();
+        }
+        if let Some(id) = &self.id {
+            client.id = Some(id.clone());
+        }
+        if let Some(peer_id) = &self.peer_id {
+            client.peer_id = Some(peer_id.clone());
+        }
+        if let Some(strategy) = self.strategy {
+            client.strategy = strategy;
+        }
+        if let Some(path) = &self.report {
+            client.report = Some(path.clone());
+        }
+        if let Some(level) = &self.log_level {
+            config.log_level = Some(level.clone());
+        }
+        if let Some(format) = self.log_format {
+            config.log_format = format;
+        }
+    }
+}
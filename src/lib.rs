@@ -1,27 +1,75 @@
 use anyhow::{bail, Result};
+use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
-use serde_json;
 use std::{net::SocketAddr, time::Duration};
 use tokio::{
     net::{lookup_host, TcpListener, TcpSocket, TcpStream, ToSocketAddrs},
     time::timeout,
 };
 
+pub mod admin;
+pub mod chat;
+pub mod config;
+pub mod http;
+pub mod limit;
+pub mod logging;
+pub mod metrics;
+pub mod mux;
+pub mod net;
+pub mod puncher;
+pub mod report;
+pub mod rendezvous;
+pub mod rudp;
+pub mod server;
+pub mod session;
+pub mod shutdown;
+pub mod signaling;
+pub mod sim;
+pub mod socks;
+pub mod store;
+pub mod transfer;
+pub mod trail;
+pub mod trigger;
+pub mod tunnel;
+
 //用于hybrid_client/hybrid_server
 pub mod hybrid {
     use super::*;
 
     #[allow(non_camel_case_types)]
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
     pub enum Message {
         register_request(String),     // id_A
         register_response(u8),        // one byte
         punchA2S(String),             // id_B
         punchS2B(SocketAddr),         // A_tcp_addr
-        punchB2S(u8),                 // one byte
+        punchB2S(Answer),             // which A to answer
         punchS2A(Option<SocketAddr>), // B_tcp_addr
         messageAB(String),            // message between AB
+        helloAB(String),              // id, first message between AB
+        addr_request(String),         // id, asks for the udp addr the server sees
+        addr_response(SocketAddr),    // that addr
+        routeA2S(String, SocketAddr), // id_B, A_tcp_addr: another server asks for its B, answered with punchS2A
+        unregister_request(String),   // id, the client leaves, not answered
+        going_away,                   // the server shuts down, register at the next one
+        lookup_request(String, String), // asker's id, id: sent where the asker registered, answered with lookup_response
+        lookup_response(Option<SocketAddr>), // udp addr of the id in the asker's family
+        signal(signaling::Signal),    // sent where signal.from registered, relayed to the registered signal.to
+        signal_sent(bool),            // whether the signal went out
+    }
+
+    /// What B answers punchS2B with. Clients from before it said which A
+    /// send one byte, the server then goes by B's ip, which is ambiguous
+    /// when two Bs behind one nat are asked at once. Servers from before it
+    /// only decode the byte, B sends it to servers that never answered its
+    /// addr_request.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    #[serde(untagged)]
+    pub enum Answer {
+        A(SocketAddr), // A_tcp_addr from punchS2B
+        Legacy(u8),    // one byte
     }
+
     impl Message {
         pub fn encode(&self) -> Vec<u8> {
             serde_json::to_vec(self).unwrap()
@@ -37,7 +85,7 @@ pub mod hybrid {
 pub mod simple {
     use super::*;
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
     pub struct Register {
         pub id: String,
         pub peer_id: String,
@@ -53,7 +101,72 @@ pub mod simple {
         }
     }
 
-    #[derive(Serialize, Deserialize, Debug, Default)]
+    /// Asks the server for the addr it sees `id` at, answered with [`Observed`].
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct Observe {
+        pub id: String,
+    }
+
+    impl Observe {
+        pub fn encode(&self) -> Vec<u8> {
+            serde_json::to_vec(self).unwrap()
+        }
+
+        pub fn decode(data: &[u8]) -> Result<Self> {
+            Ok(serde_json::from_slice(data)?)
+        }
+    }
+
+    /// Drops the registration of the id, sent by a client before it exits
+    /// from the addr it registered from. Not answered.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct Unregister {
+        pub unregister: String,
+    }
+
+    impl Unregister {
+        pub fn encode(&self) -> Vec<u8> {
+            serde_json::to_vec(self).unwrap()
+        }
+
+        pub fn decode(data: &[u8]) -> Result<Self> {
+            Ok(serde_json::from_slice(data)?)
+        }
+    }
+
+    /// The server at this addr shuts down, register at the next one. Sent to
+    /// the registered clients; decode it before [`Peer`], which takes any object.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct GoingAway {
+        pub going_away: SocketAddr,
+    }
+
+    impl GoingAway {
+        pub fn encode(&self) -> Vec<u8> {
+            serde_json::to_vec(self).unwrap()
+        }
+
+        pub fn decode(data: &[u8]) -> Result<Self> {
+            Ok(serde_json::from_slice(data)?)
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    pub struct Observed {
+        pub addr: SocketAddr,
+    }
+
+    impl Observed {
+        pub fn encode(&self) -> Vec<u8> {
+            serde_json::to_vec(self).unwrap()
+        }
+
+        pub fn decode(data: &[u8]) -> Result<Self> {
+            Ok(serde_json::from_slice(data)?)
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
     pub struct Peer {
         pub peer_addr: Option<SocketAddr>,
     }
@@ -69,6 +182,90 @@ pub mod simple {
     }
 }
 
+/// An addr one side of a punch may be reached at, what a [`PunchStrategy`]
+/// gathers and trades with the peer.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    /// Where the other side sends to, the public addr when a server told it
+    pub addr: SocketAddr,
+    /// The local addr it maps to
+    #[serde(default)]
+    pub local: Option<SocketAddr>,
+}
+
+/// A local addr to punch from and the peer's addr to punch to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pair {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+}
+
+/// Who punches to whom through which server, handed to every step of a
+/// [`PunchStrategy`].
+pub struct PunchContext {
+    pub id: String,
+    /// None for the hybrid side that waits to be asked
+    pub peer_id: Option<String>,
+    /// Addrs of the rendezvous server in use, ipv6 first
+    pub server_addrs: Vec<SocketAddr>,
+    /// Where udp sockets are bound
+    pub network: net::Network,
+    /// Where the steps are recorded for the report
+    pub events: report::Events,
+}
+
+/// A way to punch a hole, in three steps [`punch_with`] runs one after the
+/// other. The built-in ones are [`puncher::UdpPunch`], [`puncher::TcpPunch`]
+/// and [`puncher::HybridPunch`]; others are added to a [`puncher::Puncher`]
+/// with [`puncher::Puncher::strategy`] and run alongside its built-in one.
+pub trait PunchStrategy: Send + Sync {
+    /// For the logs
+    fn name(&self) -> &str;
+
+    /// Binds our local addrs and finds out where the peer may reach them.
+    fn gather<'a>(&'a self, punch: &'a PunchContext) -> BoxFuture<'a, Result<Vec<Candidate>>>;
+
+    /// Trades `ours` for the peer's candidates through the signaling, and
+    /// pairs them up with the most promising pair first.
+    fn exchange<'a>(
+        &'a self,
+        punch: &'a PunchContext,
+        ours: Vec<Candidate>,
+    ) -> BoxFuture<'a, Result<Vec<Pair>>>;
+
+    /// Punches through `pairs` until one of them connects.
+    fn connect<'a>(
+        &'a self,
+        punch: &'a PunchContext,
+        pairs: Vec<Pair>,
+    ) -> BoxFuture<'a, Result<puncher::Punched>>;
+}
+
+/// Gathers, exchanges and connects with `strategy`.
+pub async fn punch_with(
+    strategy: &dyn PunchStrategy,
+    punch: &PunchContext,
+) -> Result<puncher::Punched> {
+    let ours = strategy.gather(punch).await?;
+    tracing::debug!("{} gathered {:?}", strategy.name(), ours);
+    let pairs = strategy.exchange(punch, ours).await?;
+    tracing::debug!("{} pairs {:?}", strategy.name(), pairs);
+    strategy.connect(punch, pairs).await
+}
+
+/// Udp requests are padded to this many bytes. Servers do not answer with
+/// more bytes than they were sent, and none of their answers is longer.
+pub const UDP_REQUEST_SIZE: usize = 96;
+
+/// Pads an encoded udp request with spaces, which json ignores, to
+/// [`UDP_REQUEST_SIZE`].
+pub fn pad_request(mut data: Vec<u8>) -> Vec<u8> {
+    if data.len() < UDP_REQUEST_SIZE {
+        data.resize(UDP_REQUEST_SIZE, b' ');
+    }
+    data
+}
+
 pub fn new_tcp_socket(
     addr: std::net::SocketAddr,
     reuse: bool,
@@ -89,6 +286,7 @@ pub fn new_tcp_socket(
     Ok(socket)
 }
 
+#[allow(clippy::never_loop)]
 pub async fn new_tcp_stream<T1: ToSocketAddrs, T2: ToSocketAddrs>(
     remote_addr: T1,
     local_addr: T2,
@@ -121,14 +319,17 @@ pub async fn new_tcp_listener<T: ToSocketAddrs>(addr: T, reuse: bool) -> Result<
     }
 }
 
+#[doc(hidden)]
+pub use tracing as __tracing;
+
+/// Logs an error that is not worth more, in the spans of the caller.
 #[macro_export]
 macro_rules! allow_err {
     ($e:expr) => {
         if let Err(err) = $e {
-            log::warn!(
-                "{:?}, {}:{}:{}:{}",
-                err,
-                module_path!(),
+            $crate::__tracing::warn!(
+                error = ?err,
+                "ignored at {}:{}:{}",
                 file!(),
                 line!(),
                 column!()
@@ -0,0 +1,307 @@
+//! Abuse protection of the rendezvous servers. Every request costs a token
+//! from the bucket of its source ip, a registration one from the bucket of
+//! its id and a punch request one from the bucket of the B it asks for, so
+//! the server can not be used to flood a third party. A source ip refused
+//! too often is banned for a while.
+
+use crate::metrics::Metrics;
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Buckets kept per kind, new sources beyond it are refused until the purge
+/// made room again.
+pub const MAX_BUCKETS: usize = 100_000;
+
+/// Rates in requests per second, 0 turns that limit off.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    /// Requests per second from one source ip
+    pub ip_rate: u32,
+    pub ip_burst: u32,
+    /// Registrations per second of one id, heartbeats included
+    pub id_rate: u32,
+    pub id_burst: u32,
+    /// Punch requests per second for one B
+    pub punch_rate: u32,
+    pub punch_burst: u32,
+    /// Refused requests of one source ip before it is banned, 0 never bans
+    pub ban_after: u32,
+    /// How long a ban lasts, and how long refusals are counted towards one
+    #[serde(deserialize_with = "duration")]
+    pub ban_for: Duration,
+    /// Drop udp replies larger than their request
+    pub no_amplification: bool,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            ip_rate: 50,
+            ip_burst: 100,
+            id_rate: 5,
+            id_burst: 20,
+            punch_rate: 2,
+            punch_burst: 20,
+            ban_after: 100,
+            ban_for: Duration::from_secs(600),
+            no_amplification: true,
+        }
+    }
+}
+
+impl RateLimit {
+    /// No limits and no bans, replies to udp requests may be larger.
+    pub fn off() -> Self {
+        Self {
+            ip_rate: 0,
+            id_rate: 0,
+            punch_rate: 0,
+            ban_after: 0,
+            no_amplification: false,
+            ..Self::default()
+        }
+    }
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    humantime::parse_duration(&text).map_err(serde::de::Error::custom)
+}
+
+/// What a request is, for the buckets it takes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request<'a> {
+    /// A registration or heartbeat of this id
+    Register(&'a str),
+    /// A punch request for this B
+    Punch(&'a str),
+    Other,
+}
+
+/// Why a request was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refused {
+    /// The source ip is banned for now
+    Banned,
+    /// Too many requests from the source ip
+    Ip,
+    /// Too many registrations of the id
+    Id,
+    /// Too many punch requests for the B
+    Punch,
+    /// Udp: the reply would be larger than the request
+    Amplification,
+}
+
+impl Refused {
+    pub(crate) const ALL: [Refused; 5] = [
+        Refused::Banned,
+        Refused::Ip,
+        Refused::Id,
+        Refused::Punch,
+        Refused::Amplification,
+    ];
+
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Refused::Banned => "banned",
+            Refused::Ip => "ip",
+            Refused::Id => "id",
+            Refused::Punch => "punch",
+            Refused::Amplification => "amplification",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+#[derive(Debug)]
+struct Buckets<K> {
+    rate: f64,
+    burst: f64,
+    buckets: HashMap<K, Bucket>,
+}
+
+impl<K: Hash + Eq> Buckets<K> {
+    fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token for `key`, false if there is none left.
+    fn take(&mut self, key: K, now: Instant) -> bool {
+        if self.rate <= 0.0 {
+            return true;
+        }
+        if self.buckets.len() >= MAX_BUCKETS && !self.buckets.contains_key(&key) {
+            return false;
+        }
+        let burst = self.burst;
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+        });
+        let refill = now.duration_since(bucket.last).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.last = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    // a bucket that filled up again is the same as none
+    fn purge(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * rate < burst
+        });
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    ips: Buckets<IpAddr>,
+    ids: Buckets<String>,
+    punches: Buckets<String>,
+    // refusals since, per source ip
+    strikes: HashMap<IpAddr, (u32, Instant)>,
+    // source ip -> banned until
+    banned: HashMap<IpAddr, Instant>,
+}
+
+/// The buckets and bans of one server, shared by its sockets and listeners.
+#[derive(Debug)]
+pub struct Limiter {
+    config: RateLimit,
+    inner: Mutex<Inner>,
+    metrics: Arc<Metrics>,
+}
+
+impl Limiter {
+    /// Refusals and bans are counted in `metrics`.
+    pub fn new(config: RateLimit, metrics: Arc<Metrics>) -> Self {
+        let inner = Inner {
+            ips: Buckets::new(config.ip_rate, config.ip_burst),
+            ids: Buckets::new(config.id_rate, config.id_burst),
+            punches: Buckets::new(config.punch_rate, config.punch_burst),
+            strikes: HashMap::new(),
+            banned: HashMap::new(),
+        };
+        Self {
+            config,
+            inner: Mutex::new(inner),
+            metrics,
+        }
+    }
+
+    pub fn config(&self) -> &RateLimit {
+        &self.config
+    }
+
+    /// Takes the tokens `request` from `ip` costs, or tells why it is dropped.
+    pub fn check(&self, ip: IpAddr, request: Request<'_>) -> Result<(), Refused> {
+        let now = Instant::now();
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        let banned = matches!(inner.banned.get(&ip), Some(until) if *until > now);
+        let refused = match request {
+            _ if banned => Some(Refused::Banned),
+            _ if !inner.ips.take(ip, now) => Some(Refused::Ip),
+            Request::Register(id) if !inner.ids.take(id.to_owned(), now) => Some(Refused::Id),
+            Request::Punch(id_b) if !inner.punches.take(id_b.to_owned(), now) => {
+                Some(Refused::Punch)
+            }
+            _ => None,
+        };
+        match refused {
+            None => Ok(()),
+            Some(refused) => {
+                tracing::debug!("{} refused: {:?}", ip, refused);
+                self.metrics.rate_limited(refused);
+                // an id or a B asked for too often may be someone else's doing
+                if refused == Refused::Ip {
+                    self.strike(inner, ip, now);
+                }
+                Err(refused)
+            }
+        }
+    }
+
+    /// Udp replies of `reply` bytes to a request of `request` bytes, refused
+    /// when they would amplify what a spoofed source sent.
+    pub fn check_reply(&self, request: usize, reply: usize) -> Result<(), Refused> {
+        if self.config.no_amplification && reply > request {
+            tracing::debug!("reply of {} bytes to {} dropped", reply, request);
+            self.metrics.rate_limited(Refused::Amplification);
+            return Err(Refused::Amplification);
+        }
+        Ok(())
+    }
+
+    fn strike(&self, inner: &mut Inner, ip: IpAddr, now: Instant) {
+        if self.config.ban_after == 0 {
+            return;
+        }
+        // as many sources as buckets are struck already, likely a spoofed flood
+        if inner.strikes.len() >= MAX_BUCKETS && !inner.strikes.contains_key(&ip) {
+            return;
+        }
+        let ban_for = self.config.ban_for;
+        let strikes = inner.strikes.entry(ip).or_insert((0, now));
+        if now.duration_since(strikes.1) >= ban_for {
+            *strikes = (0, now);
+        }
+        strikes.0 += 1;
+        if strikes.0 >= self.config.ban_after {
+            inner.strikes.remove(&ip);
+            inner.banned.insert(ip, now + ban_for);
+            self.metrics.temp_banned(inner.banned.len());
+            tracing::warn!("{} banned for {:?}, too many requests", ip, ban_for);
+        }
+    }
+
+    /// Source ips banned for now, with the time left.
+    pub fn banned(&self) -> Vec<(IpAddr, Duration)> {
+        let now = Instant::now();
+        let inner = self.inner.lock().unwrap();
+        let mut banned: Vec<_> = inner
+            .banned
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(ip, until)| (*ip, *until - now))
+            .collect();
+        banned.sort();
+        banned
+    }
+
+    /// Drops full buckets, old strikes and expired bans.
+    pub fn purge(&self) {
+        let now = Instant::now();
+        let ban_for = self.config.ban_for;
+        let mut inner = self.inner.lock().unwrap();
+        inner.ips.purge(now);
+        inner.ids.purge(now);
+        inner.punches.purge(now);
+        inner
+            .strikes
+            .retain(|_, (_, since)| now.duration_since(*since) < ban_for);
+        inner.banned.retain(|_, until| *until > now);
+        self.metrics.set_banned_ips(inner.banned.len());
+    }
+}
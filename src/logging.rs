@@ -0,0 +1,55 @@
+//! Log output of the binaries: `tracing` events on stderr, as text or as one
+//! json object per line, with the fields of the spans they happened in
+//! (strategy, ids and session of a punch, addr of a server's client).
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::io::IsTerminal;
+use tracing::Subscriber;
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
+
+/// Filter when neither the command line nor RUST_LOG has one.
+const DEFAULT_FILTER: &str = "info";
+
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One json object per line, with the current span and the span list
+    Json,
+}
+
+/// Starts logging with `filter` like `info,punch=debug`, or RUST_LOG if
+/// there is none, or at `info`.
+pub fn init(filter: Option<&str>, format: LogFormat) -> Result<()> {
+    let filter = match filter {
+        Some(filter) => EnvFilter::try_new(filter)?,
+        None => {
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER))
+        }
+    };
+    let ansi = std::io::stderr().is_terminal();
+    tracing::subscriber::set_global_default(subscriber(filter, format, std::io::stderr, ansi))
+        .map_err(|e| anyhow!("logging already started: {}", e))
+}
+
+/// The subscriber [`init`] installs, writing to `writer`, text in colour if `ansi`.
+pub fn subscriber<W>(
+    filter: EnvFilter,
+    format: LogFormat,
+    writer: W,
+    ansi: bool,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().with_span_list(true).finish()),
+    }
+}
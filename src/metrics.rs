@@ -0,0 +1,358 @@
+//! Counters of the rendezvous servers, served over http in the Prometheus
+//! text format.
+
+use crate::{
+    http::{self, Request, Response},
+    limit::Refused,
+};
+use anyhow::Result;
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::net::TcpListener;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    WebSocket,
+}
+
+impl Protocol {
+    const ALL: [Protocol; 3] = [Protocol::Tcp, Protocol::Udp, Protocol::WebSocket];
+
+    fn label(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+            Protocol::WebSocket => "websocket",
+        }
+    }
+}
+
+/// Why the server could not bring two peers together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PunchFailure {
+    /// The peer asked for is not registered in the asker's ip family
+    PeerNotRegistered,
+    /// Hybrid: B did not answer in time
+    PeerNoAnswer,
+    /// Hybrid: telling B failed
+    SendFailed,
+}
+
+impl PunchFailure {
+    const ALL: [PunchFailure; 3] = [
+        PunchFailure::PeerNotRegistered,
+        PunchFailure::PeerNoAnswer,
+        PunchFailure::SendFailed,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            PunchFailure::PeerNotRegistered => "peer_not_registered",
+            PunchFailure::PeerNoAnswer => "peer_no_answer",
+            PunchFailure::SendFailed => "send_failed",
+        }
+    }
+}
+
+// one counter per label value
+#[derive(Debug, Default)]
+struct ByProtocol([AtomicU64; 3]);
+
+impl ByProtocol {
+    fn add(&self, protocol: Protocol, n: u64) {
+        self.0[protocol as usize].fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+/// What a server did since it started. Cheap to update, every server has
+/// one even when nobody scrapes it.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    registered: AtomicU64,
+    registrations: AtomicU64,
+    registrations_refused: AtomicU64,
+    punch_requests: AtomicU64,
+    punch_successes: AtomicU64,
+    punch_routed: AtomicU64,
+    punch_failures: [AtomicU64; 3],
+    rate_limited: [AtomicU64; 5],
+    temp_bans: AtomicU64,
+    banned_ips: AtomicU64,
+    active_sessions: AtomicU64,
+    tcp_connections: AtomicU64,
+    decode_failures: ByProtocol,
+    received_bytes: ByProtocol,
+    received_messages: ByProtocol,
+    sent_bytes: ByProtocol,
+    sent_messages: ByProtocol,
+    dropped_messages: ByProtocol,
+    relay_bytes: AtomicU64,
+}
+
+/// Holds a gauge up while alive.
+pub struct Guard<'a>(&'a AtomicU64);
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn set_registered(&self, n: usize) {
+        self.registered.store(n as u64, Ordering::Relaxed);
+    }
+
+    /// A registration or refresh, refused by the allow list or a full registry.
+    pub fn registration(&self, accepted: bool) {
+        if accepted {
+            self.registrations.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.registrations_refused.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn punch_request(&self) {
+        self.punch_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn punch_succeeded(&self) {
+        self.punch_successes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A request for an id registered on another server of the cluster.
+    pub fn punch_routed(&self) {
+        self.punch_routed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn punch_failed(&self, reason: PunchFailure) {
+        self.punch_failures[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A request dropped by the [`crate::limit::Limiter`].
+    pub fn rate_limited(&self, reason: Refused) {
+        self.rate_limited[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A source ip was banned, `banned` are banned now.
+    pub fn temp_banned(&self, banned: usize) {
+        self.temp_bans.fetch_add(1, Ordering::Relaxed);
+        self.set_banned_ips(banned);
+    }
+
+    pub fn set_banned_ips(&self, n: usize) {
+        self.banned_ips.store(n as u64, Ordering::Relaxed);
+    }
+
+    /// A punch the server takes part in until the guard is dropped.
+    pub fn session(&self) -> Guard<'_> {
+        self.active_sessions.fetch_add(1, Ordering::Relaxed);
+        Guard(&self.active_sessions)
+    }
+
+    /// Punches the server takes part in now.
+    pub fn active_sessions(&self) -> u64 {
+        self.active_sessions.load(Ordering::Relaxed)
+    }
+
+    /// A tcp client being served until the guard is dropped.
+    pub fn tcp_connection(&self) -> Guard<'_> {
+        self.tcp_connections.fetch_add(1, Ordering::Relaxed);
+        Guard(&self.tcp_connections)
+    }
+
+    pub fn decode_failed(&self, protocol: Protocol) {
+        self.decode_failures.add(protocol, 1);
+    }
+
+    pub fn received(&self, protocol: Protocol, bytes: usize) {
+        self.received_bytes.add(protocol, bytes as u64);
+        self.received_messages.add(protocol, 1);
+    }
+
+    pub fn sent(&self, protocol: Protocol, bytes: usize) {
+        self.sent_bytes.add(protocol, bytes as u64);
+        self.sent_messages.add(protocol, 1);
+    }
+
+    /// A message for a client was dropped, its queue was full.
+    pub fn dropped(&self, protocol: Protocol) {
+        self.dropped_messages.add(protocol, 1);
+    }
+
+    pub fn relayed(&self, bytes: usize) {
+        self.relay_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// All metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let get = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        let mut single = |name: &str, kind: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            writeln!(out, "{} {}", name, value).unwrap();
+        };
+        single(
+            "punch_registered_peers",
+            "gauge",
+            "Registrations that have not expired, one per id and ip family.",
+            get(&self.registered),
+        );
+        single(
+            "punch_registrations_total",
+            "counter",
+            "Registrations and refreshes accepted.",
+            get(&self.registrations),
+        );
+        single(
+            "punch_registrations_refused_total",
+            "counter",
+            "Registrations refused by the allow list or a full registry.",
+            get(&self.registrations_refused),
+        );
+        single(
+            "punch_requests_total",
+            "counter",
+            "Requests for the addr of a peer.",
+            get(&self.punch_requests),
+        );
+        single(
+            "punch_successes_total",
+            "counter",
+            "Requests answered with the addr of the peer.",
+            get(&self.punch_successes),
+        );
+        single(
+            "punch_routed_total",
+            "counter",
+            "Requests for ids not registered here, asked of the cluster.",
+            get(&self.punch_routed),
+        );
+        single(
+            "punch_active_sessions",
+            "gauge",
+            "Punches waiting for the peer to answer.",
+            get(&self.active_sessions),
+        );
+        single(
+            "punch_tcp_connections",
+            "gauge",
+            "Tcp clients being served.",
+            get(&self.tcp_connections),
+        );
+        single(
+            "punch_temp_bans_total",
+            "counter",
+            "Source ips banned for a while after too many refused requests.",
+            get(&self.temp_bans),
+        );
+        single(
+            "punch_banned_ips",
+            "gauge",
+            "Source ips banned for now.",
+            get(&self.banned_ips),
+        );
+        single(
+            "punch_relay_bytes_total",
+            "counter",
+            "Bytes relayed between peers, relay is not supported yet.",
+            get(&self.relay_bytes),
+        );
+
+        out.push_str("# HELP punch_failures_total Requests that did not get the addr of the peer, by reason.\n");
+        out.push_str("# TYPE punch_failures_total counter\n");
+        for reason in PunchFailure::ALL {
+            writeln!(
+                out,
+                "punch_failures_total{{reason=\"{}\"}} {}",
+                reason.label(),
+                get(&self.punch_failures[reason as usize])
+            )
+            .unwrap();
+        }
+        out.push_str("# HELP punch_rate_limited_total Requests and replies dropped by the rate limits, by reason.\n");
+        out.push_str("# TYPE punch_rate_limited_total counter\n");
+        for reason in Refused::ALL {
+            writeln!(
+                out,
+                "punch_rate_limited_total{{reason=\"{}\"}} {}",
+                reason.label(),
+                get(&self.rate_limited[reason as usize])
+            )
+            .unwrap();
+        }
+        for (name, help, counter) in [
+            (
+                "punch_decode_failures_total",
+                "Messages that could not be decoded.",
+                &self.decode_failures,
+            ),
+            (
+                "punch_received_bytes_total",
+                "Bytes of messages received.",
+                &self.received_bytes,
+            ),
+            (
+                "punch_received_messages_total",
+                "Messages received.",
+                &self.received_messages,
+            ),
+            (
+                "punch_sent_bytes_total",
+                "Bytes of messages sent.",
+                &self.sent_bytes,
+            ),
+            (
+                "punch_sent_messages_total",
+                "Messages sent.",
+                &self.sent_messages,
+            ),
+            (
+                "punch_dropped_messages_total",
+                "Messages for clients dropped because their queue was full.",
+                &self.dropped_messages,
+            ),
+        ] {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            for protocol in Protocol::ALL {
+                writeln!(
+                    out,
+                    "{}{{protocol=\"{}\"}} {}",
+                    name,
+                    protocol.label(),
+                    get(&counter.0[protocol as usize])
+                )
+                .unwrap();
+            }
+        }
+        out
+    }
+}
+
+/// Serves `metrics` on `GET /metrics`.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> Result<()> {
+    http::serve(listener, move |request: Request| {
+        let metrics = metrics.clone();
+        async move {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/metrics") => Response::new(
+                    200,
+                    "text/plain; version=0.0.4; charset=utf-8",
+                    metrics.render(),
+                ),
+                (_, "/metrics") => Response::text(405, "only GET\n"),
+                _ => Response::text(404, "try /metrics\n"),
+            }
+        }
+    })
+    .await
+}
@@ -0,0 +1,446 @@
+use anyhow::{anyhow, bail, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use std::{collections::HashMap, io, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    sync::{mpsc, oneshot, Semaphore},
+};
+use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+
+/// A logical channel carried over the mux, used like a tcp stream.
+pub type Channel = DuplexStream;
+
+const OPEN: u8 = 0;
+const ACCEPT: u8 = 1;
+const DATA: u8 = 2;
+const CLOSE: u8 = 3;
+const WINDOW: u8 = 4;
+
+pub const MAX_FRAME_PAYLOAD: usize = 64 * 1024;
+const HEADER_LEN: usize = 9; // u32 len + u8 kind + u32 id
+/// Bytes a channel may send before the peer's reader took them, so one
+/// channel nobody reads does not hold up the others.
+pub const CHANNEL_WINDOW: usize = 256 * 1024;
+/// Bytes of one data frame.
+const CHUNK: usize = 16 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Open {
+        id: u32,
+        payload: Bytes,
+    },
+    Accept {
+        id: u32,
+    },
+    Data {
+        id: u32,
+        payload: Bytes,
+    },
+    Close {
+        id: u32,
+    },
+    /// The reader took this many more bytes of the channel
+    Window {
+        id: u32,
+        credit: u32,
+    },
+}
+
+/// Length prefixed binary framing for [`Frame`]:
+/// `u32 payload len | u8 kind | u32 channel id | payload`.
+#[derive(Debug, Default)]
+pub struct FrameCodec;
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, io::Error> {
+        if src.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if len > MAX_FRAME_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame too large: {}", len),
+            ));
+        }
+        if src.len() < HEADER_LEN + len {
+            src.reserve(HEADER_LEN + len - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        let kind = src.get_u8();
+        let id = src.get_u32();
+        let payload = src.split_to(len).freeze();
+        let frame = match kind {
+            OPEN => Frame::Open { id, payload },
+            ACCEPT => Frame::Accept { id },
+            DATA => Frame::Data { id, payload },
+            CLOSE => Frame::Close { id },
+            WINDOW if payload.len() == 4 => Frame::Window {
+                id,
+                credit: u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]),
+            },
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown frame kind: {}", kind),
+                ))
+            }
+        };
+        Ok(Some(frame))
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let (kind, id, payload) = match frame {
+            Frame::Open { id, payload } => (OPEN, id, payload),
+            Frame::Accept { id } => (ACCEPT, id, Bytes::new()),
+            Frame::Data { id, payload } => (DATA, id, payload),
+            Frame::Close { id } => (CLOSE, id, Bytes::new()),
+            Frame::Window { id, credit } => {
+                (WINDOW, id, Bytes::copy_from_slice(&credit.to_be_bytes()))
+            }
+        };
+        if payload.len() > MAX_FRAME_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("frame too large: {}", payload.len()),
+            ));
+        }
+        dst.reserve(HEADER_LEN + payload.len());
+        dst.put_u32(payload.len() as u32);
+        dst.put_u8(kind);
+        dst.put_u32(id);
+        dst.put_slice(&payload);
+        Ok(())
+    }
+}
+
+enum Command {
+    Open {
+        payload: Bytes,
+        reply: oneshot::Sender<Result<Channel>>,
+    },
+    Accept {
+        id: u32,
+        reply: oneshot::Sender<Channel>,
+    },
+    Reject(u32),
+    LocalClosed(u32),
+    // the user read this many bytes of the channel
+    Consumed(u32, usize),
+}
+
+/// Opens channels to the peer. Cheap to clone.
+#[derive(Clone)]
+pub struct Opener {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Opener {
+    /// Asks the peer to open a channel, `payload` tells it what the channel is for.
+    pub async fn open(&self, payload: Bytes) -> Result<Channel> {
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(Command::Open { payload, reply })
+            .map_err(|_| anyhow!("mux closed"))?;
+        rx.await.map_err(|_| anyhow!("mux closed"))?
+    }
+}
+
+/// A channel the peer wants to open. Dropping it rejects the request.
+pub struct Pending {
+    pub payload: Bytes,
+    id: u32,
+    commands: mpsc::UnboundedSender<Command>,
+    answered: bool,
+}
+
+impl Pending {
+    pub async fn accept(mut self) -> Result<Channel> {
+        self.answered = true;
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(Command::Accept { id: self.id, reply })
+            .map_err(|_| anyhow!("mux closed"))?;
+        rx.await.map_err(|_| anyhow!("mux closed"))
+    }
+
+    pub fn reject(self) {
+        drop(self)
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if !self.answered {
+            self.commands.send(Command::Reject(self.id)).ok();
+        }
+    }
+}
+
+/// Receives channels opened by the peer.
+pub struct Acceptor {
+    incoming: mpsc::Receiver<Pending>,
+}
+
+impl Acceptor {
+    /// Returns `None` once the underlying connection is gone.
+    pub async fn accept(&mut self) -> Option<Pending> {
+        self.incoming.recv().await
+    }
+}
+
+/// Multiplexes many channels over one punched connection.
+///
+/// `initiator` must differ between the two sides, it splits the channel id space.
+pub fn new<S>(stream: S, initiator: bool) -> (Opener, Acceptor)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (rd, wr) = tokio::io::split(stream);
+    let (commands, commands_rx) = mpsc::unbounded_channel();
+    let (incoming_tx, incoming) = mpsc::channel(16);
+    // control frames are never blocked behind channel data
+    let (control_tx, control_rx) = mpsc::unbounded_channel();
+    let (data_tx, data_rx) = mpsc::channel(64);
+
+    tokio::spawn(async move {
+        if let Err(e) = write_frames(FramedWrite::new(wr, FrameCodec), control_rx, data_rx).await {
+            tracing::debug!("mux write closed: {:?}", e);
+        }
+    });
+
+    let driver = Driver {
+        next_id: if initiator { 1 } else { 2 },
+        commands: commands.clone(),
+        control: control_tx,
+        data: data_tx,
+        incoming: incoming_tx,
+        slots: HashMap::new(),
+        opening: HashMap::new(),
+    };
+    tokio::spawn(async move {
+        if let Err(e) = driver
+            .run(FramedRead::new(rd, FrameCodec), commands_rx)
+            .await
+        {
+            tracing::debug!("mux closed: {:?}", e);
+        }
+    });
+    (Opener { commands }, Acceptor { incoming })
+}
+
+async fn write_frames<W: AsyncWrite + Unpin>(
+    mut sink: FramedWrite<W, FrameCodec>,
+    mut control: mpsc::UnboundedReceiver<Frame>,
+    mut data: mpsc::Receiver<Frame>,
+) -> Result<()> {
+    loop {
+        let frame = tokio::select! {
+            biased;
+            Some(frame) = control.recv() => frame,
+            Some(frame) = data.recv() => frame,
+            else => return Ok(()),
+        };
+        sink.send(frame).await?;
+    }
+}
+
+struct Slot {
+    // 写往用户的数据, drop即通知用户EOF
+    data: Option<mpsc::UnboundedSender<Bytes>>,
+    // 已收到但用户还没读走的字节, 不超过CHANNEL_WINDOW
+    buffered: usize,
+    // 还可以发给对端的字节
+    credit: Arc<Semaphore>,
+    local_closed: bool,
+}
+
+// the sending side of the channel stops waiting for credit once the mux is gone
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.credit.close();
+    }
+}
+
+struct Driver {
+    next_id: u32,
+    commands: mpsc::UnboundedSender<Command>,
+    control: mpsc::UnboundedSender<Frame>,
+    data: mpsc::Sender<Frame>,
+    incoming: mpsc::Sender<Pending>,
+    slots: HashMap<u32, Slot>,
+    opening: HashMap<u32, oneshot::Sender<Result<Channel>>>,
+}
+
+impl Driver {
+    async fn run<R: AsyncRead + Unpin>(
+        mut self,
+        mut frames: FramedRead<R, FrameCodec>,
+        mut commands: mpsc::UnboundedReceiver<Command>,
+    ) -> Result<()> {
+        loop {
+            tokio::select! {
+                frame = frames.next() => match frame {
+                    Some(frame) => self.on_frame(frame?),
+                    None => bail!("connection closed"),
+                },
+                Some(command) = commands.recv() => self.on_command(command),
+            }
+        }
+    }
+
+    fn on_frame(&mut self, frame: Frame) {
+        match frame {
+            Frame::Open { id, payload } => {
+                let pending = Pending {
+                    payload,
+                    id,
+                    commands: self.commands.clone(),
+                    answered: false,
+                };
+                // a dropped Pending rejects itself, also when nobody takes it
+                if self.incoming.try_send(pending).is_err() {
+                    tracing::warn!("channel {} refused, too many pending", id);
+                }
+            }
+            Frame::Accept { id } => {
+                if let Some(reply) = self.opening.remove(&id) {
+                    // if the opener went away its channel end is dropped and closes itself
+                    reply.send(Ok(self.new_channel(id))).ok();
+                }
+            }
+            Frame::Data { id, payload } => {
+                let slot = match self.slots.get_mut(&id) {
+                    Some(slot) => slot,
+                    None => return,
+                };
+                if let Some(data) = &slot.data {
+                    slot.buffered += payload.len();
+                    if slot.buffered > CHANNEL_WINDOW {
+                        // the peer ignores the window, the channel is given up
+                        tracing::warn!("channel {} overran its window, reset", id);
+                        slot.data = None;
+                        self.control.send(Frame::Close { id }).ok();
+                    } else {
+                        data.send(payload).ok();
+                    }
+                }
+            }
+            Frame::Window { id, credit } => {
+                if let Some(slot) = self.slots.get(&id) {
+                    slot.credit.add_permits(credit as usize);
+                }
+            }
+            Frame::Close { id } => {
+                if let Some(reply) = self.opening.remove(&id) {
+                    reply.send(Err(anyhow!("peer rejected channel"))).ok();
+                }
+                if let Some(slot) = self.slots.get_mut(&id) {
+                    slot.data = None;
+                    if slot.local_closed {
+                        self.slots.remove(&id);
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_command(&mut self, command: Command) {
+        match command {
+            Command::Open { payload, reply } => {
+                let id = self.next_id;
+                self.next_id = self.next_id.wrapping_add(2);
+                self.opening.insert(id, reply);
+                self.control.send(Frame::Open { id, payload }).ok();
+            }
+            Command::Accept { id, reply } => {
+                let channel = self.new_channel(id);
+                self.control.send(Frame::Accept { id }).ok();
+                reply.send(channel).ok();
+            }
+            Command::Reject(id) => {
+                self.control.send(Frame::Close { id }).ok();
+            }
+            Command::Consumed(id, n) => {
+                if let Some(slot) = self.slots.get_mut(&id) {
+                    slot.buffered = slot.buffered.saturating_sub(n);
+                    if slot.data.is_some() {
+                        let credit = n as u32;
+                        self.control.send(Frame::Window { id, credit }).ok();
+                    }
+                }
+            }
+            Command::LocalClosed(id) => {
+                if let Some(slot) = self.slots.get_mut(&id) {
+                    slot.local_closed = true;
+                    if slot.data.is_none() {
+                        self.slots.remove(&id);
+                    }
+                }
+            }
+        }
+    }
+
+    fn new_channel(&mut self, id: u32) -> Channel {
+        let (user, inner) = tokio::io::duplex(64 * 1024);
+        let (mut rd, mut wr) = tokio::io::split(inner);
+        let (data_tx, mut data_rx) = mpsc::unbounded_channel::<Bytes>();
+        let credit = Arc::new(Semaphore::new(CHANNEL_WINDOW));
+        self.slots.insert(
+            id,
+            Slot {
+                data: Some(data_tx),
+                buffered: 0,
+                credit: credit.clone(),
+                local_closed: false,
+            },
+        );
+
+        let frames = self.data.clone();
+        let commands = self.commands.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; CHUNK];
+            loop {
+                match rd.read(&mut buf).await {
+                    Ok(n) if n > 0 => {
+                        // wait until the peer has room for it
+                        match credit.acquire_many(n as u32).await {
+                            Ok(permit) => permit.forget(),
+                            Err(_) => return,
+                        }
+                        let payload = Bytes::copy_from_slice(&buf[..n]);
+                        if frames.send(Frame::Data { id, payload }).await.is_err() {
+                            return;
+                        }
+                    }
+                    _ => break,
+                }
+            }
+            frames.send(Frame::Close { id }).await.ok();
+            commands.send(Command::LocalClosed(id)).ok();
+        });
+
+        let commands = self.commands.clone();
+        tokio::spawn(async move {
+            while let Some(data) = data_rx.recv().await {
+                if wr.write_all(&data).await.is_err() {
+                    break;
+                }
+                commands.send(Command::Consumed(id, data.len())).ok();
+            }
+            wr.shutdown().await.ok();
+        });
+
+        user
+    }
+}
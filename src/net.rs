@@ -0,0 +1,114 @@
+use crate::sim;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{io, net::SocketAddr};
+use tokio::net::{TcpListener, UdpSocket};
+
+/// Where sockets come from: the real network, or a host in a [`sim::Sim`].
+#[derive(Clone, Default)]
+pub enum Network {
+    #[default]
+    Os,
+    Sim(sim::Host),
+}
+
+impl Network {
+    pub async fn bind_udp(&self, addr: SocketAddr) -> io::Result<Datagram> {
+        match self {
+            Network::Os => Ok(Datagram::Os(UdpSocket::bind(addr).await?)),
+            Network::Sim(host) => Ok(Datagram::Sim(host.bind(addr)?)),
+        }
+    }
+}
+
+/// A udp socket on either kind of [`Network`].
+pub enum Datagram {
+    Os(UdpSocket),
+    Sim(sim::Socket),
+}
+
+impl Datagram {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Datagram::Os(socket) => socket.local_addr(),
+            Datagram::Sim(socket) => socket.local_addr(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Datagram::Os(socket) => socket.peer_addr(),
+            Datagram::Sim(socket) => socket.peer_addr(),
+        }
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        match self {
+            Datagram::Os(socket) => socket.connect(addr).await,
+            Datagram::Sim(socket) => socket.connect(addr).await,
+        }
+    }
+
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Datagram::Os(socket) => socket.send(buf).await,
+            Datagram::Sim(socket) => socket.send(buf).await,
+        }
+    }
+
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Datagram::Os(socket) => socket.recv(buf).await,
+            Datagram::Sim(socket) => socket.recv(buf).await,
+        }
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        match self {
+            Datagram::Os(socket) => socket.send_to(buf, target).await,
+            Datagram::Sim(socket) => socket.send_to(buf, target).await,
+        }
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            Datagram::Os(socket) => socket.recv_from(buf).await,
+            Datagram::Sim(socket) => socket.recv_from(buf).await,
+        }
+    }
+}
+
+/// The unspecified addr of the same family as `addr`, port 0.
+pub fn any_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    }
+}
+
+// an ipv6 socket that also took ipv4 would clash with the ipv4 one on the same port
+fn socket(addr: SocketAddr, kind: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), kind, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Binds a udp socket for a server, ipv6 addrs take ipv6 only so `0.0.0.0`
+/// and `[::]` can share a port.
+pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = socket(addr, Type::DGRAM, Protocol::UDP)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Like [`bind_udp`], for a tcp listener.
+pub fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = socket(addr, Type::STREAM, Protocol::TCP)?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
@@ -0,0 +1,1814 @@
+use crate::{
+    hybrid::{Answer, Message},
+    net::{any_addr, Datagram, Network},
+    new_tcp_listener, new_tcp_socket, new_tcp_stream, pad_request, punch_with,
+    rendezvous::{ServerList, RESOLVE_INTERVAL},
+    report::{Event, Events, NatType, Report},
+    rudp::RudpStream,
+    signaling::{Signal, Signaling, MAX_CANDIDATES},
+    simple::*,
+    trigger::Trigger,
+    Candidate, Pair, PunchContext, PunchStrategy,
+};
+use anyhow::{anyhow, bail, Result};
+use futures::{
+    future::{join_all, BoxFuture},
+    stream::FuturesUnordered,
+    StreamExt,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    io, mem,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+    sync::{mpsc, watch, Notify},
+    task::JoinHandle,
+    time::{interval, sleep, timeout, Duration, Instant},
+};
+use tracing::{field, Instrument, Span};
+
+/// RFC 8305's connection attempt delay: how long an attempt runs alone
+/// before the next addr is tried alongside it.
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+/// How long B listens for A after answering the server.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+/// A server that did not answer for this long is given up for the next one.
+const SERVER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for the server to tell our public addr, old servers never do.
+const OBSERVE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long sending the unregister to one server addr may take.
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often [`SignaledPunch`] probes the peer's candidates.
+const PROBE_INTERVAL: Duration = Duration::from_millis(200);
+/// What [`SignaledPunch`] probes with, too short for rudp to take it.
+const PROBE: &[u8] = b"punch";
+
+/// How the hole is punched.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// udp_server: both sides register over udp and talk udp directly
+    Udp,
+    /// tcp_server: both sides register over tcp and reuse the port, one listens
+    Tcp,
+    /// hybrid_server: udp for registration and signalling, tcp for data
+    Hybrid,
+}
+
+impl Strategy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Strategy::Udp => "udp",
+            Strategy::Tcp => "tcp",
+            Strategy::Hybrid => "hybrid",
+        }
+    }
+
+    /// Asks the servers which kind they are, with the requests only that kind
+    /// answers, moving on to the next server when one does not answer.
+    pub async fn detect(servers: &mut ServerList, network: &Network, id: &str) -> Result<Self> {
+        let first = servers.current().to_owned();
+        loop {
+            let server_addrs = servers.addrs().await?;
+            match happy_eyeballs(&server_addrs, |server_addr| probe(server_addr, network, id)).await
+            {
+                Ok(strategy) => {
+                    tracing::info!("server {} runs {}", servers.current(), strategy.name());
+                    return Ok(strategy);
+                }
+                Err(e) => {
+                    servers.fail_over();
+                    if servers.current() == first {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The connection a [`Puncher`] produces.
+pub enum PunchedStream {
+    Tcp(TcpStream),
+    Udp(RudpStream),
+}
+
+impl PunchedStream {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            PunchedStream::Tcp(s) => s.local_addr(),
+            PunchedStream::Udp(s) => s.local_addr(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            PunchedStream::Tcp(s) => s.peer_addr(),
+            PunchedStream::Udp(s) => s.peer_addr(),
+        }
+    }
+}
+
+impl AsyncRead for PunchedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PunchedStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            PunchedStream::Udp(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PunchedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PunchedStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            PunchedStream::Udp(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PunchedStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            PunchedStream::Udp(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PunchedStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            PunchedStream::Udp(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+pub struct Punched {
+    pub stream: PunchedStream,
+    /// The side that asked for the punch. The two ends always disagree on this.
+    pub initiator: bool,
+}
+
+struct Registration {
+    notify: Arc<Notify>,
+    handle: JoinHandle<()>,
+}
+
+/// Where we registered at a server addr from, servers take the unregister
+/// from nowhere else.
+#[derive(Clone)]
+enum Source {
+    /// The port, free again once the punched connection is gone
+    Port(SocketAddr),
+    /// The hybrid heartbeat socket
+    Socket(Arc<Datagram>),
+}
+
+/// By server addr, filled by the registrations and read by [`Leave`].
+type Sources = Arc<Mutex<HashMap<SocketAddr, Source>>>;
+
+/// Punches a hole to the peer through one of the rendezvous servers.
+///
+/// A server with both an ipv4 and an ipv6 addr is used over both, every punch
+/// races the families with [`happy_eyeballs`] so native ipv6 wins when it works.
+/// When the server stops answering the next one of the [`ServerList`] is used.
+pub struct Puncher {
+    strategy: Strategy,
+    servers: ServerList,
+    id: String,
+    peer_id: Option<String>,
+    network: Network,
+    // the one of `strategy`, then the added ones
+    strategies: Vec<Box<dyn PunchStrategy>>,
+    // hybrid: what the registration tells B of the As asking, until it starts
+    punch_requests: Option<mpsc::Sender<PunchRequest>>,
+    registration: Option<Registration>,
+    // addrs of the server in use, ipv6 first
+    server_addrs: Arc<Mutex<Vec<SocketAddr>>>,
+    sources: Sources,
+    // set once unregistered, stops the hybrid heartbeat
+    leaving: Arc<watch::Sender<bool>>,
+    events: Events,
+    span: Span,
+    // taken by the first punch
+    trigger: Option<Trigger>,
+}
+
+impl Drop for Puncher {
+    fn drop(&mut self) {
+        if let Some(registration) = &self.registration {
+            registration.handle.abort();
+        }
+    }
+}
+
+impl Puncher {
+    /// `peer_id` is required for udp/tcp. For hybrid, the side with a `peer_id`
+    /// actively connects (A) and the side without waits (B).
+    pub fn new(
+        strategy: Strategy,
+        servers: impl Into<ServerList>,
+        id: String,
+        peer_id: Option<String>,
+    ) -> Self {
+        let events = Events::new(&id, peer_id.as_deref(), strategy);
+        let span = tracing::info_span!(
+            "puncher",
+            strategy = %strategy.name(),
+            id = %id,
+            peer_id = field::Empty
+        );
+        if let Some(peer_id) = &peer_id {
+            span.record("peer_id", peer_id.as_str());
+        }
+        let (sender, receiver) = mpsc::channel::<PunchRequest>(4);
+        let sources = Sources::default();
+        let builtin: Box<dyn PunchStrategy> = match strategy {
+            Strategy::Udp => Box::new(UdpPunch {
+                sources: sources.clone(),
+                ..Default::default()
+            }),
+            Strategy::Tcp => Box::new(TcpPunch {
+                listener: false,
+                sources: sources.clone(),
+            }),
+            Strategy::Hybrid => Box::new(HybridPunch::new(receiver)),
+        };
+        Self {
+            strategy,
+            servers: servers.into(),
+            id,
+            peer_id,
+            network: Network::Os,
+            strategies: vec![builtin],
+            punch_requests: Some(sender),
+            registration: None,
+            server_addrs: Arc::default(),
+            sources,
+            leaving: Arc::new(watch::channel(false).0),
+            events,
+            span,
+            trigger: None,
+        }
+    }
+
+    /// Tcp strategy only: listen on the registered port instead of connecting.
+    pub fn tcp_listener(mut self, listener: bool) -> Self {
+        if self.strategy == Strategy::Tcp {
+            self.strategies[0] = Box::new(TcpPunch {
+                listener,
+                sources: self.sources.clone(),
+            });
+        }
+        self
+    }
+
+    /// Also punches with `strategy`, alongside the built-in one of the
+    /// [`Strategy`] and through the same server. The first connection wins.
+    pub fn strategy(mut self, strategy: impl PunchStrategy + 'static) -> Self {
+        self.strategies.push(Box::new(strategy));
+        self
+    }
+
+    /// Where udp sockets are bound, tcp always uses the real network.
+    pub fn network(mut self, network: Network) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Holds the first punch back until `trigger` fires, the side that starts
+    /// it waits: hybrid A once it is registered, udp and tcp before they
+    /// register. Punching again after a lost connection does not wait.
+    pub fn trigger(mut self, trigger: Trigger) -> Self {
+        self.trigger = Some(trigger);
+        self
+    }
+
+    /// Writes the json [`crate::report::Report`] to `path` whenever a punch
+    /// ends or a server is given up.
+    pub fn report(self, path: impl Into<PathBuf>) -> Self {
+        self.events.write_to(path);
+        self
+    }
+
+    /// Everything that happened while punching so far.
+    pub fn events(&self) -> Events {
+        self.events.clone()
+    }
+
+    /// The span punches and the registration log in, with the strategy and ids.
+    pub fn span(&self) -> Span {
+        self.span.clone()
+    }
+
+    /// A handle that unregisters this puncher, see [`Leave::unregister`].
+    pub fn leave(&self) -> Leave {
+        Leave {
+            strategy: self.strategy,
+            id: self.id.clone(),
+            network: self.network.clone(),
+            sources: self.sources.clone(),
+            leaving: self.leaving.clone(),
+        }
+    }
+
+    /// Tells the server in use that we leave, see [`Leave::unregister`].
+    pub async fn unregister(&self) {
+        self.leave().unregister().await
+    }
+
+    /// Hybrid only: starts the udp registration heartbeat and waits for the
+    /// first response, which may take a fail over or two. The other strategies
+    /// register while punching.
+    pub async fn register(&mut self) -> Result<()> {
+        if self.strategy != Strategy::Hybrid {
+            return Ok(());
+        }
+        if let Some(sender) = self.punch_requests.take() {
+            let notify = Arc::new(Notify::new());
+            let handle = tokio::spawn(
+                registration_task(
+                    self.servers.clone(),
+                    self.network.clone(),
+                    self.id.clone(),
+                    sender,
+                    notify.clone(),
+                    self.server_addrs.clone(),
+                    self.sources.clone(),
+                    self.leaving.subscribe(),
+                    self.events.clone(),
+                )
+                .instrument(self.span.clone()),
+            );
+            self.registration = Some(Registration { notify, handle });
+        }
+        if let Some(registration) = &self.registration {
+            registration.notify.notified().await;
+        }
+        Ok(())
+    }
+
+    pub async fn punch(&mut self) -> Result<Punched> {
+        let span = self.span.clone();
+        let punched = self.punch_with_strategy().instrument(span).await;
+        match &punched {
+            Ok(punched) => {
+                if let (Ok(local), Ok(remote)) =
+                    (punched.stream.local_addr(), punched.stream.peer_addr())
+                {
+                    self.events.record(Event::Connected { local, remote });
+                }
+            }
+            Err(e) => self.events.record(Event::Failed {
+                error: format!("{:#}", e),
+            }),
+        }
+        punched
+    }
+
+    async fn punch_with_strategy(&mut self) -> Result<Punched> {
+        match self.strategy {
+            Strategy::Udp | Strategy::Tcp => {
+                self.wait_trigger().await?;
+                loop {
+                    let server_addrs = self.resolve().await?;
+                    match self.race(server_addrs).await {
+                        Ok(punched) => return Ok(punched),
+                        Err(e) => self.fail_over(e),
+                    }
+                }
+            }
+            // the registration fails over on its own
+            Strategy::Hybrid => {
+                self.register().await?;
+                if self.peer_id.is_some() {
+                    self.wait_trigger().await?;
+                }
+                let server_addrs = self.server_addrs.lock().unwrap().clone();
+                self.race(server_addrs).await
+            }
+        }
+    }
+
+    /// Punches with every strategy at once and takes the first connection,
+    /// the error of the built-in one when none connects.
+    async fn race(&self, server_addrs: Vec<SocketAddr>) -> Result<Punched> {
+        let context = PunchContext {
+            id: self.id.clone(),
+            peer_id: self.peer_id.clone(),
+            server_addrs,
+            network: self.network.clone(),
+            events: self.events.clone(),
+        };
+        let context = &context;
+        let mut running: FuturesUnordered<_> = self
+            .strategies
+            .iter()
+            .enumerate()
+            .map(|(i, strategy)| async move { (i, punch_with(strategy.as_ref(), context).await) })
+            .collect();
+        let mut error = None;
+        while let Some((i, punched)) = running.next().await {
+            let name = self.strategies[i].name();
+            match punched {
+                Ok(punched) => {
+                    tracing::info!("punched with {}", name);
+                    return Ok(punched);
+                }
+                Err(e) => {
+                    tracing::warn!("{} failed {:?}", name, e);
+                    if i == 0 || error.is_none() {
+                        error = Some(e);
+                    }
+                }
+            }
+        }
+        Err(error.unwrap_or_else(|| anyhow!("no strategy")))
+    }
+
+    /// Asks every addr of the server what it sees of us, without registering
+    /// or punching, and tells the nat type found in the report.
+    pub async fn nat_check(&mut self) -> Result<Report> {
+        let first = self.servers.current().to_owned();
+        loop {
+            let server_addrs = self.resolve().await?;
+            let mut answered = false;
+            for &server_addr in &server_addrs {
+                match self.observe(server_addr).await {
+                    Ok(()) => answered = true,
+                    Err(e) => {
+                        tracing::warn!("server {} does not tell our addr: {:?}", server_addr, e)
+                    }
+                }
+            }
+            if answered {
+                return Ok(self.events.report());
+            }
+            self.fail_over(anyhow!("no addr of the server tells our addr"));
+            if self.servers.current() == first {
+                bail!("no server tells our addr");
+            }
+        }
+    }
+
+    async fn observe(&self, server_addr: SocketAddr) -> Result<()> {
+        match self.strategy {
+            Strategy::Tcp => observe_tcp(server_addr, &self.id, &self.events).await,
+            Strategy::Udp | Strategy::Hybrid => {
+                let socket = self.network.bind_udp(any_addr(server_addr)).await?;
+                socket.connect(server_addr).await?;
+                if self.strategy == Strategy::Udp {
+                    observe_udp(&socket, server_addr, &self.id, &self.events).await?;
+                    return Ok(());
+                }
+                let mut buf = vec![0u8; 1024];
+                socket
+                    .send(&pad_request(
+                        Message::addr_request(self.id.clone()).encode(),
+                    ))
+                    .await?;
+                let n = timeout(OBSERVE_TIMEOUT, socket.recv(&mut buf)).await??;
+                match Message::decode(&buf[..n])? {
+                    Message::addr_response(public) => {
+                        record_mapped(&self.events, server_addr, socket.local_addr()?, public);
+                        Ok(())
+                    }
+                    message => bail!("unexpected {:?}", message),
+                }
+            }
+        }
+    }
+
+    async fn wait_trigger(&mut self) -> Result<()> {
+        match self.trigger.take() {
+            Some(trigger) => trigger.wait().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn resolve(&mut self) -> Result<Vec<SocketAddr>> {
+        let addrs = self.servers.addrs().await?;
+        *self.server_addrs.lock().unwrap() = addrs.clone();
+        self.events.record(Event::Server {
+            server: self.servers.current().to_owned(),
+            addrs: addrs.clone(),
+        });
+        Ok(addrs)
+    }
+
+    fn fail_over(&mut self, e: anyhow::Error) {
+        tracing::warn!("server {} failed {:?}", self.servers.current(), e);
+        self.events.record(Event::ServerFailed {
+            server: self.servers.current().to_owned(),
+            error: format!("{:#}", e),
+        });
+        self.servers.fail_over();
+    }
+}
+
+/// The built-in strategy of [`Strategy::Udp`]: one socket per server addr,
+/// registered until the server tells the peer's addr, then talking to the
+/// peer from the socket that learnt it first.
+#[derive(Default)]
+pub struct UdpPunch {
+    // punch again from the same ports, so the addresses the peer got stay valid
+    local_addrs: Mutex<Vec<SocketAddr>>,
+    // by local addr, bound by gather and taken by exchange and connect
+    sockets: Mutex<HashMap<SocketAddr, Datagram>>,
+    sources: Sources,
+}
+
+impl PunchStrategy for UdpPunch {
+    fn name(&self) -> &str {
+        Strategy::Udp.name()
+    }
+
+    fn gather<'a>(&'a self, punch: &'a PunchContext) -> BoxFuture<'a, Result<Vec<Candidate>>> {
+        Box::pin(async move {
+            let old = self.local_addrs.lock().unwrap().clone();
+            let mut sockets = Vec::new();
+            for &server_addr in &punch.server_addrs {
+                let old = old
+                    .iter()
+                    .find(|addr| addr.is_ipv6() == server_addr.is_ipv6());
+                let socket = match old {
+                    Some(&addr) => match punch.network.bind_udp(addr).await {
+                        Ok(socket) => socket,
+                        Err(_) => punch.network.bind_udp(any_addr(server_addr)).await?,
+                    },
+                    None => punch.network.bind_udp(any_addr(server_addr)).await?,
+                };
+                socket.connect(server_addr).await?;
+                sockets.push((server_addr, socket));
+            }
+            *self.local_addrs.lock().unwrap() = sockets
+                .iter()
+                .map(|(_, socket)| socket.local_addr())
+                .collect::<io::Result<_>>()?;
+
+            let candidates = join_all(sockets.iter().map(|(server_addr, socket)| async move {
+                let local = socket.local_addr()?;
+                let addr = match observe_udp(socket, *server_addr, &punch.id, &punch.events).await {
+                    Ok(public) => public,
+                    Err(e) => {
+                        tracing::info!("server {} does not tell our addr: {:?}", server_addr, e);
+                        local
+                    }
+                };
+                Ok(Candidate {
+                    addr,
+                    local: Some(local),
+                })
+            }))
+            .await
+            .into_iter()
+            .collect::<Result<_>>()?;
+            *self.sockets.lock().unwrap() = sockets
+                .into_iter()
+                .map(|(_, socket)| Ok((socket.local_addr()?, socket)))
+                .collect::<io::Result<_>>()?;
+            Ok(candidates)
+        })
+    }
+
+    /// Udp has no connection to race on, both sides take the first family in
+    /// which they learn the peer's addr. Ipv6 asks first, so it wins whenever
+    /// both peers registered it.
+    fn exchange<'a>(
+        &'a self,
+        punch: &'a PunchContext,
+        _ours: Vec<Candidate>,
+    ) -> BoxFuture<'a, Result<Vec<Pair>>> {
+        Box::pin(async move {
+            let msg = register_message(punch, Strategy::Udp)?;
+            let mut sockets = mem::take(&mut *self.sockets.lock().unwrap());
+            let (socket, remote) = happy_eyeballs(&punch.server_addrs, |server_addr| {
+                let local = sockets
+                    .keys()
+                    .copied()
+                    .find(|local| local.is_ipv6() == server_addr.is_ipv6());
+                let socket = local.and_then(|local| sockets.remove(&local));
+                let msg = &msg;
+                async move {
+                    let socket = socket.ok_or_else(|| anyhow!("no socket for {}", server_addr))?;
+                    let local = socket.local_addr()?;
+                    self.sources
+                        .lock()
+                        .unwrap()
+                        .insert(server_addr, Source::Port(local));
+                    let remote = udp_register(&socket, server_addr, msg, &punch.events).await?;
+                    Ok((socket, remote))
+                }
+            })
+            .await?;
+            let local = socket.local_addr()?;
+            self.sockets.lock().unwrap().insert(local, socket);
+            Ok(vec![Pair { local, remote }])
+        })
+    }
+
+    fn connect<'a>(
+        &'a self,
+        punch: &'a PunchContext,
+        pairs: Vec<Pair>,
+    ) -> BoxFuture<'a, Result<Punched>> {
+        Box::pin(async move {
+            let mut sockets = mem::take(&mut *self.sockets.lock().unwrap());
+            let (pair, socket) = pairs
+                .into_iter()
+                .find_map(|pair| Some((pair, sockets.remove(&pair.local)?)))
+                .ok_or_else(|| anyhow!("no socket for the pairs"))?;
+
+            // step 3 connect to peer
+            punch.events.record(Event::Attempt {
+                local: pair.local,
+                remote: pair.remote,
+            });
+            loop {
+                match timeout(Duration::from_secs(3), socket.connect(pair.remote)).await {
+                    Ok(_) => {
+                        tracing::info!("connect to peer {:?} success.", pair.remote);
+                        break;
+                    }
+                    Err(e) => {
+                        tracing::warn!("Failed to connect to peer {:?} {:?}", pair.remote, e);
+                    }
+                }
+            }
+            let msg = register_message(punch, Strategy::Udp)?;
+            Ok(Punched {
+                stream: PunchedStream::Udp(RudpStream::new(socket)?),
+                initiator: msg.id < msg.peer_id,
+            })
+        })
+    }
+}
+
+/// The built-in strategy of [`Strategy::Tcp`]: registers over tcp until the
+/// server tells the peer's addr, then connects to it from the port the
+/// registration went out from, or listens on that port. The connector takes
+/// a fresh port for every registration, the listener keeps its first one so
+/// the connector is never told a port it left.
+#[derive(Default)]
+pub struct TcpPunch {
+    /// Listen on the registered port instead of connecting
+    pub listener: bool,
+    sources: Sources,
+}
+
+impl PunchStrategy for TcpPunch {
+    fn name(&self) -> &str {
+        Strategy::Tcp.name()
+    }
+
+    fn gather<'a>(&'a self, punch: &'a PunchContext) -> BoxFuture<'a, Result<Vec<Candidate>>> {
+        Box::pin(async move {
+            let observed = join_all(punch.server_addrs.iter().map(|&server_addr| async move {
+                match observed_tcp(server_addr, &punch.id).await {
+                    Ok((local, public)) => {
+                        record_mapped(&punch.events, server_addr, local, public);
+                        Some(Candidate {
+                            addr: public,
+                            local: Some(local),
+                        })
+                    }
+                    Err(e) => {
+                        tracing::info!("server {} does not tell our addr: {:?}", server_addr, e);
+                        None
+                    }
+                }
+            }))
+            .await;
+            Ok(observed.into_iter().flatten().collect())
+        })
+    }
+
+    fn exchange<'a>(
+        &'a self,
+        punch: &'a PunchContext,
+        _ours: Vec<Candidate>,
+    ) -> BoxFuture<'a, Result<Vec<Pair>>> {
+        Box::pin(async move {
+            let msg = register_message(punch, Strategy::Tcp)?;
+            let pair = happy_eyeballs(&punch.server_addrs, |server_addr| {
+                tcp_register(
+                    server_addr,
+                    &msg,
+                    self.listener,
+                    &self.sources,
+                    &punch.events,
+                )
+            })
+            .await?;
+            Ok(vec![pair])
+        })
+    }
+
+    fn connect<'a>(
+        &'a self,
+        punch: &'a PunchContext,
+        pairs: Vec<Pair>,
+    ) -> BoxFuture<'a, Result<Punched>> {
+        Box::pin(async move {
+            let Pair {
+                local: local_addr,
+                remote: peer_addr,
+            } = *pairs.first().ok_or_else(|| anyhow!("no pair"))?;
+
+            // step 2 get stream
+            let stream = if self.listener {
+                //监听之前的端口
+                let listener = new_tcp_listener(local_addr, true).await?;
+                tracing::info!("A listening at {:?}", local_addr);
+                loop {
+                    let (stream, addr) = listener.accept().await?;
+                    tracing::info!("accept client from {:?}", addr);
+                    // 对端每次注册都换端口, 只认ip
+                    if addr.ip() == peer_addr.ip() {
+                        break stream;
+                    }
+                    tracing::warn!("expect {:?}, but accept {:?}", peer_addr, addr);
+                }
+            } else {
+                loop {
+                    //用之前的端口去连接
+                    let socket = new_tcp_socket(local_addr, true)?;
+                    punch.events.record(Event::Attempt {
+                        local: local_addr,
+                        remote: peer_addr,
+                    });
+                    let error =
+                        match timeout(Duration::from_secs(3), socket.connect(peer_addr)).await {
+                            Ok(Ok(stream)) => {
+                                tracing::info!("connect to peer {:?} success.", peer_addr);
+                                break stream;
+                            }
+                            Ok(Err(e)) => e.to_string(),
+                            Err(_) => "timed out".to_owned(),
+                        };
+                    tracing::warn!("Failed to connect to peer {:?}: {}", peer_addr, error);
+                    punch.events.record(Event::AttemptFailed {
+                        local: local_addr,
+                        remote: peer_addr,
+                        error,
+                    });
+                    // the peer may not listen yet
+                    sleep(Duration::from_millis(500)).await;
+                }
+            };
+
+            Ok(Punched {
+                stream: PunchedStream::Tcp(stream),
+                initiator: !self.listener,
+            })
+        })
+    }
+}
+
+/// The built-in strategy of [`Strategy::Hybrid`]: A asks the server over tcp
+/// for B's addr and connects to it from the same port, B hears of A through
+/// its udp registration, opens the hole, answers and listens. The server
+/// learns the addrs from the connections, there is nothing to gather.
+pub struct HybridPunch {
+    punch_requests: tokio::sync::Mutex<mpsc::Receiver<PunchRequest>>,
+}
+
+/// An A the udp registration of B was told of with punchS2B.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PunchRequest {
+    pub tcp_addr_a: SocketAddr,
+    /// What B answers the server with: servers from before [`Answer::A`]
+    /// only take the legacy byte, they are the ones that never answered
+    /// addr_request
+    pub answer: Answer,
+}
+
+impl HybridPunch {
+    /// `punch_requests` are the As the udp registration of B is told of, a
+    /// [`Puncher`] sends them from its registration.
+    pub fn new(punch_requests: mpsc::Receiver<PunchRequest>) -> Self {
+        Self {
+            punch_requests: tokio::sync::Mutex::new(punch_requests),
+        }
+    }
+}
+
+impl PunchStrategy for HybridPunch {
+    fn name(&self) -> &str {
+        Strategy::Hybrid.name()
+    }
+
+    fn gather<'a>(&'a self, _punch: &'a PunchContext) -> BoxFuture<'a, Result<Vec<Candidate>>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+
+    fn exchange<'a>(
+        &'a self,
+        punch: &'a PunchContext,
+        _ours: Vec<Candidate>,
+    ) -> BoxFuture<'a, Result<Vec<Pair>>> {
+        Box::pin(async move {
+            let pair = match &punch.peer_id {
+                Some(peer_id) => {
+                    happy_eyeballs(&punch.server_addrs, |server_addr| {
+                        tcp_ask_a(server_addr, peer_id.clone(), &punch.events)
+                    })
+                    .await?
+                }
+                None => {
+                    let PunchRequest { tcp_addr_a, answer } = self
+                        .punch_requests
+                        .lock()
+                        .await
+                        .recv()
+                        .await
+                        .ok_or_else(|| anyhow!("udp task stopped"))?;
+                    //A选的协议族
+                    let server_addr = punch
+                        .server_addrs
+                        .iter()
+                        .copied()
+                        .find(|addr| addr.is_ipv6() == tcp_addr_a.is_ipv6())
+                        .ok_or_else(|| anyhow!("no server addr for {:?}", tcp_addr_a))?;
+                    tcp_answer_b(server_addr, tcp_addr_a, answer, &punch.events).await?
+                }
+            };
+            Ok(vec![pair])
+        })
+    }
+
+    fn connect<'a>(
+        &'a self,
+        punch: &'a PunchContext,
+        pairs: Vec<Pair>,
+    ) -> BoxFuture<'a, Result<Punched>> {
+        Box::pin(async move {
+            let pair = *pairs.first().ok_or_else(|| anyhow!("no pair"))?;
+            match punch.peer_id {
+                Some(_) => tcp_connect_a(pair, &punch.events).await,
+                None => tcp_listen_b(pair).await,
+            }
+        })
+    }
+}
+
+/// Punches udp holes with the candidates traded through a [`Signaling`]
+/// instead of a rendezvous server: both sides send probes to every
+/// candidate of the other until one gets through, probes from anywhere
+/// else are ignored. The server addrs, if any, only tell the public addrs
+/// of our sockets.
+pub struct SignaledPunch {
+    signaling: Arc<dyn Signaling>,
+    bind: Vec<SocketAddr>,
+    // the ids answered without a peer_id
+    accept: Vec<String>,
+    // by local addr, bound by gather and taken by connect
+    sockets: Mutex<HashMap<SocketAddr, Datagram>>,
+    // the peer_id, or the id of the first peer that signaled us
+    peer: Mutex<Option<String>>,
+}
+
+impl SignaledPunch {
+    pub fn new(signaling: Arc<dyn Signaling>) -> Self {
+        Self {
+            signaling,
+            bind: Vec::new(),
+            accept: Vec::new(),
+            sockets: Mutex::new(HashMap::new()),
+            peer: Mutex::new(None),
+        }
+    }
+
+    /// Local addrs to bind, instead of any addr in the families of the servers.
+    pub fn bind(mut self, addrs: Vec<SocketAddr>) -> Self {
+        self.bind = addrs;
+        self
+    }
+
+    /// The ids that may signal us when there is no peer_id, the signals of
+    /// other ids are skipped. Without a peer_id or these nobody is answered.
+    pub fn accept(mut self, ids: Vec<String>) -> Self {
+        self.accept = ids;
+        self
+    }
+
+    // the signal of the peer, after ours when we know whom to send to
+    async fn trade(&self, punch: &PunchContext, ours: Vec<Candidate>) -> Result<Signal> {
+        let signal = |to: &str| Signal {
+            from: punch.id.clone(),
+            to: to.to_owned(),
+            candidates: ours.clone(),
+        };
+        match &punch.peer_id {
+            Some(peer_id) => {
+                let start = Instant::now();
+                let known = loop {
+                    if let Some(known) = self.signaling.lookup(peer_id).await? {
+                        break known;
+                    }
+                    if start.elapsed() >= ACCEPT_TIMEOUT {
+                        bail!("{} did not register", peer_id);
+                    }
+                    sleep(Duration::from_secs(1)).await;
+                };
+                self.signaling.send(signal(peer_id)).await?;
+                loop {
+                    let mut theirs = self.signaling.receive().await?;
+                    if &theirs.from == peer_id {
+                        theirs.candidates.extend(known);
+                        return Ok(theirs);
+                    }
+                    tracing::info!("signal from {} skipped", theirs.from);
+                }
+            }
+            None => {
+                if self.accept.is_empty() {
+                    bail!("no peer id and no ids to accept");
+                }
+                loop {
+                    let theirs = self.signaling.receive().await?;
+                    if self.accept.contains(&theirs.from) {
+                        self.signaling.send(signal(&theirs.from)).await?;
+                        return Ok(theirs);
+                    }
+                    tracing::info!("signal from {} skipped", theirs.from);
+                }
+            }
+        }
+    }
+}
+
+impl PunchStrategy for SignaledPunch {
+    fn name(&self) -> &str {
+        "signaled"
+    }
+
+    fn gather<'a>(&'a self, punch: &'a PunchContext) -> BoxFuture<'a, Result<Vec<Candidate>>> {
+        Box::pin(async move {
+            let bind = match self.bind.is_empty() {
+                true => punch
+                    .server_addrs
+                    .iter()
+                    .map(|&addr| any_addr(addr))
+                    .collect(),
+                false => self.bind.clone(),
+            };
+            let mut candidates = Vec::new();
+            let mut sockets = HashMap::new();
+            for addr in bind {
+                let socket = punch.network.bind_udp(addr).await?;
+                let local = socket.local_addr()?;
+                if !local.ip().is_unspecified() {
+                    candidates.push(Candidate {
+                        addr: local,
+                        local: Some(local),
+                    });
+                }
+                for &server_addr in &punch.server_addrs {
+                    if server_addr.is_ipv6() != local.is_ipv6() {
+                        continue;
+                    }
+                    match observe_from(&socket, server_addr, &punch.id, &punch.events).await {
+                        Ok(public) => candidates.push(Candidate {
+                            addr: public,
+                            local: Some(local),
+                        }),
+                        Err(e) => {
+                            tracing::info!("server {} does not tell our addr: {:?}", server_addr, e)
+                        }
+                    }
+                }
+                sockets.insert(local, socket);
+            }
+            candidates.truncate(MAX_CANDIDATES);
+            *self.sockets.lock().unwrap() = sockets;
+            Ok(candidates)
+        })
+    }
+
+    fn exchange<'a>(
+        &'a self,
+        punch: &'a PunchContext,
+        ours: Vec<Candidate>,
+    ) -> BoxFuture<'a, Result<Vec<Pair>>> {
+        Box::pin(async move {
+            self.signaling.register(&punch.id).await?;
+            let theirs = self.trade(punch, ours).await?;
+            *self.peer.lock().unwrap() = Some(theirs.from.clone());
+            let locals: Vec<SocketAddr> = self.sockets.lock().unwrap().keys().copied().collect();
+            let mut pairs = Vec::new();
+            for candidate in theirs.candidates {
+                for &local in &locals {
+                    let pair = Pair {
+                        local,
+                        remote: candidate.addr,
+                    };
+                    if local.is_ipv6() == candidate.addr.is_ipv6() && !pairs.contains(&pair) {
+                        pairs.push(pair);
+                    }
+                }
+            }
+            if pairs.is_empty() {
+                bail!("no candidate of {} in our families", theirs.from);
+            }
+            Ok(pairs)
+        })
+    }
+
+    fn connect<'a>(
+        &'a self,
+        punch: &'a PunchContext,
+        pairs: Vec<Pair>,
+    ) -> BoxFuture<'a, Result<Punched>> {
+        Box::pin(async move {
+            let sockets = mem::take(&mut *self.sockets.lock().unwrap());
+            let peer = self.peer.lock().unwrap().clone().unwrap_or_default();
+            for pair in &pairs {
+                punch.events.record(Event::Attempt {
+                    local: pair.local,
+                    remote: pair.remote,
+                });
+            }
+            let probing = async {
+                let mut timer = interval(PROBE_INTERVAL);
+                loop {
+                    timer.tick().await;
+                    for pair in &pairs {
+                        if let Some(socket) = sockets.get(&pair.local) {
+                            socket.send_to(PROBE, pair.remote).await.ok();
+                        }
+                    }
+                }
+            };
+            let answered = FuturesUnordered::new();
+            for (&local, socket) in &sockets {
+                let remotes: Vec<SocketAddr> = pairs
+                    .iter()
+                    .filter(|pair| pair.local == local)
+                    .map(|pair| pair.remote)
+                    .collect();
+                answered.push(async move {
+                    let mut buf = vec![0u8; 1024];
+                    loop {
+                        match socket.recv_from(&mut buf).await {
+                            Ok((n, from)) if &buf[..n] == PROBE && remotes.contains(&from) => {
+                                return (local, from)
+                            }
+                            // icmp unreachable of a candidate that is not there,
+                            // or a probe from none of them
+                            _ => continue,
+                        }
+                    }
+                });
+            }
+            let (local, remote) = tokio::select! {
+                _ = probing => unreachable!(),
+                answered = answered.into_future() => answered.0.ok_or_else(|| anyhow!("no socket for the pairs"))?,
+                _ = sleep(ACCEPT_TIMEOUT) => bail!("no probe of {} got through", peer),
+            };
+            tracing::info!("probe from {:?} at {:?}", remote, local);
+            let socket = sockets
+                .into_iter()
+                .find_map(|(addr, socket)| (addr == local).then_some(socket))
+                .ok_or_else(|| anyhow!("no socket at {:?}", local))?;
+            // the peer may not have heard us yet
+            for _ in 0..3 {
+                socket.send_to(PROBE, remote).await.ok();
+            }
+            socket.connect(remote).await?;
+            Ok(Punched {
+                stream: PunchedStream::Udp(RudpStream::new(socket)?),
+                initiator: punch.id < peer,
+            })
+        })
+    }
+}
+
+fn register_message(punch: &PunchContext, strategy: Strategy) -> Result<Register> {
+    match &punch.peer_id {
+        Some(peer_id) => Ok(Register {
+            id: punch.id.clone(),
+            peer_id: peer_id.clone(),
+        }),
+        None => bail!("{:?} needs a peer id", strategy),
+    }
+}
+
+/// Registers `msg` at the tcp server until it tells the peer's addr, the
+/// port it went out from is punched from. The `listener` registers from its
+/// first port every time, the other side from a fresh one.
+async fn tcp_register(
+    server_addr: SocketAddr,
+    msg: &Register,
+    listener: bool,
+    sources: &Sources,
+    events: &Events,
+) -> Result<Pair> {
+    let mut buf = vec![0u8; 1024];
+    let mut first_port = None;
+
+    // step 1: connect server && register && get peer addr and local addr
+    let mut answered = Instant::now();
+    loop {
+        sleep(Duration::from_secs(1)).await;
+        if answered.elapsed() >= SERVER_TIMEOUT {
+            bail!("server {} does not answer", server_addr);
+        }
+
+        // connect server
+        let connect = async {
+            match first_port {
+                Some(local) => new_tcp_socket(local, true)?.connect(server_addr).await,
+                None if listener => {
+                    let local = match server_addr {
+                        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+                        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+                    };
+                    new_tcp_socket(local, true)?.connect(server_addr).await
+                }
+                None => TcpStream::connect(server_addr).await,
+            }
+        };
+        let mut stream = match timeout(Duration::from_secs(3), connect).await {
+            Ok(Ok(s)) => {
+                tracing::info!("connect to {} ok!", server_addr);
+                s
+            }
+            _ => {
+                tracing::warn!("connect to {} time out", server_addr);
+                continue;
+            }
+        };
+
+        // register
+        if let Ok(local) = stream.local_addr() {
+            if listener {
+                first_port = Some(local);
+            }
+            sources
+                .lock()
+                .unwrap()
+                .insert(server_addr, Source::Port(local));
+        }
+        match stream.write_all(&msg.encode()).await {
+            Ok(_) => tracing::info!("send register ok"),
+            Err(e) => {
+                tracing::error!("send register falied. {:?}", e);
+                continue;
+            }
+        }
+
+        // get peer addr and local addr
+        match timeout(Duration::from_secs(3), stream.read(&mut buf)).await {
+            Ok(Ok(size)) => {
+                if let Ok(peer) = Peer::decode(&buf[..size]) {
+                    answered = Instant::now();
+                    if let Some(addr) = peer.peer_addr {
+                        tracing::info!("get peer addr {:?}", addr);
+                        events.record(Event::PeerAddr {
+                            server: server_addr,
+                            peer: addr,
+                        });
+                        return Ok(Pair {
+                            local: stream.local_addr()?,
+                            remote: addr,
+                        });
+                    } else {
+                        tracing::info!("peer is not registered yet");
+                    }
+                }
+            }
+            _ => {
+                tracing::warn!("wait register response timeout");
+                continue;
+            }
+        }
+    }
+}
+
+/// Unregisters a [`Puncher`] from outside, also once it moved into a
+/// [`crate::session::Session`].
+#[derive(Clone)]
+pub struct Leave {
+    strategy: Strategy,
+    id: String,
+    network: Network,
+    sources: Sources,
+    leaving: Arc<watch::Sender<bool>>,
+}
+
+impl Leave {
+    /// Tells every server addr we registered at that we leave, so it stops
+    /// handing out our addrs, and stops the hybrid heartbeat. Nothing is
+    /// answered, a lost message only leaves the registration to expire. So
+    /// does a udp or tcp port the punched connection still holds.
+    pub async fn unregister(&self) {
+        self.leaving.send_replace(true);
+        let sources = self.sources.lock().unwrap().clone();
+        for (server_addr, source) in sources {
+            match timeout(LEAVE_TIMEOUT, self.unregister_at(server_addr, source)).await {
+                Ok(Ok(())) => tracing::info!("unregistered at {}", server_addr),
+                Ok(Err(e)) => tracing::warn!("could not unregister at {}: {:?}", server_addr, e),
+                Err(_) => tracing::warn!("could not unregister at {} in time", server_addr),
+            }
+        }
+    }
+
+    // servers only take it from the addr and socket we registered from
+    async fn unregister_at(&self, server_addr: SocketAddr, source: Source) -> Result<()> {
+        let unregister = Unregister {
+            unregister: self.id.clone(),
+        };
+        match (self.strategy, source) {
+            (_, Source::Socket(socket)) => {
+                let data = Message::unregister_request(self.id.clone()).encode();
+                socket.send_to(&data, server_addr).await?;
+            }
+            (Strategy::Tcp, Source::Port(local)) => {
+                let mut stream = new_tcp_socket(local, true)?.connect(server_addr).await?;
+                stream.write_all(&unregister.encode()).await?;
+            }
+            (_, Source::Port(local)) => {
+                let socket = self.network.bind_udp(local).await?;
+                socket.send_to(&unregister.encode(), server_addr).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the hybrid registration alive: a udp heartbeat for every addr of the
+/// current server, the next server when none of them gets answers, and a new
+/// lookup of the name every [`RESOLVE_INTERVAL`].
+#[allow(clippy::too_many_arguments)]
+async fn registration_task(
+    mut servers: ServerList,
+    network: Network,
+    id: String,
+    sender: mpsc::Sender<PunchRequest>,
+    notify: Arc<Notify>,
+    server_addrs: Arc<Mutex<Vec<SocketAddr>>>,
+    sources: Sources,
+    mut leaving: watch::Receiver<bool>,
+    events: Events,
+) {
+    loop {
+        if *leaving.borrow() {
+            return;
+        }
+        let addrs = match servers.addrs().await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                tracing::warn!("{:?}", e);
+                events.record(Event::ServerFailed {
+                    server: servers.current().to_owned(),
+                    error: format!("{:#}", e),
+                });
+                sleep(Duration::from_secs(3)).await;
+                continue;
+            }
+        };
+        events.record(Event::Server {
+            server: servers.current().to_owned(),
+            addrs: addrs.clone(),
+        });
+        *server_addrs.lock().unwrap() = addrs.clone();
+        sources.lock().unwrap().clear();
+        let mut heartbeats = FuturesUnordered::new();
+        for &addr in &addrs {
+            match network.bind_udp(any_addr(addr)).await {
+                Ok(socket) => {
+                    let socket = Arc::new(socket);
+                    sources
+                        .lock()
+                        .unwrap()
+                        .insert(addr, Source::Socket(socket.clone()));
+                    heartbeats.push(udp_task(
+                        socket,
+                        addr,
+                        id.clone(),
+                        sender.clone(),
+                        notify.clone(),
+                        events.clone(),
+                    ))
+                }
+                Err(e) => tracing::warn!("no udp socket for {}: {:?}", addr, e),
+            }
+        }
+        if heartbeats.is_empty() {
+            sleep(Duration::from_secs(3)).await;
+        }
+
+        let mut resolve_at = Instant::now() + RESOLVE_INTERVAL;
+        let mut error = "no heartbeat is answered".to_owned();
+        loop {
+            tokio::select! {
+                stopped = heartbeats.next() => match stopped {
+                    Some(result) => {
+                        tracing::warn!("heartbeat stopped {:?}", result);
+                        if let Err(e) = result {
+                            error = format!("{:#}", e);
+                        }
+                    }
+                    None => {
+                        events.record(Event::ServerFailed {
+                            server: servers.current().to_owned(),
+                            error,
+                        });
+                        servers.fail_over();
+                        break;
+                    }
+                },
+                _ = leaving.changed() => return,
+                _ = tokio::time::sleep_until(resolve_at) => {
+                    match servers.addrs().await {
+                        Ok(new) if new != addrs => break,
+                        _ => resolve_at = Instant::now() + RESOLVE_INTERVAL,
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Runs `attempt` for each addr in order and returns the first success. The
+/// next addr starts when the previous ones failed or ran for
+/// [`HAPPY_EYEBALLS_DELAY`], attempts still running when one succeeds are dropped.
+pub async fn happy_eyeballs<T, F, Fut>(addrs: &[SocketAddr], mut attempt: F) -> Result<T>
+where
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut waiting: VecDeque<_> = addrs.iter().copied().collect();
+    let mut running = FuturesUnordered::new();
+    let mut error = anyhow!("no addr to try");
+    loop {
+        if running.is_empty() {
+            match waiting.pop_front() {
+                Some(addr) => running.push(attempt(addr)),
+                None => return Err(error),
+            }
+        }
+        tokio::select! {
+            Some(result) = running.next() => match result {
+                Ok(t) => return Ok(t),
+                Err(e) => {
+                    tracing::warn!("attempt failed {:?}", e);
+                    error = e;
+                    if let Some(addr) = waiting.pop_front() {
+                        running.push(attempt(addr));
+                    }
+                }
+            },
+            _ = sleep(HAPPY_EYEBALLS_DELAY), if !waiting.is_empty() => {
+                let addr = waiting.pop_front().unwrap();
+                tracing::info!("no luck yet, also trying {}", addr);
+                running.push(attempt(addr));
+            }
+        }
+    }
+}
+
+/// Registers `msg` at the server `socket` is connected to until it tells the
+/// peer's addr.
+async fn udp_register(
+    socket: &Datagram,
+    server_addr: SocketAddr,
+    msg: &Register,
+    events: &Events,
+) -> Result<SocketAddr> {
+    let mut buf = vec![0u8; 1024];
+    let mut answered = Instant::now();
+    loop {
+        sleep(Duration::from_secs(1)).await;
+        if answered.elapsed() >= SERVER_TIMEOUT {
+            bail!("server {} does not answer", server_addr);
+        }
+        match socket.send(&pad_request(msg.encode())).await {
+            Ok(_) => tracing::info!("send register ok"),
+            Err(e) => {
+                tracing::error!("send register falied. {:?}", e);
+                continue;
+            }
+        }
+
+        match timeout(Duration::from_secs(3), socket.recv(&mut buf)).await {
+            Ok(Ok(size)) => {
+                if GoingAway::decode(&buf[..size]).is_ok() {
+                    bail!("server {} is going away", server_addr);
+                }
+                if let Ok(peer) = Peer::decode(&buf[..size]) {
+                    answered = Instant::now();
+                    if let Some(addr) = peer.peer_addr {
+                        tracing::info!("get peer addr {:?}", addr);
+                        events.record(Event::PeerAddr {
+                            server: server_addr,
+                            peer: addr,
+                        });
+                        return Ok(addr);
+                    } else {
+                        tracing::info!("peer is not registered yet");
+                    }
+                }
+            }
+            _ => {
+                tracing::warn!("wait register response timeout");
+                continue;
+            }
+        }
+    }
+}
+
+/// Asks the server over udp what it sees of the connected `socket`.
+async fn observe_udp(
+    socket: &Datagram,
+    server_addr: SocketAddr,
+    id: &str,
+    events: &Events,
+) -> Result<SocketAddr> {
+    let mut buf = vec![0u8; 1024];
+    socket
+        .send(&pad_request(Observe { id: id.to_owned() }.encode()))
+        .await?;
+    let n = timeout(OBSERVE_TIMEOUT, socket.recv(&mut buf)).await??;
+    let observed = Observed::decode(&buf[..n])?;
+    record_mapped(events, server_addr, socket.local_addr()?, observed.addr);
+    Ok(observed.addr)
+}
+
+/// [`observe_udp`] for a socket that is not connected to the server.
+async fn observe_from(
+    socket: &Datagram,
+    server_addr: SocketAddr,
+    id: &str,
+    events: &Events,
+) -> Result<SocketAddr> {
+    let mut buf = vec![0u8; 1024];
+    socket
+        .send_to(
+            &pad_request(Observe { id: id.to_owned() }.encode()),
+            server_addr,
+        )
+        .await?;
+    let observed = timeout(OBSERVE_TIMEOUT, async {
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await?;
+            if from == server_addr {
+                return Observed::decode(&buf[..n]);
+            }
+        }
+    })
+    .await??;
+    record_mapped(events, server_addr, socket.local_addr()?, observed.addr);
+    Ok(observed.addr)
+}
+
+/// Asks the tcp server what it sees of a connection from a fresh port.
+async fn observe_tcp(server_addr: SocketAddr, id: &str, events: &Events) -> Result<()> {
+    let (local, public) = observed_tcp(server_addr, id).await?;
+    record_mapped(events, server_addr, local, public);
+    Ok(())
+}
+
+// the local addr of the connection and the addr the server saw
+async fn observed_tcp(server_addr: SocketAddr, id: &str) -> Result<(SocketAddr, SocketAddr)> {
+    let mut buf = vec![0u8; 1024];
+    let mut stream = timeout(Duration::from_secs(3), TcpStream::connect(server_addr)).await??;
+    stream
+        .write_all(&Observe { id: id.to_owned() }.encode())
+        .await?;
+    let n = timeout(OBSERVE_TIMEOUT, stream.read(&mut buf)).await??;
+    let observed = Observed::decode(&buf[..n])?;
+    Ok((stream.local_addr()?, observed.addr))
+}
+
+/// Which kind of server is at `server_addr`: a hybrid server answers
+/// addr_request over udp, the udp and tcp servers answer Observe over theirs.
+async fn probe(server_addr: SocketAddr, network: &Network, id: &str) -> Result<Strategy> {
+    let udp = async {
+        let socket = network.bind_udp(any_addr(server_addr)).await?;
+        socket.connect(server_addr).await?;
+        socket
+            .send(&pad_request(Message::addr_request(id.to_owned()).encode()))
+            .await?;
+        socket
+            .send(&pad_request(Observe { id: id.to_owned() }.encode()))
+            .await?;
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            if Observed::decode(&buf[..n]).is_ok() {
+                return Ok(Strategy::Udp);
+            }
+            if let Ok(Message::addr_response(_)) = Message::decode(&buf[..n]) {
+                return Ok(Strategy::Hybrid);
+            }
+        }
+    };
+    let tcp = async {
+        observed_tcp(server_addr, id).await?;
+        Ok(Strategy::Tcp)
+    };
+    let mut probes: FuturesUnordered<Pin<Box<dyn Future<Output = Result<Strategy>> + '_>>> =
+        FuturesUnordered::new();
+    probes.push(Box::pin(udp));
+    probes.push(Box::pin(tcp));
+    let mut error = anyhow!("no probe");
+    loop {
+        match timeout(OBSERVE_TIMEOUT, probes.next()).await {
+            Ok(Some(Ok(strategy))) => return Ok(strategy),
+            Ok(Some(Err(e))) => error = e,
+            Ok(None) | Err(_) => {
+                return Err(error.context(format!("server {} does not tell its kind", server_addr)))
+            }
+        }
+    }
+}
+
+fn record_mapped(events: &Events, server: SocketAddr, local: SocketAddr, public: SocketAddr) {
+    let nat_type = NatType::classify(local, public);
+    tracing::info!(
+        "server {} sees {} as {} ({:?})",
+        server,
+        local,
+        public,
+        nat_type
+    );
+    events.record(Event::Mapped {
+        server,
+        local,
+        public,
+        nat_type,
+    });
+}
+
+//主动连接的客户端A, 从服务器得到B的地址
+async fn tcp_ask_a(server_addr: SocketAddr, peer_id: String, events: &Events) -> Result<Pair> {
+    //step 1: 连接服务器
+    let mut stream = connect_server(server_addr).await?;
+
+    //step 2: 主动打洞, 请求B地址
+    let punch_a2s = Message::punchA2S(peer_id);
+    match stream.write_all(&punch_a2s.encode()).await {
+        Ok(_) => tracing::info!("send punch request to server"),
+        Err(e) => {
+            tracing::error!("tcp send failed {:?}", e);
+            return Err(anyhow!("{:?}", e));
+        }
+    }
+
+    //step 3: 等待打洞成功
+    let mut buf = vec![0u8; 1024];
+    let n = match timeout(Duration::from_secs(10), stream.read(&mut buf)).await {
+        Ok(Ok(n)) => n,
+        _ => bail!("punch failed. timeout"),
+    };
+    let message = Message::decode(&buf[..n])?;
+    tracing::info!("tcp recv {:?}", message);
+    let tcp_addr_b = match message {
+        Message::punchS2A(Some(tcp_addr_b)) => {
+            events.record(Event::PeerAddr {
+                server: server_addr,
+                peer: tcp_addr_b,
+            });
+            tcp_addr_b
+        }
+        Message::punchS2A(None) => bail!("punch failed. B not register"),
+        Message::going_away => bail!("server {} is going away", server_addr),
+        _ => bail!("punch failed. unexpected {:?}", message),
+    };
+    Ok(Pair {
+        local: stream.local_addr()?,
+        remote: tcp_addr_b,
+    })
+}
+
+async fn tcp_connect_a(pair: Pair, events: &Events) -> Result<Punched> {
+    //step 4: 用同一个本地端口连接B
+    let Pair {
+        local: local_addr,
+        remote: tcp_addr_b,
+    } = pair;
+    for _ in 0..5 {
+        tracing::info!(
+            "try new_tcp_stream with local: {:?}, remote:{:?}",
+            local_addr,
+            tcp_addr_b
+        );
+        events.record(Event::Attempt {
+            local: local_addr,
+            remote: tcp_addr_b,
+        });
+        match new_tcp_stream(tcp_addr_b, local_addr, 5).await {
+            Ok(stream) => {
+                return Ok(Punched {
+                    stream: PunchedStream::Tcp(stream),
+                    initiator: true,
+                })
+            }
+            Err(e) => {
+                tracing::error!("Failed new_tcp_stream {:?}", e);
+                events.record(Event::AttemptFailed {
+                    local: local_addr,
+                    remote: tcp_addr_b,
+                    error: format!("{:#}", e),
+                });
+            }
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+    bail!("punch failed. could not connect to {:?}", tcp_addr_b)
+}
+
+//被动连接的客户端B, 为A打开洞并回复服务器
+async fn tcp_answer_b(
+    server_addr: SocketAddr,
+    tcp_addr_a: SocketAddr,
+    answer: Answer,
+    events: &Events,
+) -> Result<Pair> {
+    //step 1: 连接服务器, 获取本地地址
+    let mut stream = connect_server(server_addr).await?;
+    let local_addr = stream.local_addr()?;
+    tracing::info!("my local addr:{:?}", local_addr);
+
+    // step 2: 向A发一个任意消息
+    events.record(Event::Attempt {
+        local: local_addr,
+        remote: tcp_addr_a,
+    });
+    match new_tcp_stream(tcp_addr_a, local_addr, 3).await {
+        Ok(mut test_stream) => {
+            if test_stream.write_all(b" ").await.is_ok() {
+                tracing::info!("send one byte ok");
+            }
+        }
+        // expected behind most nats, it only opens the hole for A
+        Err(e) => events.record(Event::AttemptFailed {
+            local: local_addr,
+            remote: tcp_addr_a,
+            error: format!("{:#}", e),
+        }),
+    }
+
+    //step 3: 回复服务器, 使其获取自己的地址
+    let punch_b2s = Message::punchB2S(answer);
+    match stream.write_all(&punch_b2s.encode()).await {
+        Ok(_) => tracing::info!("send punch response to server"),
+        Err(e) => {
+            tracing::error!("tcp send failed {:?}", e);
+            return Err(anyhow!("{:?}", e));
+        }
+    }
+    Ok(Pair {
+        local: local_addr,
+        remote: tcp_addr_a,
+    })
+}
+
+async fn tcp_listen_b(pair: Pair) -> Result<Punched> {
+    //step 4: 监听端口, 等待连接
+    let Pair {
+        local: local_addr,
+        remote: tcp_addr_a,
+    } = pair;
+    tracing::info!("try listen at {:?}", local_addr);
+    let listener = new_tcp_listener(local_addr, true).await?;
+    tracing::info!("listen at {:?} ok", local_addr);
+    // A may have got through in another family already
+    let (stream, addr) = timeout(ACCEPT_TIMEOUT, listener.accept())
+        .await
+        .map_err(|_| anyhow!("A {:?} did not connect", tcp_addr_a))??;
+    tracing::info!("accept connection {:?}", addr);
+    if addr != tcp_addr_a {
+        tracing::error!("different addr:{:?}, {:?}", addr, tcp_addr_a);
+    }
+    Ok(Punched {
+        stream: PunchedStream::Tcp(stream),
+        initiator: false,
+    })
+}
+
+async fn connect_server(server_addr: SocketAddr) -> Result<TcpStream> {
+    let start = Instant::now();
+    while start.elapsed() < SERVER_TIMEOUT {
+        match timeout(
+            Duration::from_secs(3),
+            new_tcp_stream(server_addr, any_addr(server_addr), 3),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => {
+                tracing::info!("tcp connect to server {} ok!", server_addr);
+                return Ok(stream);
+            }
+            _ => {
+                tracing::warn!("tcp connect to server {} time out", server_addr);
+                sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+    bail!("server {} does not answer", server_addr)
+}
+
+async fn udp_task(
+    socket: Arc<Datagram>,
+    server_addr: SocketAddr,
+    id: String,
+    sender: mpsc::Sender<PunchRequest>,
+    notify_register: Arc<Notify>,
+    events: Events,
+) -> Result<()> {
+    tracing::info!("start udp task...");
+    let mut buf = vec![0u8; 1024];
+
+    // step 1: connect server
+    loop {
+        match timeout(Duration::from_secs(3), socket.connect(server_addr)).await {
+            Ok(_) => {
+                tracing::info!("udp connect to {:?} ok!", server_addr);
+                break;
+            }
+            Err(e) => {
+                tracing::warn!("udp connect to {:?} time out :{:?}", server_addr, e);
+            }
+        }
+    }
+
+    //step 2: regiter repeatly and waiting punch request
+    let mut timer = interval(Duration::from_secs(3));
+    let mut answered = Instant::now();
+    let mut registered = false;
+    // the server answered addr_request, so it takes Answer::A
+    let mut knows_answers = false;
+
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {
+                if answered.elapsed() >= SERVER_TIMEOUT {
+                    bail!("server {} does not answer", server_addr);
+                }
+                let register_request = Message::register_request(id.clone());
+                match socket.send(&pad_request(register_request.encode())).await {
+                    Ok(_) => tracing::debug!("register ok"),
+                    Err(e) => tracing::error!("failed to register {:?}", e)
+                }
+            }
+            Ok(n) = socket.recv(&mut buf) => {
+                if let Ok(message) = Message::decode(&buf[..n]) {
+                    match message {
+                        Message::register_response(0) => {
+                            tracing::debug!("register response");
+                            answered = Instant::now();
+                            if !registered {
+                                registered = true;
+                                events.record(Event::Registered { server: server_addr });
+                            }
+                            //询问服务器看到的地址, 直到它回答
+                            if !knows_answers {
+                                let addr_request = Message::addr_request(id.clone());
+                                socket.send(&pad_request(addr_request.encode())).await.ok();
+                            }
+                            notify_register.notify_one();
+                        }
+                        Message::addr_response(public) => {
+                            knows_answers = true;
+                            if let Ok(local) = socket.local_addr() {
+                                record_mapped(&events, server_addr, local, public);
+                            }
+                        }
+                        Message::register_response(code) => {
+                            tracing::warn!("server refused registration, code {}", code);
+                        }
+                        //服务器关闭, 去下一个服务器注册
+                        Message::going_away => bail!("server {} is going away", server_addr),
+                        Message::punchS2B(tcp_addr_a) => {
+                            tracing::info!("B recv tcp_addr_a: {:?}", tcp_addr_a);
+                            events.record(Event::PeerAddr { server: server_addr, peer: tcp_addr_a });
+                            let answer = if knows_answers {
+                                Answer::A(tcp_addr_a)
+                            } else {
+                                Answer::Legacy(0)
+                            };
+                            sender.send(PunchRequest { tcp_addr_a, answer }).await.ok();
+                        }
+                        _ => tracing::warn!("other udp message {:?}", message),
+                    }
+                }
+            }
+        }
+    }
+}
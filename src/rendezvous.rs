@@ -0,0 +1,145 @@
+//! The rendezvous servers a client may use, looked up by name and looked up
+//! again from time to time, so a server can move to a new ip.
+
+use anyhow::{bail, Result};
+use std::net::SocketAddr;
+use tokio::{
+    net::lookup_host,
+    time::{Duration, Instant},
+};
+
+/// How long resolved addrs are trusted before the name is looked up again.
+pub const RESOLVE_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone)]
+struct Server {
+    // as given: `host:port`, or literal addrs joined with commas
+    spec: String,
+    addrs: Vec<SocketAddr>,
+    // None for literal addrs, they never change
+    resolved: Option<Instant>,
+}
+
+/// Servers in order of preference. A client sticks to the current one and
+/// only moves on with [`ServerList::fail_over`] when it stops answering, so
+/// two peers with the same list end up on the same server.
+#[derive(Debug, Clone)]
+pub struct ServerList {
+    servers: Vec<Server>,
+    current: usize,
+}
+
+impl ServerList {
+    /// Each spec is `host:port`, or the literal ipv4 and ipv6 addrs of one
+    /// dual-stack server joined with a comma.
+    pub fn new<S: AsRef<str>>(specs: &[S]) -> Result<Self> {
+        let mut servers = Vec::new();
+        for spec in specs {
+            let spec = spec.as_ref();
+            let literal: Result<Vec<SocketAddr>, _> =
+                spec.split(',').map(|addr| addr.trim().parse()).collect();
+            match literal {
+                Ok(addrs) => servers.push(Server {
+                    spec: spec.to_owned(),
+                    addrs: sorted(addrs),
+                    resolved: None,
+                }),
+                Err(_) if spec.contains(',') => bail!("bad server addr list {:?}", spec),
+                Err(_) => servers.push(Server {
+                    spec: spec.to_owned(),
+                    addrs: Vec::new(),
+                    resolved: Some(Instant::now()),
+                }),
+            }
+        }
+        if servers.is_empty() {
+            bail!("no server given");
+        }
+        Ok(Self {
+            servers,
+            current: 0,
+        })
+    }
+
+    /// The server currently in use, as it was given.
+    pub fn current(&self) -> &str {
+        &self.servers[self.current].spec
+    }
+
+    /// Addrs of the current server, ipv6 first, looking the name up again
+    /// when it is due. Servers whose name does not resolve are skipped.
+    pub async fn addrs(&mut self) -> Result<Vec<SocketAddr>> {
+        for _ in 0..self.servers.len() {
+            let server = &mut self.servers[self.current];
+            match server.resolved {
+                Some(at) if at.elapsed() >= RESOLVE_INTERVAL || server.addrs.is_empty() => {
+                    match lookup_host(&server.spec).await {
+                        Ok(addrs) => {
+                            let addrs = sorted(addrs.collect());
+                            if addrs != server.addrs {
+                                tracing::info!("server {} is at {:?}", server.spec, addrs);
+                            }
+                            server.addrs = addrs;
+                            server.resolved = Some(Instant::now());
+                        }
+                        Err(e) => tracing::warn!("could not resolve {}: {}", server.spec, e),
+                    }
+                }
+                _ => {}
+            }
+            if !server.addrs.is_empty() {
+                return Ok(server.addrs.clone());
+            }
+            self.fail_over();
+        }
+        bail!("none of the servers resolves")
+    }
+
+    /// The current server did not answer, move on to the next one.
+    pub fn fail_over(&mut self) {
+        let from = self.current;
+        self.current = (self.current + 1) % self.servers.len();
+        if self.current != from {
+            tracing::warn!(
+                "server {} failed, trying {}",
+                self.servers[from].spec,
+                self.current()
+            );
+        }
+    }
+}
+
+impl From<SocketAddr> for ServerList {
+    fn from(addr: SocketAddr) -> Self {
+        vec![addr].into()
+    }
+}
+
+/// One server with these addrs.
+impl From<Vec<SocketAddr>> for ServerList {
+    fn from(addrs: Vec<SocketAddr>) -> Self {
+        Self {
+            servers: vec![Server {
+                spec: addrs
+                    .iter()
+                    .map(SocketAddr::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+                addrs: sorted(addrs),
+                resolved: None,
+            }],
+            current: 0,
+        }
+    }
+}
+
+fn sorted(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let mut unique = Vec::new();
+    for addr in addrs {
+        if !unique.contains(&addr) {
+            unique.push(addr);
+        }
+    }
+    unique.sort_by_key(|addr| addr.is_ipv4());
+    unique
+}
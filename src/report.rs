@@ -0,0 +1,270 @@
+//! What a client went through while punching: every server, mapping and
+//! candidate connection as a timestamped event, summarised into a json
+//! [`Report`]. The server keeps the other half, see [`crate::trail`].
+
+use crate::puncher::Strategy;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime},
+};
+
+/// Older events are dropped once a long running client recorded this many.
+const MAX_EVENTS: usize = 1000;
+
+/// What the server saw of our address, from the most open to the most
+/// restrictive. Only the port mapping is told apart, that is what decides
+/// whether the peer can guess where to punch.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum NatType {
+    /// The server did not tell
+    Unknown,
+    /// The server saw our local addr, no nat in between
+    Open,
+    /// Translated, but the port was kept
+    PortPreserving,
+    /// Translated to another port
+    PortChanging,
+}
+
+impl NatType {
+    /// `local` has to be the addr of a connected socket, not a wildcard.
+    pub fn classify(local: SocketAddr, public: SocketAddr) -> Self {
+        if local == public {
+            NatType::Open
+        } else if local.port() == public.port() {
+            NatType::PortPreserving
+        } else {
+            NatType::PortChanging
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The server name resolved to these addrs
+    Server {
+        server: String,
+        addrs: Vec<SocketAddr>,
+    },
+    /// Given up for the next server
+    ServerFailed { server: String, error: String },
+    /// The hybrid server answered our first heartbeat
+    Registered { server: SocketAddr },
+    /// The server saw `local` as `public`
+    Mapped {
+        server: SocketAddr,
+        local: SocketAddr,
+        public: SocketAddr,
+        nat_type: NatType,
+    },
+    /// The server told us where the peer is
+    PeerAddr {
+        server: SocketAddr,
+        peer: SocketAddr,
+    },
+    /// A connection to a candidate addr of the peer
+    Attempt {
+        local: SocketAddr,
+        remote: SocketAddr,
+    },
+    AttemptFailed {
+        local: SocketAddr,
+        remote: SocketAddr,
+        error: String,
+    },
+    Connected {
+        local: SocketAddr,
+        remote: SocketAddr,
+    },
+    /// The punch gave up
+    Failed { error: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// rfc3339, to the millisecond
+    pub at: String,
+    /// Since the puncher was created
+    pub elapsed_ms: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// One local/remote pair that was tried, maybe several times.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    pub tries: usize,
+    pub connected: bool,
+    pub errors: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Outcome {
+    Connected {
+        local: SocketAddr,
+        remote: SocketAddr,
+    },
+    Failed {
+        error: String,
+    },
+    /// Still punching, or stuck at the servers
+    Unfinished,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub id: String,
+    pub peer_id: Option<String>,
+    pub strategy: String,
+    /// rfc3339, to the millisecond
+    pub started: String,
+    pub duration_ms: u64,
+    /// The most restrictive of the mappings
+    pub nat_type: NatType,
+    pub candidates: Vec<Candidate>,
+    pub outcome: Outcome,
+    pub events: Vec<Entry>,
+}
+
+impl Report {
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// The events of one [`crate::puncher::Puncher`], shared by its tasks.
+#[derive(Clone)]
+pub struct Events {
+    inner: Arc<Mutex<Trail>>,
+}
+
+struct Trail {
+    id: String,
+    peer_id: Option<String>,
+    strategy: Strategy,
+    started: SystemTime,
+    start: Instant,
+    entries: VecDeque<Entry>,
+    // rewritten whenever a punch ends or a server fails
+    path: Option<PathBuf>,
+}
+
+impl Events {
+    pub fn new(id: &str, peer_id: Option<&str>, strategy: Strategy) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Trail {
+                id: id.to_owned(),
+                peer_id: peer_id.map(str::to_owned),
+                strategy,
+                started: SystemTime::now(),
+                start: Instant::now(),
+                entries: VecDeque::new(),
+                path: None,
+            })),
+        }
+    }
+
+    /// Keeps the report at `path` up to date.
+    pub fn write_to(&self, path: impl Into<PathBuf>) {
+        self.inner.lock().unwrap().path = Some(path.into());
+    }
+
+    pub fn record(&self, event: Event) {
+        tracing::debug!("punch event {:?}", event);
+        let write = matches!(
+            event,
+            Event::Connected { .. } | Event::Failed { .. } | Event::ServerFailed { .. }
+        );
+        let path = {
+            let mut trail = self.inner.lock().unwrap();
+            let entry = Entry {
+                at: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+                elapsed_ms: trail.start.elapsed().as_millis() as u64,
+                event,
+            };
+            if trail.entries.len() >= MAX_EVENTS {
+                trail.entries.pop_front();
+            }
+            trail.entries.push_back(entry);
+            trail.path.clone().filter(|_| write)
+        };
+        if let Some(path) = path {
+            match self.report().write(&path) {
+                Ok(()) => tracing::info!("punch report written to {:?}", path),
+                Err(e) => tracing::warn!("failed to write punch report {:?}: {:?}", path, e),
+            }
+        }
+    }
+
+    pub fn report(&self) -> Report {
+        let trail = self.inner.lock().unwrap();
+        let mut nat_type = NatType::Unknown;
+        let mut candidates: Vec<Candidate> = Vec::new();
+        let mut outcome = Outcome::Unfinished;
+        for entry in &trail.entries {
+            let (local, remote) = match &entry.event {
+                Event::Mapped { nat_type: t, .. } => {
+                    nat_type = nat_type.max(*t);
+                    continue;
+                }
+                Event::Failed { error } => {
+                    outcome = Outcome::Failed {
+                        error: error.clone(),
+                    };
+                    continue;
+                }
+                Event::Attempt { local, remote }
+                | Event::AttemptFailed { local, remote, .. }
+                | Event::Connected { local, remote } => (*local, *remote),
+                _ => continue,
+            };
+            let at = match candidates
+                .iter()
+                .position(|c| c.local == local && c.remote == remote)
+            {
+                Some(at) => at,
+                None => {
+                    candidates.push(Candidate {
+                        local,
+                        remote,
+                        tries: 0,
+                        connected: false,
+                        errors: Vec::new(),
+                    });
+                    candidates.len() - 1
+                }
+            };
+            let candidate = &mut candidates[at];
+            match &entry.event {
+                Event::Attempt { .. } => candidate.tries += 1,
+                Event::AttemptFailed { error, .. } => candidate.errors.push(error.clone()),
+                _ => {
+                    candidate.connected = true;
+                    outcome = Outcome::Connected { local, remote };
+                }
+            }
+        }
+        Report {
+            id: trail.id.clone(),
+            peer_id: trail.peer_id.clone(),
+            strategy: trail.strategy.name().to_owned(),
+            started: humantime::format_rfc3339_millis(trail.started).to_string(),
+            duration_ms: trail.start.elapsed().as_millis() as u64,
+            nat_type,
+            candidates,
+            outcome,
+            events: trail.entries.iter().cloned().collect(),
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use crate::net::Datagram;
+use anyhow::Result;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{
+    collections::BTreeMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+    sync::mpsc,
+    time::{interval, Duration, Instant},
+};
+use tracing::Instrument;
+
+const MAGIC: u8 = b'P';
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+const FIN: u8 = 2;
+const PING: u8 = 3;
+const HEADER_LEN: usize = 10; // magic + kind + u64 seq
+const MAX_PAYLOAD: usize = 1200;
+const WINDOW: u64 = 64;
+const RTO: Duration = Duration::from_millis(300);
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+const DEAD_TIMEOUT: Duration = Duration::from_secs(10);
+const LINGER: Duration = Duration::from_secs(1);
+
+/// Reliable, ordered byte stream on top of a udp socket that is already
+/// connected to the punched peer.
+///
+/// A background task does sequencing, cumulative acks, retransmission and
+/// keepalives; the stream itself is one end of an in-memory pipe.
+pub struct RudpStream {
+    inner: DuplexStream,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+impl RudpStream {
+    pub fn new(socket: Datagram) -> Result<Self> {
+        let local_addr = socket.local_addr()?;
+        let peer_addr = socket.peer_addr()?;
+        let (inner, driver_side) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(
+            async move {
+                if let Err(e) = drive(socket, driver_side).await {
+                    tracing::warn!("rudp {:?} closed: {:?}", peer_addr, e);
+                }
+            }
+            .in_current_span(),
+        );
+        Ok(Self {
+            inner,
+            local_addr,
+            peer_addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+}
+
+impl AsyncRead for RudpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for RudpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn packet(kind: u8, seq: u64, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + payload.len());
+    buf.put_u8(MAGIC);
+    buf.put_u8(kind);
+    buf.put_u64(seq);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+struct Unacked {
+    packet: Bytes,
+    sent_at: Instant,
+}
+
+async fn drive(socket: Datagram, pipe: DuplexStream) -> Result<()> {
+    let (mut pipe_rd, mut pipe_wr) = tokio::io::split(pipe);
+
+    // 按序交付给用户的数据, 空Bytes表示对端已关闭写端
+    let (deliver_tx, mut deliver_rx) = mpsc::channel::<Bytes>(WINDOW as usize);
+    tokio::spawn(async move {
+        while let Some(data) = deliver_rx.recv().await {
+            if data.is_empty() {
+                break;
+            }
+            if pipe_wr.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        pipe_wr.shutdown().await.ok();
+    });
+
+    let mut send_next: u64 = 0;
+    let mut unacked = BTreeMap::<u64, Unacked>::new();
+    let mut recv_next: u64 = 0;
+    let mut out_of_order = BTreeMap::<u64, (u8, Bytes)>::new();
+    let mut local_eof = false;
+    let mut peer_eof = false;
+    let mut done_at: Option<Instant> = None;
+    let mut last_recv = Instant::now();
+    let mut last_send = Instant::now();
+
+    let mut read_buf = vec![0u8; MAX_PAYLOAD];
+    let mut recv_buf = vec![0u8; HEADER_LEN + MAX_PAYLOAD + 64];
+    let mut timer = interval(Duration::from_millis(50));
+
+    loop {
+        let can_send = !local_eof && (unacked.len() as u64) < WINDOW;
+        tokio::select! {
+            n = pipe_rd.read(&mut read_buf), if can_send => {
+                let n = n.unwrap_or(0);
+                let pkt = if n == 0 {
+                    local_eof = true;
+                    packet(FIN, send_next, &[])
+                } else {
+                    packet(DATA, send_next, &read_buf[..n])
+                };
+                socket.send(&pkt).await.ok();
+                last_send = Instant::now();
+                unacked.insert(send_next, Unacked { packet: pkt, sent_at: last_send });
+                send_next += 1;
+            }
+            r = socket.recv(&mut recv_buf) => {
+                let n = match r {
+                    Ok(n) => n,
+                    // icmp unreachable etc, the peer may not be up yet
+                    Err(_) => continue,
+                };
+                if n < HEADER_LEN || recv_buf[0] != MAGIC {
+                    continue;
+                }
+                last_recv = Instant::now();
+                let mut header = &recv_buf[1..HEADER_LEN];
+                let kind = header.get_u8();
+                let seq = header.get_u64();
+                match kind {
+                    DATA | FIN => {
+                        if seq >= recv_next && seq < recv_next + WINDOW {
+                            out_of_order
+                                .entry(seq)
+                                .or_insert_with(|| (kind, Bytes::copy_from_slice(&recv_buf[HEADER_LEN..n])));
+                        }
+                        deliver(&deliver_tx, &mut out_of_order, &mut recv_next, &mut peer_eof);
+                        socket.send(&packet(ACK, recv_next, &[])).await.ok();
+                        last_send = Instant::now();
+                    }
+                    ACK => {
+                        unacked = unacked.split_off(&seq);
+                    }
+                    _ => {}
+                }
+            }
+            _ = timer.tick() => {
+                let now = Instant::now();
+                let before = recv_next;
+                deliver(&deliver_tx, &mut out_of_order, &mut recv_next, &mut peer_eof);
+                if recv_next != before {
+                    socket.send(&packet(ACK, recv_next, &[])).await.ok();
+                    last_send = now;
+                }
+                for item in unacked.values_mut() {
+                    if now.duration_since(item.sent_at) >= RTO {
+                        socket.send(&item.packet).await.ok();
+                        item.sent_at = now;
+                        last_send = now;
+                    }
+                }
+                if now.duration_since(last_send) >= PING_INTERVAL {
+                    socket.send(&packet(PING, 0, &[])).await.ok();
+                    last_send = now;
+                }
+                if now.duration_since(last_recv) >= DEAD_TIMEOUT {
+                    anyhow::bail!("peer silent for {:?}", DEAD_TIMEOUT);
+                }
+                if local_eof && peer_eof && unacked.is_empty() {
+                    // 留一点时间给对端重传的FIN回ack
+                    let done_at = *done_at.get_or_insert(now);
+                    if now.duration_since(done_at) >= LINGER {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn deliver(
+    tx: &mpsc::Sender<Bytes>,
+    out_of_order: &mut BTreeMap<u64, (u8, Bytes)>,
+    recv_next: &mut u64,
+    peer_eof: &mut bool,
+) {
+    while let Some(entry) = out_of_order.first_entry() {
+        if *entry.key() != *recv_next {
+            break;
+        }
+        let permit = match tx.try_reserve() {
+            Ok(permit) => Some(permit),
+            Err(mpsc::error::TrySendError::Full(_)) => break,
+            // reader is gone, keep acking so the peer can finish
+            Err(mpsc::error::TrySendError::Closed(_)) => None,
+        };
+        let (kind, data) = entry.remove();
+        if kind == FIN {
+            *peer_eof = true;
+            if let Some(permit) = permit {
+                permit.send(Bytes::new());
+            }
+        } else if let Some(permit) = permit {
+            permit.send(data);
+        }
+        *recv_next += 1;
+    }
+}
@@ -0,0 +1,1697 @@
+//! Rendezvous servers, shared by the server binaries and the tests.
+
+use crate::{
+    hybrid::{Answer, Message},
+    limit::{Limiter, RateLimit, Request},
+    metrics::{Metrics, Protocol, PunchFailure},
+    net::Datagram,
+    shutdown::Shutdown,
+    signaling::MAX_CANDIDATES,
+    simple::*,
+    store::{MemoryStore, Store},
+    trail::{TrailEvent, Trails},
+};
+use anyhow::{anyhow, bail, Result};
+use futures::{future::try_join_all, stream::FuturesUnordered, Sink, SinkExt, StreamExt};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+    time::SystemTime,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot, Semaphore,
+    },
+    time::{interval, sleep, timeout, Duration, Instant},
+};
+use tokio_tungstenite::{
+    accept_async_with_config,
+    tungstenite::{self, protocol::WebSocketConfig, Message as WebSocketMessage},
+};
+use tracing::{field, Instrument, Span};
+
+/// Every message is one json object that has to arrive in a single read.
+const MAX_MESSAGE: usize = 1024;
+/// A client that connects and says nothing is dropped after this.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long A is kept waiting for B, a bit less than A waits for us.
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(8);
+/// How long another server of the cluster may take to answer for its B.
+const ROUTE_TIMEOUT: Duration = Duration::from_secs(9);
+/// How often expired registrations are dropped and the gauge is refreshed.
+const PURGE_INTERVAL: Duration = Duration::from_secs(1);
+/// A registration refreshed from the same addr is written to the store at most this often.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// How often a draining server looks whether its sessions are done.
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
+/// Rules shared by all rendezvous servers.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Registrations not refreshed for this long are forgotten
+    pub registration_ttl: Duration,
+    /// New ids are refused once this many are registered
+    pub max_registrations: usize,
+    /// Tcp connections handled at once, more wait in the backlog
+    pub max_connections: usize,
+    /// Hybrid: WebSocket connections open at once, apart from the tcp ones
+    /// since they stay open
+    pub max_websockets: usize,
+    /// Only these ids may register, empty allows everyone
+    pub allow_ids: Vec<String>,
+    /// Hybrid: tcp addrs of the other servers of the cluster, asked for the
+    /// ids not registered here and allowed to ask us for ours
+    pub cluster: Vec<SocketAddr>,
+    /// Counters the server keeps, see [`crate::metrics::serve`]
+    pub metrics: Arc<Metrics>,
+    /// Rate limits and temporary bans by source ip, counted in `metrics`
+    pub limiter: Arc<Limiter>,
+    /// Bans, the store and a view of the running server, see [`crate::admin::serve`]
+    pub control: Arc<Control>,
+    /// Stops the server once triggered, see [`crate::shutdown`]
+    pub shutdown: Shutdown,
+    /// How long a stopping server waits for the punches under way
+    pub drain_timeout: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        let metrics = Arc::new(Metrics::default());
+        Self {
+            registration_ttl: Duration::from_secs(120),
+            max_registrations: 10000,
+            max_connections: 1024,
+            max_websockets: 1024,
+            allow_ids: Vec::new(),
+            cluster: Vec::new(),
+            limiter: Arc::new(Limiter::new(RateLimit::default(), metrics.clone())),
+            metrics,
+            control: Arc::new(Control::default()),
+            shutdown: Shutdown::default(),
+            drain_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A live registration, as the admin api shows it.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Registration {
+    pub id: String,
+    pub addr: SocketAddr,
+    /// rfc3339, to the second
+    pub last_seen: String,
+    pub idle_secs: u64,
+}
+
+impl Registration {
+    fn new(id: &str, addr: SocketAddr, seen: Instant) -> Self {
+        let idle = seen.elapsed();
+        Self {
+            id: id.to_owned(),
+            addr,
+            last_seen: humantime::format_rfc3339_seconds(SystemTime::now() - idle).to_string(),
+            idle_secs: idle.as_secs(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PunchState {
+    /// A asked for B, the server is sending B the punchS2B
+    TellingB,
+    /// B was told, A waits for B's punchB2S
+    WaitingForB,
+}
+
+/// A punch the hybrid server is in the middle of.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PunchSession {
+    /// Its trail, see [`Control::trails`]
+    pub session: u64,
+    /// Tcp addr of A
+    pub a: SocketAddr,
+    pub b_id: String,
+    /// Udp addr B registered from
+    pub b: SocketAddr,
+    pub state: PunchState,
+    pub age_ms: u64,
+}
+
+/// What the admin api can see and do on a running server.
+pub trait Inspect: Send + Sync {
+    /// Live registrations of `id`, or of everyone, sorted by id.
+    fn registrations(&self, id: Option<&str>) -> Vec<Registration>;
+    /// Forgets every registration of `id`, returns how many there were.
+    fn kick(&self, id: &str) -> usize;
+    fn sessions(&self) -> Vec<PunchSession> {
+        Vec::new()
+    }
+}
+
+/// The admin side of a server: the bans, the trails of recent punches, the
+/// store both bans and registrations are kept in, and the server once it
+/// runs. Every [`Options`] has one, shared by its clones.
+pub struct Control {
+    banned: Mutex<HashSet<String>>,
+    server: Mutex<Option<Weak<dyn Inspect>>>,
+    trails: Trails,
+    store: Arc<dyn Store>,
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Self {
+            banned: Mutex::default(),
+            server: Mutex::default(),
+            trails: Trails::default(),
+            store: Arc::new(MemoryStore::default()),
+        }
+    }
+}
+
+impl fmt::Debug for Control {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Control")
+            .field("banned", &self.banned())
+            .finish()
+    }
+}
+
+impl Control {
+    /// Keeps bans and registrations in `store`, starting with the bans in it.
+    pub fn with_store(store: Arc<dyn Store>) -> Result<Self> {
+        let banned = store.load()?.banned.into_iter().collect();
+        Ok(Self {
+            banned: Mutex::new(banned),
+            store,
+            ..Self::default()
+        })
+    }
+
+    pub fn store(&self) -> &Arc<dyn Store> {
+        &self.store
+    }
+
+    fn attach(&self, server: Weak<dyn Inspect>) {
+        *self.server.lock().unwrap() = Some(server);
+    }
+
+    fn server(&self) -> Option<Arc<dyn Inspect>> {
+        self.server.lock().unwrap().as_ref()?.upgrade()
+    }
+
+    /// Empty before the server started.
+    pub fn registrations(&self, id: Option<&str>) -> Vec<Registration> {
+        self.server()
+            .map(|server| server.registrations(id))
+            .unwrap_or_default()
+    }
+
+    pub fn sessions(&self) -> Vec<PunchSession> {
+        self.server()
+            .map(|server| server.sessions())
+            .unwrap_or_default()
+    }
+
+    /// Forgets `id` until it registers again, returns how many registrations it had.
+    pub fn kick(&self, id: &str) -> usize {
+        let kicked = self.server().map(|server| server.kick(id)).unwrap_or(0);
+        if let Err(e) = self.store.forget(id) {
+            tracing::warn!("could not forget {} in the store: {:?}", id, e);
+        }
+        tracing::info!("kicked {} ({} registrations)", id, kicked);
+        kicked
+    }
+
+    /// Kicks `id` and refuses it from now on.
+    pub fn ban(&self, id: &str) -> usize {
+        // not under the lock, the registry takes it to check bans
+        self.banned.lock().unwrap().insert(id.to_owned());
+        if let Err(e) = self.store.ban(id, true) {
+            tracing::warn!("could not store the ban of {}: {:?}", id, e);
+        }
+        tracing::info!("banned {}", id);
+        self.kick(id)
+    }
+
+    /// False if `id` was not banned.
+    pub fn unban(&self, id: &str) -> bool {
+        let unbanned = self.banned.lock().unwrap().remove(id);
+        if unbanned {
+            if let Err(e) = self.store.ban(id, false) {
+                tracing::warn!("could not store the unban of {}: {:?}", id, e);
+            }
+            tracing::info!("unbanned {}", id);
+        }
+        unbanned
+    }
+
+    pub fn banned(&self) -> Vec<String> {
+        let mut banned: Vec<_> = self.banned.lock().unwrap().iter().cloned().collect();
+        banned.sort();
+        banned
+    }
+
+    pub fn is_banned(&self, id: &str) -> bool {
+        self.banned.lock().unwrap().contains(id)
+    }
+
+    pub fn trails(&self) -> &Trails {
+        &self.trails
+    }
+}
+
+/// Where every id registered from and when, one entry per ip family so a
+/// dual-stack client can be reached over both. `T` is anything else the
+/// server needs to reach it. Entries are written through to the store.
+struct Registry<T> {
+    ttl: Duration,
+    max: usize,
+    allow_ids: HashSet<String>,
+    // (id, is ipv6) -> addr
+    entries: HashMap<(String, bool), Entry<T>>,
+    // whether a registration with this extra is written to the store
+    saves: fn(T) -> bool,
+    metrics: Arc<Metrics>,
+    control: Arc<Control>,
+}
+
+struct Entry<T> {
+    addr: SocketAddr,
+    extra: T,
+    seen: Instant,
+    saved: Instant,
+}
+
+impl<T: Copy> Registry<T> {
+    fn new(options: &Options) -> Self {
+        Self {
+            ttl: options.registration_ttl,
+            max: options.max_registrations,
+            allow_ids: options.allow_ids.iter().cloned().collect(),
+            entries: HashMap::new(),
+            saves: |_| true,
+            metrics: options.metrics.clone(),
+            control: options.control.clone(),
+        }
+    }
+
+    /// Records or refreshes `id`, false if it may not register.
+    fn register(&mut self, id: &str, addr: SocketAddr, extra: T) -> bool {
+        let accepted = self.try_register(id, addr, extra);
+        self.metrics.registration(accepted);
+        accepted
+    }
+
+    fn try_register(&mut self, id: &str, addr: SocketAddr, extra: T) -> bool {
+        if !self.allow_ids.is_empty() && !self.allow_ids.contains(id) {
+            tracing::warn!("id {} is not allowed", id);
+            return false;
+        }
+        if self.control.is_banned(id) {
+            tracing::warn!("id {} is banned", id);
+            return false;
+        }
+        let key = (id.to_owned(), addr.is_ipv6());
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.max {
+            self.purge();
+            if self.entries.len() >= self.max {
+                tracing::warn!("registry full, id {} refused", id);
+                return false;
+            }
+        }
+        let now = Instant::now();
+        let saved = match self.entries.get(&key) {
+            Some(entry) if entry.addr == addr && entry.saved.elapsed() < SAVE_INTERVAL => {
+                entry.saved
+            }
+            _ if !(self.saves)(extra) => now,
+            // a failed write is tried again after the interval too
+            _ => {
+                if let Err(e) = self.control.store().seen(id, addr) {
+                    tracing::warn!("could not store {} at {}: {:?}", id, addr, e);
+                }
+                now
+            }
+        };
+        let entry = Entry {
+            addr,
+            extra,
+            seen: now,
+            saved,
+        };
+        self.entries.insert(key, entry);
+        true
+    }
+
+    /// Takes back the stored registrations that have not expired, `extra`
+    /// tells how to reach an addr, None skips it.
+    fn restore(&mut self, extra: impl Fn(SocketAddr) -> Option<T>) -> Result<usize> {
+        let stored = self.control.store().load()?;
+        let now = Instant::now();
+        let mut restored = 0;
+        for device in stored.devices {
+            if self.control.is_banned(&device.id) {
+                continue;
+            }
+            let age = SystemTime::now()
+                .duration_since(device.last_seen)
+                .unwrap_or_default();
+            let seen = match now.checked_sub(age) {
+                Some(seen) if age < self.ttl => seen,
+                _ => continue,
+            };
+            for addr in device.addrs {
+                if self.entries.len() >= self.max {
+                    break;
+                }
+                if let Some(extra) = extra(addr) {
+                    let entry = Entry {
+                        addr,
+                        extra,
+                        seen,
+                        saved: now,
+                    };
+                    self.entries
+                        .insert((device.id.clone(), addr.is_ipv6()), entry);
+                    restored += 1;
+                }
+            }
+        }
+        self.metrics.set_registered(self.entries.len());
+        tracing::info!("restored {} registrations", restored);
+        Ok(restored)
+    }
+
+    /// Drops expired registrations.
+    fn purge(&mut self) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, entry| entry.seen.elapsed() < ttl);
+        self.metrics.set_registered(self.entries.len());
+    }
+
+    /// Where `id` registered from in the same ip family as `from`.
+    fn get(&self, id: &str, from: SocketAddr) -> Option<(SocketAddr, T)> {
+        match self.entries.get(&(id.to_owned(), from.is_ipv6())) {
+            Some(entry) if entry.seen.elapsed() < self.ttl => Some((entry.addr, entry.extra)),
+            _ => None,
+        }
+    }
+
+    /// Whether `id` is registered at `addr` through `extra`.
+    fn holds(&self, id: &str, addr: SocketAddr, extra: T) -> bool
+    where
+        T: PartialEq,
+    {
+        self.get(id, addr) == Some((addr, extra))
+    }
+
+    fn len(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|entry| entry.seen.elapsed() < self.ttl)
+            .count()
+    }
+
+    fn list(&self, id: Option<&str>) -> Vec<Registration> {
+        let mut list: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|((entry_id, _), entry)| {
+                entry.seen.elapsed() < self.ttl && id.is_none_or(|id| id == entry_id)
+            })
+            .map(|((id, _), entry)| Registration::new(id, entry.addr, entry.seen))
+            .collect();
+        list.sort_by(|a, b| (&a.id, a.addr).cmp(&(&b.id, b.addr)));
+        list
+    }
+
+    fn remove(&mut self, id: &str) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|(entry_id, _), _| entry_id != id);
+        before - self.entries.len()
+    }
+
+    /// `id` leaves, asked from the addr and socket it registered from. It is
+    /// forgotten in the store once none of its registrations is left.
+    fn unregister(&mut self, id: &str, from: SocketAddr, extra: T) -> usize
+    where
+        T: PartialEq,
+    {
+        let before = self.entries.len();
+        self.entries.retain(|(entry_id, _), entry| {
+            entry_id != id || entry.addr != from || entry.extra != extra
+        });
+        let removed = before - self.entries.len();
+        if removed > 0 {
+            tracing::info!("{} unregistered from {:?}", id, from);
+            if !self.entries.keys().any(|(entry_id, _)| entry_id == id) {
+                if let Err(e) = self.control.store().forget(id) {
+                    tracing::warn!("could not forget {} in the store: {:?}", id, e);
+                }
+            }
+            self.metrics.set_registered(self.entries.len());
+        }
+        removed
+    }
+
+    /// Every live registration, to tell them the server goes away.
+    fn reachable(&self) -> Vec<(SocketAddr, T)> {
+        self.entries
+            .values()
+            .filter(|entry| entry.seen.elapsed() < self.ttl)
+            .map(|entry| (entry.addr, entry.extra))
+            .collect()
+    }
+}
+
+impl<T: Copy + Send> Inspect for Mutex<Registry<T>> {
+    fn registrations(&self, id: Option<&str>) -> Vec<Registration> {
+        self.lock().unwrap().list(id)
+    }
+
+    fn kick(&self, id: &str) -> usize {
+        self.lock().unwrap().remove(id)
+    }
+}
+
+type SharedRegistry = Arc<Mutex<Registry<()>>>;
+
+/// Waits until `in_flight` is 0, at most `deadline`.
+async fn drain(deadline: Duration, in_flight: impl Fn() -> usize) {
+    let drained = timeout(deadline, async {
+        while in_flight() > 0 {
+            sleep(DRAIN_POLL).await;
+        }
+    })
+    .await;
+    match drained {
+        Ok(()) => tracing::info!("drained"),
+        Err(_) => tracing::warn!("{} still in flight after {:?}", in_flight(), deadline),
+    }
+}
+
+/// Calls `purge` every [`PURGE_INTERVAL`], forever.
+async fn purge_loop(mut purge: impl FnMut()) -> Result<()> {
+    let mut timer = interval(PURGE_INTERVAL);
+    loop {
+        timer.tick().await;
+        purge();
+    }
+}
+
+/// Udp rendezvous: remembers the address every `Register` came from and
+/// answers with the peer's address once the peer registered too.
+pub async fn udp(socket: Datagram) -> Result<()> {
+    udp_with(vec![socket], Options::default()).await
+}
+
+/// Like [`udp`], on several sockets sharing one registry.
+pub async fn udp_with(sockets: Vec<Datagram>, options: Options) -> Result<()> {
+    let mut registry = Registry::new(&options);
+    registry.restore(|_| Some(()))?;
+    let id_map: SharedRegistry = Arc::new(Mutex::new(registry));
+    options
+        .control
+        .attach(Arc::downgrade(&id_map) as Weak<dyn Inspect>);
+    let serve = async {
+        let sockets = try_join_all(sockets.iter().map(|socket| {
+            udp_socket(
+                socket,
+                id_map.clone(),
+                options.metrics.clone(),
+                options.limiter.clone(),
+            )
+        }));
+        let purge = purge_loop(|| {
+            id_map.lock().unwrap().purge();
+            options.limiter.purge();
+        });
+        tokio::try_join!(sockets, purge)?;
+        Ok(())
+    };
+    // nothing is under way between two udp messages, there is nothing to drain
+    let going_away = async {
+        options.shutdown.wait().await;
+        let registered = id_map.lock().unwrap().reachable();
+        tell_going_away(&sockets, &options.metrics, registered).await;
+    };
+    tokio::select! {
+        served = serve => served,
+        () = going_away => Ok(()),
+    }
+}
+
+/// Sends a [`GoingAway`] to every registered addr, from the first socket of its ip family.
+async fn tell_going_away(
+    sockets: &[Datagram],
+    metrics: &Metrics,
+    registered: Vec<(SocketAddr, ())>,
+) {
+    tracing::info!("shutting down, telling {} clients", registered.len());
+    for (addr, ()) in registered {
+        let socket = sockets.iter().find(|socket| {
+            socket
+                .local_addr()
+                .is_ok_and(|local| local.is_ipv6() == addr.is_ipv6())
+        });
+        if let Some(socket) = socket {
+            let going_away = GoingAway {
+                going_away: socket.local_addr().unwrap(),
+            }
+            .encode();
+            if socket.send_to(&going_away, addr).await.is_ok() {
+                metrics.sent(Protocol::Udp, going_away.len());
+            }
+        }
+    }
+}
+
+async fn udp_socket(
+    socket: &Datagram,
+    id_map: SharedRegistry,
+    metrics: Arc<Metrics>,
+    limiter: Arc<Limiter>,
+) -> Result<()> {
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    tracing::info!("listening on udp {:?}", socket.local_addr());
+
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                tracing::warn!("recv failed {:?}", e);
+                continue;
+            }
+        };
+        tracing::info!("new msg from {:?}", addr);
+        metrics.received(Protocol::Udp, len);
+        let rsp = if let Ok(req) = Register::decode(&buf[..len]) {
+            if limiter
+                .check(addr.ip(), Request::Register(&req.id))
+                .is_err()
+            {
+                continue;
+            }
+            tracing::info!("{:?} id {} want {}", addr, req.id, req.peer_id);
+            let rsp = match lookup(&id_map, &req, addr) {
+                Some(rsp) => rsp,
+                None => continue,
+            };
+            count_lookup(&metrics, &rsp);
+            rsp.encode()
+        } else if let Ok(req) = Observe::decode(&buf[..len]) {
+            if limiter.check(addr.ip(), Request::Other).is_err() {
+                continue;
+            }
+            tracing::info!("{:?} id {} observes its addr", addr, req.id);
+            Observed { addr }.encode()
+        } else if let Ok(req) = Unregister::decode(&buf[..len]) {
+            if limiter.check(addr.ip(), Request::Other).is_ok() {
+                id_map.lock().unwrap().unregister(&req.unregister, addr, ());
+            }
+            continue;
+        } else {
+            metrics.decode_failed(Protocol::Udp);
+            limiter.check(addr.ip(), Request::Other).ok();
+            tracing::error!("decode failed");
+            continue;
+        };
+        if limiter.check_reply(len, rsp.len()).is_err() {
+            continue;
+        }
+        match socket.send_to(&rsp, addr).await {
+            Ok(_) => {
+                metrics.sent(Protocol::Udp, rsp.len());
+                tracing::info!("send {:?} to addr {:?}", rsp, addr);
+            }
+            Err(e) => tracing::error!("Send rsp to {:?} failed. {:?}", addr, e),
+        }
+    }
+}
+
+/// Registers `req` and looks up its peer, None if it may not register.
+fn lookup(id_map: &SharedRegistry, req: &Register, addr: SocketAddr) -> Option<Peer> {
+    let mut id_map = id_map.lock().unwrap();
+    if !id_map.register(&req.id, addr, ()) {
+        return None;
+    }
+    let peer_addr = id_map.get(&req.peer_id, addr).map(|(a, _)| a); //peer地址, 未注册为None
+    let session = id_map
+        .control
+        .trails()
+        .lookup(&req.id, &req.peer_id, addr, peer_addr);
+    Span::current().record("session", session);
+    Some(Peer { peer_addr })
+}
+
+// a `Register` of the udp and tcp servers asks for the peer every time
+fn count_lookup(metrics: &Metrics, rsp: &Peer) {
+    metrics.punch_request();
+    match rsp.peer_addr {
+        Some(_) => metrics.punch_succeeded(),
+        None => metrics.punch_failed(PunchFailure::PeerNotRegistered),
+    }
+}
+
+/// Tcp rendezvous: like [`udp`], one `Register` and one `Peer` per connection.
+pub async fn tcp(listener: TcpListener) -> Result<()> {
+    tcp_with(vec![listener], Options::default()).await
+}
+
+/// Like [`tcp`], on several listeners sharing one registry.
+pub async fn tcp_with(listeners: Vec<TcpListener>, options: Options) -> Result<()> {
+    let mut registry = Registry::new(&options);
+    registry.restore(|_| Some(()))?;
+    let id_map: SharedRegistry = Arc::new(Mutex::new(registry));
+    options
+        .control
+        .attach(Arc::downgrade(&id_map) as Weak<dyn Inspect>);
+    let connections = Arc::new(Semaphore::new(options.max_connections));
+    let listeners = try_join_all(listeners.into_iter().map(|listener| {
+        let id_map = id_map.clone();
+        let metrics = options.metrics.clone();
+        let limiter = options.limiter.clone();
+        accept_loop(
+            listener,
+            connections.clone(),
+            metrics.clone(),
+            move |stream, addr| {
+                tcp_client(
+                    stream,
+                    addr,
+                    id_map.clone(),
+                    metrics.clone(),
+                    limiter.clone(),
+                )
+            },
+        )
+    }));
+    let purge = purge_loop(|| {
+        id_map.lock().unwrap().purge();
+        options.limiter.purge();
+    });
+    let serve = async {
+        tokio::try_join!(listeners, purge)?;
+        Ok(())
+    };
+    tokio::select! {
+        served = serve => served,
+        () = options.shutdown.wait() => {
+            // the listeners are closed, the clients already accepted go on
+            let max = options.max_connections;
+            drain(options.drain_timeout, || max - connections.available_permits()).await;
+            Ok(())
+        }
+    }
+}
+
+/// Accepts until the listener fails, with at most `connections` clients at once.
+async fn accept_loop<F, Fut>(
+    listener: TcpListener,
+    connections: Arc<Semaphore>,
+    metrics: Arc<Metrics>,
+    mut handle: F,
+) -> Result<()>
+where
+    F: FnMut(TcpStream, SocketAddr) -> Fut,
+    Fut: std::future::Future<Output = Result<()>> + Send + 'static,
+{
+    tracing::info!("listening on tcp {:?}", listener.local_addr());
+    loop {
+        let permit = connections.clone().acquire_owned().await?;
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("accept failed {:?}", e);
+                continue;
+            }
+        };
+        tracing::info!("new client from {:?}", addr);
+        // the handler fills in the trail session it works on
+        let span = tracing::info_span!("client", %addr, session = field::Empty);
+        let client = handle(stream, addr);
+        let metrics = metrics.clone();
+        tokio::spawn(
+            async move {
+                let _connection = metrics.tcp_connection();
+                if let Err(e) = client.await {
+                    tracing::error!("client {:?}: {:?}", addr, e);
+                }
+                drop(permit);
+            }
+            .instrument(span),
+        );
+    }
+}
+
+async fn tcp_client(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    id_map: SharedRegistry,
+    metrics: Arc<Metrics>,
+    limiter: Arc<Limiter>,
+) -> Result<()> {
+    let msg = read_message(&mut stream).await?;
+    metrics.received(Protocol::Tcp, msg.len());
+    let data = match Register::decode(&msg) {
+        Ok(reg) => {
+            if limiter
+                .check(addr.ip(), Request::Register(&reg.id))
+                .is_err()
+            {
+                return Ok(());
+            }
+            tracing::info!("{:?} id {} want {}", addr, reg.id, reg.peer_id);
+            let rsp = match lookup(&id_map, &reg, addr) {
+                Some(rsp) => rsp,
+                None => bail!("registration of {} refused", reg.id),
+            };
+            count_lookup(&metrics, &rsp);
+            rsp.encode()
+        }
+        Err(e) => match Observe::decode(&msg) {
+            Ok(req) => {
+                if limiter.check(addr.ip(), Request::Other).is_err() {
+                    return Ok(());
+                }
+                tracing::info!("{:?} id {} observes its addr", addr, req.id);
+                Observed { addr }.encode()
+            }
+            Err(_) => match Unregister::decode(&msg) {
+                Ok(req) => {
+                    if limiter.check(addr.ip(), Request::Other).is_ok() {
+                        id_map.lock().unwrap().unregister(&req.unregister, addr, ());
+                    }
+                    return Ok(());
+                }
+                Err(_) => {
+                    metrics.decode_failed(Protocol::Tcp);
+                    limiter.check(addr.ip(), Request::Other).ok();
+                    return Err(e);
+                }
+            },
+        },
+    };
+    stream.write_all(&data).await?;
+    metrics.sent(Protocol::Tcp, data.len());
+    tracing::info!("send {:?} to addr {:?}", data, addr);
+    Ok(())
+}
+
+/// What the hybrid server does after one tcp message, or after a request
+/// of a registered client, see [`HybridState::on_registered`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum TcpAction<W> {
+    /// Answer the sender, on the same connection and hang up over tcp
+    Reply(Message),
+    /// Send the message to B through the udp socket with this index, then wait for B's reply
+    TellB(SocketAddr, usize, Message),
+    /// Give B's tcp addr to the A that waits there
+    AnswerA(W, SocketAddr),
+    /// Send the message to a registered client through the udp socket with
+    /// this index, then tell the sender whether it went out
+    Forward(SocketAddr, usize, Message),
+    /// B is not registered here: send the message to the cluster, the first
+    /// punchS2A with an addr answers A, then call [`HybridState::routed`]
+    Route(u64, Message),
+    Ignore,
+}
+
+/// `register_response` code for a refused registration.
+pub const REGISTER_REFUSED: u8 = 1;
+
+/// Registrations and waiting A's of the hybrid server, without any io, so
+/// it can be driven by tests and fuzzers. `W` is how a waiting A is reached.
+pub struct HybridState<W> {
+    // id -> udp addr of registered clients and the socket they used
+    registered: Registry<usize>,
+    // tcp addr of A -> A waiting for B's reply
+    waiting_a: HashMap<SocketAddr, Waiting<W>>,
+    cluster: Vec<SocketAddr>,
+    // shutting down: no new registrations or punches
+    closing: bool,
+}
+
+struct Waiting<W> {
+    waiter: W,
+    session: u64,
+    id_b: String,
+    b: SocketAddr,
+    since: Instant,
+    state: PunchState,
+}
+
+impl<W> Default for HybridState<W> {
+    fn default() -> Self {
+        Self::new(&Options::default())
+    }
+}
+
+impl<W> HybridState<W> {
+    pub fn new(options: &Options) -> Self {
+        let mut registered = Registry::new(options);
+        // a WebSocket registration ends with its connection, there is
+        // nothing to restore
+        registered.saves = |via| via != WEBSOCKET;
+        Self {
+            registered,
+            waiting_a: HashMap::new(),
+            cluster: options.cluster.clone(),
+            closing: false,
+        }
+    }
+
+    /// Handles a udp message that came in on socket `via`, returns the reply for `from`.
+    pub fn on_udp(&mut self, from: SocketAddr, via: usize, msg: Message) -> Option<Message> {
+        match msg {
+            //正在关闭, 让客户端去下一个服务器注册
+            Message::register_request(_) if self.closing => Some(Message::going_away),
+            Message::register_request(id) => {
+                //更新udp 地址
+                tracing::debug!("{:?} id {} register", from, id);
+                if !self.registered.register(&id, from, via) {
+                    return Some(Message::register_response(REGISTER_REFUSED));
+                }
+                //注册确认
+                Some(Message::register_response(0))
+            }
+            //回复客户端的公网地址
+            Message::addr_request(id) => {
+                tracing::debug!("{:?} id {} observes its addr", from, id);
+                Some(Message::addr_response(from))
+            }
+            //客户端退出
+            Message::unregister_request(id) => {
+                self.registered.unregister(&id, from, via);
+                None
+            }
+            msg => {
+                tracing::warn!("udp recv {:?}", msg);
+                None
+            }
+        }
+    }
+
+    /// Handles a lookup or a signal that came in on socket `via`, answered
+    /// only when `from` is where the asker registered through `via`.
+    pub fn on_registered(&mut self, from: SocketAddr, via: usize, msg: Message) -> TcpAction<W> {
+        match msg {
+            Message::lookup_request(..) | Message::signal(_) if self.closing => {
+                TcpAction::Reply(Message::going_away)
+            }
+            //查询id在请求方协议族里的注册地址, 只回答注册过的客户端
+            Message::lookup_request(asker, id) => {
+                let addr = match self.registered.holds(&asker, from, via) {
+                    true => self.registered.get(&id, from).map(|(addr, _)| addr),
+                    false => {
+                        tracing::warn!("{:?} is not registered as {}", from, asker);
+                        None
+                    }
+                };
+                TcpAction::Reply(Message::lookup_response(addr))
+            }
+            //把候选地址转发给已注册的对端, 发送方须以signal.from注册在这里
+            Message::signal(signal) => {
+                if !self.registered.holds(&signal.from, from, via) {
+                    tracing::warn!("{:?} is not registered as {}", from, signal.from);
+                    return TcpAction::Reply(Message::signal_sent(false));
+                }
+                match self.registered.get(&signal.to, from) {
+                    Some((addr, via)) if signal.candidates.len() <= MAX_CANDIDATES => {
+                        TcpAction::Forward(addr, via, Message::signal(signal))
+                    }
+                    _ => TcpAction::Reply(Message::signal_sent(false)),
+                }
+            }
+            msg => {
+                tracing::warn!("registered client sent {:?}", msg);
+                TcpAction::Ignore
+            }
+        }
+    }
+
+    /// Handles the single message of a tcp connection from `from`.
+    pub fn on_tcp(&mut self, from: SocketAddr, msg: Message, waiter: W) -> TcpAction<W> {
+        match msg {
+            //正在关闭, 只等进行中的打洞完成
+            Message::punchA2S(_) if self.closing => TcpAction::Reply(Message::going_away),
+            Message::routeA2S(..) if self.closing => TcpAction::Reply(Message::punchS2A(None)),
+            //来自A的打洞请求, B在A的协议族里的地址
+            Message::punchA2S(id_b) => match self.registered.get(&id_b, from) {
+                //B已注册, 向B发访问请求
+                Some((b_udp_addr, via)) => {
+                    let session = self.trails().start(vec![id_b.clone()]);
+                    self.trails().record(
+                        session,
+                        TrailEvent::PunchRequest {
+                            a: from,
+                            b_id: id_b.clone(),
+                            b: Some(b_udp_addr),
+                        },
+                    );
+                    self.tell_b(session, from, id_b, b_udp_addr, via, waiter)
+                }
+                //B未注册, 问集群里的其他服务器, 没有集群则立即回复A
+                None => {
+                    let session = self.trails().start(vec![id_b.clone()]);
+                    Span::current().record("session", session);
+                    self.trails().record(
+                        session,
+                        TrailEvent::PunchRequest {
+                            a: from,
+                            b_id: id_b.clone(),
+                            b: None,
+                        },
+                    );
+                    if self.cluster.is_empty() {
+                        self.trails().finish(session);
+                        TcpAction::Reply(Message::punchS2A(None))
+                    } else {
+                        TcpAction::Route(session, Message::routeA2S(id_b, from))
+                    }
+                }
+            },
+            //集群里的其他服务器替它的A请求B, 只在本地找, 不再转发
+            Message::routeA2S(id_b, a) => {
+                if !self.cluster.iter().any(|node| node.ip() == from.ip()) {
+                    tracing::warn!("{:?} is not in the cluster, route ignored", from);
+                    return TcpAction::Ignore;
+                }
+                match self.registered.get(&id_b, a) {
+                    Some((b_udp_addr, via)) => {
+                        let session = self.trails().start(vec![id_b.clone()]);
+                        self.trails().record(
+                            session,
+                            TrailEvent::RouteRequest {
+                                node: from,
+                                a,
+                                b_id: id_b.clone(),
+                                b: b_udp_addr,
+                            },
+                        );
+                        self.tell_b(session, a, id_b, b_udp_addr, via, waiter)
+                    }
+                    None => TcpAction::Reply(Message::punchS2A(None)),
+                }
+            }
+            //来自B的打洞回复
+            Message::punchB2S(answer) => match self.answered(from, answer) {
+                Some(waiting) => {
+                    Span::current().record("session", waiting.session);
+                    self.trails()
+                        .record(waiting.session, TrailEvent::BAnswered { b: from });
+                    self.trails().finish(waiting.session);
+                    TcpAction::AnswerA(waiting.waiter, from)
+                }
+                None => {
+                    tracing::warn!("B {:?} answered {:?}, but no A waits", from, answer);
+                    TcpAction::Ignore
+                }
+            },
+            msg => {
+                tracing::warn!("tcp recv msg {:?}", msg);
+                TcpAction::Ignore
+            }
+        }
+    }
+
+    // the A that B answers, the one told the longest ago about a B at that ip
+    // for clients that do not say
+    fn answered(&mut self, from: SocketAddr, answer: Answer) -> Option<Waiting<W>> {
+        let a = match answer {
+            Answer::A(a) => a,
+            Answer::Legacy(_) => self
+                .waiting_a
+                .iter()
+                .filter(|(_, waiting)| waiting.b.ip() == from.ip())
+                .min_by_key(|(_, waiting)| waiting.since)
+                .map(|(a, _)| *a)?,
+        };
+        self.waiting_a.remove(&a)
+    }
+
+    fn trails(&self) -> &Trails {
+        self.registered.control.trails()
+    }
+
+    // A at `a` waits while B is told
+    fn tell_b(
+        &mut self,
+        session: u64,
+        a: SocketAddr,
+        id_b: String,
+        b: SocketAddr,
+        via: usize,
+        waiter: W,
+    ) -> TcpAction<W> {
+        Span::current().record("session", session);
+        let waiting = Waiting {
+            waiter,
+            session,
+            id_b,
+            b,
+            since: Instant::now(),
+            state: PunchState::TellingB,
+        };
+        self.waiting_a.insert(a, waiting);
+        TcpAction::TellB(b, via, Message::punchS2B(a))
+    }
+
+    /// The cluster answered the [`TcpAction::Route`] of `session`, through
+    /// `node` if any node knew B.
+    pub fn routed(&mut self, session: u64, node: Option<SocketAddr>, b: Option<SocketAddr>) {
+        self.trails()
+            .record(session, TrailEvent::Routed { node, b });
+        self.trails().finish(session);
+    }
+
+    /// The punchS2B for the A at `tcp_addr_a` was sent.
+    pub fn told_b(&mut self, tcp_addr_a: SocketAddr) {
+        if let Some(waiting) = self.waiting_a.get_mut(&tcp_addr_a) {
+            waiting.state = PunchState::WaitingForB;
+            let b = waiting.b;
+            let session = waiting.session;
+            self.trails().record(session, TrailEvent::ToldB { b });
+        }
+    }
+
+    /// The punchS2B for the A at `tcp_addr_a` could not be sent.
+    pub fn send_failed(&mut self, tcp_addr_a: SocketAddr, error: String) {
+        if let Some(waiting) = self.waiting_a.remove(&tcp_addr_a) {
+            self.trails()
+                .record(waiting.session, TrailEvent::SendFailed { error });
+            self.trails().finish(waiting.session);
+        }
+    }
+
+    /// A stopped waiting for B.
+    pub fn forget_a(&mut self, tcp_addr_a: SocketAddr) {
+        if let Some(waiting) = self.waiting_a.remove(&tcp_addr_a) {
+            self.trails().record(waiting.session, TrailEvent::NoAnswer);
+            self.trails().finish(waiting.session);
+        }
+    }
+
+    /// Stops taking registrations and punch requests, the punches under way
+    /// go on. Returns the registered clients and their sockets, to tell them.
+    pub fn close(&mut self) -> Vec<(SocketAddr, usize)> {
+        self.closing = true;
+        self.registered.reachable()
+    }
+
+    /// Takes back the stored registrations that have not expired, each on
+    /// the first udp socket of its ip family in `sockets`.
+    pub fn restore(&mut self, sockets: &[SocketAddr]) -> Result<usize> {
+        self.registered.restore(|addr| {
+            sockets
+                .iter()
+                .position(|socket| socket.is_ipv6() == addr.is_ipv6())
+        })
+    }
+
+    /// Drops expired registrations.
+    pub fn purge(&mut self) {
+        self.registered.purge();
+    }
+
+    /// Registrations that have not expired.
+    pub fn registered(&self) -> usize {
+        self.registered.len()
+    }
+
+    pub fn waiting(&self) -> usize {
+        self.waiting_a.len()
+    }
+
+    pub fn registrations(&self, id: Option<&str>) -> Vec<Registration> {
+        self.registered.list(id)
+    }
+
+    /// Forgets every registration of `id`, returns how many there were.
+    pub fn kick(&mut self, id: &str) -> usize {
+        self.registered.remove(id)
+    }
+
+    /// Punches in flight, oldest first.
+    pub fn sessions(&self) -> Vec<PunchSession> {
+        let mut sessions: Vec<_> = self
+            .waiting_a
+            .iter()
+            .map(|(a, waiting)| PunchSession {
+                session: waiting.session,
+                a: *a,
+                b_id: waiting.id_b.clone(),
+                b: waiting.b,
+                state: waiting.state,
+                age_ms: waiting.since.elapsed().as_millis() as u64,
+            })
+            .collect();
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.age_ms));
+        sessions
+    }
+}
+
+/// The `via` of clients registered over a WebSocket, they are reached
+/// through their connection instead of a udp socket.
+const WEBSOCKET: usize = usize::MAX;
+/// Messages from other clients queued for a WebSocket client before more
+/// are dropped.
+const WEBSOCKET_QUEUE: usize = 16;
+
+struct Hybrid {
+    udp: Vec<Datagram>,
+    // by client addr, the messages to write to its WebSocket
+    websockets: Mutex<HashMap<SocketAddr, mpsc::Sender<String>>>,
+    state: Mutex<HybridState<oneshot::Sender<SocketAddr>>>,
+    metrics: Arc<Metrics>,
+    limiter: Arc<Limiter>,
+    cluster: Vec<SocketAddr>,
+    // a WebSocket quiet for this long is closed
+    registration_ttl: Duration,
+}
+
+impl Hybrid {
+    /// Sends `msg` to a registered client the way it registered. A message
+    /// for a WebSocket whose queue is full is dropped and counted.
+    async fn push(&self, addr: SocketAddr, via: usize, msg: &Message) -> Result<()> {
+        let data = msg.encode();
+        if via == WEBSOCKET {
+            let sender = self.websockets.lock().unwrap().get(&addr).cloned();
+            let sender = sender.ok_or_else(|| anyhow!("no WebSocket from {:?}", addr))?;
+            let len = data.len();
+            if let Err(e) = sender.try_send(String::from_utf8(data)?) {
+                if matches!(e, TrySendError::Full(_)) {
+                    self.metrics.dropped(Protocol::WebSocket);
+                }
+                bail!("WebSocket of {:?} takes nothing: {}", addr, e);
+            }
+            self.metrics.sent(Protocol::WebSocket, len);
+        } else {
+            self.udp[via].send_to(&data, addr).await?;
+            self.metrics.sent(Protocol::Udp, data.len());
+        }
+        Ok(())
+    }
+}
+
+impl Inspect for Hybrid {
+    fn registrations(&self, id: Option<&str>) -> Vec<Registration> {
+        self.state.lock().unwrap().registrations(id)
+    }
+
+    fn kick(&self, id: &str) -> usize {
+        self.state.lock().unwrap().kick(id)
+    }
+
+    fn sessions(&self) -> Vec<PunchSession> {
+        self.state.lock().unwrap().sessions()
+    }
+}
+
+/// Hybrid rendezvous: clients register over udp, A asks for B over tcp, the
+/// server tells B over udp, and B's tcp reply gives away its address to A.
+pub async fn hybrid(listener: TcpListener, socket: Datagram) -> Result<()> {
+    hybrid_with(vec![listener], vec![socket], Options::default()).await
+}
+
+/// Like [`hybrid`], on several listeners and sockets sharing one registry.
+pub async fn hybrid_with(
+    listeners: Vec<TcpListener>,
+    sockets: Vec<Datagram>,
+    options: Options,
+) -> Result<()> {
+    hybrid_with_websocket(listeners, sockets, Vec::new(), options).await
+}
+
+/// Like [`hybrid_with`], also taking WebSocket connections on `websockets`.
+/// Every text frame is one message: registrations, heartbeats, lookups and
+/// signals as over udp, and what the server tells a client registered over
+/// a WebSocket comes on its connection. Punch requests and answers are not
+/// taken, the addr of the connection is not the one punched from.
+pub async fn hybrid_with_websocket(
+    listeners: Vec<TcpListener>,
+    sockets: Vec<Datagram>,
+    websockets: Vec<TcpListener>,
+    options: Options,
+) -> Result<()> {
+    if listeners.is_empty() || sockets.is_empty() {
+        bail!("hybrid needs at least one tcp listener and one udp socket");
+    }
+    let udp_count = sockets.len();
+    let mut state = HybridState::new(&options);
+    let udp_addrs = sockets
+        .iter()
+        .map(Datagram::local_addr)
+        .collect::<std::io::Result<Vec<_>>>()?;
+    state.restore(&udp_addrs)?;
+    let shared = Arc::new(Hybrid {
+        udp: sockets,
+        websockets: Mutex::new(HashMap::new()),
+        state: Mutex::new(state),
+        metrics: options.metrics.clone(),
+        limiter: options.limiter.clone(),
+        cluster: options.cluster.clone(),
+        registration_ttl: options.registration_ttl,
+    });
+    options
+        .control
+        .attach(Arc::downgrade(&shared) as Weak<dyn Inspect>);
+    let connections = Arc::new(Semaphore::new(options.max_connections));
+    let tcp = try_join_all(listeners.into_iter().map(|listener| {
+        let shared = shared.clone();
+        accept_loop(
+            listener,
+            connections.clone(),
+            options.metrics.clone(),
+            move |stream, addr| hybrid_tcp_client(stream, addr, shared.clone()),
+        )
+    }));
+    // they stay open, so they do not take from the tcp ones
+    let websocket_connections = Arc::new(Semaphore::new(options.max_websockets));
+    let websocket = try_join_all(websockets.into_iter().map(|listener| {
+        let shared = shared.clone();
+        accept_loop(
+            listener,
+            websocket_connections.clone(),
+            options.metrics.clone(),
+            move |stream, addr| hybrid_websocket_client(stream, addr, shared.clone()),
+        )
+    }));
+    let udp = try_join_all((0..udp_count).map(|via| hybrid_udp(shared.clone(), via)));
+    let purge = purge_loop(|| {
+        shared.state.lock().unwrap().purge();
+        shared.limiter.purge();
+    });
+    let serve = async {
+        tokio::try_join!(tcp, websocket, udp, purge)?;
+        Ok(())
+    };
+    // still serving, B's answers to the punches under way come over tcp
+    let drained = async {
+        options.shutdown.wait().await;
+        let registered = shared.state.lock().unwrap().close();
+        tracing::info!("shutting down, telling {} clients", registered.len());
+        for (addr, via) in registered {
+            shared.push(addr, via, &Message::going_away).await.ok();
+        }
+        drain(options.drain_timeout, || {
+            shared.metrics.active_sessions() as usize
+        })
+        .await;
+    };
+    tokio::select! {
+        served = serve => served,
+        () = drained => Ok(()),
+    }
+}
+
+async fn hybrid_tcp_client(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    shared: Arc<Hybrid>,
+) -> Result<()> {
+    let metrics = &shared.metrics;
+    let msg = read_message(&mut stream).await?;
+    metrics.received(Protocol::Tcp, msg.len());
+    let msg = match Message::decode(&msg) {
+        Ok(msg) => msg,
+        Err(e) => {
+            metrics.decode_failed(Protocol::Tcp);
+            shared.limiter.check(addr.ip(), Request::Other).ok();
+            return Err(e);
+        }
+    };
+    // the other servers of the cluster ask for all their clients
+    if !shared.cluster.iter().any(|node| node.ip() == addr.ip())
+        && shared.limiter.check(addr.ip(), request(&msg)).is_err()
+    {
+        return Ok(());
+    }
+    tracing::info!("tcp recv {:?} from {:?}", msg, addr);
+    if let Some(rsp) = hybrid_request(&shared, addr, msg).await {
+        let data = rsp.encode();
+        stream.write_all(&data).await?;
+        metrics.sent(Protocol::Tcp, data.len());
+        tracing::info!("send {:?} to {:?}", rsp, addr);
+    }
+    Ok(())
+}
+
+// what the limiter charges a message that is not a registration
+fn request(msg: &Message) -> Request<'_> {
+    match msg {
+        Message::punchA2S(id_b) => Request::Punch(id_b),
+        Message::signal(signal) => Request::Punch(&signal.to),
+        _ => Request::Other,
+    }
+}
+
+/// Does what a request over tcp from `addr` asks for, returns
+/// the answer to it if there is one.
+async fn hybrid_request(shared: &Hybrid, addr: SocketAddr, msg: Message) -> Option<Message> {
+    let metrics = &shared.metrics;
+    let (sender, receiver) = oneshot::channel();
+    let action = shared.state.lock().unwrap().on_tcp(addr, msg, sender);
+    match action {
+        TcpAction::Reply(rsp) => {
+            if rsp == Message::punchS2A(None) {
+                metrics.punch_request();
+                metrics.punch_failed(PunchFailure::PeerNotRegistered);
+            }
+            Some(rsp)
+        }
+        TcpAction::TellB(b_udp_addr, via, punch_s2b) => {
+            metrics.punch_request();
+            let _session = metrics.session();
+            // `addr` is another server when it routes for its A
+            let a = match punch_s2b {
+                Message::punchS2B(a) => a,
+                _ => addr,
+            };
+            let sent = shared.push(b_udp_addr, via, &punch_s2b).await;
+            tracing::info!("Send A addr {:?} to B:{:?}", a, b_udp_addr);
+            let tcp_addr_b = match sent {
+                Ok(_) => {
+                    shared.state.lock().unwrap().told_b(a);
+                    let answer = timeout(PUNCH_TIMEOUT, receiver)
+                        .await
+                        .ok()
+                        .and_then(|r| r.ok());
+                    if answer.is_none() {
+                        metrics.punch_failed(PunchFailure::PeerNoAnswer);
+                    }
+                    answer
+                }
+                Err(e) => {
+                    tracing::error!("Failed to Send udp to B: {:?}", e);
+                    metrics.punch_failed(PunchFailure::SendFailed);
+                    shared.state.lock().unwrap().send_failed(a, e.to_string());
+                    None
+                }
+            };
+            shared.state.lock().unwrap().forget_a(a);
+            match tcp_addr_b {
+                Some(_) => metrics.punch_succeeded(),
+                None => tracing::warn!("B did not answer A {:?}", a),
+            }
+            Some(Message::punchS2A(tcp_addr_b))
+        }
+        TcpAction::Route(session, route) => {
+            metrics.punch_request();
+            metrics.punch_routed();
+            let _session = metrics.session();
+            let (node, tcp_addr_b) = match ask_cluster(&shared.cluster, &route).await {
+                Some((node, b)) => (Some(node), Some(b)),
+                None => (None, None),
+            };
+            shared
+                .state
+                .lock()
+                .unwrap()
+                .routed(session, node, tcp_addr_b);
+            match tcp_addr_b {
+                Some(_) => metrics.punch_succeeded(),
+                None => metrics.punch_failed(PunchFailure::PeerNotRegistered),
+            }
+            tracing::info!(
+                "A {:?} gets addr of B {:?} via {:?}",
+                addr,
+                tcp_addr_b,
+                node
+            );
+            Some(Message::punchS2A(tcp_addr_b))
+        }
+        TcpAction::AnswerA(sender, tcp_addr_b) => {
+            sender.send(tcp_addr_b).ok();
+            None
+        }
+        TcpAction::Forward(..) | TcpAction::Ignore => None,
+    }
+}
+
+/// Does what a lookup or a signal from `addr` on socket `via` asks for,
+/// returns the answer to it.
+async fn registered_request(
+    shared: &Hybrid,
+    addr: SocketAddr,
+    via: usize,
+    msg: Message,
+) -> Option<Message> {
+    let action = shared.state.lock().unwrap().on_registered(addr, via, msg);
+    match action {
+        TcpAction::Reply(rsp) => Some(rsp),
+        TcpAction::Forward(peer, via, msg) => {
+            let sent = match shared.push(peer, via, &msg).await {
+                Ok(_) => true,
+                Err(e) => {
+                    tracing::error!("Failed to forward to {:?}: {:?}", peer, e);
+                    false
+                }
+            };
+            Some(Message::signal_sent(sent))
+        }
+        _ => None,
+    }
+}
+
+/// A client that keeps a WebSocket open, it may register and ask as many
+/// times as it likes. It is closed when it does not register within
+/// [`READ_TIMEOUT`] or sends nothing for the registration ttl, and its
+/// registrations end with the connection.
+async fn hybrid_websocket_client(
+    stream: TcpStream,
+    addr: SocketAddr,
+    shared: Arc<Hybrid>,
+) -> Result<()> {
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE),
+        max_frame_size: Some(MAX_MESSAGE),
+        ..Default::default()
+    };
+    let websocket = timeout(READ_TIMEOUT, accept_async_with_config(stream, Some(config)))
+        .await
+        .map_err(|_| anyhow!("no WebSocket handshake in {:?}", READ_TIMEOUT))??;
+    let (mut sink, mut source) = websocket.split();
+    let (sender, mut outgoing) = mpsc::channel(WEBSOCKET_QUEUE);
+    shared
+        .websockets
+        .lock()
+        .unwrap()
+        .insert(addr, sender.clone());
+    let mut ids = HashSet::new();
+    let mut deadline = Instant::now() + READ_TIMEOUT;
+    let served = async {
+        loop {
+            let frame = tokio::select! {
+                frame = source.next() => frame,
+                Some(text) = outgoing.recv() => {
+                    sink.send(WebSocketMessage::Text(text)).await?;
+                    continue;
+                }
+                _ = tokio::time::sleep_until(deadline) => match ids.is_empty() {
+                    true => bail!("not registered in {:?}", READ_TIMEOUT),
+                    false => bail!("nothing received in {:?}", shared.registration_ttl),
+                },
+            };
+            if !ids.is_empty() {
+                deadline = Instant::now() + shared.registration_ttl;
+            }
+            let data = match frame {
+                Some(Ok(WebSocketMessage::Text(text))) => text.into_bytes(),
+                Some(Ok(WebSocketMessage::Binary(data))) => data,
+                // pings are answered by the next write
+                Some(Ok(WebSocketMessage::Ping(_) | WebSocketMessage::Pong(_))) => {
+                    sink.flush().await?;
+                    continue;
+                }
+                Some(Ok(WebSocketMessage::Close(_) | WebSocketMessage::Frame(_))) | None => {
+                    return Ok(())
+                }
+                Some(Err(e)) => return Err(e.into()),
+            };
+            shared.metrics.received(Protocol::WebSocket, data.len());
+            let msg = match Message::decode(&data) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    shared.metrics.decode_failed(Protocol::WebSocket);
+                    shared.limiter.check(addr.ip(), Request::Other).ok();
+                    tracing::error!("websocket msg decode failed");
+                    continue;
+                }
+            };
+            let charged = match &msg {
+                Message::register_request(id) => Request::Register(id),
+                msg => request(msg),
+            };
+            if shared.limiter.check(addr.ip(), charged).is_err() {
+                continue;
+            }
+            tracing::debug!("websocket recv {:?} from {:?}", msg, addr);
+            match msg {
+                //注册, 心跳和退出与udp相同
+                Message::register_request(_)
+                | Message::addr_request(_)
+                | Message::unregister_request(_) => {
+                    let registering = match &msg {
+                        Message::register_request(id) => Some(id.clone()),
+                        Message::unregister_request(id) => {
+                            ids.remove(id);
+                            None
+                        }
+                        _ => None,
+                    };
+                    let rsp = shared.state.lock().unwrap().on_udp(addr, WEBSOCKET, msg);
+                    if let (Some(id), Some(Message::register_response(0))) = (registering, &rsp) {
+                        ids.insert(id);
+                        deadline = Instant::now() + shared.registration_ttl;
+                    }
+                    if let Some(rsp) = rsp {
+                        reply(&mut sink, &shared.metrics, &rsp).await?;
+                    }
+                }
+                //连接的地址打不了洞, A和B要从打洞的端口用tcp请求和回复
+                Message::punchA2S(_) | Message::punchB2S(_) | Message::routeA2S(..) => {
+                    tracing::warn!("{:?} over a WebSocket from {:?} ignored", msg, addr);
+                }
+                //查询和信号与udp相同, 只回答注册过的客户端
+                Message::lookup_request(..) | Message::signal(_) => {
+                    if let Some(rsp) = registered_request(&shared, addr, WEBSOCKET, msg).await {
+                        reply(&mut sink, &shared.metrics, &rsp).await?;
+                    }
+                }
+                msg => tracing::warn!("unexpected websocket msg {:?} from {:?}", msg, addr),
+            }
+        }
+    };
+    let served: Result<()> = served.await;
+    shared.websockets.lock().unwrap().remove(&addr);
+    let mut state = shared.state.lock().unwrap();
+    for id in ids {
+        state.on_udp(addr, WEBSOCKET, Message::unregister_request(id));
+    }
+    served
+}
+
+/// Answers a WebSocket client on its connection, past the queue of what
+/// other clients send it.
+async fn reply<S>(sink: &mut S, metrics: &Metrics, msg: &Message) -> Result<()>
+where
+    S: Sink<WebSocketMessage, Error = tungstenite::Error> + Unpin,
+{
+    let text = String::from_utf8(msg.encode())?;
+    let len = text.len();
+    sink.send(WebSocketMessage::Text(text)).await?;
+    metrics.sent(Protocol::WebSocket, len);
+    Ok(())
+}
+
+/// Sends `route` to every node of the cluster at once, returns the first
+/// node that answers with B's tcp addr, and that addr.
+async fn ask_cluster(cluster: &[SocketAddr], route: &Message) -> Option<(SocketAddr, SocketAddr)> {
+    let data = route.encode();
+    let mut asked: FuturesUnordered<_> = cluster
+        .iter()
+        .map(|&node| {
+            let data = &data;
+            async move {
+                let answer = timeout(ROUTE_TIMEOUT, async {
+                    let mut stream = TcpStream::connect(node).await?;
+                    stream.write_all(data).await?;
+                    Message::decode(&read_message(&mut stream).await?)
+                })
+                .await;
+                match answer {
+                    Ok(Ok(Message::punchS2A(b))) => b.map(|b| (node, b)),
+                    Ok(Ok(msg)) => {
+                        tracing::warn!("node {} answered {:?}", node, msg);
+                        None
+                    }
+                    Ok(Err(e)) => {
+                        tracing::warn!("node {} failed: {:?}", node, e);
+                        None
+                    }
+                    Err(_) => {
+                        tracing::warn!("node {} did not answer", node);
+                        None
+                    }
+                }
+            }
+        })
+        .collect();
+    while let Some(answer) = asked.next().await {
+        if answer.is_some() {
+            return answer;
+        }
+    }
+    None
+}
+
+async fn hybrid_udp(shared: Arc<Hybrid>, via: usize) -> Result<()> {
+    let socket = &shared.udp[via];
+    tracing::info!("listening on udp {:?}", socket.local_addr());
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                tracing::warn!("udp recv failed {:?}", e);
+                continue;
+            }
+        };
+        tracing::debug!("udp new msg from {:?}", addr);
+        shared.metrics.received(Protocol::Udp, len);
+        let msg = match Message::decode(&buf[..len]) {
+            Ok(msg) => msg,
+            Err(_) => {
+                shared.metrics.decode_failed(Protocol::Udp);
+                shared.limiter.check(addr.ip(), Request::Other).ok();
+                tracing::error!("udp msg decode failed");
+                continue;
+            }
+        };
+        let charged = match &msg {
+            Message::register_request(id) => Request::Register(id),
+            msg => request(msg),
+        };
+        if shared.limiter.check(addr.ip(), charged).is_err() {
+            continue;
+        }
+        let rsp = match msg {
+            Message::lookup_request(..) | Message::signal(_) => {
+                registered_request(&shared, addr, via, msg).await
+            }
+            msg => shared.state.lock().unwrap().on_udp(addr, via, msg),
+        };
+        if let Some(rsp) = rsp {
+            let data = rsp.encode();
+            if shared.limiter.check_reply(len, data.len()).is_err() {
+                continue;
+            }
+            match socket.send_to(&data, addr).await {
+                Ok(_) => shared.metrics.sent(Protocol::Udp, data.len()),
+                Err(e) => tracing::error!("Send rsp to {:?} failed. {:?}", addr, e),
+            }
+        }
+    }
+}
+
+async fn read_message(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    let n = match timeout(READ_TIMEOUT, stream.read(&mut buf)).await {
+        Ok(n) => n?,
+        Err(_) => bail!("nothing received in {:?}", READ_TIMEOUT),
+    };
+    if n == 0 {
+        bail!("closed before sending anything");
+    }
+    buf.truncate(n);
+    Ok(buf)
+}
@@ -0,0 +1,491 @@
+use crate::puncher::{Punched, Puncher};
+use anyhow::{anyhow, bail, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::SystemTime,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf, ReadHalf},
+    sync::{mpsc, watch},
+    time::{interval, sleep, timeout, Duration, Instant},
+};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tracing::{field, Instrument, Span};
+
+const HELLO: u8 = 0;
+const DATA: u8 = 1;
+const ACK: u8 = 2;
+const FIN: u8 = 3;
+const PING: u8 = 4;
+
+const PING_INTERVAL: Duration = Duration::from_secs(2);
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const RESUME_TIMEOUT: Duration = Duration::from_secs(120);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_UNACKED: u64 = 1024 * 1024;
+const ACK_EVERY: u64 = 32 * 1024;
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Hello {
+    session: u64, // 0: no session yet
+    received: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub local_addr: Option<SocketAddr>,
+    pub peer_addr: Option<SocketAddr>,
+    /// How many times the hole was punched again after a loss
+    pub reconnects: u32,
+    /// Set once the session can not be resumed any more
+    pub error: Option<String>,
+}
+
+/// A byte stream to the peer that survives connection loss.
+///
+/// When the punched connection hits EOF or stays silent past the keepalive
+/// timeout, the puncher goes back through the rendezvous server, and the
+/// session is resumed under the same id: unacknowledged data is sent again,
+/// readers and writers only see a stall.
+pub struct Session {
+    inner: DuplexStream,
+    initiator: bool,
+    info: Arc<Mutex<SessionInfo>>,
+    closed: watch::Receiver<bool>,
+}
+
+impl Session {
+    /// Punches the first connection, later ones are punched in the background.
+    pub async fn connect(mut puncher: Puncher) -> Result<Self> {
+        let punched = puncher.punch().await?;
+        let initiator = punched.initiator;
+        let info = Arc::new(Mutex::new(SessionInfo {
+            local_addr: punched.stream.local_addr().ok(),
+            peer_addr: punched.stream.peer_addr().ok(),
+            ..Default::default()
+        }));
+        // the id is known after the first handshake
+        let span = tracing::info_span!(parent: &puncher.span(), "session", session = field::Empty);
+        let (closed_tx, closed) = watch::channel(false);
+        let (inner, driver_side) = tokio::io::duplex(64 * 1024);
+        let mut driver = Driver {
+            puncher,
+            info: info.clone(),
+            closed: closed_tx,
+            id: None,
+            established: false,
+            sent_base: 0,
+            unacked: VecDeque::new(),
+            unacked_bytes: 0,
+            received: 0,
+            undelivered: VecDeque::new(),
+            undelivered_bytes: 0,
+            local_fin: false,
+            peer_fin: false,
+        };
+        let (mut pipe_rd, mut pipe_wr) = tokio::io::split(driver_side);
+        // 按序交付给用户的数据, 空Bytes表示对端已关闭写端
+        let (deliver, mut deliver_rx) = mpsc::channel::<Bytes>(64);
+        tokio::spawn(async move {
+            while let Some(data) = deliver_rx.recv().await {
+                if data.is_empty() || pipe_wr.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+            pipe_wr.shutdown().await.ok();
+        });
+        let driver_info = info.clone();
+        tokio::spawn(async move {
+            if let Err(e) = driver.run(punched, &mut pipe_rd, &deliver).await {
+                tracing::error!("session closed: {:?}", e);
+                // before the reader sees EOF
+                driver_info.lock().unwrap().error = Some(e.to_string());
+            }
+        }.instrument(span));
+        Ok(Self {
+            inner,
+            initiator,
+            info,
+            closed,
+        })
+    }
+
+    pub fn initiator(&self) -> bool {
+        self.initiator
+    }
+
+    pub fn info(&self) -> SessionInfo {
+        self.info.lock().unwrap().clone()
+    }
+
+    /// Closes our direction and waits a little for the peer to acknowledge
+    /// everything and close too, so nothing is lost when the process exits
+    /// right after.
+    pub async fn close(mut self) -> Result<()> {
+        self.inner.shutdown().await?;
+        timeout(CLOSE_TIMEOUT, self.closed.wait_for(|closed| *closed))
+            .await
+            .map_err(|_| anyhow!("peer did not close in {:?}", CLOSE_TIMEOUT))??;
+        Ok(())
+    }
+}
+
+impl AsyncRead for Session {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            // EOF from a session that failed is an error, not a clean close
+            if buf.filled().len() == before && buf.remaining() > 0 {
+                if let Some(error) = &self.info.lock().unwrap().error {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        error.clone(),
+                    )));
+                }
+            }
+        }
+        poll
+    }
+}
+
+impl AsyncWrite for Session {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+enum Chunk {
+    Data(Bytes),
+    Fin, // takes one byte of the offset space
+}
+
+impl Chunk {
+    fn len(&self) -> u64 {
+        match self {
+            Chunk::Data(data) => data.len() as u64,
+            Chunk::Fin => 1,
+        }
+    }
+
+    fn frame(&self) -> Bytes {
+        match self {
+            Chunk::Data(data) => {
+                let mut frame = BytesMut::with_capacity(data.len() + 1);
+                frame.put_u8(DATA);
+                frame.put_slice(data);
+                frame.freeze()
+            }
+            Chunk::Fin => Bytes::from_static(&[FIN]),
+        }
+    }
+}
+
+type Connection = Framed<crate::puncher::PunchedStream, LengthDelimitedCodec>;
+
+struct Driver {
+    puncher: Puncher,
+    info: Arc<Mutex<SessionInfo>>,
+    // both sides closed and everything acked
+    closed: watch::Sender<bool>,
+    id: Option<u64>,
+    established: bool,
+    // offset of the first unacked chunk
+    sent_base: u64,
+    unacked: VecDeque<Chunk>,
+    unacked_bytes: u64,
+    received: u64,
+    // received but not yet taken by the reader, an empty one is the peer's fin
+    undelivered: VecDeque<Bytes>,
+    undelivered_bytes: u64,
+    local_fin: bool,
+    peer_fin: bool,
+}
+
+impl Driver {
+    async fn run(
+        &mut self,
+        first: Punched,
+        pipe_rd: &mut ReadHalf<DuplexStream>,
+        deliver: &mpsc::Sender<Bytes>,
+    ) -> Result<()> {
+        let mut punched = first;
+        loop {
+            let initiator = punched.initiator;
+            {
+                let mut info = self.info.lock().unwrap();
+                info.local_addr = punched.stream.local_addr().ok();
+                info.peer_addr = punched.stream.peer_addr().ok();
+            }
+            let mut conn = Framed::new(punched.stream, LengthDelimitedCodec::new());
+            let result = match self.handshake(&mut conn, initiator).await {
+                Ok(()) => self.serve(&mut conn, pipe_rd, deliver).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if self.established => {
+                    tracing::warn!("session {:x} lost: {:?}", self.id.unwrap_or(0), e)
+                }
+                Err(e) => return Err(e),
+            }
+            drop(conn);
+            punched = self.punch_again().await?;
+            self.info.lock().unwrap().reconnects += 1;
+        }
+    }
+
+    async fn punch_again(&mut self) -> Result<Punched> {
+        let deadline = Instant::now() + RESUME_TIMEOUT;
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                bail!("could not resume within {:?}", RESUME_TIMEOUT);
+            }
+            match timeout(remaining, self.puncher.punch()).await {
+                Ok(Ok(punched)) => return Ok(punched),
+                Ok(Err(e)) => tracing::warn!("punch again failed: {:?}", e),
+                Err(_) => bail!("could not resume within {:?}", RESUME_TIMEOUT),
+            }
+            tracing::info!("punch again in {:?}", backoff);
+            sleep(backoff.min(deadline.saturating_duration_since(Instant::now()))).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    async fn handshake(&mut self, conn: &mut Connection, initiator: bool) -> Result<()> {
+        // the peer sends again what the reader did not take yet
+        self.received = self.delivered();
+        if self.undelivered.back().is_some_and(|data| data.is_empty()) {
+            self.peer_fin = false;
+        }
+        self.undelivered.clear();
+        self.undelivered_bytes = 0;
+        if initiator && self.id.is_none() {
+            self.id = Some(new_session_id());
+        }
+        let hello = Hello {
+            session: self.id.unwrap_or(0),
+            received: self.received,
+        };
+        let mut frame = BytesMut::new();
+        frame.put_u8(HELLO);
+        frame.put_slice(&serde_json::to_vec(&hello)?);
+        conn.send(frame.freeze()).await?;
+
+        let frame = match timeout(HANDSHAKE_TIMEOUT, conn.next()).await {
+            Ok(Some(frame)) => frame?,
+            Ok(None) => bail!("closed during handshake"),
+            Err(_) => bail!("handshake timeout"),
+        };
+        if frame.first() != Some(&HELLO) {
+            bail!("expect hello");
+        }
+        let peer: Hello = serde_json::from_slice(&frame[1..])?;
+
+        // the initiator picks the id, the other side takes it on first contact
+        let same = match self.id {
+            None => {
+                self.id = Some(peer.session);
+                peer.session != 0
+            }
+            Some(id) => peer.session == id || (peer.session == 0 && !self.established),
+        };
+        if !same {
+            // the peer restarted, nothing to resume, give up instead of punching again
+            self.established = false;
+            bail!("peer lost session {:x}, got {:x}", hello.session, peer.session);
+        }
+
+        let id = self.id.unwrap_or(0);
+        if self.established {
+            tracing::info!(
+                "session {:x} resumed, peer has {} of {} bytes",
+                id,
+                peer.received,
+                self.sent_base + self.unacked_bytes
+            );
+        } else {
+            tracing::info!("session {:x} established", id);
+        }
+        self.established = true;
+        self.info.lock().unwrap().id = id;
+        Span::current().record("session", format!("{:x}", id).as_str());
+
+        self.ack(peer.received)?;
+        for chunk in &self.unacked {
+            conn.send(chunk.frame()).await?;
+        }
+        Ok(())
+    }
+
+    fn ack(&mut self, acked: u64) -> Result<()> {
+        if acked > self.sent_base + self.unacked_bytes {
+            bail!("peer acked {} beyond {}", acked, self.sent_base + self.unacked_bytes);
+        }
+        while let Some(chunk) = self.unacked.front() {
+            if self.sent_base + chunk.len() > acked {
+                break;
+            }
+            self.sent_base += chunk.len();
+            self.unacked_bytes -= chunk.len();
+            self.unacked.pop_front();
+        }
+        self.check_closed();
+        Ok(())
+    }
+
+    // acked to the peer, what is held back counts once the reader took it
+    fn delivered(&self) -> u64 {
+        self.received - self.undelivered_bytes
+    }
+
+    fn check_closed(&self) {
+        if self.local_fin
+            && self.peer_fin
+            && self.unacked.is_empty()
+            && self.undelivered.is_empty()
+        {
+            self.closed.send(true).ok();
+        }
+    }
+
+    async fn serve(
+        &mut self,
+        conn: &mut Connection,
+        pipe_rd: &mut ReadHalf<DuplexStream>,
+        deliver: &mpsc::Sender<Bytes>,
+    ) -> Result<()> {
+        let mut buf = vec![0u8; 16 * 1024];
+        let mut timer = interval(PING_INTERVAL);
+        let mut last_recv = Instant::now();
+        let mut last_send = Instant::now();
+        let mut pending_ack: u64 = 0;
+
+        loop {
+            let can_read = !self.local_fin && self.unacked_bytes < MAX_UNACKED;
+            tokio::select! {
+                n = pipe_rd.read(&mut buf), if can_read => {
+                    let chunk = match n {
+                        Ok(n) if n > 0 => Chunk::Data(Bytes::copy_from_slice(&buf[..n])),
+                        _ => {
+                            self.local_fin = true;
+                            Chunk::Fin
+                        }
+                    };
+                    conn.send(chunk.frame()).await?;
+                    last_send = Instant::now();
+                    self.unacked_bytes += chunk.len();
+                    self.unacked.push_back(chunk);
+                }
+                frame = conn.next() => {
+                    let mut frame = match frame {
+                        Some(frame) => frame?,
+                        None => bail!("connection closed"),
+                    };
+                    last_recv = Instant::now();
+                    if frame.is_empty() {
+                        continue;
+                    }
+                    match frame.get_u8() {
+                        DATA if !frame.is_empty() => {
+                            self.received += frame.len() as u64;
+                            self.undelivered_bytes += frame.len() as u64;
+                            self.undelivered.push_back(frame.freeze());
+                        }
+                        FIN if !self.peer_fin => {
+                            self.peer_fin = true;
+                            self.received += 1;
+                            self.undelivered_bytes += 1;
+                            self.undelivered.push_back(Bytes::new());
+                        }
+                        ACK if frame.len() >= 8 => self.ack(frame.get_u64())?,
+                        _ => {}
+                    }
+                }
+                // 读者慢时照样收发ping和ack, 对端靠未确认的上限停下来
+                permit = deliver.reserve(), if !self.undelivered.is_empty() => {
+                    let data = self.undelivered.pop_front().unwrap_or_default();
+                    let len = (data.len() as u64).max(1);
+                    self.undelivered_bytes -= len;
+                    pending_ack += len;
+                    if let Ok(permit) = permit {
+                        permit.send(data);
+                    }
+                    if pending_ack >= ACK_EVERY || (self.peer_fin && self.undelivered.is_empty()) {
+                        conn.send(ack_frame(self.delivered())).await?;
+                        last_send = Instant::now();
+                        pending_ack = 0;
+                    }
+                    self.check_closed();
+                }
+                _ = timer.tick() => {
+                    if pending_ack > 0 {
+                        conn.send(ack_frame(self.delivered())).await?;
+                        last_send = Instant::now();
+                        pending_ack = 0;
+                    }
+                    if last_send.elapsed() >= PING_INTERVAL {
+                        conn.send(Bytes::from_static(&[PING])).await?;
+                        last_send = Instant::now();
+                    }
+                    if last_recv.elapsed() >= KEEPALIVE_TIMEOUT {
+                        bail!("peer silent for {:?}", KEEPALIVE_TIMEOUT);
+                    }
+                }
+            }
+            if self.local_fin
+                && self.peer_fin
+                && self.unacked.is_empty()
+                && self.undelivered.is_empty()
+                && pending_ack == 0
+            {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn ack_frame(received: u64) -> Bytes {
+    let mut frame = BytesMut::with_capacity(9);
+    frame.put_u8(ACK);
+    frame.put_u64(received);
+    frame.freeze()
+}
+
+fn new_session_id() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    // never 0, that means "no session"
+    (nanos ^ ((std::process::id() as u64) << 32)) | 1
+}
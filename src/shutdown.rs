@@ -0,0 +1,86 @@
+//! Stopping a server without cutting off the punches it is in the middle of.
+//! Once a [`Shutdown`] is triggered the servers stop taking new work, tell
+//! the registered clients they are going away so they move to the next
+//! server, and return when their sessions are done or the drain time is up.
+
+use crate::puncher::Leave;
+use anyhow::Result;
+use std::{future::Future, sync::Arc};
+use tokio::sync::watch;
+
+/// Shared by everything that stops together, clones trigger the same one.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Returns once triggered, right away if it already is.
+    pub async fn wait(&self) {
+        let mut receiver = self.receiver.clone();
+        while !*receiver.borrow_and_update() {
+            if receiver.changed().await.is_err() {
+                // the sender lives as long as any clone, never dropped first
+                return;
+            }
+        }
+    }
+
+    /// Triggers on the first SIGINT or SIGTERM.
+    pub fn on_signal(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            match signal().await {
+                Ok(()) => shutdown.trigger(),
+                Err(e) => tracing::warn!("no signal handler: {:?}", e),
+            }
+        });
+    }
+}
+
+/// Returns on SIGINT or SIGTERM, on ctrl-c where there are no unix signals.
+pub async fn signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            interrupted = tokio::signal::ctrl_c() => interrupted?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    tracing::info!("shutting down");
+    Ok(())
+}
+
+/// Runs the work of a client until it is done or SIGINT or SIGTERM arrives,
+/// then unregisters it either way so the server stops handing out its addrs.
+pub async fn client(leave: Leave, work: impl Future<Output = Result<()>>) -> Result<()> {
+    let result = tokio::select! {
+        result = work => result,
+        signaled = signal() => signaled,
+    };
+    leave.unregister().await;
+    result
+}
@@ -0,0 +1,585 @@
+//! How the two sides of a punch find each other and trade their candidates.
+//! The hybrid server is one way, over udp with [`ServerSignaling`] or over
+//! a WebSocket with [`WebSocketSignaling`]; [`MemorySignaling`]
+//! keeps everything in the process for tests and [`PipeSignaling`] writes
+//! and reads json lines, so the candidates can be carried over by hand.
+
+use crate::{
+    hybrid::Message,
+    net::{any_addr, Datagram, Network},
+    pad_request, Candidate,
+};
+use anyhow::{anyhow, bail, Result};
+use futures::{future::BoxFuture, SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{interval, sleep, timeout, Duration},
+};
+use tokio_tungstenite::{client_async, tungstenite::Message as WebSocketMessage};
+
+/// Candidates a signal may carry, so the hybrid server can relay it in one
+/// datagram.
+pub const MAX_CANDIDATES: usize = 8;
+/// How often the registration at the server is renewed.
+const HEARTBEAT: Duration = Duration::from_secs(3);
+/// How long the server may take to answer.
+const SERVER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a request to the server waits for its answer before it is sent again.
+const RESEND_INTERVAL: Duration = Duration::from_secs(1);
+/// How often a followed file is read again once it has no more lines.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Our candidates for a peer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Signal {
+    pub from: String,
+    pub to: String,
+    pub candidates: Vec<Candidate>,
+}
+
+/// Carries [`Signal`]s between the ids registered with it.
+pub trait Signaling: Send + Sync {
+    /// Makes `id` known, the signals sent to it arrive at [`Signaling::receive`].
+    fn register<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// None when `id` is not registered, otherwise the addrs the signaling
+    /// knows it by, if any.
+    fn lookup<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Vec<Candidate>>>>;
+
+    /// Sends `signal` to `signal.to`, an error when it can not be reached.
+    fn send(&self, signal: Signal) -> BoxFuture<'_, Result<()>>;
+
+    /// The next signal to the registered id.
+    fn receive(&self) -> BoxFuture<'_, Result<Signal>>;
+}
+
+/// Signaling between the endpoints of one process, see [`MemorySignaling::peer`].
+#[derive(Default)]
+pub struct MemorySignaling {
+    hub: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Signal>>>>,
+    inbox: tokio::sync::Mutex<Option<mpsc::UnboundedReceiver<Signal>>>,
+}
+
+impl MemorySignaling {
+    /// Another endpoint that reaches the ids registered with this one.
+    pub fn peer(&self) -> Self {
+        Self {
+            hub: self.hub.clone(),
+            inbox: Default::default(),
+        }
+    }
+}
+
+impl Signaling for MemorySignaling {
+    fn register<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            self.hub.lock().unwrap().insert(id.to_owned(), sender);
+            *self.inbox.lock().await = Some(receiver);
+            Ok(())
+        })
+    }
+
+    fn lookup<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Vec<Candidate>>>> {
+        let known = self.hub.lock().unwrap().contains_key(id);
+        Box::pin(async move { Ok(known.then(Vec::new)) })
+    }
+
+    fn send(&self, signal: Signal) -> BoxFuture<'_, Result<()>> {
+        let sender = self.hub.lock().unwrap().get(&signal.to).cloned();
+        Box::pin(async move {
+            let to = signal.to.clone();
+            match sender {
+                Some(sender) if sender.send(signal).is_ok() => Ok(()),
+                _ => bail!("{} is not registered", to),
+            }
+        })
+    }
+
+    fn receive(&self) -> BoxFuture<'_, Result<Signal>> {
+        Box::pin(async move {
+            match &mut *self.inbox.lock().await {
+                Some(inbox) => inbox
+                    .recv()
+                    .await
+                    .ok_or_else(|| anyhow!("signaling closed")),
+                None => bail!("receive before register"),
+            }
+        })
+    }
+}
+
+struct Input {
+    reader: BufReader<Box<dyn AsyncRead + Send + Unpin>>,
+    // a line written by halves is read by halves
+    line: String,
+    // a file someone appends to: no more lines yet is not the end
+    follow: bool,
+}
+
+/// Signals as json lines, written to one stream and read from another:
+/// stdin and stdout, files or named pipes. There is no directory, every id
+/// counts as registered and the lines to other ids are skipped.
+pub struct PipeSignaling {
+    input: tokio::sync::Mutex<Input>,
+    output: tokio::sync::Mutex<Box<dyn AsyncWrite + Send + Unpin>>,
+    id: Mutex<Option<String>>,
+}
+
+impl PipeSignaling {
+    /// Reads the lines of `input` until it ends, writes to `output`.
+    pub fn new(
+        input: impl AsyncRead + Send + Unpin + 'static,
+        output: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> Self {
+        Self::with(Box::new(input), Box::new(output), false)
+    }
+
+    /// Our signals go to stdout, the peer's are pasted into stdin.
+    pub fn stdio() -> Self {
+        Self::new(tokio::io::stdin(), tokio::io::stdout())
+    }
+
+    /// Follows `input` for the lines appended to it and appends to `output`,
+    /// both are created when missing.
+    pub async fn files(input: &Path, output: &Path) -> Result<Self> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true).read(true);
+        let (input, output) = (options.open(input).await?, options.open(output).await?);
+        Ok(Self::with(Box::new(input), Box::new(output), true))
+    }
+
+    fn with(
+        input: Box<dyn AsyncRead + Send + Unpin>,
+        output: Box<dyn AsyncWrite + Send + Unpin>,
+        follow: bool,
+    ) -> Self {
+        Self {
+            input: tokio::sync::Mutex::new(Input {
+                reader: BufReader::new(input),
+                line: String::new(),
+                follow,
+            }),
+            output: tokio::sync::Mutex::new(output),
+            id: Mutex::new(None),
+        }
+    }
+}
+
+impl Signaling for PipeSignaling {
+    fn register<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<()>> {
+        *self.id.lock().unwrap() = Some(id.to_owned());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn lookup<'a>(&'a self, _id: &'a str) -> BoxFuture<'a, Result<Option<Vec<Candidate>>>> {
+        Box::pin(async { Ok(Some(Vec::new())) })
+    }
+
+    fn send(&self, signal: Signal) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let mut line = serde_json::to_vec(&signal)?;
+            line.push(b'\n');
+            let mut output = self.output.lock().await;
+            output.write_all(&line).await?;
+            output.flush().await?;
+            Ok(())
+        })
+    }
+
+    fn receive(&self) -> BoxFuture<'_, Result<Signal>> {
+        Box::pin(async move {
+            let id = self.id.lock().unwrap().clone();
+            let mut input = self.input.lock().await;
+            let input = &mut *input;
+            loop {
+                let n = input.reader.read_line(&mut input.line).await?;
+                if !input.line.ends_with('\n') {
+                    if n == 0 && !input.follow {
+                        bail!("signaling input ended");
+                    }
+                    sleep(FOLLOW_INTERVAL).await;
+                    continue;
+                }
+                let line = std::mem::take(&mut input.line);
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<Signal>(&line) {
+                    Ok(signal) if id.is_none() || id.as_ref() == Some(&signal.to) => {
+                        return Ok(signal)
+                    }
+                    Ok(signal) => tracing::debug!("signal to {} skipped", signal.to),
+                    Err(e) => tracing::warn!("not a signal {:?}: {}", line.trim(), e),
+                }
+            }
+        })
+    }
+}
+
+/// The hybrid server as signaling: registered over udp like a hybrid client,
+/// lookups and signals go out on the same socket, the server only answers
+/// them there, and that is also where the signals relayed to us arrive.
+pub struct ServerSignaling {
+    server_addr: SocketAddr,
+    network: Network,
+    sender: mpsc::Sender<Signal>,
+    inbox: tokio::sync::Mutex<mpsc::Receiver<Signal>>,
+    // what the server answers on the registration socket
+    answer_sender: mpsc::Sender<Message>,
+    answers: tokio::sync::Mutex<mpsc::Receiver<Message>>,
+    // our id and the socket it is registered from
+    registered: Mutex<Option<(String, Arc<Datagram>)>>,
+    heartbeat: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ServerSignaling {
+    pub fn new(server_addr: SocketAddr) -> Self {
+        let (sender, receiver) = mpsc::channel(16);
+        let (answer_sender, answers) = mpsc::channel(4);
+        Self {
+            server_addr,
+            network: Network::Os,
+            sender,
+            inbox: tokio::sync::Mutex::new(receiver),
+            answer_sender,
+            answers: tokio::sync::Mutex::new(answers),
+            registered: Mutex::new(None),
+            heartbeat: Mutex::new(None),
+        }
+    }
+
+    /// Where the registration socket is bound.
+    pub fn network(mut self, network: Network) -> Self {
+        self.network = network;
+        self
+    }
+
+    fn id(&self) -> Result<String> {
+        match &*self.registered.lock().unwrap() {
+            Some((id, _)) => Ok(id.clone()),
+            None => bail!("not registered"),
+        }
+    }
+
+    // one request on the registration socket, sent again until answered
+    async fn ask(&self, msg: Message) -> Result<Message> {
+        let socket = match &*self.registered.lock().unwrap() {
+            Some((_, socket)) => socket.clone(),
+            None => bail!("not registered"),
+        };
+        let mut answers = self.answers.lock().await;
+        // late answers to requests given up on
+        while answers.try_recv().is_ok() {}
+        let data = pad_request(msg.encode());
+        let answer = timeout(SERVER_TIMEOUT, async {
+            loop {
+                socket.send(&data).await?;
+                match timeout(RESEND_INTERVAL, answers.recv()).await {
+                    Ok(Some(answer)) => return Ok(answer),
+                    Ok(None) => bail!("signaling closed"),
+                    Err(_) => continue,
+                }
+            }
+        });
+        match answer.await {
+            Ok(answer) => answer,
+            Err(_) => bail!("server {} does not answer", self.server_addr),
+        }
+    }
+}
+
+/// Where [`ServerSignaling`] and [`WebSocketSignaling`] put what the server
+/// says on the registration.
+struct Inbound {
+    server_addr: SocketAddr,
+    // told the code of the first register_response
+    registered: Option<oneshot::Sender<u8>>,
+    signals: mpsc::Sender<Signal>,
+    answers: mpsc::Sender<Message>,
+}
+
+impl Inbound {
+    // false once the registration is over
+    async fn take(&mut self, message: Result<Message>) -> bool {
+        match message {
+            Ok(Message::register_response(code)) => {
+                if let Some(registered) = self.registered.take() {
+                    registered.send(code).ok();
+                }
+            }
+            Ok(Message::signal(signal)) => return self.signals.send(signal).await.is_ok(),
+            Ok(Message::going_away) => {
+                tracing::warn!("server {} is going away", self.server_addr);
+                return false;
+            }
+            Ok(answer @ (Message::lookup_response(_) | Message::signal_sent(_))) => {
+                self.answers.try_send(answer).ok();
+            }
+            message => tracing::debug!("other message {:?}", message),
+        }
+        true
+    }
+}
+
+impl Drop for ServerSignaling {
+    fn drop(&mut self) {
+        if let Some(heartbeat) = self.heartbeat.lock().unwrap().take() {
+            heartbeat.abort();
+        }
+    }
+}
+
+impl Signaling for ServerSignaling {
+    fn register<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let socket = self.network.bind_udp(any_addr(self.server_addr)).await?;
+            socket.connect(self.server_addr).await?;
+            let socket = Arc::new(socket);
+            *self.registered.lock().unwrap() = Some((id.to_owned(), socket.clone()));
+            let (registered, answered) = oneshot::channel();
+            let mut inbound = Inbound {
+                server_addr: self.server_addr,
+                registered: Some(registered),
+                signals: self.sender.clone(),
+                answers: self.answer_sender.clone(),
+            };
+            let id = id.to_owned();
+            let heartbeat = tokio::spawn(async move {
+                let mut timer = interval(HEARTBEAT);
+                let mut buf = vec![0u8; 2048];
+                loop {
+                    tokio::select! {
+                        _ = timer.tick() => {
+                            let register_request = Message::register_request(id.clone());
+                            crate::allow_err!(socket.send(&pad_request(register_request.encode())).await);
+                        }
+                        Ok(n) = socket.recv(&mut buf) => {
+                            if !inbound.take(Message::decode(&buf[..n])).await {
+                                return;
+                            }
+                        }
+                    }
+                }
+            });
+            if let Some(old) = self.heartbeat.lock().unwrap().replace(heartbeat) {
+                old.abort();
+            }
+            match timeout(SERVER_TIMEOUT, answered).await {
+                Ok(Ok(0)) => Ok(()),
+                Ok(Ok(code)) => bail!("server refused the registration, code {}", code),
+                _ => bail!("server {} does not answer", self.server_addr),
+            }
+        })
+    }
+
+    fn lookup<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Vec<Candidate>>>> {
+        Box::pin(async move {
+            let asker = self.id()?;
+            match self
+                .ask(Message::lookup_request(asker, id.to_owned()))
+                .await?
+            {
+                Message::lookup_response(addr) => {
+                    Ok(addr.map(|addr| vec![Candidate { addr, local: None }]))
+                }
+                answer => bail!("unexpected {:?}", answer),
+            }
+        })
+    }
+
+    fn send(&self, signal: Signal) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let (from, to) = (signal.from.clone(), signal.to.clone());
+            match self.ask(Message::signal(signal)).await? {
+                Message::signal_sent(true) => Ok(()),
+                Message::signal_sent(false) => bail!("signal from {} to {} refused", from, to),
+                answer => bail!("unexpected {:?}", answer),
+            }
+        })
+    }
+
+    fn receive(&self) -> BoxFuture<'_, Result<Signal>> {
+        Box::pin(async move {
+            self.inbox
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or_else(|| anyhow!("signaling closed"))
+        })
+    }
+}
+
+/// The WebSocket listener of the hybrid server as signaling, for clients
+/// that only get out over http: registered, looked up and signaled on one
+/// connection, which is also where the signals relayed to us arrive.
+pub struct WebSocketSignaling {
+    server_addr: SocketAddr,
+    sender: mpsc::Sender<Signal>,
+    inbox: tokio::sync::Mutex<mpsc::Receiver<Signal>>,
+    // what the server answers on the connection
+    answer_sender: mpsc::Sender<Message>,
+    answers: tokio::sync::Mutex<mpsc::Receiver<Message>>,
+    // our id and the messages to write to the connection
+    registered: Mutex<Option<(String, mpsc::Sender<Message>)>>,
+    connection: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl WebSocketSignaling {
+    /// Connects to ws://`server_addr`/ on register.
+    pub fn new(server_addr: SocketAddr) -> Self {
+        let (sender, receiver) = mpsc::channel(16);
+        let (answer_sender, answers) = mpsc::channel(4);
+        Self {
+            server_addr,
+            sender,
+            inbox: tokio::sync::Mutex::new(receiver),
+            answer_sender,
+            answers: tokio::sync::Mutex::new(answers),
+            registered: Mutex::new(None),
+            connection: Mutex::new(None),
+        }
+    }
+
+    fn id(&self) -> Result<String> {
+        match &*self.registered.lock().unwrap() {
+            Some((id, _)) => Ok(id.clone()),
+            None => bail!("not registered"),
+        }
+    }
+
+    // one request on the connection, and its answer
+    async fn ask(&self, msg: Message) -> Result<Message> {
+        let outgoing = match &*self.registered.lock().unwrap() {
+            Some((_, outgoing)) => outgoing.clone(),
+            None => bail!("not registered"),
+        };
+        let mut answers = self.answers.lock().await;
+        // late answers to requests given up on
+        while answers.try_recv().is_ok() {}
+        if outgoing.send(msg).await.is_err() {
+            bail!("connection to {} closed", self.server_addr);
+        }
+        match timeout(SERVER_TIMEOUT, answers.recv()).await {
+            Ok(Some(answer)) => Ok(answer),
+            Ok(None) => bail!("signaling closed"),
+            Err(_) => bail!("server {} does not answer", self.server_addr),
+        }
+    }
+}
+
+impl Drop for WebSocketSignaling {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.lock().unwrap().take() {
+            connection.abort();
+        }
+    }
+}
+
+impl Signaling for WebSocketSignaling {
+    fn register<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let stream = timeout(SERVER_TIMEOUT, TcpStream::connect(self.server_addr)).await??;
+            let url = format!("ws://{}/", self.server_addr);
+            let (websocket, _) = timeout(SERVER_TIMEOUT, client_async(url, stream)).await??;
+            let (outgoing, mut to_write) = mpsc::channel(16);
+            *self.registered.lock().unwrap() = Some((id.to_owned(), outgoing));
+            let (registered, answered) = oneshot::channel();
+            let mut inbound = Inbound {
+                server_addr: self.server_addr,
+                registered: Some(registered),
+                signals: self.sender.clone(),
+                answers: self.answer_sender.clone(),
+            };
+            let id = id.to_owned();
+            let connection = tokio::spawn(async move {
+                let (mut sink, mut source) = websocket.split();
+                let mut timer = interval(HEARTBEAT);
+                loop {
+                    let msg = tokio::select! {
+                        _ = timer.tick() => Message::register_request(id.clone()),
+                        Some(msg) = to_write.recv() => msg,
+                        frame = source.next() => {
+                            let text = match frame {
+                                Some(Ok(WebSocketMessage::Text(text))) => text,
+                                // pings are answered by the next write
+                                Some(Ok(WebSocketMessage::Close(_))) | None => return,
+                                Some(Ok(_)) => continue,
+                                Some(Err(e)) => {
+                                    tracing::warn!("connection to {} failed: {:?}", inbound.server_addr, e);
+                                    return;
+                                }
+                            };
+                            if !inbound.take(Message::decode(text.as_bytes())).await {
+                                return;
+                            }
+                            continue;
+                        }
+                    };
+                    let text = String::from_utf8_lossy(&msg.encode()).into_owned();
+                    if let Err(e) = sink.send(WebSocketMessage::Text(text)).await {
+                        tracing::warn!("connection to {} failed: {:?}", inbound.server_addr, e);
+                        return;
+                    }
+                }
+            });
+            if let Some(old) = self.connection.lock().unwrap().replace(connection) {
+                old.abort();
+            }
+            match timeout(SERVER_TIMEOUT, answered).await {
+                Ok(Ok(0)) => Ok(()),
+                Ok(Ok(code)) => bail!("server refused the registration, code {}", code),
+                _ => bail!("server {} does not answer", self.server_addr),
+            }
+        })
+    }
+
+    fn lookup<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Vec<Candidate>>>> {
+        Box::pin(async move {
+            let asker = self.id()?;
+            match self
+                .ask(Message::lookup_request(asker, id.to_owned()))
+                .await?
+            {
+                Message::lookup_response(addr) => {
+                    Ok(addr.map(|addr| vec![Candidate { addr, local: None }]))
+                }
+                answer => bail!("unexpected {:?}", answer),
+            }
+        })
+    }
+
+    fn send(&self, signal: Signal) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let (from, to) = (signal.from.clone(), signal.to.clone());
+            match self.ask(Message::signal(signal)).await? {
+                Message::signal_sent(true) => Ok(()),
+                Message::signal_sent(false) => bail!("signal from {} to {} refused", from, to),
+                answer => bail!("unexpected {:?}", answer),
+            }
+        })
+    }
+
+    fn receive(&self) -> BoxFuture<'_, Result<Signal>> {
+        Box::pin(async move {
+            self.inbox
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or_else(|| anyhow!("signaling closed"))
+        })
+    }
+}
@@ -0,0 +1,370 @@
+//! In-process virtual network with NATs, for running the real punch code in
+//! tests without real machines.
+//!
+//! Every host has its own ip, hosts behind a [`Nat`] use private ips and are
+//! translated to the nat's public ip. Packets are delivered instantly and
+//! never lost, so with a paused tokio clock a run is fully deterministic.
+//! Only udp is modelled.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+const FIRST_EPHEMERAL_PORT: u16 = 50000;
+const FIRST_MAPPED_PORT: u16 = 40000;
+
+/// How a nat maps private addresses and filters inbound packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatKind {
+    /// One mapping per private address, anyone may send to it
+    FullCone,
+    /// One mapping per private address, only ips it sent to may answer
+    Restricted,
+    /// One mapping per private address, only addresses it sent to may answer
+    PortRestricted,
+    /// One mapping per destination, only that destination may answer
+    Symmetric,
+}
+
+#[derive(Debug, Clone)]
+pub struct NatConfig {
+    pub kind: NatKind,
+    /// Whether a host may reach another host behind the same nat by its public address
+    pub hairpin: bool,
+    /// Mappings idle for longer than this are dropped, only outbound packets refresh them
+    pub mapping_timeout: Duration,
+}
+
+impl NatConfig {
+    pub fn new(kind: NatKind) -> Self {
+        Self {
+            kind,
+            hairpin: false,
+            mapping_timeout: Duration::from_secs(120),
+        }
+    }
+
+    pub fn hairpin(mut self, hairpin: bool) -> Self {
+        self.hairpin = hairpin;
+        self
+    }
+
+    pub fn mapping_timeout(mut self, timeout: Duration) -> Self {
+        self.mapping_timeout = timeout;
+        self
+    }
+}
+
+type Packet = (Vec<u8>, SocketAddr);
+
+struct Mapping {
+    private: SocketAddr,
+    // symmetric only: the destination this mapping was made for
+    remote: Option<SocketAddr>,
+    public_port: u16,
+    // destinations we sent to, used for filtering
+    sent_to: HashSet<SocketAddr>,
+    last_used: Instant,
+}
+
+struct NatState {
+    public_ip: IpAddr,
+    config: NatConfig,
+    next_port: u16,
+    mappings: Vec<Mapping>,
+}
+
+impl NatState {
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.config.mapping_timeout;
+        self.mappings.retain(|m| now.duration_since(m.last_used) < timeout);
+    }
+
+    fn outbound(&mut self, private: SocketAddr, dst: SocketAddr, now: Instant) -> SocketAddr {
+        self.expire(now);
+        let symmetric = self.config.kind == NatKind::Symmetric;
+        let found = self
+            .mappings
+            .iter()
+            .position(|m| m.private == private && (!symmetric || m.remote == Some(dst)));
+        let index = match found {
+            Some(index) => index,
+            None => {
+                let public_port = self.next_port;
+                self.next_port = self.next_port.wrapping_add(1);
+                tracing::debug!(
+                    "nat {} maps {} to port {}",
+                    self.public_ip,
+                    private,
+                    public_port
+                );
+                self.mappings.push(Mapping {
+                    private,
+                    remote: if symmetric { Some(dst) } else { None },
+                    public_port,
+                    sent_to: HashSet::new(),
+                    last_used: now,
+                });
+                self.mappings.len() - 1
+            }
+        };
+        let mapping = &mut self.mappings[index];
+        mapping.sent_to.insert(dst);
+        mapping.last_used = now;
+        SocketAddr::new(self.public_ip, mapping.public_port)
+    }
+
+    fn inbound(&mut self, src: SocketAddr, port: u16, now: Instant) -> Option<SocketAddr> {
+        self.expire(now);
+        let mapping = self.mappings.iter().find(|m| m.public_port == port)?;
+        let allowed = match self.config.kind {
+            NatKind::FullCone => true,
+            NatKind::Restricted => mapping.sent_to.iter().any(|addr| addr.ip() == src.ip()),
+            NatKind::PortRestricted | NatKind::Symmetric => mapping.sent_to.contains(&src),
+        };
+        if allowed {
+            Some(mapping.private)
+        } else {
+            tracing::debug!("nat {} drops {} -> port {}", self.public_ip, src, port);
+            None
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    // host ip -> index of the nat it sits behind
+    hosts: HashMap<IpAddr, Option<usize>>,
+    nats: Vec<NatState>,
+    sockets: HashMap<SocketAddr, mpsc::UnboundedSender<Packet>>,
+    next_ephemeral: HashMap<IpAddr, u16>,
+}
+
+impl State {
+    fn nat_of_public(&self, ip: IpAddr) -> Option<usize> {
+        self.nats.iter().position(|nat| nat.public_ip == ip)
+    }
+
+    fn route(&mut self, data: Vec<u8>, src: SocketAddr, dst: SocketAddr) {
+        let now = Instant::now();
+        let mut src = src;
+        if let Some(Some(nat)) = self.hosts.get(&src.ip()).copied() {
+            // same lan, no translation
+            if self.hosts.get(&dst.ip()) == Some(&Some(nat)) {
+                self.deliver(data, src, dst);
+                return;
+            }
+            if dst.ip() == self.nats[nat].public_ip && !self.nats[nat].config.hairpin {
+                tracing::debug!("nat {} has no hairpin, drops {}", dst.ip(), src);
+                return;
+            }
+            src = self.nats[nat].outbound(src, dst, now);
+        }
+        match self.nat_of_public(dst.ip()) {
+            Some(nat) => {
+                if let Some(private) = self.nats[nat].inbound(src, dst.port(), now) {
+                    self.deliver(data, src, private);
+                }
+            }
+            None => self.deliver(data, src, dst),
+        }
+    }
+
+    fn deliver(&self, data: Vec<u8>, src: SocketAddr, dst: SocketAddr) {
+        if let Some(socket) = self.sockets.get(&dst) {
+            socket.send((data, src)).ok();
+        }
+    }
+}
+
+/// The whole virtual network.
+#[derive(Clone, Default)]
+pub struct Sim {
+    state: Arc<Mutex<State>>,
+}
+
+impl Sim {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A host with a public ip.
+    pub fn host(&self, ip: IpAddr) -> Host {
+        self.state.lock().unwrap().hosts.insert(ip, None);
+        Host {
+            sim: self.clone(),
+            ip,
+        }
+    }
+
+    /// A nat with the given public ip, hosts behind it come from [`Nat::host`].
+    pub fn nat(&self, public_ip: IpAddr, config: NatConfig) -> Nat {
+        let mut state = self.state.lock().unwrap();
+        state.nats.push(NatState {
+            public_ip,
+            config,
+            next_port: FIRST_MAPPED_PORT,
+            mappings: Vec::new(),
+        });
+        Nat {
+            sim: self.clone(),
+            index: state.nats.len() - 1,
+        }
+    }
+}
+
+pub struct Nat {
+    sim: Sim,
+    index: usize,
+}
+
+impl Nat {
+    /// A host behind this nat. Private ips must be unique in the whole sim.
+    pub fn host(&self, private_ip: IpAddr) -> Host {
+        self.sim
+            .state
+            .lock()
+            .unwrap()
+            .hosts
+            .insert(private_ip, Some(self.index));
+        Host {
+            sim: self.sim.clone(),
+            ip: private_ip,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Host {
+    sim: Sim,
+    ip: IpAddr,
+}
+
+impl Host {
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    /// Binds a udp socket, an unspecified ip means the host's ip and port 0
+    /// picks the next free port.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<Socket> {
+        if !addr.ip().is_unspecified() && addr.ip() != self.ip {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("{} is not on host {}", addr, self.ip),
+            ));
+        }
+        let mut state = self.sim.state.lock().unwrap();
+        let port = match addr.port() {
+            0 => loop {
+                let next = state
+                    .next_ephemeral
+                    .entry(self.ip)
+                    .or_insert(FIRST_EPHEMERAL_PORT);
+                let port = *next;
+                *next = next.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+                if !state.sockets.contains_key(&SocketAddr::new(self.ip, port)) {
+                    break port;
+                }
+            },
+            port => port,
+        };
+        let local_addr = SocketAddr::new(self.ip, port);
+        if state.sockets.contains_key(&local_addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} in use", local_addr),
+            ));
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        state.sockets.insert(local_addr, sender);
+        Ok(Socket {
+            sim: self.sim.clone(),
+            local_addr,
+            peer_addr: Mutex::new(None),
+            receiver: tokio::sync::Mutex::new(receiver),
+        })
+    }
+}
+
+/// A udp socket on a [`Host`], with the same methods as tokio's.
+pub struct Socket {
+    sim: Sim,
+    local_addr: SocketAddr,
+    peer_addr: Mutex<Option<SocketAddr>>,
+    receiver: tokio::sync::Mutex<mpsc::UnboundedReceiver<Packet>>,
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        self.sim
+            .state
+            .lock()
+            .unwrap()
+            .sockets
+            .remove(&self.local_addr);
+    }
+}
+
+impl Socket {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.peer_addr
+            .lock()
+            .unwrap()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        *self.peer_addr.lock().unwrap() = Some(addr);
+        Ok(())
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.sim
+            .state
+            .lock()
+            .unwrap()
+            .route(buf.to_vec(), self.local_addr, target);
+        Ok(buf.len())
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (data, src) = self
+            .receiver
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        // like udp, the rest of a datagram that does not fit is lost
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok((n, src))
+    }
+
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let peer_addr = self.peer_addr()?;
+        self.send_to(buf, peer_addr).await
+    }
+
+    /// Like a connected udp socket, only takes packets from the peer.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let (n, src) = self.recv_from(buf).await?;
+            if self.peer_addr().ok() == Some(src) {
+                return Ok(n);
+            }
+        }
+    }
+}
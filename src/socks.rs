@@ -0,0 +1,106 @@
+use crate::{mux::Opener, tunnel::Request};
+use anyhow::{bail, Result};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tokio::{
+    io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+const REP_SUCCEEDED: u8 = 0;
+const REP_GENERAL_FAILURE: u8 = 1;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// Local SOCKS5 server (no auth, CONNECT only). Every CONNECT is carried over
+/// the mux and dialed by the peer, which acts as the exit.
+pub async fn serve(listener: TcpListener, opener: Opener) -> Result<()> {
+    tracing::info!("socks5 listening on {:?}", listener.local_addr());
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let opener = opener.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, opener).await {
+                tracing::warn!("socks5 {:?}: {:?}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, opener: Opener) -> Result<()> {
+    // greeting: ver nmethods methods...
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        bail!("unsupported socks version {}", header[0]);
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTH) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        bail!("client offers no acceptable auth method");
+    }
+    stream.write_all(&[VERSION, NO_AUTH]).await?;
+
+    // request: ver cmd rsv atyp addr port
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    if request[0] != VERSION {
+        bail!("unsupported socks version {}", request[0]);
+    }
+    let host = match request[3] {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut domain = vec![0u8; len as usize];
+            stream.read_exact(&mut domain).await?;
+            String::from_utf8(domain)?
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            format!("[{}]", Ipv6Addr::from(ip))
+        }
+        atyp => {
+            reply(&mut stream, REP_ADDRESS_NOT_SUPPORTED).await?;
+            bail!("unsupported address type {}", atyp);
+        }
+    };
+    let port = stream.read_u16().await?;
+    if request[1] != CMD_CONNECT {
+        reply(&mut stream, REP_COMMAND_NOT_SUPPORTED).await?;
+        bail!("unsupported command {}", request[1]);
+    }
+
+    let target = format!("{}:{}", host, port);
+    tracing::info!("socks5 connect {}", target);
+    let mut channel = match opener.open(Request::connect(target).encode().into()).await {
+        Ok(channel) => channel,
+        Err(e) => {
+            // the peer does not tell why, so report a general failure
+            reply(&mut stream, REP_GENERAL_FAILURE).await?;
+            return Err(e);
+        }
+    };
+    reply(&mut stream, REP_SUCCEEDED).await?;
+    copy_bidirectional(&mut stream, &mut channel).await?;
+    Ok(())
+}
+
+async fn reply(stream: &mut TcpStream, rep: u8) -> Result<()> {
+    // bound address is meaningless here, always 0.0.0.0:0
+    stream
+        .write_all(&[VERSION, rep, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,393 @@
+//! What a rendezvous server remembers across restarts: the devices that
+//! registered, where and when they were last seen, and the banned ids. A
+//! restarted server takes its registrations back from here instead of
+//! waiting for every device's next heartbeat.
+//!
+//! [`LogStore`] keeps it in a file of json lines, one per change:
+//!
+//! ```text
+//! {"op":"put","id":"A","addrs":["27.216.129.86:3854"],"last_seen":"2022-02-20T08:11:13.000Z"}
+//! {"op":"forget","id":"A"}
+//! {"op":"ban","id":"C"}
+//! {"op":"unban","id":"C"}
+//! ```
+//!
+//! The lines are written by a thread of its own, so the server never waits
+//! for the disk while it holds its registry.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+/// The log is rewritten once it has this many more lines than it needs.
+const COMPACT_SLACK: usize = 10000;
+
+/// One id as the store keeps it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub id: String,
+    /// Where it last registered from, one addr per ip family
+    pub addrs: Vec<SocketAddr>,
+    #[serde(serialize_with = "rfc3339", deserialize_with = "system_time")]
+    pub last_seen: SystemTime,
+}
+
+impl Device {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            addrs: Vec::new(),
+            last_seen: SystemTime::UNIX_EPOCH,
+        }
+    }
+}
+
+fn rfc3339<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&humantime::format_rfc3339_millis(*time))
+}
+
+fn system_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    humantime::parse_rfc3339(&text).map_err(serde::de::Error::custom)
+}
+
+/// Everything in a store, as a server reads it when it starts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stored {
+    /// Sorted by id
+    pub devices: Vec<Device>,
+    pub banned: Vec<String>,
+}
+
+/// Where a server keeps its [`Stored`] state.
+pub trait Store: Send + Sync + fmt::Debug {
+    fn load(&self) -> Result<Stored>;
+    fn get(&self, id: &str) -> Result<Option<Device>>;
+    /// Replaces what is stored for `device.id`.
+    fn put(&self, device: Device) -> Result<()>;
+    /// Drops what is stored for `id`, it is not to be restored.
+    fn forget(&self, id: &str) -> Result<()>;
+    fn ban(&self, id: &str, banned: bool) -> Result<()>;
+
+    /// `id` registered from `addr` just now.
+    fn seen(&self, id: &str, addr: SocketAddr) -> Result<()> {
+        let mut device = self.get(id)?.unwrap_or_else(|| Device::new(id));
+        device.addrs.retain(|a| a.is_ipv6() != addr.is_ipv6());
+        device.addrs.push(addr);
+        device.addrs.sort_by_key(|addr| addr.is_ipv4());
+        device.last_seen = SystemTime::now();
+        self.put(device)
+    }
+}
+
+/// A store that lives as long as the server, the default.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    inner: Mutex<State>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct State {
+    devices: BTreeMap<String, Device>,
+    banned: BTreeSet<String>,
+}
+
+impl State {
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Put(device) => {
+                self.devices.insert(device.id.clone(), device);
+            }
+            Record::Forget { id } => {
+                self.devices.remove(&id);
+            }
+            Record::Ban { id } => {
+                self.banned.insert(id);
+            }
+            Record::Unban { id } => {
+                self.banned.remove(&id);
+            }
+        }
+    }
+
+    fn stored(&self) -> Stored {
+        Stored {
+            devices: self.devices.values().cloned().collect(),
+            banned: self.banned.iter().cloned().collect(),
+        }
+    }
+
+    /// Drops the devices not seen for `ttl`.
+    fn expire(&mut self, ttl: Duration) {
+        let now = SystemTime::now();
+        self.devices
+            .retain(|_, device| now.duration_since(device.last_seen).unwrap_or_default() < ttl);
+    }
+
+    // the records that build this state again
+    fn records(&self) -> impl Iterator<Item = Record> + '_ {
+        let devices = self.devices.values().cloned().map(Record::Put);
+        let banned = self.banned.iter().map(|id| Record::Ban { id: id.clone() });
+        devices.chain(banned)
+    }
+
+    fn len(&self) -> usize {
+        self.devices.len() + self.banned.len()
+    }
+}
+
+impl Store for MemoryStore {
+    fn load(&self) -> Result<Stored> {
+        Ok(self.inner.lock().unwrap().stored())
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Device>> {
+        Ok(self.inner.lock().unwrap().devices.get(id).cloned())
+    }
+
+    fn put(&self, device: Device) -> Result<()> {
+        self.inner.lock().unwrap().apply(Record::Put(device));
+        Ok(())
+    }
+
+    fn forget(&self, id: &str) -> Result<()> {
+        let id = id.to_owned();
+        self.inner.lock().unwrap().apply(Record::Forget { id });
+        Ok(())
+    }
+
+    fn ban(&self, id: &str, banned: bool) -> Result<()> {
+        self.inner.lock().unwrap().apply(Record::ban(id, banned));
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Put(Device),
+    Forget { id: String },
+    Ban { id: String },
+    Unban { id: String },
+}
+
+impl Record {
+    fn ban(id: &str, banned: bool) -> Self {
+        let id = id.to_owned();
+        match banned {
+            true => Record::Ban { id },
+            false => Record::Unban { id },
+        }
+    }
+}
+
+/// A store in an append-only file of json lines, read back and compacted
+/// when it is opened and compacted again as it grows. Compacting drops the
+/// devices not seen for the ttl given to [`LogStore::open`].
+#[derive(Debug)]
+pub struct LogStore {
+    state: Mutex<State>,
+    // to the writer thread, closed and joined on drop
+    commands: Option<mpsc::Sender<Command>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+enum Command {
+    Append(Record),
+    // answered once everything before it is on disk
+    Sync(mpsc::Sender<()>),
+}
+
+#[derive(Debug)]
+struct Log {
+    path: PathBuf,
+    ttl: Duration,
+    state: State,
+    file: BufWriter<File>,
+    lines: usize,
+}
+
+impl LogStore {
+    /// Creates the file if there is none. A torn last line, left by a crash
+    /// in the middle of a write, is dropped.
+    pub fn open(path: impl Into<PathBuf>, ttl: Duration) -> Result<Self> {
+        let path = path.into();
+        let mut state = State::default();
+        match File::open(&path) {
+            Ok(file) => {
+                let lines: Vec<String> = BufReader::new(file)
+                    .lines()
+                    .collect::<std::io::Result<_>>()
+                    .with_context(|| format!("read {:?}", path))?;
+                for (n, line) in lines.iter().enumerate() {
+                    match serde_json::from_str(line) {
+                        Ok(record) => state.apply(record),
+                        Err(_) if n + 1 == lines.len() => {
+                            tracing::warn!("{:?} ends in a torn line, dropped", path)
+                        }
+                        Err(e) => {
+                            return Err(e).with_context(|| format!("{:?} line {}", path, n + 1))
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("open {:?}", path)),
+        }
+        state.expire(ttl);
+        let (file, lines) = compact(&path, &state)?;
+        tracing::info!(
+            "{} devices and {} bans in {:?}",
+            state.devices.len(),
+            state.banned.len(),
+            path
+        );
+        let log = Log {
+            path,
+            ttl,
+            state: state.clone(),
+            file,
+            lines,
+        };
+        let (commands, received) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("punch-store".to_owned())
+            .spawn(move || log.write(received))?;
+        Ok(Self {
+            state: Mutex::new(state),
+            commands: Some(commands),
+            writer: Some(writer),
+        })
+    }
+
+    /// Waits until everything stored so far is on disk.
+    pub fn sync(&self) -> Result<()> {
+        let (done, synced) = mpsc::channel();
+        self.send(Command::Sync(done))?;
+        synced.recv().map_err(|_| anyhow!("store writer stopped"))
+    }
+
+    fn append(&self, record: Record) -> Result<()> {
+        // under the lock, so the file gets the records in the same order
+        let mut state = self.state.lock().unwrap();
+        state.apply(record.clone());
+        self.send(Command::Append(record))
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        let commands = self.commands.as_ref().expect("closed on drop only");
+        commands
+            .send(command)
+            .map_err(|_| anyhow!("store writer stopped"))
+    }
+}
+
+impl Drop for LogStore {
+    fn drop(&mut self) {
+        // the writer drains what is left once the channel closes
+        self.commands.take();
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
+        }
+    }
+}
+
+impl Log {
+    fn write(mut self, commands: mpsc::Receiver<Command>) {
+        while let Ok(command) = commands.recv() {
+            // a burst goes out with one flush
+            for command in std::iter::once(command).chain(commands.try_iter()) {
+                match command {
+                    Command::Append(record) => {
+                        if let Err(e) = self.append(record) {
+                            tracing::error!("could not write {:?}: {:?}", self.path, e);
+                        }
+                    }
+                    Command::Sync(done) => {
+                        self.flush();
+                        done.send(()).ok();
+                    }
+                }
+            }
+            self.flush();
+        }
+    }
+
+    fn append(&mut self, record: Record) -> Result<()> {
+        let line = serde_json::to_string(&record)?;
+        self.state.apply(record);
+        writeln!(self.file, "{}", line)?;
+        self.lines += 1;
+        if self.lines > self.state.len() + COMPACT_SLACK {
+            self.file.flush()?;
+            self.state.expire(self.ttl);
+            let (file, lines) = compact(&self.path, &self.state)?;
+            self.file = file;
+            self.lines = lines;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.file.flush() {
+            tracing::error!("could not write {:?}: {:?}", self.path, e);
+        }
+    }
+}
+
+/// Writes `state` to a new file that replaces the one at `path`, returns it
+/// open for appending and the number of lines in it.
+fn compact(path: &Path, state: &State) -> Result<(BufWriter<File>, usize)> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = BufWriter::new(File::create(&tmp).with_context(|| format!("create {:?}", tmp))?);
+    let mut lines = 0;
+    for record in state.records() {
+        serde_json::to_writer(&mut file, &record)?;
+        file.write_all(b"\n")?;
+        lines += 1;
+    }
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("replace {:?}", path))?;
+    let file = OpenOptions::new().append(true).open(path)?;
+    Ok((BufWriter::new(file), lines))
+}
+
+impl Store for LogStore {
+    fn load(&self) -> Result<Stored> {
+        Ok(self.state.lock().unwrap().stored())
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Device>> {
+        Ok(self.state.lock().unwrap().devices.get(id).cloned())
+    }
+
+    fn put(&self, device: Device) -> Result<()> {
+        self.append(Record::Put(device))
+    }
+
+    fn forget(&self, id: &str) -> Result<()> {
+        if self.get(id)?.is_none() {
+            return Ok(());
+        }
+        let id = id.to_owned();
+        self.append(Record::Forget { id })
+    }
+
+    fn ban(&self, id: &str, banned: bool) -> Result<()> {
+        self.append(Record::ban(id, banned))
+    }
+}
@@ -0,0 +1,211 @@
+//! The server's half of a punch: what it did for each session, kept for the
+//! admin api and logged as one json line per event under the `punch::trail`
+//! target. Match them with a client's [`crate::report`] by ids, addrs and time.
+
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Instant, SystemTime},
+};
+
+/// Finished trails kept for the admin api, the oldest are dropped first.
+const KEEP: usize = 1000;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TrailEvent {
+    /// Udp and tcp servers: `id` at `from` asked for its peer
+    Lookup {
+        id: String,
+        from: SocketAddr,
+        peer: Option<SocketAddr>,
+    },
+    /// Hybrid: A asked for B, who registered at `b` if at all
+    PunchRequest {
+        a: SocketAddr,
+        b_id: String,
+        b: Option<SocketAddr>,
+    },
+    /// Hybrid: another server of the cluster asked for B, registered here at `b`
+    RouteRequest {
+        node: SocketAddr,
+        a: SocketAddr,
+        b_id: String,
+        b: SocketAddr,
+    },
+    /// Hybrid: B is not registered here, the cluster answered through `node`
+    Routed {
+        node: Option<SocketAddr>,
+        b: Option<SocketAddr>,
+    },
+    /// Hybrid: B got A's addr
+    ToldB {
+        b: SocketAddr,
+    },
+    SendFailed {
+        error: String,
+    },
+    /// Hybrid: B answered from this tcp addr, A is told
+    BAnswered {
+        b: SocketAddr,
+    },
+    NoAnswer,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Stamped {
+    /// rfc3339, to the millisecond
+    pub at: String,
+    /// Since the session started
+    pub elapsed_ms: u64,
+    #[serde(flatten)]
+    pub event: TrailEvent,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Trail {
+    pub session: u64,
+    /// The ids involved, the hybrid server only knows B's
+    pub ids: Vec<String>,
+    /// rfc3339, to the millisecond
+    pub started: String,
+    pub finished: bool,
+    pub events: Vec<Stamped>,
+    #[serde(skip)]
+    start: Instant,
+}
+
+impl Trail {
+    fn has(&self, id: &str) -> bool {
+        self.ids.iter().any(|i| i == id)
+    }
+}
+
+/// Recent punch sessions of a server, oldest first.
+#[derive(Debug, Default)]
+pub struct Trails {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    next: u64,
+    trails: VecDeque<Trail>,
+}
+
+impl Trails {
+    /// Opens a session, returns its number.
+    pub fn start(&self, ids: Vec<String>) -> u64 {
+        self.inner.lock().unwrap().start(ids).session
+    }
+
+    pub fn record(&self, session: u64, event: TrailEvent) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(trail) = inner.trails.iter_mut().find(|t| t.session == session) {
+            push(trail, event);
+        }
+    }
+
+    /// No more events for `session`.
+    pub fn finish(&self, session: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(trail) = inner.trails.iter_mut().find(|t| t.session == session) {
+            trail.finished = true;
+        }
+    }
+
+    /// A lookup of the udp and tcp servers. Both ids of a pair share a
+    /// session that ends once each got the other's addr, repeated lookups
+    /// with the same answer are left out. Returns the session.
+    pub fn lookup(
+        &self,
+        id: &str,
+        peer_id: &str,
+        from: SocketAddr,
+        peer: Option<SocketAddr>,
+    ) -> u64 {
+        let mut ids = vec![id.to_owned(), peer_id.to_owned()];
+        ids.sort();
+        let mut inner = self.inner.lock().unwrap();
+        let trail = match inner
+            .trails
+            .iter()
+            .position(|t| !t.finished && t.ids == ids)
+        {
+            Some(at) => &mut inner.trails[at],
+            None => inner.start(ids),
+        };
+        let last = trail.events.iter().rev().find_map(|e| match &e.event {
+            TrailEvent::Lookup {
+                id: last_id,
+                from: last_from,
+                peer: last_peer,
+            } if last_id == id => Some((*last_from, *last_peer)),
+            _ => None,
+        });
+        if last != Some((from, peer)) {
+            push(
+                trail,
+                TrailEvent::Lookup {
+                    id: id.to_owned(),
+                    from,
+                    peer,
+                },
+            );
+        }
+        let answered = |id: &str| {
+            trail.events.iter().any(
+                |e| matches!(&e.event, TrailEvent::Lookup { id: i, peer: Some(_), .. } if i == id),
+            )
+        };
+        if answered(id) && answered(peer_id) {
+            trail.finished = true;
+        }
+        trail.session
+    }
+
+    /// Recent trails that involve `id`, or all of them.
+    pub fn list(&self, id: Option<&str>) -> Vec<Trail> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .trails
+            .iter()
+            .filter(|t| id.is_none_or(|id| t.has(id)))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Inner {
+    fn start(&mut self, ids: Vec<String>) -> &mut Trail {
+        self.next += 1;
+        if self.trails.len() >= KEEP {
+            // the oldest finished one, or the oldest of all
+            let at = self.trails.iter().position(|t| t.finished).unwrap_or(0);
+            self.trails.remove(at);
+        }
+        self.trails.push_back(Trail {
+            session: self.next,
+            ids,
+            started: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            finished: false,
+            events: Vec::new(),
+            start: Instant::now(),
+        });
+        self.trails.back_mut().unwrap()
+    }
+}
+
+fn push(trail: &mut Trail, event: TrailEvent) {
+    let stamped = Stamped {
+        at: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        elapsed_ms: trail.start.elapsed().as_millis() as u64,
+        event,
+    };
+    let line = json!({ "session": trail.session, "ids": trail.ids, "event": stamped });
+    tracing::info!(target: "punch::trail", "{}", line);
+    trail.events.push(stamped);
+}
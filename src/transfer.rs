@@ -0,0 +1,393 @@
+use crate::puncher::{PunchedStream, Puncher};
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{future::BoxFuture, FutureExt, SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    ffi::OsStr,
+    fmt,
+    future::Future,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, SeekFrom},
+    time::{sleep, timeout, Duration, Instant},
+};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+const CONTROL: u8 = 0;
+const DATA: u8 = 1;
+const CHUNK_SIZE: usize = 64 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Punches and transfers tried before giving up.
+pub const MAX_ATTEMPTS: usize = 10;
+
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    offer(Offer), // sender -> receiver
+    resume(u64),  // receiver -> sender, offset to continue from
+    complete,     // receiver -> sender, sha256 matched
+    corrupt,      // receiver -> sender, sha256 mismatch, partial file dropped
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Offer {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl Offer {
+    pub async fn from_file(path: &Path) -> Result<Self> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("bad file name {:?}", path))?
+            .to_owned();
+        let size = fs::metadata(path).await?.len();
+        let sha256 = sha256_file(path).await?;
+        Ok(Self { name, size, sha256 })
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct SendArgs {
+    /// File to send
+    pub path: PathBuf,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ReceiveArgs {
+    /// Directory to store the received file in
+    #[clap(default_value = ".")]
+    pub dir: PathBuf,
+}
+
+/// The connection broke or the content got damaged on the way: punching
+/// again and resuming may help. Every other error of a transfer is for good.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("transfer interrupted")
+    }
+}
+
+/// Makes the connection a transfer runs over, again after it dropped.
+pub trait Connect {
+    type Stream;
+    fn connect(&mut self) -> BoxFuture<'_, Result<Self::Stream>>;
+}
+
+impl Connect for Puncher {
+    type Stream = PunchedStream;
+    fn connect(&mut self) -> BoxFuture<'_, Result<PunchedStream>> {
+        async move { Ok(self.punch().await?.stream) }.boxed()
+    }
+}
+
+/// Sends `path`, punching again and resuming whenever the connection drops.
+pub async fn send_file(puncher: &mut Puncher, path: &Path) -> Result<()> {
+    let offer = Offer::from_file(path).await?;
+    tracing::info!("send {:?} sha256 {}", offer.name, offer.sha256);
+    with_retry(puncher, |stream| send(stream, path, &offer)).await
+}
+
+/// Receives one file into `dir`, punching again and resuming whenever the connection drops.
+pub async fn receive_file(puncher: &mut Puncher, dir: &Path) -> Result<PathBuf> {
+    with_retry(puncher, |stream| receive(stream, dir)).await
+}
+
+/// Connects and transfers until the transfer is done, at most
+/// [`MAX_ATTEMPTS`] times. Only failed connects and [`Interrupted`]
+/// transfers are tried again.
+pub async fn with_retry<C, T, F, Fut>(connector: &mut C, mut transfer: F) -> Result<T>
+where
+    C: Connect,
+    F: FnMut(C::Stream) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut backoff = Duration::from_secs(1);
+    for attempt in 1..=MAX_ATTEMPTS {
+        let e = match connector.connect().await {
+            Ok(stream) => match transfer(stream).await {
+                Ok(result) => return Ok(result),
+                Err(e) if e.downcast_ref::<Interrupted>().is_some() => e,
+                Err(e) => return Err(e),
+            },
+            Err(e) => e.context("punch failed"),
+        };
+        tracing::warn!("attempt {}/{}: {:?}", attempt, MAX_ATTEMPTS, e);
+        if attempt == MAX_ATTEMPTS {
+            return Err(e.context(format!("gave up after {} attempts", MAX_ATTEMPTS)));
+        }
+        tracing::info!("punch again in {:?}", backoff);
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    unreachable!()
+}
+
+/// Streams one file over an already punched connection.
+pub async fn send<S>(stream: S, path: &Path, offer: &Offer) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+    send_message(&mut framed, &Message::offer(offer.clone())).await?;
+    let offset = match recv_message(&mut framed).await? {
+        Message::resume(offset) if offset <= offer.size => offset,
+        msg => bail!("unexpected {:?}", msg),
+    };
+    if offset > 0 {
+        tracing::info!("resume {:?} from {}", offer.name, offset);
+    }
+
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut progress = Progress::new(&offer.name, offer.size, offset);
+    let mut sent = offset;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    while sent < offer.size {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            bail!("{:?} shrank while sending", path);
+        }
+        let mut frame = BytesMut::with_capacity(n + 1);
+        frame.put_u8(DATA);
+        frame.put_slice(&buf[..n]);
+        framed.send(frame.freeze()).await.context(Interrupted)?;
+        sent += n as u64;
+        progress.update(sent);
+    }
+
+    match recv_message(&mut framed).await? {
+        Message::complete => {
+            progress.finish();
+            Ok(())
+        }
+        Message::corrupt => {
+            Err(anyhow!(Interrupted).context("receiver reported sha256 mismatch, start over"))
+        }
+        msg => bail!("unexpected {:?}", msg),
+    }
+}
+
+/// Receives one file over an already punched connection, returns where it was stored.
+pub async fn receive<S>(stream: S, dir: &Path) -> Result<PathBuf>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+    let offer = match recv_message(&mut framed).await? {
+        Message::offer(offer) => offer,
+        msg => bail!("unexpected {:?}", msg),
+    };
+    // never trust a path from the peer
+    let name = Path::new(&offer.name)
+        .file_name()
+        .ok_or_else(|| anyhow!("bad file name {:?}", offer.name))?;
+    if offer.sha256.len() != 64 || !offer.sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("bad sha256 {:?}", offer.sha256);
+    }
+    // the hash in the name makes sure we only resume the same content
+    let part = dir.join(format!(
+        "{}.{}.part",
+        name.to_string_lossy(),
+        &offer.sha256[..16]
+    ));
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&part)
+        .await?;
+    let mut received = file.metadata().await?.len();
+    if received > offer.size {
+        file.set_len(0).await?;
+        received = 0;
+    }
+    tracing::info!(
+        "receive {:?} ({} bytes) into {:?}, have {}",
+        offer.name,
+        offer.size,
+        dir,
+        received
+    );
+    send_message(&mut framed, &Message::resume(received)).await?;
+    // hashed while written, the sender does not wait for a big file to be read again
+    let mut hasher = Sha256::new();
+    hash_prefix(&part, received, &mut hasher).await?;
+
+    let mut progress = Progress::new(&offer.name, offer.size, received);
+    while received < offer.size {
+        let frame = recv_frame(&mut framed).await?;
+        if frame.first() != Some(&DATA) {
+            bail!("expect data frame");
+        }
+        let data = &frame[1..];
+        if received + data.len() as u64 > offer.size {
+            bail!("peer sent more than {} bytes", offer.size);
+        }
+        file.write_all(data).await?;
+        hasher.update(data);
+        received += data.len() as u64;
+        progress.update(received);
+    }
+    file.flush().await?;
+    drop(file);
+
+    let sha256 = format!("{:x}", hasher.finalize());
+    if sha256 != offer.sha256 {
+        fs::remove_file(&part).await.ok();
+        send_message(&mut framed, &Message::corrupt).await.ok();
+        return Err(anyhow!(Interrupted).context(format!(
+            "sha256 mismatch: expect {} got {}",
+            offer.sha256, sha256
+        )));
+    }
+    let target = unused_path(dir, name).await?;
+    fs::rename(&part, &target).await?;
+    send_message(&mut framed, &Message::complete).await?;
+    // let the sender hang up first so `complete` is not lost when we exit
+    timeout(Duration::from_secs(5), framed.next()).await.ok();
+    progress.finish();
+    Ok(target)
+}
+
+async fn send_message<S>(framed: &mut Framed<S, LengthDelimitedCodec>, msg: &Message) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let json = serde_json::to_vec(msg)?;
+    let mut frame = BytesMut::with_capacity(json.len() + 1);
+    frame.put_u8(CONTROL);
+    frame.put_slice(&json);
+    framed.send(frame.freeze()).await.context(Interrupted)?;
+    Ok(())
+}
+
+async fn recv_frame<S>(framed: &mut Framed<S, LengthDelimitedCodec>) -> Result<Bytes>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match timeout(READ_TIMEOUT, framed.next()).await {
+        Ok(Some(frame)) => Ok(frame.context(Interrupted)?.freeze()),
+        Ok(None) => Err(anyhow!(Interrupted).context("connection closed")),
+        Err(_) => Err(anyhow!(Interrupted).context(format!("no data for {:?}", READ_TIMEOUT))),
+    }
+}
+
+async fn recv_message<S>(framed: &mut Framed<S, LengthDelimitedCodec>) -> Result<Message>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let frame = recv_frame(framed).await?;
+    if frame.first() != Some(&CONTROL) {
+        bail!("expect control frame");
+    }
+    Ok(serde_json::from_slice(&frame[1..])?)
+}
+
+async fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// the first `len` bytes of the part file we resume
+async fn hash_prefix(path: &Path, len: u64, hasher: &mut Sha256) -> Result<()> {
+    let mut file = File::open(path).await?.take(len);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+// a file of the same name is kept, the received one gets a number
+async fn unused_path(dir: &Path, name: &OsStr) -> Result<PathBuf> {
+    let target = dir.join(name);
+    if !fs::try_exists(&target).await? {
+        return Ok(target);
+    }
+    let name = Path::new(name);
+    let stem = name
+        .file_stem()
+        .unwrap_or(name.as_os_str())
+        .to_string_lossy();
+    let extension = name
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    for n in 1.. {
+        let numbered = dir.join(format!("{}.{}{}", stem, n, extension));
+        if !fs::try_exists(&numbered).await? {
+            tracing::info!("{:?} exists, store as {:?}", target, numbered);
+            return Ok(numbered);
+        }
+    }
+    unreachable!()
+}
+
+struct Progress<'a> {
+    name: &'a str,
+    size: u64,
+    start: u64,
+    started_at: Instant,
+    reported_at: Instant,
+}
+
+impl<'a> Progress<'a> {
+    fn new(name: &'a str, size: u64, start: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            name,
+            size,
+            start,
+            started_at: now,
+            reported_at: now,
+        }
+    }
+
+    fn update(&mut self, done: u64) {
+        if self.reported_at.elapsed() < Duration::from_secs(1) {
+            return;
+        }
+        self.reported_at = Instant::now();
+        let rate = (done - self.start) as f64 / self.started_at.elapsed().as_secs_f64();
+        tracing::info!(
+            "{}: {}/{} bytes ({:.1}%) {:.1} KiB/s",
+            self.name,
+            done,
+            self.size,
+            done as f64 * 100.0 / self.size.max(1) as f64,
+            rate / 1024.0
+        );
+    }
+
+    fn finish(&self) {
+        tracing::info!(
+            "{}: {} bytes done in {:.1}s, sha256 ok",
+            self.name,
+            self.size,
+            self.started_at.elapsed().as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! When the side that starts a punch starts it. Hybrid A used to wait for a
+//! line on stdin, now the caller picks: right away, after a delay, on a line
+//! written to a unix socket, on stdin, or from code through a [`Handle`].
+
+use anyhow::{bail, Result};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::{sync::Notify, time::sleep};
+
+pub enum Trigger {
+    /// Punch right away
+    Now,
+    /// Punch after this long, to give the peer time to register
+    After(Duration),
+    /// Punch when a line is written to a unix socket created at this path
+    Socket(PathBuf),
+    /// Ask on stdin and punch once a line is entered
+    Stdin,
+    /// Punch once [`Handle::punch`] is called
+    Manual(Arc<Notify>),
+}
+
+/// Fires a [`Trigger::Manual`], see [`Trigger::manual`].
+#[derive(Clone)]
+pub struct Handle(Arc<Notify>);
+
+impl Handle {
+    /// Lets the punch start, also when it is not waiting yet.
+    pub fn punch(&self) {
+        self.0.notify_one();
+    }
+}
+
+impl Trigger {
+    /// A trigger fired from code.
+    pub fn manual() -> (Self, Handle) {
+        let notify = Arc::new(Notify::new());
+        (Trigger::Manual(notify.clone()), Handle(notify))
+    }
+
+    /// Returns once the punch may start.
+    pub async fn wait(self) -> Result<()> {
+        match self {
+            Trigger::Now => {}
+            Trigger::After(delay) => {
+                tracing::info!("punch in {:?}", delay);
+                sleep(delay).await;
+            }
+            Trigger::Socket(path) => wait_socket(path).await?,
+            Trigger::Stdin => {
+                tracing::info!("Enter any words to start punch...");
+                tokio::task::spawn_blocking(|| {
+                    let mut line = String::new();
+                    std::io::stdin().read_line(&mut line)
+                })
+                .await??;
+            }
+            Trigger::Manual(notify) => notify.notified().await,
+        }
+        tracing::info!("get command and start punch hole!");
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+async fn wait_socket(path: PathBuf) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::UnixListener,
+    };
+
+    // a socket left over from an earlier run, never anything else
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{:?} exists and is not a socket", path);
+        }
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    tracing::info!("write a line to {:?} to start punch...", path);
+    let result = async {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let (reader, mut writer) = stream.into_split();
+            let mut line = String::new();
+            if BufReader::new(reader).read_line(&mut line).await? > 0 {
+                writer.write_all(b"punching\n").await.ok();
+                return Ok(());
+            }
+        }
+    }
+    .await;
+    std::fs::remove_file(&path).ok();
+    result
+}
+
+#[cfg(not(unix))]
+async fn wait_socket(path: PathBuf) -> Result<()> {
+    bail!("no unix sockets here for {:?}", path)
+}
+
+/// Command line of the clients that start a punch; without any flag they
+/// punch right away.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct TriggerArgs {
+    /// Wait this long before punching, like `5s`
+    #[clap(
+        long,
+        value_parser = humantime::parse_duration,
+        conflicts_with_all = &["punch-socket", "interactive"]
+    )]
+    pub punch_after: Option<Duration>,
+
+    /// Punch when a line is written to a unix socket created at this path
+    #[clap(long, conflicts_with = "interactive")]
+    pub punch_socket: Option<PathBuf>,
+
+    /// Ask on stdin before punching
+    #[clap(long)]
+    pub interactive: bool,
+}
+
+impl TriggerArgs {
+    pub fn trigger(&self) -> Result<Trigger> {
+        if let Some(delay) = self.punch_after {
+            return Ok(Trigger::After(delay));
+        }
+        if let Some(path) = &self.punch_socket {
+            if cfg!(not(unix)) {
+                bail!("--punch-socket needs unix sockets");
+            }
+            return Ok(Trigger::Socket(path.clone()));
+        }
+        if self.interactive {
+            return Ok(Trigger::Stdin);
+        }
+        Ok(Trigger::Now)
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{
+    mux::{self, Opener, Pending},
+    socks,
+};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::{
+    io::{copy, copy_bidirectional, sink, AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+
+/// What a channel opened through the tunnel is for.
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    connect(String),        // target the receiving side dials
+    listen(String, String), // addr the receiving side listens on, target dialed back by the requester
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+/// Port forwarding over a punched connection, like `ssh -L` / `ssh -R`.
+/// Both peers must run it; a peer without forwards only serves the other side,
+/// and only with the targets and listen addrs it allows.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ForwardArgs {
+    /// Local listen addr, each connection is carried to the peer
+    #[clap(short = 'L', long, requires = "remote-target")]
+    pub local: Option<String>,
+
+    /// Target the peer connects to for --local
+    #[clap(long, requires = "local")]
+    pub remote_target: Option<String>,
+
+    /// Listen addr on the peer, each connection is carried back to us
+    #[clap(short = 'R', long, requires = "local-target")]
+    pub remote: Option<String>,
+
+    /// Target we connect to for --remote
+    #[clap(long, requires = "remote")]
+    pub local_target: Option<String>,
+
+    /// Local SOCKS5 listen addr, the peer dials the requested destinations if it runs with --exit
+    #[clap(long)]
+    pub socks: Option<String>,
+
+    /// Target the peer may have us connect to, may be repeated; --local-target is always allowed
+    #[clap(long)]
+    pub allow_target: Vec<String>,
+
+    /// Addr the peer may have us listen on, may be repeated
+    #[clap(long)]
+    pub allow_listen: Vec<String>,
+
+    /// Connect to any target the peer asks for, to be its SOCKS5 exit
+    #[clap(long)]
+    pub exit: bool,
+}
+
+// what the peer may ask of us
+#[derive(Debug)]
+struct Allowed {
+    targets: Vec<String>,
+    listens: Vec<String>,
+    any_target: bool,
+}
+
+impl Allowed {
+    fn new(args: &ForwardArgs) -> Self {
+        Self {
+            targets: args
+                .allow_target
+                .iter()
+                .chain(&args.local_target)
+                .cloned()
+                .collect(),
+            listens: args.allow_listen.clone(),
+            any_target: args.exit,
+        }
+    }
+
+    fn target(&self, target: &str) -> bool {
+        self.any_target || self.targets.iter().any(|allowed| allowed == target)
+    }
+
+    fn listen(&self, addr: &str) -> bool {
+        self.listens.iter().any(|allowed| allowed == addr)
+    }
+}
+
+pub async fn forward<S>(stream: S, initiator: bool, args: &ForwardArgs) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (opener, mut acceptor) = mux::new(stream, initiator);
+    let allowed = Arc::new(Allowed::new(args));
+
+    if let (Some(local), Some(target)) = (&args.local, &args.remote_target) {
+        let listener = TcpListener::bind(local).await?;
+        tracing::info!("forward {} -> peer -> {}", local, target);
+        tokio::spawn(accept_loop(listener, target.clone(), opener.clone()));
+    }
+
+    if let Some(socks) = &args.socks {
+        let listener = TcpListener::bind(socks).await?;
+        tokio::spawn(socks::serve(listener, opener.clone()));
+    }
+
+    if let (Some(remote), Some(target)) = (&args.remote, &args.local_target) {
+        let request = Request::listen(remote.clone(), target.clone());
+        let mut control = opener.open(request.encode().into()).await?;
+        tracing::info!("forward peer {} -> {}", remote, target);
+        let remote = remote.clone();
+        tokio::spawn(async move {
+            // the peer closes the channel when its listener is gone
+            copy(&mut control, &mut sink()).await.ok();
+            tracing::warn!("peer listener {} closed", remote);
+        });
+    }
+
+    while let Some(pending) = acceptor.accept().await {
+        tokio::spawn(serve(pending, opener.clone(), allowed.clone()));
+    }
+    bail!("peer closed")
+}
+
+async fn accept_loop(listener: TcpListener, target: String, opener: Opener) {
+    loop {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!("accept failed: {:?}", e);
+                return;
+            }
+        };
+        tracing::info!("forward {:?} to {}", addr, target);
+        let opener = opener.clone();
+        let request = Request::connect(target.clone());
+        tokio::spawn(async move {
+            match opener.open(request.encode().into()).await {
+                Ok(mut channel) => {
+                    crate::allow_err!(copy_bidirectional(&mut stream, &mut channel).await);
+                }
+                Err(e) => tracing::warn!("open channel for {:?} failed: {:?}", addr, e),
+            }
+        });
+    }
+}
+
+async fn serve(pending: Pending, opener: Opener, allowed: Arc<Allowed>) {
+    let request = match Request::decode(&pending.payload) {
+        Ok(request) => request,
+        Err(e) => {
+            tracing::warn!("bad tunnel request: {:?}", e);
+            return;
+        }
+    };
+    tracing::info!("peer request {:?}", request);
+    match request {
+        Request::connect(target) if !allowed.target(&target) => {
+            tracing::warn!("peer may not connect to {}", target);
+            pending.reject();
+        }
+        Request::listen(addr, _) if !allowed.listen(&addr) => {
+            tracing::warn!("peer may not listen on {}", addr);
+            pending.reject();
+        }
+        Request::connect(target) => match TcpStream::connect(&target).await {
+            Ok(mut stream) => {
+                if let Ok(mut channel) = pending.accept().await {
+                    crate::allow_err!(copy_bidirectional(&mut channel, &mut stream).await);
+                }
+            }
+            Err(e) => {
+                tracing::warn!("connect {} failed: {:?}", target, e);
+                pending.reject();
+            }
+        },
+        Request::listen(addr, target) => match TcpListener::bind(&addr).await {
+            Ok(listener) => {
+                if let Ok(mut control) = pending.accept().await {
+                    let mut drain = sink();
+                    tokio::select! {
+                        _ = accept_loop(listener, target, opener) => {}
+                        _ = copy(&mut control, &mut drain) => {}
+                    }
+                    tracing::info!("stop listening on {}", addr);
+                }
+            }
+            Err(e) => {
+                tracing::warn!("listen on {} failed: {:?}", addr, e);
+                pending.reject();
+            }
+        },
+    }
+}
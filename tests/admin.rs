@@ -0,0 +1,253 @@
+use punch::{
+    admin,
+    config::Config,
+    hybrid::Message,
+    net::Datagram,
+    server::{self, Options, REGISTER_REFUSED},
+    simple::Register,
+};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::{sleep, timeout, Duration},
+};
+
+const TOKEN: &str = "s3cret";
+
+async fn start_admin(options: &Options) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(admin::serve(
+        listener,
+        options.control.clone(),
+        TOKEN.to_owned(),
+    ));
+    addr
+}
+
+async fn start_hybrid(options: Options) -> SocketAddr {
+    loop {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        if let Ok(socket) = UdpSocket::bind(addr).await {
+            tokio::spawn(server::hybrid_with(
+                vec![listener],
+                vec![Datagram::Os(socket)],
+                options,
+            ));
+            return addr;
+        }
+    }
+}
+
+async fn call_with(api: SocketAddr, method: &str, path: &str, token: Option<&str>) -> (u16, Value) {
+    let mut stream = TcpStream::connect(api).await.unwrap();
+    let auth = token
+        .map(|token| format!("Authorization: Bearer {}\r\n", token))
+        .unwrap_or_default();
+    let request = format!("{} {} HTTP/1.1\r\n{}\r\n", method, path, auth);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+        .await
+        .expect("no response")
+        .unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+async fn call(api: SocketAddr, method: &str, path: &str) -> (u16, Value) {
+    call_with(api, method, path, Some(TOKEN)).await
+}
+
+async fn register(server: SocketAddr, socket: &UdpSocket, id: &str) -> Message {
+    socket
+        .send_to(&Message::register_request(id.to_owned()).encode(), server)
+        .await
+        .unwrap();
+    let mut buf = vec![0u8; 2048];
+    let n = timeout(Duration::from_secs(1), socket.recv(&mut buf))
+        .await
+        .expect("no register response")
+        .unwrap();
+    Message::decode(&buf[..n]).unwrap()
+}
+
+#[tokio::test]
+async fn token_is_required() {
+    let options = Options::default();
+    let api = start_admin(&options).await;
+    assert_eq!(call_with(api, "GET", "/bans", None).await.0, 401);
+    assert_eq!(call_with(api, "GET", "/bans", Some("s3cres")).await.0, 401);
+    assert_eq!(call_with(api, "GET", "/bans", Some("s3")).await.0, 401);
+    assert_eq!(call(api, "GET", "/bans").await, (200, json!([])));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    assert!(
+        admin::serve(listener, options.control.clone(), String::new())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn admin_section_needs_a_token() {
+    std::env::remove_var(punch::config::ADMIN_TOKEN_ENV);
+    let config = Config::from_toml("[admin]\nlisten = \"127.0.0.1:0\"").unwrap();
+    assert!(config.serve_admin(&Options::default()).is_err());
+    let config = Config::from_toml("[admin]\nlisten = \"127.0.0.1:0\"\ntoken = \"t\"").unwrap();
+    config.serve_admin(&Options::default()).unwrap();
+}
+
+#[tokio::test]
+async fn list_lookup_and_kick() {
+    let options = Options::default();
+    let api = start_admin(&options).await;
+    let server = start_hybrid(options).await;
+
+    let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    assert_eq!(
+        register(server, &b, "b").await,
+        Message::register_response(0)
+    );
+    let c = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    register(server, &c, "c d").await;
+
+    let (status, list) = call(api, "GET", "/registrations").await;
+    assert_eq!(status, 200);
+    let ids: Vec<_> = list.as_array().unwrap().iter().map(|r| &r["id"]).collect();
+    assert_eq!(ids, vec!["b", "c d"]);
+    assert_eq!(list[0]["addr"], b.local_addr().unwrap().to_string());
+    assert_eq!(list[0]["idle_secs"], 0);
+    assert!(list[0]["last_seen"].as_str().unwrap().ends_with('Z'));
+
+    let (status, found) = call(api, "GET", "/registrations/c%20d").await;
+    assert_eq!(status, 200);
+    assert_eq!(found[0]["addr"], c.local_addr().unwrap().to_string());
+    assert_eq!(call(api, "GET", "/registrations/nobody").await.0, 404);
+    assert_eq!(call(api, "GET", "/registrations/%zz").await.0, 400);
+    assert_eq!(call(api, "GET", "/nothing").await.0, 404);
+    assert_eq!(call(api, "POST", "/registrations").await.0, 405);
+
+    assert_eq!(
+        call(api, "DELETE", "/registrations/b").await,
+        (200, json!({ "kicked": 1 }))
+    );
+    assert_eq!(call(api, "GET", "/registrations/b").await.0, 404);
+    // a kicked id comes back with its next heartbeat
+    register(server, &b, "b").await;
+    assert_eq!(call(api, "GET", "/registrations/b").await.0, 200);
+}
+
+#[tokio::test]
+async fn ban_and_unban() {
+    let options = Options::default();
+    let api = start_admin(&options).await;
+    let server = start_hybrid(options).await;
+    let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    register(server, &b, "b").await;
+
+    assert_eq!(
+        call(api, "PUT", "/bans/b").await,
+        (200, json!({ "kicked": 1 }))
+    );
+    assert_eq!(call(api, "GET", "/bans").await, (200, json!(["b"])));
+    assert_eq!(
+        register(server, &b, "b").await,
+        Message::register_response(REGISTER_REFUSED)
+    );
+    assert_eq!(call(api, "GET", "/registrations/b").await.0, 404);
+
+    assert_eq!(call(api, "DELETE", "/bans/b").await.0, 200);
+    assert_eq!(call(api, "DELETE", "/bans/b").await.0, 404);
+    assert_eq!(
+        register(server, &b, "b").await,
+        Message::register_response(0)
+    );
+}
+
+#[tokio::test]
+async fn sessions_in_flight() {
+    let options = Options::default();
+    let api = start_admin(&options).await;
+    let server = start_hybrid(options).await;
+    assert_eq!(call(api, "GET", "/sessions").await, (200, json!([])));
+
+    // b registers and then never answers the punch
+    let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    register(server, &b, "b").await;
+    let mut a = TcpStream::connect(server).await.unwrap();
+    a.write_all(&Message::punchA2S("b".to_owned()).encode())
+        .await
+        .unwrap();
+    let mut buf = vec![0u8; 2048];
+    b.recv(&mut buf).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+
+    let (status, sessions) = call(api, "GET", "/sessions").await;
+    assert_eq!(status, 200);
+    assert_eq!(sessions.as_array().unwrap().len(), 1);
+    assert_eq!(sessions[0]["a"], a.local_addr().unwrap().to_string());
+    assert_eq!(sessions[0]["b_id"], "b");
+    assert_eq!(sessions[0]["b"], b.local_addr().unwrap().to_string());
+    assert_eq!(sessions[0]["state"], "waiting_for_b");
+}
+
+#[tokio::test]
+async fn trails_of_recent_punches() {
+    let options = Options::default();
+    let api = start_admin(&options).await;
+    let server = start_hybrid(options).await;
+    assert_eq!(call(api, "GET", "/trails").await, (200, json!([])));
+
+    let mut a = TcpStream::connect(server).await.unwrap();
+    a.write_all(&Message::punchA2S("c d".to_owned()).encode())
+        .await
+        .unwrap();
+    let mut buf = Vec::new();
+    a.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(Message::decode(&buf).unwrap(), Message::punchS2A(None));
+
+    let (status, trails) = call(api, "GET", "/trails/c%20d").await;
+    assert_eq!(status, 200);
+    assert_eq!(trails[0]["ids"], json!(["c d"]));
+    assert_eq!(trails[0]["finished"], true);
+    assert_eq!(trails[0]["events"][0]["event"], "punch_request");
+    assert_eq!(
+        trails[0]["events"][0]["a"],
+        a.local_addr().unwrap().to_string()
+    );
+    assert_eq!(call(api, "GET", "/trails/b").await, (200, json!([])));
+    assert_eq!(call(api, "DELETE", "/trails").await.0, 405);
+}
+
+#[tokio::test]
+async fn udp_server_is_inspected_too() {
+    let options = Options::default();
+    let api = start_admin(&options).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = socket.local_addr().unwrap();
+    tokio::spawn(server::udp_with(vec![Datagram::Os(socket)], options));
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let register = Register {
+        id: "1".to_owned(),
+        peer_id: "2".to_owned(),
+    };
+    client.send_to(&register.encode(), server).await.unwrap();
+    let mut buf = vec![0u8; 2048];
+    client.recv(&mut buf).await.unwrap();
+
+    let (status, found) = call(api, "GET", "/registrations/1").await;
+    assert_eq!(status, 200);
+    assert_eq!(found[0]["addr"], client.local_addr().unwrap().to_string());
+    assert_eq!(call(api, "GET", "/sessions").await, (200, json!([])));
+    call(api, "PUT", "/bans/1").await;
+    client.send_to(&register.encode(), server).await.unwrap();
+    assert!(timeout(Duration::from_millis(200), client.recv(&mut buf))
+        .await
+        .is_err());
+}
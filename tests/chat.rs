@@ -0,0 +1,224 @@
+use anyhow::Result;
+use futures::{future::BoxFuture, FutureExt, SinkExt, StreamExt};
+use punch::{
+    chat::{self, Link},
+    hybrid::Message,
+    session::SessionInfo,
+};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream, Lines,
+        ReadBuf,
+    },
+    sync::mpsc,
+    task::JoinHandle,
+    time::{timeout, Duration},
+};
+use tokio_util::codec::{Framed, LinesCodec};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+// an in-memory connection that tells about itself like a session
+struct Loopback(DuplexStream);
+
+impl Link for Loopback {
+    fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: 42,
+            local_addr: Some("127.0.0.1:1000".parse().unwrap()),
+            peer_addr: Some("127.0.0.1:2000".parse().unwrap()),
+            reconnects: 1,
+            error: None,
+        }
+    }
+
+    fn close(mut self) -> BoxFuture<'static, Result<()>> {
+        async move { Ok(self.0.shutdown().await?) }.boxed()
+    }
+}
+
+impl AsyncRead for Loopback {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Loopback {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+struct Chat {
+    stdin: mpsc::Sender<String>,
+    printed: Lines<BufReader<DuplexStream>>,
+    peer: Framed<DuplexStream, LinesCodec>,
+    done: JoinHandle<Result<()>>,
+}
+
+impl Chat {
+    fn start() -> Self {
+        let (ours, theirs) = tokio::io::duplex(4096);
+        let (mut out, printed) = tokio::io::duplex(4096);
+        let (stdin, mut lines) = mpsc::channel(16);
+        let done = tokio::spawn(async move {
+            chat::run_with(Loopback(ours), &mut lines, &mut out, "A", None).await
+        });
+        Self {
+            stdin,
+            printed: BufReader::new(printed).lines(),
+            peer: Framed::new(theirs, LinesCodec::new()),
+            done,
+        }
+    }
+
+    async fn type_line(&self, line: &str) {
+        self.stdin.send(line.to_owned()).await.unwrap();
+    }
+
+    async fn printed(&mut self) -> String {
+        timeout(WAIT, self.printed.next_line())
+            .await
+            .expect("nothing printed")
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn peer_sends(&mut self, line: &str) {
+        self.peer.send(line.to_owned()).await.unwrap();
+    }
+
+    async fn peer_receives(&mut self) -> Message {
+        let line = timeout(WAIT, self.peer.next())
+            .await
+            .expect("nothing sent")
+            .unwrap()
+            .unwrap();
+        Message::decode(line.as_bytes()).unwrap()
+    }
+}
+
+fn encoded(msg: Message) -> String {
+    String::from_utf8(msg.encode()).unwrap()
+}
+
+#[tokio::test]
+async fn messages_are_sent_and_printed_with_peer_id_and_time() {
+    let mut chat = Chat::start();
+    assert_eq!(chat.peer_receives().await, Message::helloAB("A".to_owned()));
+    assert_eq!(
+        chat.printed().await,
+        "connected to 127.0.0.1:2000, commands: /quit /stats /peer"
+    );
+
+    // named by addr until the peer said hello
+    chat.peer_sends(&encoded(Message::messageAB("who".to_owned())))
+        .await;
+    let line = chat.printed().await;
+    assert!(line.ends_with("Z] 127.0.0.1:2000: who"), "{}", line);
+    chat.peer_sends(&encoded(Message::helloAB("B".to_owned())))
+        .await;
+    chat.peer_sends(&encoded(Message::messageAB("hi".to_owned())))
+        .await;
+    let line = chat.printed().await;
+    assert!(line.starts_with('['), "{}", line);
+    assert!(line.ends_with("Z] B: hi"), "{}", line);
+    humantime::parse_rfc3339(&line[1..line.find(']').unwrap()]).unwrap();
+
+    chat.type_line("hello there").await;
+    assert_eq!(
+        chat.peer_receives().await,
+        Message::messageAB("hello there".to_owned())
+    );
+    // blank lines are not sent
+    chat.type_line("  ").await;
+    chat.type_line("/stats").await;
+    let line = chat.printed().await;
+    assert!(
+        line.ends_with("sent 1 messages (11 bytes), received 2 messages (5 bytes)"),
+        "{}",
+        line
+    );
+}
+
+#[tokio::test]
+async fn commands_and_bad_input() {
+    let mut chat = Chat::start();
+    chat.peer_receives().await;
+    chat.printed().await;
+    chat.peer_sends(&encoded(Message::helloAB("B".to_owned())))
+        .await;
+    // the hello is taken once something after it is printed
+    chat.peer_sends(&encoded(Message::messageAB("hi".to_owned())))
+        .await;
+    chat.printed().await;
+
+    chat.type_line("/peer").await;
+    assert_eq!(
+        chat.printed().await,
+        "peer B at Some(127.0.0.1:2000), local Some(127.0.0.1:1000), session 2a, reconnected 1 times"
+    );
+    chat.type_line("/nope").await;
+    assert_eq!(
+        chat.printed().await,
+        "unknown command /nope, try /quit /stats /peer"
+    );
+
+    // what does not decode or is not chat is skipped
+    chat.peer_sends("not json").await;
+    chat.peer_sends(&encoded(Message::register_response(0)))
+        .await;
+    chat.peer_sends(&encoded(Message::messageAB("still here".to_owned())))
+        .await;
+    assert!(chat.printed().await.ends_with("B: still here"));
+
+    chat.type_line("/quit").await;
+    timeout(WAIT, chat.done).await.unwrap().unwrap().unwrap();
+    assert!(timeout(WAIT, chat.peer.next()).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn the_chat_ends_when_the_peer_leaves() {
+    let mut chat = Chat::start();
+    chat.peer_receives().await;
+    chat.printed().await;
+    chat.peer_sends(&encoded(Message::helloAB("B".to_owned())))
+        .await;
+    SinkExt::<String>::close(&mut chat.peer).await.unwrap();
+    assert_eq!(chat.printed().await, "B left");
+    timeout(WAIT, chat.done).await.unwrap().unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn the_chat_goes_on_after_stdin_closed() {
+    let mut chat = Chat::start();
+    chat.peer_receives().await;
+    chat.printed().await;
+    // drops the only sender
+    chat.stdin = mpsc::channel(1).0;
+    chat.peer_sends(&encoded(Message::messageAB("hi".to_owned())))
+        .await;
+    assert!(chat.printed().await.ends_with(": hi"));
+    assert!(!chat.done.is_finished());
+}
@@ -0,0 +1,190 @@
+use punch::{
+    hybrid::Message,
+    net::Datagram,
+    puncher::{Puncher, Strategy},
+    server::{self, HybridState, Options, TcpAction},
+};
+use serde_json::{json, Value};
+use std::{
+    net::SocketAddr,
+    process::{Child, Command, Stdio},
+};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    time::{sleep, timeout, Duration},
+};
+
+fn addr(text: &str) -> SocketAddr {
+    text.parse().unwrap()
+}
+
+// a tcp listener and a udp socket on the same port
+async fn bind_pair() -> (TcpListener, UdpSocket) {
+    loop {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        if let Ok(socket) = UdpSocket::bind(listener.local_addr().unwrap()).await {
+            return (listener, socket);
+        }
+    }
+}
+
+fn start(listener: TcpListener, socket: UdpSocket, options: Options) {
+    tokio::spawn(server::hybrid_with(
+        vec![listener],
+        vec![Datagram::Os(socket)],
+        options,
+    ));
+}
+
+fn event_names(trail: &Value) -> Vec<&str> {
+    trail["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["event"].as_str().unwrap())
+        .collect()
+}
+
+async fn punch_across(one: SocketAddr, two: SocketAddr) -> anyhow::Result<()> {
+    let mut b = Puncher::new(Strategy::Hybrid, two, "b".to_owned(), None);
+    let mut a = Puncher::new(Strategy::Hybrid, one, "a".to_owned(), Some("b".to_owned()));
+    timeout(Duration::from_secs(30), async {
+        b.register().await?;
+        a.register().await?;
+        let (a, b) = tokio::join!(a.punch(), b.punch());
+        let (a, b) = (a?, b?);
+        assert_eq!(
+            a.stream.peer_addr().unwrap(),
+            b.stream.local_addr().unwrap()
+        );
+        Ok(())
+    })
+    .await
+    .expect("punch timed out")
+}
+
+#[tokio::test]
+async fn a_finds_b_registered_on_another_node() {
+    let (listener_one, socket_one) = bind_pair().await;
+    let (listener_two, socket_two) = bind_pair().await;
+    let one = listener_one.local_addr().unwrap();
+    let two = listener_two.local_addr().unwrap();
+    let options_one = Options {
+        cluster: vec![two],
+        ..Options::default()
+    };
+    let options_two = Options {
+        cluster: vec![one],
+        ..Options::default()
+    };
+    let (control_one, control_two) = (options_one.control.clone(), options_two.control.clone());
+    let (metrics_one, metrics_two) = (options_one.metrics.clone(), options_two.metrics.clone());
+    start(listener_one, socket_one, options_one);
+    start(listener_two, socket_two, options_two);
+
+    punch_across(one, two).await.unwrap();
+
+    // node one asked the cluster, node two told B
+    let routed = serde_json::to_value(control_one.trails().list(Some("b"))).unwrap();
+    assert_eq!(event_names(&routed[0]), vec!["punch_request", "routed"]);
+    assert_eq!(routed[0]["events"][1]["node"], json!(two));
+    assert!(routed[0]["finished"].as_bool().unwrap());
+    let told = serde_json::to_value(control_two.trails().list(Some("b"))).unwrap();
+    assert_eq!(
+        event_names(&told[0]),
+        vec!["route_request", "told_b", "b_answered"]
+    );
+    assert!(metrics_one.render().contains("punch_routed_total 1"));
+    assert!(metrics_two.render().contains("punch_routed_total 0"));
+
+    // nobody has c
+    let mut a = Puncher::new(Strategy::Hybrid, one, "a".to_owned(), Some("c".to_owned()));
+    let error = timeout(Duration::from_secs(20), a.punch())
+        .await
+        .expect("punch timed out")
+        .err()
+        .unwrap();
+    assert!(format!("{:#}", error).contains("B not register"));
+}
+
+#[test]
+fn routes_are_taken_from_cluster_nodes_only() {
+    let options = Options {
+        cluster: vec![addr("10.0.0.2:12345")],
+        ..Options::default()
+    };
+    let mut state = HybridState::new(&options);
+    let b = addr("192.168.1.6:3854");
+    state.on_udp(b, 0, Message::register_request("b".to_owned()));
+    let a = addr("27.216.129.86:40001");
+    let route = Message::routeA2S("b".to_owned(), a);
+    assert_eq!(
+        state.on_tcp(
+            addr("127.0.0.1:50000"),
+            Message::routeA2S("b".to_owned(), a),
+            ()
+        ),
+        TcpAction::Ignore
+    );
+    assert_eq!(
+        state.on_tcp(addr("10.0.0.2:50000"), route, ()),
+        TcpAction::TellB(b, 0, Message::punchS2B(a))
+    );
+    // not routed any further
+    assert_eq!(
+        state.on_tcp(
+            addr("10.0.0.2:50001"),
+            Message::routeA2S("c".to_owned(), a),
+            ()
+        ),
+        TcpAction::Reply(Message::punchS2A(None))
+    );
+    // an unknown id of our own A goes to the cluster
+    assert!(matches!(
+        state.on_tcp(a, Message::punchA2S("c".to_owned()), ()),
+        TcpAction::Route(_, Message::routeA2S(id, from)) if id == "c" && from == a
+    ));
+}
+
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
+
+fn spawn_server(bind: SocketAddr, node: SocketAddr) -> Server {
+    let child = Command::new(env!("CARGO_BIN_EXE_punch"))
+        .args(["server", "--strategy", "hybrid", "--bind"])
+        .arg(bind.to_string())
+        .arg("--cluster-node")
+        .arg(node.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    Server(child)
+}
+
+#[tokio::test]
+async fn two_server_processes_route_for_each_other() {
+    // free ports for the processes to take
+    let (one, two) = {
+        let (listener_one, _socket_one) = bind_pair().await;
+        let (listener_two, _socket_two) = bind_pair().await;
+        (
+            listener_one.local_addr().unwrap(),
+            listener_two.local_addr().unwrap(),
+        )
+    };
+    let _one = spawn_server(one, two);
+    let _two = spawn_server(two, one);
+    for node in [one, two] {
+        while tokio::net::TcpStream::connect(node).await.is_err() {
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+    punch_across(one, two).await.unwrap();
+}
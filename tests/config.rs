@@ -0,0 +1,240 @@
+use clap::Parser;
+use punch::{
+    config::{ClientArgs, Config, ServerArgs, StrategyChoice, DEFAULT_ADDRS},
+    limit::RateLimit,
+    logging::LogFormat,
+};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+#[derive(Parser)]
+struct Args {
+    #[clap(flatten)]
+    server: ServerArgs,
+}
+
+fn args(flags: &[&str]) -> ServerArgs {
+    Args::parse_from(std::iter::once("server").chain(flags.iter().copied())).server
+}
+
+#[derive(Parser)]
+struct Client {
+    #[clap(flatten)]
+    client: ClientArgs,
+}
+
+fn client_args(flags: &[&str]) -> ClientArgs {
+    Client::parse_from(std::iter::once("client").chain(flags.iter().copied())).client
+}
+
+fn addr(text: &str) -> SocketAddr {
+    text.parse().unwrap()
+}
+
+const FILE: &str = r#"
+bind = ["127.0.0.1:1000"]
+udp = ["127.0.0.1:1001", "[::1]:1001"]
+websocket = ["127.0.0.1:8080"]
+log_level = "warn"
+log_format = "json"
+metrics = "127.0.0.1:9100"
+registration_ttl = "90s"
+drain_timeout = "3s"
+
+[limits]
+max_registrations = 5
+
+[auth]
+allow_ids = ["A", "B"]
+"#;
+
+#[test]
+fn file_settings() {
+    let config = Config::from_toml(FILE).unwrap();
+    assert_eq!(config.tcp_addrs(), vec![addr("127.0.0.1:1000")]);
+    assert_eq!(
+        config.udp_addrs(),
+        vec![
+            addr("127.0.0.1:1000"),
+            addr("127.0.0.1:1001"),
+            addr("[::1]:1001")
+        ]
+    );
+    assert_eq!(config.websocket, vec![addr("127.0.0.1:8080")]);
+    assert_eq!(config.log_level.as_deref(), Some("warn"));
+    assert_eq!(config.log_format, LogFormat::Json);
+    assert_eq!(config.metrics, Some(addr("127.0.0.1:9100")));
+    let options = config.options().unwrap();
+    assert_eq!(options.registration_ttl, Duration::from_secs(90));
+    assert_eq!(options.drain_timeout, Duration::from_secs(3));
+    assert_eq!(options.max_registrations, 5);
+    // not in the file
+    assert_eq!(
+        options.max_connections,
+        Config::default().limits.max_connections
+    );
+    assert_eq!(options.allow_ids, vec!["A", "B"]);
+}
+
+#[test]
+fn flags_override_file() {
+    let mut config = Config::from_toml(FILE).unwrap();
+    args(&[
+        "--tcp",
+        "127.0.0.1:2000",
+        "--log-level",
+        "debug",
+        "--log-format",
+        "text",
+        "--registration-ttl",
+        "5m",
+        "--max-connections",
+        "7",
+        "--max-websockets",
+        "3",
+        "--metrics",
+        "[::1]:9100",
+        "--store",
+        "/var/lib/punch/registry.log",
+        "--drain-timeout",
+        "30s",
+        "--websocket",
+        "[::1]:8443",
+    ])
+    .apply(&mut config);
+    // listen flags replace every addr from the file
+    assert_eq!(config.tcp_addrs(), vec![addr("127.0.0.1:2000")]);
+    assert!(config.udp_addrs().is_empty());
+    assert_eq!(config.websocket, vec![addr("[::1]:8443")]);
+    assert_eq!(config.log_level.as_deref(), Some("debug"));
+    assert_eq!(config.log_format, LogFormat::Text);
+    assert_eq!(config.metrics, Some(addr("[::1]:9100")));
+    assert_eq!(
+        config.store,
+        Some(PathBuf::from("/var/lib/punch/registry.log"))
+    );
+    config.store = None;
+    let options = config.options().unwrap();
+    assert_eq!(options.registration_ttl, Duration::from_secs(300));
+    assert_eq!(options.drain_timeout, Duration::from_secs(30));
+    assert_eq!(options.max_connections, 7);
+    assert_eq!(options.max_websockets, 3);
+    assert_eq!(options.max_registrations, 5);
+    assert_eq!(options.allow_ids, vec!["A", "B"]);
+}
+
+#[test]
+fn defaults_without_file() {
+    let config = args(&[]).load().unwrap();
+    let dual_stack: Vec<_> = DEFAULT_ADDRS.iter().map(|a| addr(a)).collect();
+    assert_eq!(config.tcp_addrs(), dual_stack);
+    assert_eq!(config.udp_addrs(), dual_stack);
+    assert_eq!(config.log_level, None);
+    assert_eq!(config.log_format, LogFormat::Text);
+    assert_eq!(config.metrics, None);
+    assert!(config.websocket.is_empty());
+}
+
+#[test]
+fn bad_files_are_rejected() {
+    assert!(Config::from_toml("bnid = [\"127.0.0.1:1\"]").is_err());
+    assert!(Config::from_toml("[limits]\nmax_clients = 1").is_err());
+    assert!(Config::from_toml("registration_ttl = \"soon\"").is_err());
+    assert!(Config::from_toml("bind = [\"localhost\"]").is_err());
+    assert!(Config::from_toml("[relay]\nenabled = true").is_err());
+    assert!(args(&["--config", "/nonexistent/punch.toml"])
+        .load()
+        .is_err());
+}
+
+#[test]
+fn rate_limit_settings() {
+    let config = Config::from_toml(
+        "[rate_limit]\nip_rate = 10\npunch_burst = 4\nban_for = \"1h\"\nno_amplification = false",
+    )
+    .unwrap();
+    assert_eq!(
+        config.rate_limit,
+        RateLimit {
+            ip_rate: 10,
+            punch_burst: 4,
+            ban_for: Duration::from_secs(3600),
+            no_amplification: false,
+            ..RateLimit::default()
+        }
+    );
+    assert_eq!(
+        config.options().unwrap().limiter.config(),
+        &config.rate_limit
+    );
+    assert!(Config::from_toml("[rate_limit]\nban_for = \"a while\"").is_err());
+
+    let mut config = Config::default();
+    args(&["--no-rate-limit"]).apply(&mut config);
+    assert_eq!(config.rate_limit.ip_rate, 0);
+    assert_eq!(config.rate_limit.ban_after, 0);
+    assert!(config.rate_limit.no_amplification);
+}
+
+const CLIENT_FILE: &str = r#"
+log_format = "json"
+
+[client]
+servers = ["punch.example.com:12345", "127.0.0.1:12345"]
+id = "A"
+peer_id = "B"
+strategy = "tcp"
+report = "punch.json"
+"#;
+
+#[test]
+fn client_settings() {
+    let mut config = Config::from_toml(CLIENT_FILE).unwrap();
+    assert_eq!(
+        config.client.servers,
+        vec!["punch.example.com:12345", "127.0.0.1:12345"]
+    );
+    assert_eq!(config.client.id.as_deref(), Some("A"));
+    assert_eq!(config.client.peer_id.as_deref(), Some("B"));
+    assert_eq!(config.client.strategy, StrategyChoice::Tcp);
+    assert_eq!(config.client.report, Some(PathBuf::from("punch.json")));
+
+    client_args(&[
+        "-s",
+        "10.0.0.1:1",
+        "-s",
+        "10.0.0.2:1",
+        "--peer-id",
+        "C",
+        "--strategy",
+        "hybrid",
+        "--log-level",
+        "debug",
+    ])
+    .apply(&mut config);
+    assert_eq!(config.client.servers, vec!["10.0.0.1:1", "10.0.0.2:1"]);
+    assert_eq!(config.client.id.as_deref(), Some("A"));
+    assert_eq!(config.client.peer_id.as_deref(), Some("C"));
+    assert_eq!(config.client.strategy, StrategyChoice::Hybrid);
+    assert_eq!(config.log_level.as_deref(), Some("debug"));
+    assert_eq!(config.log_format, LogFormat::Json);
+
+    // the client flags leave the server settings alone
+    let defaults = client_args(&[]).load().unwrap();
+    assert_eq!(defaults.client.strategy, StrategyChoice::Auto);
+    assert!(defaults.bind.is_empty());
+    assert!(Config::from_toml("[client]\nstrategy = \"quic\"").is_err());
+}
+
+#[tokio::test]
+async fn clients_need_ids() {
+    let config = client_args(&["-s", "127.0.0.1:1", "--strategy", "hybrid"])
+        .load()
+        .unwrap();
+    assert!(config.puncher(true).await.is_err());
+    let config = client_args(&["-s", "127.0.0.1:1", "-i", "A", "--strategy", "hybrid"])
+        .load()
+        .unwrap();
+    // B waits for anyone, A has to name its peer
+    assert!(config.puncher(true).await.is_ok());
+    assert!(config.puncher(false).await.is_err());
+}
@@ -0,0 +1,84 @@
+use punch::{
+    net::{self, Datagram, Network},
+    puncher::{Puncher, Strategy},
+    rendezvous::ServerList,
+    report::NatType,
+    server,
+};
+use std::net::SocketAddr;
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    time::{timeout, Duration},
+};
+
+async fn start(strategy: Strategy) -> SocketAddr {
+    match strategy {
+        Strategy::Udp => {
+            let socket = net::bind_udp("127.0.0.1:0".parse().unwrap()).unwrap();
+            let addr = socket.local_addr().unwrap();
+            tokio::spawn(server::udp(Datagram::Os(socket)));
+            addr
+        }
+        Strategy::Tcp => {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(server::tcp(listener));
+            addr
+        }
+        Strategy::Hybrid => loop {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            if let Ok(socket) = UdpSocket::bind(addr).await {
+                tokio::spawn(server::hybrid(listener, Datagram::Os(socket)));
+                return addr;
+            }
+        },
+    }
+}
+
+#[tokio::test]
+async fn servers_tell_their_kind() {
+    for strategy in [Strategy::Udp, Strategy::Tcp, Strategy::Hybrid] {
+        let mut servers = ServerList::from(start(strategy).await);
+        let detected = timeout(
+            Duration::from_secs(10),
+            Strategy::detect(&mut servers, &Network::Os, "A"),
+        )
+        .await
+        .expect("detect timed out")
+        .unwrap();
+        assert_eq!(detected, strategy);
+    }
+}
+
+#[tokio::test]
+async fn detect_moves_on_from_a_dead_server() {
+    // nothing listens there, neither on udp nor on tcp
+    let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dead_addr = dead.local_addr().unwrap();
+    drop(dead);
+    let alive = start(Strategy::Tcp).await;
+    let mut servers = ServerList::new(&[dead_addr.to_string(), alive.to_string()]).unwrap();
+    let detected = Strategy::detect(&mut servers, &Network::Os, "A").await;
+    assert_eq!(detected.unwrap(), Strategy::Tcp);
+    assert_eq!(servers.current(), alive.to_string());
+
+    let mut servers = ServerList::from(dead_addr);
+    assert!(Strategy::detect(&mut servers, &Network::Os, "A")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn nat_check_on_loopback_is_open() {
+    for strategy in [Strategy::Udp, Strategy::Tcp, Strategy::Hybrid] {
+        let server = start(strategy).await;
+        let mut puncher = Puncher::new(strategy, server, "A".to_owned(), None);
+        let report = timeout(Duration::from_secs(10), puncher.nat_check())
+            .await
+            .expect("nat check timed out")
+            .unwrap();
+        assert_eq!(report.nat_type, NatType::Open, "{:?}", strategy);
+        assert!(report.candidates.is_empty());
+    }
+}
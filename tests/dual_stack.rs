@@ -0,0 +1,166 @@
+use anyhow::{anyhow, Result};
+use punch::{
+    net::{self, Datagram},
+    puncher::{happy_eyeballs, Punched, Puncher, Strategy},
+    server,
+};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::{sleep, timeout, Duration, Instant},
+};
+
+const V6: &str = "[::1]:1";
+const V4: &str = "127.0.0.1:1";
+
+fn addrs() -> Vec<SocketAddr> {
+    vec![V6.parse().unwrap(), V4.parse().unwrap()]
+}
+
+// each attempt takes `v6` or `v4` and then succeeds with its addr, or fails on None
+async fn race(v6: Option<Duration>, v4: Option<Duration>) -> (Result<SocketAddr>, Duration) {
+    let start = Instant::now();
+    let result = happy_eyeballs(&addrs(), |addr| async move {
+        let delay = if addr.is_ipv6() { v6 } else { v4 };
+        match delay {
+            Some(delay) => {
+                sleep(delay).await;
+                Ok(addr)
+            }
+            None => {
+                sleep(Duration::from_millis(10)).await;
+                Err(anyhow!("{} unreachable", addr))
+            }
+        }
+    })
+    .await;
+    (result, start.elapsed())
+}
+
+#[tokio::test(start_paused = true)]
+async fn ipv6_gets_a_head_start() {
+    let (winner, _) = race(Some(Duration::from_millis(200)), Some(Duration::ZERO)).await;
+    assert!(winner.unwrap().is_ipv6());
+}
+
+#[tokio::test(start_paused = true)]
+async fn slow_ipv6_loses() {
+    let (winner, elapsed) = race(Some(Duration::from_secs(10)), Some(Duration::ZERO)).await;
+    assert!(winner.unwrap().is_ipv4());
+    assert_eq!(elapsed, Duration::from_millis(250));
+}
+
+#[tokio::test(start_paused = true)]
+async fn failed_ipv6_starts_ipv4_at_once() {
+    let (winner, elapsed) = race(None, Some(Duration::ZERO)).await;
+    assert!(winner.unwrap().is_ipv4());
+    assert_eq!(elapsed, Duration::from_millis(10));
+}
+
+#[tokio::test(start_paused = true)]
+async fn all_failed() {
+    let (result, _) = race(None, None).await;
+    assert!(result.is_err());
+}
+
+// the same port on 127.0.0.1 and [::1], like the servers' default binds
+fn dual_stack_addrs() -> (SocketAddr, SocketAddr) {
+    loop {
+        let v4 = net::bind_udp("127.0.0.1:0".parse().unwrap()).unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let v6: SocketAddr = format!("[::1]:{}", port).parse().unwrap();
+        drop(v4);
+        let v4: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let free = [v4, v6]
+            .iter()
+            .all(|&addr| net::bind_udp(addr).is_ok() && net::bind_tcp(addr).is_ok());
+        if free {
+            return (v4, v6);
+        }
+    }
+}
+
+async fn start_hybrid() -> (SocketAddr, SocketAddr) {
+    let (v4, v6) = dual_stack_addrs();
+    let listeners = vec![net::bind_tcp(v4).unwrap(), net::bind_tcp(v6).unwrap()];
+    let sockets = vec![
+        Datagram::Os(net::bind_udp(v4).unwrap()),
+        Datagram::Os(net::bind_udp(v6).unwrap()),
+    ];
+    tokio::spawn(server::hybrid_with(
+        listeners,
+        sockets,
+        server::Options::default(),
+    ));
+    (v4, v6)
+}
+
+async fn start_udp() -> (SocketAddr, SocketAddr) {
+    let (v4, v6) = dual_stack_addrs();
+    let sockets = vec![
+        Datagram::Os(net::bind_udp(v4).unwrap()),
+        Datagram::Os(net::bind_udp(v6).unwrap()),
+    ];
+    tokio::spawn(server::udp_with(sockets, server::Options::default()));
+    (v4, v6)
+}
+
+async fn punch_both(mut a: Puncher, mut b: Puncher) -> (Punched, Punched) {
+    let (a, b) = timeout(Duration::from_secs(20), async {
+        b.register().await.unwrap();
+        tokio::join!(a.punch(), b.punch())
+    })
+    .await
+    .expect("punch timed out");
+    let (mut a, mut b) = (a.unwrap(), b.unwrap());
+    a.stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    b.stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+    (a, b)
+}
+
+fn hybrid(id: &str, peer_id: Option<&str>, servers: &[SocketAddr]) -> Puncher {
+    Puncher::new(
+        Strategy::Hybrid,
+        servers.to_vec(),
+        id.to_owned(),
+        peer_id.map(str::to_owned),
+    )
+}
+
+#[tokio::test]
+async fn hybrid_prefers_ipv6() {
+    let (v4, v6) = start_hybrid().await;
+    let a = hybrid("a", Some("b"), &[v4, v6]);
+    let b = hybrid("b", None, &[v4, v6]);
+    let (a, b) = punch_both(a, b).await;
+    assert!(a.stream.peer_addr().unwrap().is_ipv6());
+    assert!(b.stream.peer_addr().unwrap().is_ipv6());
+}
+
+#[tokio::test]
+async fn hybrid_falls_back_to_ipv4() {
+    let (v4, v6) = start_hybrid().await;
+    // B has no ipv6, so the server answers A's ipv6 request with no peer
+    let a = hybrid("a", Some("b"), &[v6, v4]);
+    let b = hybrid("b", None, &[v4]);
+    let (a, _) = punch_both(a, b).await;
+    assert!(a.stream.peer_addr().unwrap().is_ipv4());
+}
+
+#[tokio::test]
+async fn udp_prefers_ipv6() {
+    let (v4, v6) = start_udp().await;
+    let puncher = |id: &str, peer_id: &str| {
+        Puncher::new(
+            Strategy::Udp,
+            vec![v4, v6],
+            id.to_owned(),
+            Some(peer_id.to_owned()),
+        )
+    };
+    let (one, two) = punch_both(puncher("1", "2"), puncher("2", "1")).await;
+    assert!(one.stream.peer_addr().unwrap().is_ipv6());
+    assert!(two.stream.peer_addr().unwrap().is_ipv6());
+}
@@ -0,0 +1,129 @@
+use punch::{
+    net::Datagram,
+    puncher::{Punched, Puncher, Strategy},
+    rendezvous::ServerList,
+    server,
+};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    time::{timeout, Duration},
+};
+
+// the hosts file of the test machine maps localhost to 127.0.0.1
+#[tokio::test]
+async fn resolves_hostnames() {
+    let mut servers = ServerList::new(&["localhost:12345"]).unwrap();
+    let addrs = servers.addrs().await.unwrap();
+    assert!(addrs.contains(&"127.0.0.1:12345".parse().unwrap()));
+    assert_eq!(servers.current(), "localhost:12345");
+}
+
+#[tokio::test]
+async fn literal_pair_puts_ipv6_first() {
+    let mut servers = ServerList::new(&["127.0.0.1:1, [::1]:1"]).unwrap();
+    let addrs = servers.addrs().await.unwrap();
+    let expected: Vec<SocketAddr> =
+        vec!["[::1]:1".parse().unwrap(), "127.0.0.1:1".parse().unwrap()];
+    assert_eq!(addrs, expected);
+}
+
+#[test]
+fn bad_specs_are_rejected() {
+    assert!(ServerList::new::<&str>(&[]).is_err());
+    assert!(ServerList::new(&["127.0.0.1:1,localhost:1"]).is_err());
+    assert!(ServerList::new(&["127.0.0.1:1,"]).is_err());
+}
+
+#[tokio::test]
+async fn unresolvable_servers_are_skipped() {
+    let mut servers = ServerList::new(&["nowhere.invalid:1", "127.0.0.1:2"]).unwrap();
+    let addrs = servers.addrs().await.unwrap();
+    assert_eq!(addrs, vec!["127.0.0.1:2".parse::<SocketAddr>().unwrap()]);
+    assert_eq!(servers.current(), "127.0.0.1:2");
+
+    let mut servers = ServerList::new(&["nowhere.invalid:1"]).unwrap();
+    assert!(servers.addrs().await.is_err());
+}
+
+#[test]
+fn fail_over_wraps_around() {
+    let mut servers = ServerList::new(&["127.0.0.1:1", "127.0.0.1:2"]).unwrap();
+    servers.fail_over();
+    assert_eq!(servers.current(), "127.0.0.1:2");
+    servers.fail_over();
+    assert_eq!(servers.current(), "127.0.0.1:1");
+}
+
+// a udp port that takes datagrams and never answers
+async fn dead_server() -> (UdpSocket, String) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap().to_string();
+    (socket, addr)
+}
+
+async fn start_udp() -> String {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let port = socket.local_addr().unwrap().port();
+    tokio::spawn(server::udp(Datagram::Os(socket)));
+    format!("localhost:{}", port)
+}
+
+async fn start_hybrid() -> String {
+    loop {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        if let Ok(socket) = UdpSocket::bind(addr).await {
+            tokio::spawn(server::hybrid(listener, Datagram::Os(socket)));
+            return format!("localhost:{}", addr.port());
+        }
+    }
+}
+
+async fn punch_both(mut a: Puncher, mut b: Puncher) -> (Punched, Punched) {
+    let (a, b) = timeout(Duration::from_secs(60), async {
+        b.register().await.unwrap();
+        tokio::join!(a.punch(), b.punch())
+    })
+    .await
+    .expect("punch timed out");
+    let (mut a, mut b) = (a.unwrap(), b.unwrap());
+    a.stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    b.stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+    (a, b)
+}
+
+#[tokio::test]
+async fn udp_fails_over_to_the_next_server() {
+    let (_dead, dead) = dead_server().await;
+    let live = start_udp().await;
+    let puncher = |id: &str, peer_id: &str| {
+        let servers = ServerList::new(&[dead.as_str(), live.as_str()]).unwrap();
+        Puncher::new(
+            Strategy::Udp,
+            servers,
+            id.to_owned(),
+            Some(peer_id.to_owned()),
+        )
+    };
+    punch_both(puncher("1", "2"), puncher("2", "1")).await;
+}
+
+#[tokio::test]
+async fn hybrid_fails_over_to_the_next_server() {
+    let (_dead, dead) = dead_server().await;
+    let live = start_hybrid().await;
+    let puncher = |id: &str, peer_id: Option<&str>| {
+        let servers = ServerList::new(&[dead.as_str(), live.as_str()]).unwrap();
+        Puncher::new(
+            Strategy::Hybrid,
+            servers,
+            id.to_owned(),
+            peer_id.map(str::to_owned),
+        )
+    };
+    punch_both(puncher("a", Some("b")), puncher("b", None)).await;
+}
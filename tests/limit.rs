@@ -0,0 +1,242 @@
+use punch::{
+    hybrid::Message,
+    limit::{Limiter, RateLimit, Refused, Request, MAX_BUCKETS},
+    metrics::Metrics,
+    net::Datagram,
+    pad_request, server,
+    simple::{Observe, Observed, Peer, Register},
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::{sleep, timeout, Duration},
+};
+
+const QUIET: Duration = Duration::from_millis(200);
+
+fn ip(text: &str) -> IpAddr {
+    text.parse().unwrap()
+}
+
+fn limiter(config: RateLimit) -> (Limiter, Arc<Metrics>) {
+    let metrics = Arc::new(Metrics::default());
+    (Limiter::new(config, metrics.clone()), metrics)
+}
+
+fn value(body: &str, series: &str) -> u64 {
+    body.lines()
+        .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no {} in\n{}", series, body))
+        .parse()
+        .unwrap()
+}
+
+async fn udp_send(socket: &UdpSocket, data: &[u8]) -> Option<Vec<u8>> {
+    socket.send(data).await.unwrap();
+    let mut buf = vec![0u8; 2048];
+    let n = timeout(QUIET, socket.recv(&mut buf)).await.ok()?.unwrap();
+    Some(buf[..n].to_vec())
+}
+
+// what the server answers before it hangs up, empty when it just hangs up
+async fn tcp_exchange(server: SocketAddr, msg: Message) -> Vec<u8> {
+    let mut stream = TcpStream::connect(server).await.unwrap();
+    stream.write_all(&msg.encode()).await.unwrap();
+    let mut buf = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut buf))
+        .await
+        .expect("connection still open")
+        .ok();
+    buf
+}
+
+#[test]
+fn buckets_are_per_id_and_refill() {
+    let (limiter, _) = limiter(RateLimit {
+        id_rate: 10,
+        id_burst: 2,
+        ban_after: 0,
+        ..RateLimit::default()
+    });
+    let a = ip("192.0.2.1");
+    assert_eq!(limiter.check(a, Request::Register("1")), Ok(()));
+    assert_eq!(limiter.check(a, Request::Register("1")), Ok(()));
+    assert_eq!(limiter.check(a, Request::Register("1")), Err(Refused::Id));
+    assert_eq!(limiter.check(a, Request::Register("2")), Ok(()));
+    assert_eq!(limiter.check(a, Request::Punch("1")), Ok(()));
+    std::thread::sleep(Duration::from_millis(150));
+    assert_eq!(limiter.check(a, Request::Register("1")), Ok(()));
+}
+
+#[test]
+fn refusals_lead_to_a_temporary_ban() {
+    let (limiter, metrics) = limiter(RateLimit {
+        ip_rate: 10,
+        ip_burst: 1,
+        ban_after: 3,
+        ban_for: Duration::from_millis(300),
+        ..RateLimit::default()
+    });
+    let (a, b) = (ip("192.0.2.1"), ip("2001:db8::1"));
+    assert_eq!(limiter.check(a, Request::Other), Ok(()));
+    for _ in 0..3 {
+        assert_eq!(limiter.check(a, Request::Other), Err(Refused::Ip));
+    }
+    assert_eq!(limiter.check(a, Request::Other), Err(Refused::Banned));
+    assert_eq!(limiter.check(b, Request::Other), Ok(()));
+    assert_eq!(limiter.banned().len(), 1);
+    assert_eq!(limiter.banned()[0].0, a);
+
+    let body = metrics.render();
+    assert_eq!(value(&body, "punch_rate_limited_total{reason=\"ip\"}"), 3);
+    assert_eq!(
+        value(&body, "punch_rate_limited_total{reason=\"banned\"}"),
+        1
+    );
+    assert_eq!(value(&body, "punch_temp_bans_total"), 1);
+    assert_eq!(value(&body, "punch_banned_ips"), 1);
+
+    std::thread::sleep(Duration::from_millis(350));
+    limiter.purge();
+    assert!(limiter.banned().is_empty());
+    assert_eq!(limiter.check(a, Request::Other), Ok(()));
+    assert_eq!(value(&metrics.render(), "punch_banned_ips"), 0);
+}
+
+#[test]
+fn only_refusals_of_the_ip_count_towards_a_ban() {
+    let (limiter, _) = limiter(RateLimit {
+        punch_rate: 1,
+        punch_burst: 1,
+        ban_after: 3,
+        ..RateLimit::default()
+    });
+    let a = ip("192.0.2.1");
+    assert_eq!(limiter.check(a, Request::Punch("b")), Ok(()));
+    for _ in 0..10 {
+        assert_eq!(limiter.check(a, Request::Punch("b")), Err(Refused::Punch));
+    }
+    assert!(limiter.banned().is_empty());
+    assert_eq!(limiter.check(a, Request::Punch("c")), Ok(()));
+}
+
+#[test]
+fn full_buckets_refuse_new_sources() {
+    let (limiter, _) = limiter(RateLimit {
+        ban_after: 0,
+        ..RateLimit::default()
+    });
+    let sources = (0..MAX_BUCKETS as u32).map(|n| IpAddr::from(n.to_be_bytes()));
+    for source in sources {
+        assert_eq!(limiter.check(source, Request::Other), Ok(()));
+    }
+    let known = IpAddr::from(0u32.to_be_bytes());
+    assert_eq!(limiter.check(known, Request::Other), Ok(()));
+    assert_eq!(
+        limiter.check(ip("2001:db8::1"), Request::Other),
+        Err(Refused::Ip)
+    );
+}
+
+#[test]
+fn off_limits_nothing() {
+    let (limiter, _) = limiter(RateLimit::off());
+    for _ in 0..1000 {
+        assert_eq!(limiter.check(ip("192.0.2.1"), Request::Punch("1")), Ok(()));
+    }
+    assert_eq!(limiter.check_reply(10, 100), Ok(()));
+}
+
+#[tokio::test]
+async fn udp_replies_are_no_larger_than_requests() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = socket.local_addr().unwrap();
+    let options = server::Options::default();
+    let metrics = options.metrics.clone();
+    tokio::spawn(server::udp_with(vec![Datagram::Os(socket)], options));
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(server).await.unwrap();
+    let observe = Observe { id: "1".to_owned() }.encode();
+    assert!(udp_send(&client, &observe).await.is_none());
+    let rsp = udp_send(&client, &pad_request(observe)).await.unwrap();
+    assert_eq!(
+        Observed::decode(&rsp).unwrap().addr,
+        client.local_addr().unwrap()
+    );
+
+    let register = |id: &str, peer_id: &str| {
+        Register {
+            id: id.to_owned(),
+            peer_id: peer_id.to_owned(),
+        }
+        .encode()
+    };
+    // nothing to amplify while the peer is unknown
+    let rsp = udp_send(&client, &register("1", "2")).await.unwrap();
+    assert_eq!(Peer::decode(&rsp).unwrap(), Peer { peer_addr: None });
+    let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    other.connect(server).await.unwrap();
+    assert!(udp_send(&other, &register("2", "1")).await.is_none());
+    let rsp = udp_send(&other, &pad_request(register("2", "1")))
+        .await
+        .unwrap();
+    assert_eq!(
+        Peer::decode(&rsp).unwrap().peer_addr,
+        Some(client.local_addr().unwrap())
+    );
+    assert_eq!(
+        value(
+            &metrics.render(),
+            "punch_rate_limited_total{reason=\"amplification\"}"
+        ),
+        2
+    );
+}
+
+#[tokio::test]
+async fn hybrid_limits_punch_requests_for_one_peer() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = listener.local_addr().unwrap();
+    let socket = UdpSocket::bind(server).await.unwrap();
+    let metrics = Arc::new(Metrics::default());
+    let config = RateLimit {
+        punch_rate: 1,
+        punch_burst: 1,
+        ..RateLimit::default()
+    };
+    let options = server::Options {
+        limiter: Arc::new(Limiter::new(config, metrics.clone())),
+        metrics: metrics.clone(),
+        ..server::Options::default()
+    };
+    tokio::spawn(server::hybrid_with(
+        vec![listener],
+        vec![Datagram::Os(socket)],
+        options,
+    ));
+
+    let answer = tcp_exchange(server, Message::punchA2S("c".to_owned())).await;
+    assert_eq!(Message::decode(&answer).unwrap(), Message::punchS2A(None));
+    // the second one for c within the second is dropped, d is still asked for
+    assert!(tcp_exchange(server, Message::punchA2S("c".to_owned()))
+        .await
+        .is_empty());
+    let answer = tcp_exchange(server, Message::punchA2S("d".to_owned())).await;
+    assert_eq!(Message::decode(&answer).unwrap(), Message::punchS2A(None));
+    assert_eq!(
+        value(
+            &metrics.render(),
+            "punch_rate_limited_total{reason=\"punch\"}"
+        ),
+        1
+    );
+
+    sleep(Duration::from_millis(1100)).await;
+    let answer = tcp_exchange(server, Message::punchA2S("c".to_owned())).await;
+    assert_eq!(Message::decode(&answer).unwrap(), Message::punchS2A(None));
+}
@@ -0,0 +1,106 @@
+use punch::{
+    logging::{self, LogFormat},
+    net::Datagram,
+    puncher::{Puncher, Strategy},
+    server,
+};
+use serde_json::Value;
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    time::{timeout, Duration},
+};
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
+
+// every line the subscriber writes
+#[derive(Clone, Default)]
+struct Lines(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Lines {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Lines {
+    type Writer = Lines;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+impl Lines {
+    fn json(&self) -> Vec<Value> {
+        String::from_utf8(self.0.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+}
+
+async fn start_hybrid() -> std::net::SocketAddr {
+    loop {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        if let Ok(socket) = UdpSocket::bind(addr).await {
+            tokio::spawn(server::hybrid(listener, Datagram::Os(socket)));
+            return addr;
+        }
+    }
+}
+
+// the current thread runtime keeps the spawned server under the subscriber
+#[tokio::test]
+async fn json_lines_carry_the_spans() {
+    let lines = Lines::default();
+    let subscriber = logging::subscriber(
+        EnvFilter::new("punch=info"),
+        LogFormat::Json,
+        lines.clone(),
+        false,
+    );
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let server = start_hybrid().await;
+    let mut a = Puncher::new(
+        Strategy::Hybrid,
+        server,
+        "a".to_owned(),
+        Some("nobody".to_owned()),
+    );
+    let punched = timeout(Duration::from_secs(20), a.punch()).await;
+    assert!(punched.expect("punch timed out").is_err());
+
+    let lines = lines.json();
+    let client = lines
+        .iter()
+        .find(|line| line["fields"]["message"] == "send punch request to server")
+        .expect("no punch request logged");
+    assert_eq!(client["level"], "INFO");
+    assert_eq!(client["span"]["name"], "puncher");
+    assert_eq!(client["span"]["strategy"], "hybrid");
+    assert_eq!(client["span"]["id"], "a");
+    assert_eq!(client["span"]["peer_id"], "nobody");
+
+    // the server side of it, with its trail session
+    let server = lines
+        .iter()
+        .find(|line| line["span"]["name"] == "client" && line["span"]["session"].is_u64())
+        .expect("no server line in a session");
+    assert!(server["span"]["addr"].is_string());
+}
+
+#[test]
+fn bad_filters_are_refused() {
+    assert!(logging::init(Some("punch=nonsense"), LogFormat::Text).is_err());
+}
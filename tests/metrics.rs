@@ -0,0 +1,239 @@
+use punch::{
+    hybrid::{Answer, Message},
+    metrics::{self, Metrics},
+    net::Datagram,
+    pad_request, server,
+    simple::{Peer, Register},
+};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::{sleep, timeout, Duration},
+};
+
+async fn start_endpoint(metrics: Arc<Metrics>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(metrics::serve(listener, metrics));
+    addr
+}
+
+// status line and body
+async fn get(endpoint: SocketAddr, request: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(endpoint).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+        .await
+        .expect("no response")
+        .unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_owned(), body.to_owned())
+}
+
+async fn scrape(endpoint: SocketAddr) -> String {
+    let (status, body) = get(endpoint, "GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    body
+}
+
+fn value(body: &str, series: &str) -> u64 {
+    body.lines()
+        .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no {} in\n{}", series, body))
+        .parse()
+        .unwrap()
+}
+
+async fn udp_send(socket: &UdpSocket, data: &[u8]) -> Option<Vec<u8>> {
+    socket.send(data).await.unwrap();
+    let mut buf = vec![0u8; 2048];
+    let n = timeout(Duration::from_millis(200), socket.recv(&mut buf))
+        .await
+        .ok()?
+        .unwrap();
+    Some(buf[..n].to_vec())
+}
+
+async fn tcp_exchange(server: SocketAddr, msg: Message) -> Vec<u8> {
+    let mut stream = TcpStream::connect(server).await.unwrap();
+    stream.write_all(&msg.encode()).await.unwrap();
+    let mut buf = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut buf))
+        .await
+        .expect("no reply")
+        .ok();
+    buf
+}
+
+#[tokio::test]
+async fn every_family_is_exposed() {
+    let body = Metrics::default().render();
+    for name in [
+        "punch_registered_peers",
+        "punch_registrations_total",
+        "punch_registrations_refused_total",
+        "punch_requests_total",
+        "punch_successes_total",
+        "punch_failures_total",
+        "punch_active_sessions",
+        "punch_tcp_connections",
+        "punch_decode_failures_total",
+        "punch_relay_bytes_total",
+        "punch_received_bytes_total",
+        "punch_received_messages_total",
+        "punch_sent_bytes_total",
+        "punch_sent_messages_total",
+        "punch_dropped_messages_total",
+    ] {
+        assert!(body.contains(&format!("# TYPE {} ", name)), "{}", name);
+        assert!(body.contains(&format!("# HELP {} ", name)), "{}", name);
+    }
+    assert_eq!(
+        value(&body, "punch_failures_total{reason=\"peer_no_answer\"}"),
+        0
+    );
+    assert_eq!(value(&body, "punch_sent_bytes_total{protocol=\"udp\"}"), 0);
+}
+
+#[tokio::test]
+async fn hybrid_counts_registrations_and_punches() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = listener.local_addr().unwrap();
+    let socket = UdpSocket::bind(server).await.unwrap();
+    let options = server::Options::default();
+    let endpoint = start_endpoint(options.metrics.clone()).await;
+    tokio::spawn(server::hybrid_with(
+        vec![listener],
+        vec![Datagram::Os(socket)],
+        options,
+    ));
+
+    let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    b.connect(server).await.unwrap();
+    let register = Message::register_request("b".to_owned()).encode();
+    udp_send(&b, &register).await.expect("no register response");
+    assert!(udp_send(&b, b"not json").await.is_none());
+
+    // nobody called c, then a full punch to b
+    let unknown = tcp_exchange(server, Message::punchA2S("c".to_owned())).await;
+    assert_eq!(Message::decode(&unknown).unwrap(), Message::punchS2A(None));
+    let mut a = TcpStream::connect(server).await.unwrap();
+    a.write_all(&Message::punchA2S("b".to_owned()).encode())
+        .await
+        .unwrap();
+    let mut buf = vec![0u8; 2048];
+    b.recv(&mut buf).await.unwrap();
+    let answer = tcp_exchange(
+        server,
+        Message::punchB2S(Answer::A(a.local_addr().unwrap())),
+    )
+    .await;
+    assert!(answer.is_empty());
+    let n = a.read(&mut buf).await.unwrap();
+    assert!(matches!(
+        Message::decode(&buf[..n]).unwrap(),
+        Message::punchS2A(Some(_))
+    ));
+
+    // the registered gauge follows the purge
+    sleep(Duration::from_millis(1500)).await;
+    let body = scrape(endpoint).await;
+    assert_eq!(value(&body, "punch_registered_peers"), 1);
+    assert_eq!(value(&body, "punch_registrations_total"), 1);
+    assert_eq!(value(&body, "punch_requests_total"), 2);
+    assert_eq!(value(&body, "punch_successes_total"), 1);
+    assert_eq!(
+        value(
+            &body,
+            "punch_failures_total{reason=\"peer_not_registered\"}"
+        ),
+        1
+    );
+    assert_eq!(value(&body, "punch_active_sessions"), 0);
+    assert_eq!(
+        value(&body, "punch_decode_failures_total{protocol=\"udp\"}"),
+        1
+    );
+    assert_eq!(
+        value(&body, "punch_received_messages_total{protocol=\"udp\"}"),
+        2
+    );
+    assert_eq!(
+        value(&body, "punch_received_messages_total{protocol=\"tcp\"}"),
+        3
+    );
+    assert_eq!(
+        value(&body, "punch_received_bytes_total{protocol=\"udp\"}"),
+        (register.len() + "not json".len()) as u64
+    );
+    // register_response and punchS2B over udp, two punchS2A over tcp
+    assert_eq!(
+        value(&body, "punch_sent_messages_total{protocol=\"udp\"}"),
+        2
+    );
+    assert_eq!(
+        value(&body, "punch_sent_messages_total{protocol=\"tcp\"}"),
+        2
+    );
+}
+
+#[tokio::test]
+async fn udp_counts_lookups_and_refusals() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = socket.local_addr().unwrap();
+    let options = server::Options {
+        allow_ids: vec!["1".to_owned(), "2".to_owned()],
+        ..Default::default()
+    };
+    let endpoint = start_endpoint(options.metrics.clone()).await;
+    tokio::spawn(server::udp_with(vec![Datagram::Os(socket)], options));
+
+    let client = |id: &str, peer_id: &str| {
+        pad_request(
+            Register {
+                id: id.to_owned(),
+                peer_id: peer_id.to_owned(),
+            }
+            .encode(),
+        )
+    };
+    let one = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    one.connect(server).await.unwrap();
+    let two = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    two.connect(server).await.unwrap();
+    let rsp = udp_send(&one, &client("1", "2")).await.unwrap();
+    assert_eq!(Peer::decode(&rsp).unwrap(), Peer { peer_addr: None });
+    let rsp = udp_send(&two, &client("2", "1")).await.unwrap();
+    assert!(Peer::decode(&rsp).unwrap().peer_addr.is_some());
+    assert!(udp_send(&one, &client("3", "1")).await.is_none());
+
+    let body = scrape(endpoint).await;
+    assert_eq!(value(&body, "punch_registrations_total"), 2);
+    assert_eq!(value(&body, "punch_registrations_refused_total"), 1);
+    assert_eq!(value(&body, "punch_requests_total"), 2);
+    assert_eq!(value(&body, "punch_successes_total"), 1);
+    assert_eq!(
+        value(
+            &body,
+            "punch_failures_total{reason=\"peer_not_registered\"}"
+        ),
+        1
+    );
+}
+
+#[tokio::test]
+async fn endpoint_only_serves_metrics() {
+    let endpoint = start_endpoint(Arc::new(Metrics::default())).await;
+    let (status, _) = get(endpoint, "GET / HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+    let (status, _) = get(endpoint, "POST /metrics HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+    let (status, _) = get(endpoint, "garbage\r\n\r\n").await;
+    assert_eq!(status, "HTTP/1.1 400 Bad Request");
+    // the query string is not part of the path
+    let (status, body) = get(endpoint, "GET /metrics?x=1 HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(value(&body, "punch_registered_peers"), 0);
+}
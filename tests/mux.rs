@@ -0,0 +1,166 @@
+use bytes::{Bytes, BytesMut};
+use punch::mux::{self, Frame, FrameCodec, CHANNEL_WINDOW, MAX_FRAME_PAYLOAD};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{timeout, Duration},
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+// a loopback tcp connection stands in for the punched one
+async fn punched_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (client, server)
+}
+
+#[test]
+fn frames_survive_arriving_in_pieces() {
+    let frames = vec![
+        Frame::Open {
+            id: 1,
+            payload: Bytes::from_static(b"for what"),
+        },
+        Frame::Accept { id: 1 },
+        Frame::Data {
+            id: 1,
+            payload: Bytes::from(vec![7u8; 1000]),
+        },
+        Frame::Window {
+            id: 1,
+            credit: 1000,
+        },
+        Frame::Close { id: 1 },
+    ];
+    let mut wire = BytesMut::new();
+    for frame in frames.clone() {
+        FrameCodec.encode(frame, &mut wire).unwrap();
+    }
+    // one byte at a time
+    let mut src = BytesMut::new();
+    let mut decoded = Vec::new();
+    for byte in wire {
+        src.extend_from_slice(&[byte]);
+        if let Some(frame) = FrameCodec.decode(&mut src).unwrap() {
+            decoded.push(frame);
+        }
+    }
+    assert_eq!(decoded, frames);
+    assert!(src.is_empty());
+}
+
+#[test]
+fn bad_frames_are_rejected() {
+    let too_large = Frame::Data {
+        id: 1,
+        payload: Bytes::from(vec![0u8; MAX_FRAME_PAYLOAD + 1]),
+    };
+    assert!(FrameCodec.encode(too_large, &mut BytesMut::new()).is_err());
+
+    let mut len = BytesMut::from(&((MAX_FRAME_PAYLOAD + 1) as u32).to_be_bytes()[..]);
+    len.extend_from_slice(&[2, 0, 0, 0, 1]);
+    assert!(FrameCodec.decode(&mut len).is_err());
+
+    let mut kind = BytesMut::from(&[0, 0, 0, 0, 9, 0, 0, 0, 1][..]);
+    assert!(FrameCodec.decode(&mut kind).is_err());
+
+    // a window carries exactly one u32
+    let mut window = BytesMut::from(&[0, 0, 0, 1, 4, 0, 0, 0, 1, 0][..]);
+    assert!(FrameCodec.decode(&mut window).is_err());
+}
+
+#[tokio::test]
+async fn channels_carry_data_both_ways_and_close() {
+    let (a, b) = punched_pair().await;
+    let (opener, _) = mux::new(a, true);
+    let (_, mut acceptor) = mux::new(b, false);
+    let opened = tokio::spawn(async move { opener.open(Bytes::from_static(b"hi")).await });
+    let pending = timeout(WAIT, acceptor.accept()).await.unwrap().unwrap();
+    assert_eq!(&pending.payload[..], b"hi");
+    let mut theirs = pending.accept().await.unwrap();
+    let mut ours = opened.await.unwrap().unwrap();
+
+    ours.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    theirs.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+    theirs.write_all(b"pong").await.unwrap();
+    ours.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"pong");
+
+    // closing our end is an eof there, their end still writes
+    ours.shutdown().await.unwrap();
+    let mut rest = Vec::new();
+    timeout(WAIT, theirs.read_to_end(&mut rest))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(rest.is_empty());
+    theirs.write_all(b"last").await.unwrap();
+    theirs.shutdown().await.unwrap();
+    let mut rest = Vec::new();
+    timeout(WAIT, ours.read_to_end(&mut rest))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(rest, b"last");
+}
+
+#[tokio::test]
+async fn dropped_pending_rejects_the_channel() {
+    let (a, b) = punched_pair().await;
+    let (opener, _) = mux::new(a, true);
+    let (_, mut acceptor) = mux::new(b, false);
+    let opened = tokio::spawn(async move { opener.open(Bytes::new()).await });
+    acceptor.accept().await.unwrap().reject();
+    assert!(timeout(WAIT, opened).await.unwrap().unwrap().is_err());
+}
+
+#[tokio::test]
+async fn unread_channel_does_not_hold_up_the_others() {
+    let (a, b) = punched_pair().await;
+    let (opener, _) = mux::new(a, true);
+    let (_, mut acceptor) = mux::new(b, false);
+    let accepted = tokio::spawn(async move {
+        let mut channels = Vec::new();
+        for _ in 0..2 {
+            channels.push(acceptor.accept().await.unwrap().accept().await.unwrap());
+        }
+        (channels, acceptor)
+    });
+    let mut stuck = opener.open(Bytes::new()).await.unwrap();
+    let mut other = opener.open(Bytes::new()).await.unwrap();
+    let (mut channels, _acceptor) = accepted.await.unwrap();
+    let mut other_end = channels.pop().unwrap();
+
+    // far more than the window, nobody reads it
+    let flood = tokio::spawn(async move {
+        stuck.write_all(&vec![1u8; 8 * CHANNEL_WINDOW]).await.ok();
+        stuck
+    });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    other.write_all(b"through").await.unwrap();
+    let mut buf = [0u8; 7];
+    timeout(WAIT, other_end.read_exact(&mut buf))
+        .await
+        .expect("held up behind the unread channel")
+        .unwrap();
+    assert_eq!(&buf, b"through");
+
+    // reading it lets the rest through
+    let mut stuck_end = channels.pop().unwrap();
+    let mut read = 0;
+    let mut buf = vec![0u8; 64 * 1024];
+    while read < 8 * CHANNEL_WINDOW {
+        read += timeout(WAIT, stuck_end.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    flood.await.unwrap();
+}
@@ -0,0 +1,75 @@
+//! End-to-end NAT matrix with the real binaries in linux network namespaces,
+//! see `tests/netns/rig.sh`. Needs root, iproute2 and iptables:
+//!
+//!     sudo cargo test --test netns -- --ignored --nocapture
+
+use std::{path::Path, process::Command};
+
+const KINDS: [&str; 4] = ["none", "full-cone", "port-restricted", "symmetric"];
+
+// expected[a][b], indexed like KINDS
+
+/// Both sides send, and the sockets only take packets from the address the
+/// server reported, so any symmetric nat breaks it.
+const UDP: [[bool; 4]; 4] = [
+    [true, true, true, false],
+    [true, true, true, false],
+    [true, true, true, false],
+    [false, false, false, false],
+];
+
+/// B listens on the port it registers from and never connects to A, so
+/// like hybrid B's nat has to let unsolicited connections in.
+const TCP: [[bool; 4]; 4] = [[true, true, false, false]; 4];
+
+/// A connects to the port B registered from, B never sends to A first, so
+/// B's nat has to let unsolicited connections in.
+const HYBRID: [[bool; 4]; 4] = [[true, true, false, false]; 4];
+
+fn nat_matrix(strategy: &str, expected: [[bool; 4]; 4]) {
+    let bin = Path::new(env!("CARGO_BIN_EXE_hybrid_server"))
+        .parent()
+        .unwrap();
+    let rig = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/netns/rig.sh");
+    let mut unexpected = Vec::new();
+    for (a, nat_a) in KINDS.iter().enumerate() {
+        for (b, nat_b) in KINDS.iter().enumerate() {
+            let status = Command::new("bash")
+                .arg(&rig)
+                .args([strategy, nat_a, nat_b])
+                .env("BIN", bin)
+                .status()
+                .expect("run rig.sh");
+            let punched = match status.code() {
+                Some(0) => true,
+                Some(1) => false,
+                _ => panic!("rig setup failed for {} {} {}", strategy, nat_a, nat_b),
+            };
+            if punched != expected[a][b] {
+                unexpected.push(format!(
+                    "{} {} <-> {}: punched {}",
+                    strategy, nat_a, nat_b, punched
+                ));
+            }
+        }
+    }
+    assert!(unexpected.is_empty(), "{:#?}", unexpected);
+}
+
+#[test]
+#[ignore = "needs root"]
+fn udp_nat_matrix() {
+    nat_matrix("udp", UDP);
+}
+
+#[test]
+#[ignore = "needs root"]
+fn tcp_nat_matrix() {
+    nat_matrix("tcp", TCP);
+}
+
+#[test]
+#[ignore = "needs root"]
+fn hybrid_nat_matrix() {
+    nat_matrix("hybrid", HYBRID);
+}
@@ -0,0 +1,161 @@
+#!/usr/bin/env bash
+# Runs one punch between two clients behind NATs, all in network namespaces.
+#
+#   rig.sh <udp|tcp|hybrid> <nat-a> <nat-b>
+#
+# nat kinds:
+#   none             routed, no translation
+#   full-cone        MASQUERADE, and every inbound packet is forwarded to the host
+#   port-restricted  MASQUERADE (linux conntrack keeps the port for new peers)
+#   symmetric        MASQUERADE --random-fully, a new port for every peer
+#
+# Topology, 203.0.113.0/24 is the "internet" on a bridge in pn-inet:
+#
+#   pn-a 10.0.1.2 -- pn-nata 203.0.113.2 --+
+#                                          +-- pn-srv 203.0.113.1
+#   pn-b 10.0.2.2 -- pn-natb 203.0.113.3 --+
+#
+# Needs root, iproute2 and iptables. BIN points at the built binaries.
+# Exit code: 0 punched and chatted both ways, 1 did not, 2 setup failed.
+
+set -u
+
+STRATEGY=${1:?strategy}
+NAT_A=${2:?nat kind of a}
+NAT_B=${3:?nat kind of b}
+BIN=${BIN:-$(dirname "$0")/../../target/debug}
+WAIT=${WAIT:-25}
+SERVER=203.0.113.1:12345
+WORK=$(mktemp -d)
+NAMESPACES="pn-inet pn-srv pn-nata pn-natb pn-a pn-b"
+
+cleanup() {
+    for ns in $NAMESPACES; do
+        ip netns pids "$ns" 2>/dev/null | xargs -r kill 2>/dev/null
+        ip netns del "$ns" 2>/dev/null
+    done
+    rm -rf "$WORK"
+}
+trap cleanup EXIT
+
+fail_setup() {
+    echo "setup failed: $*" >&2
+    exit 2
+}
+
+in_ns() {
+    local ns=$1
+    shift
+    ip netns exec "$ns" "$@"
+}
+
+# veth <ns1> <if1> <ns2> <if2>
+veth() {
+    ip link add "$2-$$" type veth peer name "$4-$$" || fail_setup "veth $2"
+    ip link set "$2-$$" netns "$1" && in_ns "$1" ip link set "$2-$$" name "$2" &&
+        ip link set "$4-$$" netns "$3" && in_ns "$3" ip link set "$4-$$" name "$4" ||
+        fail_setup "move veth $2"
+    in_ns "$1" ip link set "$2" up
+    in_ns "$3" ip link set "$4" up
+}
+
+# nat <router ns> <host ip> <kind>
+nat() {
+    local ns=$1 host=$2 kind=$3
+    case $kind in
+    none) ;;
+    full-cone)
+        in_ns "$ns" iptables -t nat -A POSTROUTING -o wan -j MASQUERADE &&
+            in_ns "$ns" iptables -t nat -A PREROUTING -i wan -j DNAT --to-destination "$host"
+        ;;
+    port-restricted)
+        in_ns "$ns" iptables -t nat -A POSTROUTING -o wan -j MASQUERADE
+        ;;
+    symmetric)
+        in_ns "$ns" iptables -t nat -A POSTROUTING -o wan -j MASQUERADE --random-fully
+        ;;
+    *) fail_setup "unknown nat kind $kind" ;;
+    esac || fail_setup "iptables in $ns"
+}
+
+[ "$(id -u)" = 0 ] || fail_setup "must run as root"
+cleanup
+WORK=$(mktemp -d)
+for ns in $NAMESPACES; do
+    ip netns add "$ns" || fail_setup "netns $ns"
+    in_ns "$ns" ip link set lo up
+done
+
+in_ns pn-inet ip link add br0 type bridge && in_ns pn-inet ip link set br0 up ||
+    fail_setup "bridge"
+i=1
+for ns in pn-srv pn-nata pn-natb; do
+    veth "$ns" wan pn-inet "br$i"
+    in_ns pn-inet ip link set "br$i" master br0
+    in_ns "$ns" ip addr add "203.0.113.$i/24" dev wan
+    i=$((i + 1))
+done
+
+i=1
+for side in a b; do
+    veth "pn-nat$side" lan "pn-$side" eth
+    in_ns "pn-nat$side" ip addr add "10.0.$i.1/24" dev lan
+    in_ns "pn-nat$side" sysctl -qw net.ipv4.ip_forward=1
+    in_ns "pn-$side" ip addr add "10.0.$i.2/24" dev eth
+    in_ns "pn-$side" ip route add default via "10.0.$i.1"
+    i=$((i + 1))
+done
+nat pn-nata 10.0.1.2 "$NAT_A"
+nat pn-natb 10.0.2.2 "$NAT_B"
+# without nat the private nets have to be routed
+if [ "$NAT_A" = none ]; then
+    in_ns pn-srv ip route add 10.0.1.0/24 via 203.0.113.2
+    in_ns pn-natb ip route add 10.0.1.0/24 via 203.0.113.2
+fi
+if [ "$NAT_B" = none ]; then
+    in_ns pn-srv ip route add 10.0.2.0/24 via 203.0.113.3
+    in_ns pn-nata ip route add 10.0.2.0/24 via 203.0.113.3
+fi
+
+case $STRATEGY in
+udp)
+    SERVER_BIN=udp_server
+    ARGS_A="$BIN/udp_client -s $SERVER -i a -p b"
+    ARGS_B="$BIN/udp_client -s $SERVER -i b -p a"
+    ;;
+tcp)
+    SERVER_BIN=tcp_server
+    ARGS_A="$BIN/tcp_client -s $SERVER -i a -p b"
+    ARGS_B="$BIN/tcp_client -s $SERVER -i b -p a --listener"
+    ;;
+hybrid)
+    SERVER_BIN=hybrid_server
+    # a punches as soon as it starts, give b the time to register
+    ARGS_A="$BIN/hybrid_client -s $SERVER -i a -p b --punch-after 2s"
+    ARGS_B="$BIN/hybrid_client -s $SERVER -i b"
+    ;;
+*) fail_setup "unknown strategy $STRATEGY" ;;
+esac
+[ -x "$BIN/$SERVER_BIN" ] || fail_setup "$BIN/$SERVER_BIN not built"
+
+in_ns pn-srv "$BIN/$SERVER_BIN" --bind 0.0.0.0:12345 >"$WORK/server.log" 2>&1 &
+sleep 0.5
+# stdin is the chat, each side says hello once the punch should be done
+(sleep 11; echo hello-from-b; sleep "$WAIT") |
+    in_ns pn-b $ARGS_B >"$WORK/b.log" 2>&1 &
+(sleep 11; echo hello-from-a; sleep "$WAIT") |
+    in_ns pn-a $ARGS_A >"$WORK/a.log" 2>&1 &
+
+deadline=$((SECONDS + WAIT))
+while [ $SECONDS -lt $deadline ]; do
+    if grep -q "b: hello-from-b" "$WORK/a.log" && grep -q "a: hello-from-a" "$WORK/b.log"; then
+        echo "$STRATEGY $NAT_A <-> $NAT_B: punched"
+        exit 0
+    fi
+    sleep 1
+done
+echo "$STRATEGY $NAT_A <-> $NAT_B: failed"
+if [ -n "${VERBOSE:-}" ]; then
+    tail -n 20 "$WORK/server.log" "$WORK/a.log" "$WORK/b.log"
+fi
+exit 1
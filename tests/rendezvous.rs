@@ -0,0 +1,464 @@
+use punch::{
+    hybrid::{Answer, Message},
+    net::Datagram,
+    pad_request,
+    puncher::{Puncher, Strategy},
+    server,
+    simple::{Peer, Register},
+};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::{timeout, Duration},
+};
+
+const QUIET: Duration = Duration::from_millis(200);
+
+// tcp and udp on the same port, like the hybrid_server binary
+async fn start_hybrid() -> SocketAddr {
+    loop {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        if let Ok(socket) = UdpSocket::bind(addr).await {
+            tokio::spawn(server::hybrid(listener, Datagram::Os(socket)));
+            return addr;
+        }
+    }
+}
+
+async fn start_tcp() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server::tcp(listener));
+    addr
+}
+
+async fn start_udp() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(server::udp(Datagram::Os(socket)));
+    addr
+}
+
+async fn udp_client(server: SocketAddr) -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(server).await.unwrap();
+    socket
+}
+
+async fn udp_recv(socket: &UdpSocket) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; 2048];
+    let n = timeout(QUIET, socket.recv(&mut buf)).await.ok()?.unwrap();
+    Some(buf[..n].to_vec())
+}
+
+async fn tcp_send(server: SocketAddr, data: &[u8]) -> TcpStream {
+    let mut stream = TcpStream::connect(server).await.unwrap();
+    stream.write_all(data).await.unwrap();
+    stream
+}
+
+async fn tcp_recv(stream: &mut TcpStream) -> Vec<u8> {
+    let mut buf = vec![0u8; 2048];
+    let n = timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .expect("no reply")
+        .unwrap();
+    buf[..n].to_vec()
+}
+
+// the server hung up without answering
+async fn assert_closed(stream: &mut TcpStream) {
+    let mut buf = vec![0u8; 2048];
+    match timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+        Ok(Ok(0)) | Ok(Err(_)) => {}
+        Ok(Ok(n)) => panic!("unexpected reply {:?}", String::from_utf8_lossy(&buf[..n])),
+        Err(_) => panic!("connection still open"),
+    }
+}
+
+async fn register(server: SocketAddr, id: &str) -> UdpSocket {
+    let socket = udp_client(server).await;
+    socket
+        .send(&Message::register_request(id.to_owned()).encode())
+        .await
+        .unwrap();
+    let rsp = udp_recv(&socket).await.expect("no register response");
+    assert_eq!(
+        Message::decode(&rsp).unwrap(),
+        Message::register_response(0)
+    );
+    socket
+}
+
+async fn expect_udp(socket: &UdpSocket) -> Message {
+    Message::decode(&udp_recv(socket).await.expect("nothing over udp")).unwrap()
+}
+
+async fn punch_request(server: SocketAddr, id_b: &str) -> TcpStream {
+    tcp_send(server, &Message::punchA2S(id_b.to_owned()).encode()).await
+}
+
+// B answers punchS2B, returns the tcp addr the server will report to A
+async fn punch_reply(server: SocketAddr, tcp_addr_a: SocketAddr) -> SocketAddr {
+    let stream = tcp_send(server, &Message::punchB2S(Answer::A(tcp_addr_a)).encode()).await;
+    stream.local_addr().unwrap()
+}
+
+async fn full_punch(server: SocketAddr, id_b: &str, b: &UdpSocket) {
+    let mut a = punch_request(server, id_b).await;
+    let tcp_addr_a = a.local_addr().unwrap();
+    assert_eq!(expect_udp(b).await, Message::punchS2B(tcp_addr_a));
+    let tcp_addr_b = punch_reply(server, tcp_addr_a).await;
+    assert_eq!(
+        Message::decode(&tcp_recv(&mut a).await).unwrap(),
+        Message::punchS2A(Some(tcp_addr_b))
+    );
+}
+
+#[tokio::test]
+async fn hybrid_register_and_punch() {
+    let server = start_hybrid().await;
+    let b = register(server, "b").await;
+    full_punch(server, "b", &b).await;
+}
+
+#[tokio::test]
+async fn hybrid_punch_unknown_peer() {
+    let server = start_hybrid().await;
+    let mut a = punch_request(server, "nobody").await;
+    assert_eq!(
+        Message::decode(&tcp_recv(&mut a).await).unwrap(),
+        Message::punchS2A(None)
+    );
+}
+
+#[tokio::test]
+async fn hybrid_b_reply_without_a() {
+    let server = start_hybrid().await;
+    let b = register(server, "b").await;
+    let mut stray = tcp_send(
+        server,
+        &Message::punchB2S(Answer::A("127.0.0.1:9".parse().unwrap())).encode(),
+    )
+    .await;
+    assert_closed(&mut stray).await;
+    assert!(udp_recv(&b).await.is_none());
+    full_punch(server, "b", &b).await;
+}
+
+#[tokio::test]
+async fn hybrid_concurrent_a_requests() {
+    let server = start_hybrid().await;
+    let b1 = register(server, "b1").await;
+    let b2 = register(server, "b2").await;
+
+    let mut a1 = punch_request(server, "b1").await;
+    let mut a2 = punch_request(server, "b2").await;
+    let tcp_addr_a1 = a1.local_addr().unwrap();
+    let tcp_addr_a2 = a2.local_addr().unwrap();
+    assert_eq!(expect_udp(&b1).await, Message::punchS2B(tcp_addr_a1));
+    assert_eq!(expect_udp(&b2).await, Message::punchS2B(tcp_addr_a2));
+
+    // answered in the opposite order
+    let tcp_addr_b2 = punch_reply(server, tcp_addr_a2).await;
+    let tcp_addr_b1 = punch_reply(server, tcp_addr_a1).await;
+    assert_eq!(
+        Message::decode(&tcp_recv(&mut a2).await).unwrap(),
+        Message::punchS2A(Some(tcp_addr_b2))
+    );
+    assert_eq!(
+        Message::decode(&tcp_recv(&mut a1).await).unwrap(),
+        Message::punchS2A(Some(tcp_addr_b1))
+    );
+}
+
+#[tokio::test]
+async fn hybrid_legacy_b_reply() {
+    let server = start_hybrid().await;
+    let b = register(server, "b").await;
+    let mut a = punch_request(server, "b").await;
+    assert!(matches!(expect_udp(&b).await, Message::punchS2B(_)));
+
+    // clients from before punchB2S said which A send one byte
+    let legacy = tcp_send(server, br#"{"punchB2S":0}"#).await;
+    assert_eq!(
+        Message::decode(&tcp_recv(&mut a).await).unwrap(),
+        Message::punchS2A(Some(legacy.local_addr().unwrap()))
+    );
+    assert_eq!(
+        Message::decode(br#"{"punchB2S":0}"#).unwrap(),
+        Message::punchB2S(Answer::Legacy(0))
+    );
+}
+
+#[tokio::test]
+async fn hybrid_malformed_json() {
+    let server = start_hybrid().await;
+    let mut stream = tcp_send(server, b"{\"punchA2S\":").await;
+    assert_closed(&mut stream).await;
+    let mut stream = tcp_send(server, b"{\"no_such_message\":1}").await;
+    assert_closed(&mut stream).await;
+
+    let socket = udp_client(server).await;
+    socket.send(b"not json").await.unwrap();
+    assert!(udp_recv(&socket).await.is_none());
+
+    let b = register(server, "b").await;
+    full_punch(server, "b", &b).await;
+}
+
+#[tokio::test]
+async fn hybrid_oversized_messages() {
+    let server = start_hybrid().await;
+    let huge = "x".repeat(4096);
+    let mut stream = punch_request(server, &huge).await;
+    assert_closed(&mut stream).await;
+
+    let socket = udp_client(server).await;
+    socket
+        .send(&Message::register_request(huge).encode())
+        .await
+        .unwrap();
+    assert!(udp_recv(&socket).await.is_none());
+
+    let b = register(server, "b").await;
+    full_punch(server, "b", &b).await;
+}
+
+#[tokio::test]
+async fn hybrid_disconnects_mid_session() {
+    let server = start_hybrid().await;
+    let b = register(server, "b").await;
+
+    // a client that connects and says nothing must not hold up the others
+    let _silent = TcpStream::connect(server).await.unwrap();
+
+    // A gives up before B answers
+    let a = punch_request(server, "b").await;
+    let tcp_addr_a = a.local_addr().unwrap();
+    assert_eq!(expect_udp(&b).await, Message::punchS2B(tcp_addr_a));
+    drop(a);
+    punch_reply(server, tcp_addr_a).await;
+
+    full_punch(server, "b", &b).await;
+}
+
+async fn tcp_register(server: SocketAddr, id: &str, peer_id: &str) -> (SocketAddr, Peer) {
+    let register = Register {
+        id: id.to_owned(),
+        peer_id: peer_id.to_owned(),
+    };
+    let mut stream = tcp_send(server, &register.encode()).await;
+    let peer = Peer::decode(&tcp_recv(&mut stream).await).unwrap();
+    (stream.local_addr().unwrap(), peer)
+}
+
+#[tokio::test]
+async fn tcp_register_both_sides() {
+    let server = start_tcp().await;
+    let _silent = TcpStream::connect(server).await.unwrap();
+    let (addr_1, peer) = tcp_register(server, "1", "2").await;
+    assert_eq!(peer, Peer { peer_addr: None });
+    let (addr_2, peer) = tcp_register(server, "2", "1").await;
+    assert_eq!(peer.peer_addr, Some(addr_1));
+    let (_, peer) = tcp_register(server, "1", "2").await;
+    assert_eq!(peer.peer_addr, Some(addr_2));
+}
+
+#[tokio::test]
+async fn tcp_malformed_and_oversized() {
+    let server = start_tcp().await;
+    let mut stream = tcp_send(server, b"{\"id\":\"1\"").await;
+    assert_closed(&mut stream).await;
+    let register = Register {
+        id: "x".repeat(4096),
+        peer_id: "2".to_owned(),
+    };
+    let mut stream = tcp_send(server, &register.encode()).await;
+    assert_closed(&mut stream).await;
+
+    let (_, peer) = tcp_register(server, "1", "2").await;
+    assert_eq!(peer, Peer { peer_addr: None });
+}
+
+async fn udp_register(socket: &UdpSocket, id: &str, peer_id: &str) -> Option<Peer> {
+    let register = Register {
+        id: id.to_owned(),
+        peer_id: peer_id.to_owned(),
+    };
+    socket.send(&pad_request(register.encode())).await.unwrap();
+    udp_recv(socket)
+        .await
+        .map(|rsp| Peer::decode(&rsp).unwrap())
+}
+
+#[tokio::test]
+async fn udp_register_both_sides() {
+    let server = start_udp().await;
+    let one = udp_client(server).await;
+    let two = udp_client(server).await;
+    assert_eq!(
+        udp_register(&one, "1", "2").await,
+        Some(Peer { peer_addr: None })
+    );
+    assert_eq!(
+        udp_register(&two, "2", "1").await,
+        Some(Peer {
+            peer_addr: Some(one.local_addr().unwrap())
+        })
+    );
+    assert_eq!(
+        udp_register(&one, "1", "2").await,
+        Some(Peer {
+            peer_addr: Some(two.local_addr().unwrap())
+        })
+    );
+}
+
+#[tokio::test]
+async fn udp_malformed_and_oversized() {
+    let server = start_udp().await;
+    let socket = udp_client(server).await;
+    socket.send(b"{\"id\":").await.unwrap();
+    assert!(udp_recv(&socket).await.is_none());
+    assert_eq!(udp_register(&socket, &"x".repeat(4096), "2").await, None);
+    assert_eq!(
+        udp_register(&socket, "1", "2").await,
+        Some(Peer { peer_addr: None })
+    );
+}
+
+#[tokio::test]
+async fn udp_registration_expires() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = socket.local_addr().unwrap();
+    let options = server::Options {
+        registration_ttl: Duration::from_millis(300),
+        ..Default::default()
+    };
+    tokio::spawn(server::udp_with(vec![Datagram::Os(socket)], options));
+
+    let one = udp_client(server).await;
+    let two = udp_client(server).await;
+    udp_register(&one, "1", "2").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(
+        udp_register(&two, "2", "1").await,
+        Some(Peer { peer_addr: None })
+    );
+}
+
+#[tokio::test]
+async fn hybrid_refuses_ids_not_allowed() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = listener.local_addr().unwrap();
+    let socket = UdpSocket::bind(server).await.unwrap();
+    let options = server::Options {
+        allow_ids: vec!["b".to_owned()],
+        ..Default::default()
+    };
+    tokio::spawn(server::hybrid_with(
+        vec![listener],
+        vec![Datagram::Os(socket)],
+        options,
+    ));
+
+    let mallory = udp_client(server).await;
+    mallory
+        .send(&Message::register_request("mallory".to_owned()).encode())
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_udp(&mallory).await,
+        Message::register_response(server::REGISTER_REFUSED)
+    );
+    let mut a = punch_request(server, "mallory").await;
+    assert_eq!(
+        Message::decode(&tcp_recv(&mut a).await).unwrap(),
+        Message::punchS2A(None)
+    );
+
+    let b = register(server, "b").await;
+    full_punch(server, "b", &b).await;
+}
+
+#[tokio::test]
+async fn tcp_registry_full() {
+    let listeners = vec![
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    let first = listeners[0].local_addr().unwrap();
+    let second = listeners[1].local_addr().unwrap();
+    let options = server::Options {
+        max_registrations: 1,
+        ..Default::default()
+    };
+    tokio::spawn(server::tcp_with(listeners, options));
+
+    tcp_register(first, "1", "2").await;
+    let register = Register {
+        id: "2".to_owned(),
+        peer_id: "1".to_owned(),
+    };
+    let mut stream = tcp_send(second, &register.encode()).await;
+    assert_closed(&mut stream).await;
+    // refreshing a known id still works, through either listener
+    let (_, peer) = tcp_register(second, "1", "2").await;
+    assert_eq!(peer, Peer { peer_addr: None });
+}
+
+// a hybrid server that registers B, asks it to answer a and returns what B answered
+async fn answer_of_b(server_knows_answers: bool) -> Vec<u8> {
+    let (listener, socket) = loop {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        if let Ok(socket) = UdpSocket::bind(listener.local_addr().unwrap()).await {
+            break (listener, socket);
+        }
+    };
+    let server = listener.local_addr().unwrap();
+    let mut b = Puncher::new(Strategy::Hybrid, server, "b".to_owned(), None);
+    let punching = tokio::spawn(async move { b.punch().await });
+
+    let a: SocketAddr = "127.0.0.1:9".parse().unwrap();
+    let mut buf = vec![0u8; 1024];
+    loop {
+        let (n, from) = timeout(Duration::from_secs(10), socket.recv_from(&mut buf))
+            .await
+            .expect("b stopped sending")
+            .unwrap();
+        let reply = match Message::decode(&buf[..n]).unwrap() {
+            Message::register_request(_) => Message::register_response(0),
+            // servers from before Answer::A did not know it either
+            Message::addr_request(_) if server_knows_answers => Message::addr_response(from),
+            _ => continue,
+        };
+        socket.send_to(&reply.encode(), from).await.unwrap();
+        // the punch request once b knows what the server takes
+        if !server_knows_answers || matches!(reply, Message::addr_response(_)) {
+            socket
+                .send_to(&Message::punchS2B(a).encode(), from)
+                .await
+                .unwrap();
+            break;
+        }
+    }
+    let (mut stream, _) = timeout(Duration::from_secs(10), listener.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    let answer = tcp_recv(&mut stream).await;
+    punching.abort();
+    answer
+}
+
+#[tokio::test]
+async fn hybrid_b_answers_old_servers_with_the_legacy_byte() {
+    assert_eq!(answer_of_b(false).await, br#"{"punchB2S":0}"#);
+    assert_eq!(
+        Message::decode(&answer_of_b(true).await).unwrap(),
+        Message::punchB2S(Answer::A("127.0.0.1:9".parse().unwrap()))
+    );
+}
@@ -0,0 +1,228 @@
+use punch::{
+    net::{self, Datagram},
+    puncher::{Punched, Puncher, Strategy},
+    report::{NatType, Report},
+    server::{self, Options},
+    simple::{Observe, Observed},
+};
+use serde_json::{json, Value};
+use std::{net::SocketAddr, path::PathBuf};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::{timeout, Duration},
+};
+
+fn report_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("punch-{}-{}.json", name, std::process::id()))
+}
+
+fn read_report(path: &PathBuf) -> Report {
+    let report = serde_json::from_slice(&std::fs::read(path).expect("no report")).unwrap();
+    std::fs::remove_file(path).ok();
+    report
+}
+
+fn event_names(events: &Value) -> Vec<&str> {
+    events
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["event"].as_str().unwrap())
+        .collect()
+}
+
+async fn start_hybrid(options: Options) -> SocketAddr {
+    loop {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        if let Ok(socket) = UdpSocket::bind(addr).await {
+            tokio::spawn(server::hybrid_with(
+                vec![listener],
+                vec![Datagram::Os(socket)],
+                options,
+            ));
+            return addr;
+        }
+    }
+}
+
+fn hybrid(id: &str, peer_id: Option<&str>, server: SocketAddr) -> Puncher {
+    Puncher::new(
+        Strategy::Hybrid,
+        server,
+        id.to_owned(),
+        peer_id.map(str::to_owned),
+    )
+}
+
+async fn punch_both(mut a: Puncher, mut b: Puncher) -> (Punched, Punched) {
+    let (a, b) = timeout(Duration::from_secs(20), async {
+        b.register().await.unwrap();
+        tokio::join!(a.punch(), b.punch())
+    })
+    .await
+    .expect("punch timed out");
+    (a.unwrap(), b.unwrap())
+}
+
+#[test]
+fn nat_types() {
+    let local: SocketAddr = "192.168.1.6:3854".parse().unwrap();
+    assert_eq!(NatType::classify(local, local), NatType::Open);
+    assert_eq!(
+        NatType::classify(local, "27.216.129.86:3854".parse().unwrap()),
+        NatType::PortPreserving
+    );
+    assert_eq!(
+        NatType::classify(local, "27.216.129.86:40001".parse().unwrap()),
+        NatType::PortChanging
+    );
+    assert!(NatType::PortChanging > NatType::PortPreserving);
+    assert!(NatType::Open > NatType::Unknown);
+}
+
+#[tokio::test]
+async fn hybrid_punch_is_reported_on_both_sides() {
+    let options = Options::default();
+    let control = options.control.clone();
+    let server = start_hybrid(options).await;
+    let path = report_path("hybrid");
+    let a = hybrid("a", Some("b"), server).report(&path);
+    let b = hybrid("b", None, server);
+    let b_events = b.events();
+    let (a, _b) = punch_both(a, b).await;
+
+    let report = read_report(&path);
+    assert_eq!(report.id, "a");
+    assert_eq!(report.peer_id.as_deref(), Some("b"));
+    assert_eq!(report.strategy, "hybrid");
+    // nothing between us and the server on loopback
+    assert_eq!(report.nat_type, NatType::Open);
+    let local = a.stream.local_addr().unwrap();
+    let remote = a.stream.peer_addr().unwrap();
+    let outcome = serde_json::to_value(&report.outcome).unwrap();
+    assert_eq!(
+        outcome,
+        json!({ "result": "connected", "local": local, "remote": remote })
+    );
+    assert_eq!(report.candidates.len(), 1);
+    assert_eq!(report.candidates[0].tries, 1);
+    assert!(report.candidates[0].connected);
+    let events = serde_json::to_value(&report.events).unwrap();
+    let names = event_names(&events);
+    for name in [
+        "server",
+        "registered",
+        "mapped",
+        "peer_addr",
+        "attempt",
+        "connected",
+    ] {
+        assert!(names.contains(&name), "{} not in {:?}", name, names);
+    }
+    assert!(events[0]["at"].as_str().unwrap().ends_with('Z'));
+
+    // b got a's addr from the server, and the server kept its half
+    let b_report = b_events.report();
+    assert_eq!(b_report.strategy, "hybrid");
+    assert!(b_report.candidates.iter().any(|c| c.remote == local));
+    let trails = control.trails().list(Some("b"));
+    assert_eq!(trails.len(), 1);
+    assert!(trails[0].finished);
+    let trail = serde_json::to_value(&trails[0]).unwrap();
+    assert_eq!(
+        event_names(&trail["events"]),
+        vec!["punch_request", "told_b", "b_answered"]
+    );
+    assert_eq!(trail["events"][0]["a"], json!(local));
+    assert_eq!(trail["events"][2]["b"], json!(remote));
+}
+
+#[tokio::test]
+async fn failed_punch_is_reported() {
+    let options = Options::default();
+    let control = options.control.clone();
+    let server = start_hybrid(options).await;
+    let path = report_path("failed");
+    let mut a = hybrid("a", Some("nobody"), server).report(&path);
+    assert!(timeout(Duration::from_secs(20), a.punch())
+        .await
+        .expect("punch timed out")
+        .is_err());
+
+    let report = read_report(&path);
+    let outcome = serde_json::to_value(&report.outcome).unwrap();
+    assert_eq!(outcome["result"], "failed");
+    assert!(outcome["error"]
+        .as_str()
+        .unwrap()
+        .contains("B not register"));
+    assert!(report.candidates.is_empty());
+
+    let trails = control.trails().list(Some("nobody"));
+    assert_eq!(trails.len(), 1);
+    assert!(trails[0].finished);
+    let trail = serde_json::to_value(&trails[0]).unwrap();
+    assert_eq!(trail["events"][0]["event"], "punch_request");
+    assert_eq!(trail["events"][0]["b"], Value::Null);
+    assert!(control.trails().list(Some("b")).is_empty());
+}
+
+#[tokio::test]
+async fn udp_lookups_share_a_trail() {
+    let options = Options::default();
+    let control = options.control.clone();
+    let socket = net::bind_udp("127.0.0.1:0".parse().unwrap()).unwrap();
+    let server = socket.local_addr().unwrap();
+    tokio::spawn(server::udp_with(vec![Datagram::Os(socket)], options));
+
+    let puncher = |id: &str, peer_id: &str| {
+        Puncher::new(
+            Strategy::Udp,
+            server,
+            id.to_owned(),
+            Some(peer_id.to_owned()),
+        )
+    };
+    let one = puncher("1", "2");
+    let events = one.events();
+    punch_both(one, puncher("2", "1")).await;
+
+    let report = events.report();
+    assert_eq!(report.nat_type, NatType::Open);
+    assert_eq!(report.candidates.len(), 1);
+    assert!(report.candidates[0].connected);
+
+    let trails = control.trails().list(Some("1"));
+    assert_eq!(trails.len(), 1);
+    assert_eq!(trails[0].ids, vec!["1", "2"]);
+    assert!(trails[0].finished);
+    // repeated lookups with the same answer are left out
+    let trail = serde_json::to_value(&trails[0]).unwrap();
+    let lookups = trail["events"].as_array().unwrap();
+    assert!(lookups.len() <= 3, "{:?}", lookups);
+    assert!(lookups.iter().all(|e| e["event"] == "lookup"));
+}
+
+#[tokio::test]
+async fn tcp_server_tells_the_observed_addr() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = listener.local_addr().unwrap();
+    tokio::spawn(server::tcp(listener));
+
+    let mut stream = TcpStream::connect(server).await.unwrap();
+    let observe = Observe { id: "1".to_owned() };
+    stream.write_all(&observe.encode()).await.unwrap();
+    let mut buf = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut buf))
+        .await
+        .expect("no reply")
+        .unwrap();
+    assert_eq!(
+        Observed::decode(&buf).unwrap(),
+        Observed {
+            addr: stream.local_addr().unwrap()
+        }
+    );
+}
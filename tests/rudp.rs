@@ -0,0 +1,162 @@
+use punch::{net::Datagram, rudp::RudpStream};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    time::{timeout, Duration},
+};
+
+const WAIT: Duration = Duration::from_secs(30);
+
+// what the relay does to one direction
+#[derive(Clone, Copy)]
+struct Faults {
+    // every nth datagram is lost, 0 for none
+    drop_every: u64,
+    // every nth datagram is held back and sent after the next one, 0 for none
+    swap_every: u64,
+}
+
+async fn forward(from: Arc<UdpSocket>, to: Arc<UdpSocket>, target: SocketAddr, faults: Faults) {
+    let mut buf = vec![0u8; 2048];
+    let mut held: Option<Vec<u8>> = None;
+    let mut n = 0u64;
+    while let Ok(len) = from.recv(&mut buf).await {
+        n += 1;
+        if faults.drop_every > 0 && n.is_multiple_of(faults.drop_every) {
+            continue;
+        }
+        if faults.swap_every > 0 && n.is_multiple_of(faults.swap_every) && held.is_none() {
+            held = Some(buf[..len].to_vec());
+            continue;
+        }
+        to.send_to(&buf[..len], target).await.ok();
+        if let Some(held) = held.take() {
+            to.send_to(&held, target).await.ok();
+        }
+    }
+}
+
+// a rudp stream at each end of a relay that loses and reorders datagrams
+async fn lossy_pair(faults: Faults) -> (RudpStream, RudpStream) {
+    let one = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let two = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let near_one = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let near_two = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    near_one.connect(one.local_addr().unwrap()).await.unwrap();
+    near_two.connect(two.local_addr().unwrap()).await.unwrap();
+    let (one_addr, two_addr) = (one.local_addr().unwrap(), two.local_addr().unwrap());
+    tokio::spawn(forward(
+        near_one.clone(),
+        near_two.clone(),
+        two_addr,
+        faults,
+    ));
+    tokio::spawn(forward(
+        near_two.clone(),
+        near_one.clone(),
+        one_addr,
+        faults,
+    ));
+    one.connect(near_one.local_addr().unwrap()).await.unwrap();
+    two.connect(near_two.local_addr().unwrap()).await.unwrap();
+    (
+        RudpStream::new(Datagram::Os(one)).unwrap(),
+        RudpStream::new(Datagram::Os(two)).unwrap(),
+    )
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+async fn carries(faults: Faults) {
+    let (mut one, mut two) = lossy_pair(faults).await;
+    let data = pattern(300 * 1024);
+    let sent = data.clone();
+    let writer = tokio::spawn(async move {
+        one.write_all(&sent).await.unwrap();
+        one.shutdown().await.unwrap();
+        one
+    });
+    let mut received = Vec::new();
+    timeout(WAIT, two.read_to_end(&mut received))
+        .await
+        .expect("stream did not finish")
+        .unwrap();
+    assert_eq!(received.len(), data.len());
+    assert!(received == data, "bytes differ");
+    writer.await.unwrap();
+}
+
+#[tokio::test]
+async fn clean_path_carries_every_byte() {
+    carries(Faults {
+        drop_every: 0,
+        swap_every: 0,
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn lost_datagrams_are_sent_again() {
+    carries(Faults {
+        drop_every: 7,
+        swap_every: 0,
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn reordered_datagrams_arrive_in_order() {
+    carries(Faults {
+        drop_every: 0,
+        swap_every: 3,
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn loss_and_reordering_together_in_both_directions() {
+    let (mut one, mut two) = lossy_pair(Faults {
+        drop_every: 11,
+        swap_every: 5,
+    })
+    .await;
+    let data = pattern(64 * 1024);
+    let (one_data, two_data) = (data.clone(), data.clone());
+    let one_side = tokio::spawn(async move {
+        let (mut rd, mut wr) = tokio::io::split(&mut one);
+        let (_, received) = tokio::join!(
+            async {
+                wr.write_all(&one_data).await.unwrap();
+                wr.shutdown().await.unwrap();
+            },
+            async {
+                let mut received = Vec::new();
+                rd.read_to_end(&mut received).await.unwrap();
+                received
+            }
+        );
+        received
+    });
+    let (mut rd, mut wr) = tokio::io::split(&mut two);
+    let (_, received) = timeout(WAIT, async {
+        tokio::join!(
+            async {
+                wr.write_all(&two_data).await.unwrap();
+                wr.shutdown().await.unwrap();
+            },
+            async {
+                let mut received = Vec::new();
+                rd.read_to_end(&mut received).await.unwrap();
+                received
+            }
+        )
+    })
+    .await
+    .expect("stream did not finish");
+    assert!(received == data);
+    let received = timeout(WAIT, one_side).await.unwrap().unwrap();
+    assert!(received == data);
+}
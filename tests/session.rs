@@ -0,0 +1,294 @@
+use anyhow::Result;
+use futures::future::BoxFuture;
+use punch::{
+    puncher::{Punched, PunchedStream, Puncher, Strategy},
+    session::Session,
+    Candidate, Pair, PunchContext, PunchStrategy,
+};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc,
+    task::AbortHandle,
+    time::{sleep, timeout, Duration},
+};
+
+const WAIT: Duration = Duration::from_secs(60);
+
+// every punch gets a fresh tcp connection through a relay the test can cut
+struct Link {
+    // the far ends, for the side that does not make them
+    far: tokio::sync::Mutex<mpsc::UnboundedReceiver<TcpStream>>,
+    to_far: mpsc::UnboundedSender<TcpStream>,
+    relays: Mutex<Vec<AbortHandle>>,
+}
+
+impl Link {
+    fn new() -> Arc<Self> {
+        let (to_far, far) = mpsc::unbounded_channel();
+        Arc::new(Self {
+            far: tokio::sync::Mutex::new(far),
+            to_far,
+            relays: Mutex::new(Vec::new()),
+        })
+    }
+
+    // drops whatever is in flight, both ends see the connection go
+    fn cut(&self) {
+        for relay in self.relays.lock().unwrap().drain(..) {
+            relay.abort();
+        }
+    }
+
+    async fn connection(&self) -> Result<TcpStream> {
+        let (near, relay_near) = tcp_pair().await?;
+        let (relay_far, far) = tcp_pair().await?;
+        let relay = tokio::spawn(async move {
+            let (mut one, mut two) = (relay_near, relay_far);
+            tokio::io::copy_bidirectional(&mut one, &mut two).await.ok();
+        });
+        self.relays.lock().unwrap().push(relay.abort_handle());
+        self.to_far.send(far).ok();
+        Ok(near)
+    }
+}
+
+async fn tcp_pair() -> Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let one = TcpStream::connect(listener.local_addr()?).await?;
+    let (two, _) = listener.accept().await?;
+    Ok((one, two))
+}
+
+/// One end of a [`Link`], the initiator makes the connections.
+struct End {
+    link: Arc<Link>,
+    initiator: bool,
+    // a stopped process does not punch any more
+    stopped: Arc<AtomicBool>,
+}
+
+impl PunchStrategy for End {
+    fn name(&self) -> &str {
+        "link"
+    }
+
+    fn gather<'a>(&'a self, _punch: &'a PunchContext) -> BoxFuture<'a, Result<Vec<Candidate>>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+
+    fn exchange<'a>(
+        &'a self,
+        _punch: &'a PunchContext,
+        _ours: Vec<Candidate>,
+    ) -> BoxFuture<'a, Result<Vec<Pair>>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+
+    fn connect<'a>(
+        &'a self,
+        _punch: &'a PunchContext,
+        _pairs: Vec<Pair>,
+    ) -> BoxFuture<'a, Result<Punched>> {
+        Box::pin(async move {
+            if self.stopped.load(Ordering::SeqCst) {
+                std::future::pending::<()>().await;
+            }
+            let stream = if self.initiator {
+                self.link.connection().await?
+            } else {
+                let far = self.link.far.lock().await.recv().await;
+                far.ok_or_else(|| anyhow::anyhow!("link gone"))?
+            };
+            Ok(Punched {
+                stream: PunchedStream::Tcp(stream),
+                initiator: self.initiator,
+            })
+        })
+    }
+}
+
+// the built-in strategy talks to a server that never answers
+fn puncher(server: SocketAddr, link: &Arc<Link>, initiator: bool) -> (Puncher, Arc<AtomicBool>) {
+    let (id, peer_id) = if initiator { ("a", "b") } else { ("b", "a") };
+    let stopped = Arc::new(AtomicBool::new(false));
+    let puncher = Puncher::new(
+        Strategy::Udp,
+        server,
+        id.to_owned(),
+        Some(peer_id.to_owned()),
+    )
+    .strategy(End {
+        link: link.clone(),
+        initiator,
+        stopped: stopped.clone(),
+    });
+    (puncher, stopped)
+}
+
+async fn sessions(server: SocketAddr, link: &Arc<Link>) -> (Session, Session) {
+    let (a, _) = puncher(server, link, true);
+    let (b, _) = puncher(server, link, false);
+    let (a, b) = timeout(WAIT, async {
+        tokio::join!(Session::connect(a), Session::connect(b))
+    })
+    .await
+    .expect("punch timed out");
+    (a.unwrap(), b.unwrap())
+}
+
+fn pattern(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i + seed) * 7 % 251) as u8).collect()
+}
+
+// writes `data` and closes, reads until the peer closes
+async fn exchange<S>(session: S, data: Vec<u8>) -> Vec<u8>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (mut rd, mut wr) = tokio::io::split(session);
+    let (_, received) = tokio::join!(
+        async {
+            for chunk in data.chunks(10_000) {
+                wr.write_all(chunk).await.unwrap();
+                // gives the cuts something in flight
+                sleep(Duration::from_millis(1)).await;
+            }
+            wr.shutdown().await.unwrap();
+        },
+        async {
+            let mut received = Vec::new();
+            rd.read_to_end(&mut received).await.unwrap();
+            received
+        }
+    );
+    received
+}
+
+#[tokio::test]
+async fn cut_connections_resume_without_loss_or_duplicates() {
+    let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let link = Link::new();
+    let (mut a, mut b) = sessions(dead.local_addr().unwrap(), &link).await;
+    assert!(a.initiator() && !b.initiator());
+
+    // nothing in flight yet
+    a.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    timeout(WAIT, b.read_exact(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf, b"ping");
+    link.cut();
+
+    let (to_b, to_a) = (pattern(2_000_000, 0), pattern(1_500_000, 3));
+    let cuts = tokio::spawn({
+        let link = link.clone();
+        async move {
+            for _ in 0..3 {
+                sleep(Duration::from_millis(50)).await;
+                link.cut();
+            }
+        }
+    });
+    let (at_b, at_a) = timeout(WAIT, async {
+        tokio::join!(
+            exchange(&mut b, to_a.clone()),
+            exchange(&mut a, to_b.clone())
+        )
+    })
+    .await
+    .expect("session did not finish");
+    cuts.await.unwrap();
+    assert_eq!(at_b.len(), to_b.len());
+    assert!(at_b == to_b, "b got different bytes");
+    assert_eq!(at_a.len(), to_a.len());
+    assert!(at_a == to_a, "a got different bytes");
+
+    assert!(a.info().reconnects >= 3);
+    assert_eq!(a.info().id, b.info().id);
+    assert!(a.info().error.is_none());
+    timeout(WAIT, a.close()).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn restarted_peer_ends_the_session() {
+    let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = dead.local_addr().unwrap();
+    let link = Link::new();
+    let (a, _) = puncher(server, &link, true);
+    let (old_b, old_b_stopped) = puncher(server, &link, false);
+    let (a, old_b) = timeout(WAIT, async {
+        tokio::join!(Session::connect(a), Session::connect(old_b))
+    })
+    .await
+    .expect("punch timed out");
+    let (mut a, mut old_b) = (a.unwrap(), old_b.unwrap());
+    a.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    timeout(WAIT, old_b.read_exact(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    let id = a.info().id;
+
+    // b goes away with its session and comes back without it
+    old_b_stopped.store(true, Ordering::SeqCst);
+    link.cut();
+    let (new_b, _) = puncher(server, &link, false);
+    let _new_b = timeout(WAIT, Session::connect(new_b))
+        .await
+        .unwrap()
+        .unwrap();
+
+    // a must not resume into someone else's byte stream
+    let mut rest = Vec::new();
+    let error = timeout(WAIT, a.read_to_end(&mut rest))
+        .await
+        .expect("a did not notice the restart")
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted);
+    assert!(rest.is_empty());
+    let info = a.info();
+    assert_eq!(info.id, id);
+    assert!(info.error.unwrap().contains("peer lost session"));
+}
+
+#[tokio::test]
+async fn a_slow_reader_stalls_the_peer_without_a_reconnect() {
+    let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let link = Link::new();
+    let (mut a, mut b) = sessions(dead.local_addr().unwrap(), &link).await;
+    let data = pattern(8_000_000, 5);
+    let writer = tokio::spawn({
+        let data = data.clone();
+        async move {
+            a.write_all(&data).await.unwrap();
+            a.shutdown().await.unwrap();
+            a
+        }
+    });
+
+    // longer than the keepalive timeout, with more sent than can be held
+    sleep(Duration::from_secs(12)).await;
+    assert!(!writer.is_finished());
+    let mut received = Vec::new();
+    timeout(WAIT, b.read_to_end(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received.len(), data.len());
+    assert!(received == data, "b got different bytes");
+    let a = timeout(WAIT, writer).await.unwrap().unwrap();
+    assert_eq!(a.info().reconnects, 0);
+    assert_eq!(b.info().reconnects, 0);
+    assert!(a.info().error.is_none() && b.info().error.is_none());
+}
@@ -0,0 +1,241 @@
+use punch::{
+    hybrid::Message,
+    net::Datagram,
+    pad_request,
+    puncher::{Puncher, Strategy},
+    rendezvous::ServerList,
+    server::{self, HybridState, TcpAction},
+    simple::{GoingAway, Register, Unregister},
+};
+use std::net::SocketAddr;
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::JoinHandle,
+    time::{sleep, timeout, Duration},
+};
+
+const QUIET: Duration = Duration::from_millis(200);
+
+fn options() -> server::Options {
+    server::Options {
+        drain_timeout: Duration::from_millis(500),
+        ..server::Options::default()
+    }
+}
+
+// tcp and udp on the same port, like the hybrid_server binary
+async fn start_hybrid(options: server::Options) -> (SocketAddr, JoinHandle<anyhow::Result<()>>) {
+    loop {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        if let Ok(socket) = UdpSocket::bind(addr).await {
+            let server = tokio::spawn(server::hybrid_with(
+                vec![listener],
+                vec![Datagram::Os(socket)],
+                options,
+            ));
+            return (addr, server);
+        }
+    }
+}
+
+async fn udp_client(server: SocketAddr) -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(server).await.unwrap();
+    socket
+}
+
+async fn udp_recv(socket: &UdpSocket) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; 2048];
+    let n = timeout(QUIET, socket.recv(&mut buf)).await.ok()?.unwrap();
+    Some(buf[..n].to_vec())
+}
+
+async fn stopped(server: JoinHandle<anyhow::Result<()>>) {
+    timeout(Duration::from_secs(5), server)
+        .await
+        .expect("server still running")
+        .unwrap()
+        .unwrap();
+}
+
+#[test]
+fn closing_hybrid_state_turns_clients_away() {
+    let mut state = HybridState::<SocketAddr>::default();
+    let (b, a) = (
+        "127.0.0.1:1000".parse().unwrap(),
+        "127.0.0.1:2000".parse().unwrap(),
+    );
+    state.on_udp(b, 7, Message::register_request("b".to_owned()));
+    assert_eq!(state.close(), vec![(b, 7)]);
+    assert_eq!(
+        state.on_udp(b, 7, Message::register_request("b".to_owned())),
+        Some(Message::going_away)
+    );
+    match state.on_tcp(a, Message::punchA2S("b".to_owned()), a) {
+        TcpAction::Reply(Message::going_away) => {}
+        action => panic!("unexpected {:?}", action),
+    }
+}
+
+#[test]
+fn only_the_registered_addr_unregisters() {
+    let mut state = HybridState::<SocketAddr>::default();
+    let b: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+    state.on_udp(b, 0, Message::register_request("b".to_owned()));
+    let other = "192.0.2.1:1000".parse().unwrap();
+    assert_eq!(
+        state.on_udp(other, 0, Message::unregister_request("b".to_owned())),
+        None
+    );
+    // another client behind the same nat
+    let neighbour = "127.0.0.1:3000".parse().unwrap();
+    state.on_udp(neighbour, 0, Message::unregister_request("b".to_owned()));
+    // the addr, but through another socket of the server
+    state.on_udp(b, 1, Message::unregister_request("b".to_owned()));
+    assert_eq!(state.registered(), 1);
+    state.on_udp(b, 0, Message::unregister_request("b".to_owned()));
+    assert_eq!(state.registered(), 0);
+}
+
+#[tokio::test]
+async fn hybrid_tells_registered_clients_it_goes_away() {
+    let options = options();
+    let shutdown = options.shutdown.clone();
+    let (addr, server) = start_hybrid(options).await;
+    let client = udp_client(addr).await;
+    let register = pad_request(Message::register_request("b".to_owned()).encode());
+    client.send(&register).await.unwrap();
+    let rsp = udp_recv(&client).await.unwrap();
+    assert_eq!(
+        Message::decode(&rsp).unwrap(),
+        Message::register_response(0)
+    );
+
+    shutdown.trigger();
+    let rsp = udp_recv(&client).await.expect("no going_away");
+    assert_eq!(Message::decode(&rsp).unwrap(), Message::going_away);
+    stopped(server).await;
+}
+
+#[tokio::test]
+async fn udp_server_tells_registered_clients_it_goes_away() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let options = options();
+    let shutdown = options.shutdown.clone();
+    let server = tokio::spawn(server::udp_with(vec![Datagram::Os(socket)], options));
+
+    let client = udp_client(addr).await;
+    let register = Register {
+        id: "1".to_owned(),
+        peer_id: "2".to_owned(),
+    };
+    client.send(&pad_request(register.encode())).await.unwrap();
+    assert!(udp_recv(&client).await.is_some());
+
+    shutdown.trigger();
+    let rsp = udp_recv(&client).await.expect("no going away");
+    assert_eq!(GoingAway::decode(&rsp).unwrap().going_away, addr);
+    stopped(server).await;
+}
+
+#[tokio::test]
+async fn tcp_server_drains_open_connections() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let options = options();
+    let shutdown = options.shutdown.clone();
+    let server = tokio::spawn(server::tcp_with(vec![listener], options));
+
+    let _idle = TcpStream::connect(addr).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+    shutdown.trigger();
+    sleep(Duration::from_millis(100)).await;
+    assert!(!server.is_finished(), "did not wait for the connection");
+    stopped(server).await;
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+#[tokio::test]
+async fn udp_unregister_forgets_the_registration() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let options = options();
+    let control = options.control.clone();
+    tokio::spawn(server::udp_with(vec![Datagram::Os(socket)], options));
+
+    let client = udp_client(addr).await;
+    let register = Register {
+        id: "1".to_owned(),
+        peer_id: "2".to_owned(),
+    };
+    client.send(&pad_request(register.encode())).await.unwrap();
+    assert!(udp_recv(&client).await.is_some());
+    assert_eq!(control.registrations(Some("1")).len(), 1);
+
+    let unregister = Unregister {
+        unregister: "1".to_owned(),
+    };
+    let neighbour = udp_client(addr).await;
+    neighbour.send(&unregister.encode()).await.unwrap();
+    sleep(QUIET).await;
+    assert_eq!(control.registrations(Some("1")).len(), 1);
+    client.send(&unregister.encode()).await.unwrap();
+    assert!(udp_recv(&client).await.is_none());
+    assert!(control.registrations(Some("1")).is_empty());
+}
+
+#[tokio::test]
+async fn puncher_unregisters_on_leave() {
+    let options = options();
+    let control = options.control.clone();
+    let (addr, _server) = start_hybrid(options).await;
+    let mut puncher = Puncher::new(
+        Strategy::Hybrid,
+        ServerList::new(&[addr.to_string()]).unwrap(),
+        "b".to_owned(),
+        None,
+    );
+    timeout(Duration::from_secs(5), puncher.register())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(control.registrations(Some("b")).len(), 1);
+
+    let leave = puncher.leave();
+    leave.unregister().await;
+    sleep(QUIET).await;
+    assert!(control.registrations(Some("b")).is_empty());
+}
+
+#[tokio::test]
+async fn udp_puncher_unregisters_from_the_registered_port() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let options = options();
+    let control = options.control.clone();
+    tokio::spawn(server::udp_with(vec![Datagram::Os(socket)], options));
+    let mut puncher = Puncher::new(
+        Strategy::Udp,
+        ServerList::new(&[addr.to_string()]).unwrap(),
+        "1".to_owned(),
+        Some("2".to_owned()),
+    );
+    let leave = puncher.leave();
+    let punching = tokio::spawn(async move { puncher.punch().await });
+    timeout(Duration::from_secs(5), async {
+        while control.registrations(Some("1")).is_empty() {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    // the port is free again once the punch is given up
+    punching.abort();
+    assert!(punching.await.is_err());
+    leave.unregister().await;
+    sleep(QUIET).await;
+    assert!(control.registrations(Some("1")).is_empty());
+}
@@ -0,0 +1,236 @@
+use punch::{
+    net::{Datagram, Network},
+    punch_with,
+    puncher::{Punched, PunchedStream, SignaledPunch, Strategy},
+    report::Events,
+    server,
+    signaling::{MemorySignaling, PipeSignaling, ServerSignaling, Signal, Signaling},
+    Candidate, PunchContext,
+};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    time::{timeout, Duration},
+};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+fn candidate(addr: &str) -> Candidate {
+    Candidate {
+        addr: addr.parse().unwrap(),
+        local: None,
+    }
+}
+
+fn signal(from: &str, to: &str) -> Signal {
+    Signal {
+        from: from.to_owned(),
+        to: to.to_owned(),
+        candidates: vec![candidate("192.0.2.1:4000")],
+    }
+}
+
+fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("punch-{}-{}.jsonl", name, std::process::id()))
+}
+
+async fn start_hybrid() -> SocketAddr {
+    loop {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        if let Ok(socket) = UdpSocket::bind(addr).await {
+            tokio::spawn(server::hybrid(listener, Datagram::Os(socket)));
+            return addr;
+        }
+    }
+}
+
+#[tokio::test]
+async fn memory_signaling_carries_signals() {
+    let a = MemorySignaling::default();
+    let b = a.peer();
+    assert_eq!(a.lookup("b").await.unwrap(), None);
+    assert!(a.send(signal("a", "b")).await.is_err());
+
+    b.register("b").await.unwrap();
+    assert_eq!(a.lookup("b").await.unwrap(), Some(Vec::new()));
+    a.send(signal("a", "b")).await.unwrap();
+    assert_eq!(b.receive().await.unwrap(), signal("a", "b"));
+}
+
+#[tokio::test]
+async fn pipe_signaling_reads_lines_to_its_id() {
+    let (ours, mut theirs) = tokio::io::duplex(1024);
+    let (reader, writer) = tokio::io::split(ours);
+    let pipe = PipeSignaling::new(reader, writer);
+    pipe.register("b").await.unwrap();
+    assert_eq!(pipe.lookup("a").await.unwrap(), Some(Vec::new()));
+
+    // a line for someone else, then one written in two halves
+    let mut other = serde_json::to_vec(&signal("a", "c")).unwrap();
+    other.push(b'\n');
+    theirs.write_all(&other).await.unwrap();
+    let mut line = serde_json::to_vec(&signal("a", "b")).unwrap();
+    line.push(b'\n');
+    let (first, second) = line.split_at(10);
+    theirs.write_all(first).await.unwrap();
+    let receive = pipe.receive();
+    tokio::pin!(receive);
+    assert!(timeout(Duration::from_millis(100), &mut receive)
+        .await
+        .is_err());
+    theirs.write_all(second).await.unwrap();
+    assert_eq!(receive.await.unwrap(), signal("a", "b"));
+
+    pipe.send(signal("b", "a")).await.unwrap();
+    let mut buf = vec![0u8; 1024];
+    let n = theirs.read(&mut buf).await.unwrap();
+    assert!(buf[..n].ends_with(b"\n"));
+    let sent: Signal = serde_json::from_slice(&buf[..n]).unwrap();
+    assert_eq!(sent, signal("b", "a"));
+
+    drop(theirs);
+    assert!(pipe.receive().await.is_err());
+}
+
+#[tokio::test]
+async fn pipe_signaling_follows_files() {
+    let (a_to_b, b_to_a) = (path("a-to-b"), path("b-to-a"));
+    std::fs::remove_file(&a_to_b).ok();
+    std::fs::remove_file(&b_to_a).ok();
+    let a = PipeSignaling::files(&b_to_a, &a_to_b).await.unwrap();
+    let b = PipeSignaling::files(&a_to_b, &b_to_a).await.unwrap();
+    a.register("a").await.unwrap();
+    b.register("b").await.unwrap();
+
+    // b waits for a line that is not written yet
+    let received = tokio::spawn(async move { b.receive().await.map(|signal| (b, signal)) });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    a.send(signal("a", "b")).await.unwrap();
+    let (b, received) = timeout(WAIT, received).await.unwrap().unwrap().unwrap();
+    assert_eq!(received, signal("a", "b"));
+
+    b.send(signal("b", "a")).await.unwrap();
+    let answer = timeout(WAIT, a.receive()).await.unwrap().unwrap();
+    assert_eq!(answer, signal("b", "a"));
+    std::fs::remove_file(&a_to_b).ok();
+    std::fs::remove_file(&b_to_a).ok();
+}
+
+#[tokio::test]
+async fn server_signaling_relays_through_the_hybrid_server() {
+    let server = start_hybrid().await;
+    let (a, b) = (ServerSignaling::new(server), ServerSignaling::new(server));
+    // only asked where we registered
+    assert!(a.lookup("b").await.is_err());
+    a.register("a").await.unwrap();
+    assert_eq!(a.lookup("b").await.unwrap(), None);
+    assert!(a.send(signal("a", "b")).await.is_err());
+
+    b.register("b").await.unwrap();
+    let known = a.lookup("b").await.unwrap().expect("b is registered");
+    assert_eq!(known.len(), 1);
+    assert_eq!(known[0].addr.ip(), server.ip());
+
+    a.send(signal("a", "b")).await.unwrap();
+    let received = timeout(WAIT, b.receive()).await.unwrap().unwrap();
+    assert_eq!(received, signal("a", "b"));
+    // a may not speak for c
+    assert!(a.send(signal("c", "b")).await.is_err());
+
+    // too many to relay in one datagram
+    let mut crowded = signal("a", "b");
+    crowded.candidates = vec![candidate("192.0.2.1:4000"); 20];
+    assert!(a.send(crowded).await.is_err());
+}
+
+fn context(id: &str, peer_id: Option<&str>) -> PunchContext {
+    PunchContext {
+        id: id.to_owned(),
+        peer_id: peer_id.map(str::to_owned),
+        server_addrs: Vec::new(),
+        network: Network::Os,
+        events: Events::new(id, peer_id, Strategy::Udp),
+    }
+}
+
+async fn ping(a: &mut Punched, b: &mut Punched) {
+    assert_ne!(a.initiator, b.initiator);
+    a.stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    b.stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[tokio::test]
+async fn signaled_punch_trades_candidates_through_the_signaling() {
+    let signaling = MemorySignaling::default();
+    let loopback = vec!["127.0.0.1:0".parse().unwrap()];
+    let a = SignaledPunch::new(Arc::new(signaling.peer())).bind(loopback.clone());
+    let b = SignaledPunch::new(Arc::new(signaling))
+        .bind(loopback)
+        .accept(vec!["a".to_owned()]);
+    // b waits for a to signal it
+    let (context_a, context_b) = (context("a", Some("b")), context("b", None));
+    let (a, b) = timeout(Duration::from_secs(20), async {
+        tokio::join!(punch_with(&a, &context_a), punch_with(&b, &context_b))
+    })
+    .await
+    .expect("punch timed out");
+    let (mut a, mut b) = (a.unwrap(), b.unwrap());
+    assert!(matches!(a.stream, PunchedStream::Udp(_)));
+    assert!(a.initiator);
+    ping(&mut a, &mut b).await;
+    ping(&mut b, &mut a).await;
+}
+
+#[tokio::test]
+async fn waiting_signaled_punch_only_answers_accepted_ids_and_their_candidates() {
+    let signaling = MemorySignaling::default();
+    let rogue = signaling.peer();
+    let a_addr = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let b_addr = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let a = SignaledPunch::new(Arc::new(signaling.peer())).bind(vec![a_addr]);
+    let b = SignaledPunch::new(Arc::new(signaling))
+        .bind(vec![b_addr])
+        .accept(vec!["a".to_owned()]);
+    let (context_a, context_b) = (context("a", Some("b")), context("b", None));
+
+    // c signals first and probes b all along
+    let outsider = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let probing = tokio::spawn(async move {
+        loop {
+            outsider.send_to(b"punch", b_addr).await.ok();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    });
+    let b = tokio::spawn(async move { punch_with(&b, &context_b).await });
+    rogue.register("c").await.unwrap();
+    while rogue.lookup("b").await.unwrap().is_none() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    rogue.send(signal("c", "b")).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let (a, b) = timeout(Duration::from_secs(20), async {
+        tokio::join!(punch_with(&a, &context_a), b)
+    })
+    .await
+    .expect("punch timed out");
+    probing.abort();
+    let (mut a, mut b) = (a.unwrap(), b.unwrap().unwrap());
+    assert_eq!(b.stream.peer_addr().unwrap(), a_addr);
+    ping(&mut a, &mut b).await;
+    assert!(timeout(Duration::from_millis(300), rogue.receive())
+        .await
+        .is_err());
+}
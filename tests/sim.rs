@@ -0,0 +1,164 @@
+use punch::{
+    net::{Datagram, Network},
+    puncher::{Puncher, Strategy},
+    server,
+    sim::{Host, NatConfig, NatKind, Sim},
+};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::{sleep, timeout, Duration},
+};
+
+const SERVER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 12345);
+
+fn ip(a: u8, b: u8, c: u8, d: u8) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+}
+
+fn start_server(sim: &Sim) {
+    let socket = sim.host(SERVER.ip()).bind(SERVER).unwrap();
+    tokio::spawn(server::udp(Datagram::Sim(socket)));
+}
+
+// punches from both hosts with the real udp puncher and checks that bytes
+// make it across in both directions
+async fn punch(a: Host, b: Host) -> bool {
+    let punch_from = |host: Host, id: &str, peer_id: &str| {
+        let mut puncher = Puncher::new(
+            Strategy::Udp,
+            SERVER,
+            id.to_owned(),
+            Some(peer_id.to_owned()),
+        )
+        .network(Network::Sim(host));
+        async move { puncher.punch().await.unwrap() }
+    };
+    let (a, b) = tokio::join!(punch_from(a, "a", "b"), punch_from(b, "b", "a"));
+    let (mut a, mut b) = (a.stream, b.stream);
+
+    let exchange = async {
+        a.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        b.read_exact(&mut buf).await?;
+        b.write_all(b"pong").await?;
+        a.read_exact(&mut buf).await?;
+        Ok::<_, std::io::Error>(&buf == b"pong")
+    };
+    matches!(timeout(Duration::from_secs(30), exchange).await, Ok(Ok(true)))
+}
+
+async fn punch_between(kind_a: NatKind, kind_b: NatKind) -> bool {
+    let sim = Sim::new();
+    start_server(&sim);
+    let a = sim
+        .nat(ip(2, 2, 2, 2), NatConfig::new(kind_a))
+        .host(ip(10, 0, 0, 2));
+    let b = sim
+        .nat(ip(3, 3, 3, 3), NatConfig::new(kind_b))
+        .host(ip(10, 1, 0, 2));
+    punch(a, b).await
+}
+
+#[tokio::test(start_paused = true)]
+async fn cone_nats_punch() {
+    use NatKind::*;
+    for kind_a in [FullCone, Restricted, PortRestricted] {
+        for kind_b in [FullCone, Restricted, PortRestricted] {
+            assert!(
+                punch_between(kind_a, kind_b).await,
+                "{:?} <-> {:?}",
+                kind_a,
+                kind_b
+            );
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn symmetric_nat_fails() {
+    use NatKind::*;
+    for kind in [FullCone, Restricted, PortRestricted, Symmetric] {
+        assert!(!punch_between(Symmetric, kind).await, "symmetric <-> {:?}", kind);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn public_host_and_nat_punch() {
+    let sim = Sim::new();
+    start_server(&sim);
+    let a = sim.host(ip(4, 4, 4, 4));
+    let b = sim
+        .nat(ip(3, 3, 3, 3), NatConfig::new(NatKind::PortRestricted))
+        .host(ip(10, 1, 0, 2));
+    assert!(punch(a, b).await);
+}
+
+#[tokio::test(start_paused = true)]
+async fn same_nat_needs_hairpin() {
+    for hairpin in [true, false] {
+        let sim = Sim::new();
+        start_server(&sim);
+        let nat = sim.nat(
+            ip(2, 2, 2, 2),
+            NatConfig::new(NatKind::PortRestricted).hairpin(hairpin),
+        );
+        let a = nat.host(ip(10, 0, 0, 2));
+        let b = nat.host(ip(10, 0, 0, 3));
+        assert_eq!(punch(a, b).await, hairpin, "hairpin {}", hairpin);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn idle_mapping_expires() {
+    let sim = Sim::new();
+    let server = sim.host(SERVER.ip()).bind(SERVER).unwrap();
+    let client = sim
+        .nat(
+            ip(2, 2, 2, 2),
+            NatConfig::new(NatKind::FullCone).mapping_timeout(Duration::from_secs(30)),
+        )
+        .host(ip(10, 0, 0, 2))
+        .bind("0.0.0.0:0".parse().unwrap())
+        .unwrap();
+    let mut buf = [0u8; 16];
+
+    client.send_to(b"hello", SERVER).await.unwrap();
+    let (_, mapped) = server.recv_from(&mut buf).await.unwrap();
+    assert_eq!(mapped.ip(), ip(2, 2, 2, 2));
+
+    sleep(Duration::from_secs(20)).await;
+    server.send_to(b"still there", mapped).await.unwrap();
+    assert!(timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+        .await
+        .is_ok());
+
+    sleep(Duration::from_secs(31)).await;
+    server.send_to(b"gone", mapped).await.unwrap();
+    assert!(timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+        .await
+        .is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn mappings_are_deterministic() {
+    let mut seen = Vec::new();
+    for _ in 0..2 {
+        let sim = Sim::new();
+        let server = sim.host(SERVER.ip()).bind(SERVER).unwrap();
+        let nat = sim.nat(ip(2, 2, 2, 2), NatConfig::new(NatKind::Symmetric));
+        let client = nat
+            .host(ip(10, 0, 0, 2))
+            .bind("0.0.0.0:0".parse().unwrap())
+            .unwrap();
+        client.send_to(b"1", SERVER).await.unwrap();
+        client.send_to(b"2", "9.9.9.9:1".parse().unwrap()).await.unwrap();
+        client.send_to(b"3", SERVER).await.unwrap();
+        let mut buf = [0u8; 1];
+        let (_, first) = server.recv_from(&mut buf).await.unwrap();
+        let (_, again) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(first, again);
+        seen.push(first);
+    }
+    assert_eq!(seen[0], seen[1]);
+}
@@ -0,0 +1,131 @@
+use punch::{
+    mux, socks,
+    tunnel::{self, ForwardArgs},
+};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+// a loopback tcp connection stands in for the punched one
+async fn punched_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (client, server)
+}
+
+async fn start_socks() -> SocketAddr {
+    let (a, b) = punched_pair().await;
+    let (opener, _acceptor) = mux::new(a, true);
+    let exit = ForwardArgs {
+        exit: true,
+        ..ForwardArgs::default()
+    };
+    tokio::spawn(async move { tunnel::forward(b, false, &exit).await });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(socks::serve(listener, opener));
+    addr
+}
+
+async fn start_echo() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut rd, mut wr) = stream.split();
+                tokio::io::copy(&mut rd, &mut wr).await.ok();
+            });
+        }
+    });
+    addr
+}
+
+async fn greet(socks: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(socks).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    let mut rsp = [0u8; 2];
+    stream.read_exact(&mut rsp).await.unwrap();
+    assert_eq!(rsp, [5, 0]);
+    stream
+}
+
+async fn reply_code(stream: &mut TcpStream) -> u8 {
+    let mut rsp = [0u8; 10];
+    stream.read_exact(&mut rsp).await.unwrap();
+    assert_eq!(rsp[0], 5);
+    rsp[1]
+}
+
+async fn assert_echo(stream: &mut TcpStream) {
+    stream.write_all(b"hello through the peer").await.unwrap();
+    let mut buf = [0u8; 22];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello through the peer");
+}
+
+#[tokio::test]
+async fn connect_ipv4() {
+    let socks = start_socks().await;
+    let echo = start_echo().await;
+    let mut stream = greet(socks).await;
+    let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend_from_slice(&echo.port().to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    assert_eq!(reply_code(&mut stream).await, 0);
+    assert_echo(&mut stream).await;
+}
+
+#[tokio::test]
+async fn connect_domain() {
+    let socks = start_socks().await;
+    let echo = start_echo().await;
+    let mut stream = greet(socks).await;
+    let mut request = vec![5, 1, 0, 3, 9];
+    request.extend_from_slice(b"127.0.0.1");
+    request.extend_from_slice(&echo.port().to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    assert_eq!(reply_code(&mut stream).await, 0);
+    assert_echo(&mut stream).await;
+}
+
+#[tokio::test]
+async fn connect_refused() {
+    let socks = start_socks().await;
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut stream = greet(socks).await;
+    let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend_from_slice(&closed.port().to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    assert_eq!(reply_code(&mut stream).await, 1);
+}
+
+#[tokio::test]
+async fn bind_not_supported() {
+    let socks = start_socks().await;
+    let mut stream = greet(socks).await;
+    stream
+        .write_all(&[5, 2, 0, 1, 127, 0, 0, 1, 0, 80])
+        .await
+        .unwrap();
+    assert_eq!(reply_code(&mut stream).await, 7);
+}
+
+#[tokio::test]
+async fn auth_required_is_refused() {
+    let socks = start_socks().await;
+    let mut stream = TcpStream::connect(socks).await.unwrap();
+    stream.write_all(&[5, 1, 2]).await.unwrap();
+    let mut rsp = [0u8; 2];
+    stream.read_exact(&mut rsp).await.unwrap();
+    assert_eq!(rsp, [5, 0xff]);
+}
@@ -0,0 +1,223 @@
+use punch::{
+    hybrid::Message,
+    net::Datagram,
+    puncher::{Puncher, Strategy},
+    server::{self, Control, HybridState, Options, TcpAction},
+    store::{LogStore, MemoryStore, Store},
+};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    time::sleep,
+};
+
+fn store_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("punch-{}-{}.log", name, std::process::id()));
+    std::fs::remove_file(&path).ok();
+    path
+}
+
+// long enough for nothing the tests write to expire
+const TTL: Duration = Duration::from_secs(3600);
+
+fn open(path: &Path) -> LogStore {
+    LogStore::open(path, TTL).unwrap()
+}
+
+fn addr(text: &str) -> SocketAddr {
+    text.parse().unwrap()
+}
+
+fn options(store: Arc<dyn Store>) -> Options {
+    Options {
+        control: Arc::new(Control::with_store(store).unwrap()),
+        ..Options::default()
+    }
+}
+
+#[test]
+fn memory_store_keeps_an_addr_per_family() {
+    let store = MemoryStore::default();
+    store.seen("A", addr("1.2.3.4:5")).unwrap();
+    store.seen("A", addr("[2001:db8::1]:5")).unwrap();
+    store.seen("A", addr("1.2.3.4:6")).unwrap();
+    let device = store.get("A").unwrap().unwrap();
+    assert_eq!(
+        device.addrs,
+        vec![addr("[2001:db8::1]:5"), addr("1.2.3.4:6")]
+    );
+    assert!(device.last_seen.elapsed().unwrap() < Duration::from_secs(5));
+    store.forget("A").unwrap();
+    assert!(store.get("A").unwrap().is_none());
+    assert!(store.get("B").unwrap().is_none());
+}
+
+#[test]
+fn log_store_reads_back_what_it_wrote() {
+    let path = store_path("reopen");
+    {
+        let store = open(&path);
+        store.put(punch::store::Device::new("A")).unwrap();
+        store.seen("A", addr("1.2.3.4:5")).unwrap();
+        store.seen("B", addr("1.2.3.4:6")).unwrap();
+        store.seen("E", addr("1.2.3.4:7")).unwrap();
+        store.forget("E").unwrap();
+        store.ban("C", true).unwrap();
+        store.ban("D", true).unwrap();
+        store.ban("D", false).unwrap();
+    }
+    let before = open(&path).load().unwrap();
+    assert_eq!(before.banned, vec!["C"]);
+    assert_eq!(before.devices.len(), 2);
+    let a = &before.devices[0];
+    assert_eq!(a.id, "A");
+    assert_eq!(a.addrs, vec![addr("1.2.3.4:5")]);
+
+    // opening compacted it: one line per device and ban
+    let text = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(text.lines().count(), 3);
+
+    // a write cut short by a crash is dropped, anything else is refused
+    std::fs::write(&path, format!("{}{{\"op\":\"put\",\"id\"", text)).unwrap();
+    assert_eq!(open(&path).load().unwrap(), before);
+    std::fs::write(&path, format!("nonsense\n{}", text)).unwrap();
+    assert!(LogStore::open(&path, TTL).is_err());
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn log_store_drops_devices_not_seen_for_the_ttl() {
+    let path = store_path("expire");
+    // as written before devices lost their key and tenant
+    std::fs::write(
+        &path,
+        concat!(
+            "{\"op\":\"put\",\"id\":\"old\",\"key\":null,\"tenant\":null,",
+            "\"addrs\":[\"1.2.3.4:5\"],\"last_seen\":\"2022-02-20T08:11:13.000Z\"}\n",
+            "{\"op\":\"ban\",\"id\":\"C\"}\n",
+        ),
+    )
+    .unwrap();
+    {
+        let store = open(&path);
+        assert!(store.get("old").unwrap().is_none());
+        store.seen("new", addr("1.2.3.4:6")).unwrap();
+    }
+    let text = std::fs::read_to_string(&path).unwrap();
+    assert!(!text.contains("\"old\""));
+    let stored = open(&path).load().unwrap();
+    assert_eq!(stored.devices.len(), 1);
+    assert_eq!(stored.devices[0].id, "new");
+    assert_eq!(stored.banned, vec!["C"]);
+
+    // a ttl that has run out by the time it is opened again
+    std::thread::sleep(Duration::from_millis(5));
+    drop(LogStore::open(&path, Duration::from_millis(1)).unwrap());
+    assert!(open(&path).load().unwrap().devices.is_empty());
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn hybrid_registrations_survive_a_restart() {
+    let path = store_path("hybrid");
+    let udp = addr("127.0.0.1:12345");
+    let b = addr("127.0.0.1:40000");
+    {
+        let mut state = HybridState::<()>::new(&options(Arc::new(open(&path))));
+        let response = state.on_udp(b, 0, Message::register_request("B".to_owned()));
+        assert_eq!(response, Some(Message::register_response(0)));
+        state.on_udp(
+            addr("127.0.0.1:40001"),
+            0,
+            Message::register_request("C".to_owned()),
+        );
+    }
+
+    let options = options(Arc::new(open(&path)));
+    // kicked ids stay gone
+    options.control.kick("C");
+    let mut state = HybridState::<()>::new(&options);
+    assert_eq!(state.restore(&[addr("[::1]:12345"), udp]).unwrap(), 1);
+    assert_eq!(state.registered(), 1);
+    assert_eq!(
+        state.on_tcp(
+            addr("127.0.0.1:50000"),
+            Message::punchA2S("B".to_owned()),
+            ()
+        ),
+        TcpAction::TellB(b, 1, Message::punchS2B(addr("127.0.0.1:50000")))
+    );
+
+    // expired ones too
+    let short = Options {
+        registration_ttl: Duration::from_millis(1),
+        ..options
+    };
+    std::thread::sleep(Duration::from_millis(5));
+    let mut state = HybridState::<()>::new(&short);
+    assert_eq!(state.restore(&[udp]).unwrap(), 0);
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn bans_survive_a_restart() {
+    let path = store_path("bans");
+    let control = Control::with_store(Arc::new(open(&path))).unwrap();
+    control.ban("mallory");
+    control.ban("eve");
+    control.unban("eve");
+    drop(control);
+    let control = Control::with_store(Arc::new(open(&path))).unwrap();
+    assert_eq!(control.banned(), vec!["mallory"]);
+    let stored = control.store().load().unwrap();
+    assert_eq!(stored.banned, vec!["mallory"]);
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn restarted_server_knows_b_at_once() {
+    let path = store_path("restart");
+    let start = |options: Options| async move {
+        loop {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            if let Ok(socket) = UdpSocket::bind(addr).await {
+                let server = tokio::spawn(server::hybrid_with(
+                    vec![listener],
+                    vec![Datagram::Os(socket)],
+                    options,
+                ));
+                return (addr, server);
+            }
+        }
+    };
+
+    let first_store = Arc::new(open(&path));
+    let first = options(first_store.clone());
+    let (server_addr, server) = start(first.clone()).await;
+    let mut b = Puncher::new(Strategy::Hybrid, server_addr, "B".to_owned(), None);
+    b.register().await.unwrap();
+    let registered = first.control.registrations(Some("B"));
+    assert_eq!(registered.len(), 1);
+    server.abort();
+    drop(b);
+    drop(first);
+    // the server's tasks may still hold it, so it is not closed yet
+    first_store.sync().unwrap();
+
+    let second = options(Arc::new(open(&path)));
+    let control = second.control.clone();
+    let (_, _server) = start(second).await;
+    sleep(Duration::from_millis(100)).await;
+    let restored = control.registrations(Some("B"));
+    assert_eq!(restored.len(), 1);
+    assert_eq!(restored[0].addr, registered[0].addr);
+    let stored = control.store().get("B").unwrap().unwrap();
+    assert!(stored.last_seen <= SystemTime::now());
+    std::fs::remove_file(&path).ok();
+}
@@ -0,0 +1,274 @@
+use anyhow::{anyhow, bail, Result};
+use futures::future::BoxFuture;
+use punch::{
+    net::{Datagram, Network},
+    punch_with,
+    puncher::{Punched, PunchedStream, Puncher, Strategy, UdpPunch},
+    report::Events,
+    server, Candidate, Pair, PunchContext, PunchStrategy,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::Notify,
+    time::{timeout, Duration},
+};
+
+// candidates by id, what the two sides of a test trade instead of a server
+#[derive(Default)]
+struct Board {
+    candidates: Mutex<HashMap<String, Candidate>>,
+    posted: Notify,
+}
+
+/// Plain tcp on loopback: the side with the lower id listens.
+struct Loopback {
+    board: Arc<Board>,
+    listener: Mutex<Option<TcpListener>>,
+}
+
+impl Loopback {
+    fn new(board: &Arc<Board>) -> Self {
+        Self {
+            board: board.clone(),
+            listener: Mutex::new(None),
+        }
+    }
+}
+
+fn listens(punch: &PunchContext) -> bool {
+    Some(&punch.id) < punch.peer_id.as_ref()
+}
+
+impl PunchStrategy for Loopback {
+    fn name(&self) -> &str {
+        "loopback"
+    }
+
+    fn gather<'a>(&'a self, punch: &'a PunchContext) -> BoxFuture<'a, Result<Vec<Candidate>>> {
+        Box::pin(async move {
+            if !listens(punch) {
+                return Ok(Vec::new());
+            }
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let local = listener.local_addr()?;
+            *self.listener.lock().unwrap() = Some(listener);
+            Ok(vec![Candidate {
+                addr: local,
+                local: Some(local),
+            }])
+        })
+    }
+
+    fn exchange<'a>(
+        &'a self,
+        punch: &'a PunchContext,
+        ours: Vec<Candidate>,
+    ) -> BoxFuture<'a, Result<Vec<Pair>>> {
+        Box::pin(async move {
+            if let Some(&candidate) = ours.first() {
+                let local = candidate.local.unwrap();
+                self.board
+                    .candidates
+                    .lock()
+                    .unwrap()
+                    .insert(punch.id.clone(), candidate);
+                self.board.posted.notify_waiters();
+                return Ok(vec![Pair {
+                    local,
+                    remote: local,
+                }]);
+            }
+            let peer_id = punch.peer_id.clone().unwrap();
+            loop {
+                let posted = self.board.posted.notified();
+                if let Some(theirs) = self.board.candidates.lock().unwrap().get(&peer_id) {
+                    return Ok(vec![Pair {
+                        local: "127.0.0.1:0".parse().unwrap(),
+                        remote: theirs.addr,
+                    }]);
+                }
+                posted.await;
+            }
+        })
+    }
+
+    fn connect<'a>(
+        &'a self,
+        punch: &'a PunchContext,
+        pairs: Vec<Pair>,
+    ) -> BoxFuture<'a, Result<Punched>> {
+        Box::pin(async move {
+            let listener = self.listener.lock().unwrap().take();
+            let stream = match listener {
+                Some(listener) => listener.accept().await?.0,
+                None => TcpStream::connect(pairs[0].remote).await?,
+            };
+            Ok(Punched {
+                stream: PunchedStream::Tcp(stream),
+                initiator: !listens(punch),
+            })
+        })
+    }
+}
+
+/// Fails right away.
+struct Broken;
+
+impl PunchStrategy for Broken {
+    fn name(&self) -> &str {
+        "broken"
+    }
+
+    fn gather<'a>(&'a self, _punch: &'a PunchContext) -> BoxFuture<'a, Result<Vec<Candidate>>> {
+        Box::pin(async { bail!("nothing to gather") })
+    }
+
+    fn exchange<'a>(
+        &'a self,
+        _punch: &'a PunchContext,
+        _ours: Vec<Candidate>,
+    ) -> BoxFuture<'a, Result<Vec<Pair>>> {
+        Box::pin(async { Err(anyhow!("unreachable")) })
+    }
+
+    fn connect<'a>(
+        &'a self,
+        _punch: &'a PunchContext,
+        _pairs: Vec<Pair>,
+    ) -> BoxFuture<'a, Result<Punched>> {
+        Box::pin(async { Err(anyhow!("unreachable")) })
+    }
+}
+
+async fn start_udp() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(server::udp(Datagram::Os(socket)));
+    addr
+}
+
+async fn ping(a: &mut Punched, b: &mut Punched) {
+    assert_ne!(a.initiator, b.initiator);
+    a.stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    b.stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+fn context(id: &str, peer_id: &str, server: SocketAddr) -> PunchContext {
+    PunchContext {
+        id: id.to_owned(),
+        peer_id: Some(peer_id.to_owned()),
+        server_addrs: vec![server],
+        network: Network::Os,
+        events: Events::new(id, Some(peer_id), Strategy::Udp),
+    }
+}
+
+#[tokio::test]
+async fn builtin_strategies_run_on_their_own() {
+    let server = start_udp().await;
+    let (one, two) = (UdpPunch::default(), UdpPunch::default());
+    let (context_one, context_two) = (context("1", "2", server), context("2", "1", server));
+    let (one, two) = timeout(Duration::from_secs(20), async {
+        tokio::join!(
+            punch_with(&one, &context_one),
+            punch_with(&two, &context_two)
+        )
+    })
+    .await
+    .expect("punch timed out");
+    let (mut one, mut two) = (one.unwrap(), two.unwrap());
+    ping(&mut one, &mut two).await;
+}
+
+#[tokio::test]
+async fn added_strategy_wins_when_the_builtin_cannot() {
+    // takes the registrations and never answers
+    let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = dead.local_addr().unwrap();
+    let board = Arc::new(Board::default());
+    let puncher = |id: &str, peer_id: &str| {
+        Puncher::new(
+            Strategy::Udp,
+            server,
+            id.to_owned(),
+            Some(peer_id.to_owned()),
+        )
+        .strategy(Loopback::new(&board))
+    };
+    let (mut a, mut b) = (puncher("a", "b"), puncher("b", "a"));
+    let (a, b) = timeout(Duration::from_secs(5), async {
+        tokio::join!(a.punch(), b.punch())
+    })
+    .await
+    .expect("punch timed out");
+    let (mut a, mut b) = (a.unwrap(), b.unwrap());
+    assert!(matches!(a.stream, PunchedStream::Tcp(_)));
+    ping(&mut b, &mut a).await;
+}
+
+#[tokio::test]
+async fn failing_strategy_leaves_the_builtin_running() {
+    let server = start_udp().await;
+    let puncher = |id: &str, peer_id: &str| {
+        Puncher::new(
+            Strategy::Udp,
+            server,
+            id.to_owned(),
+            Some(peer_id.to_owned()),
+        )
+        .strategy(Broken)
+    };
+    let (mut one, mut two) = (puncher("1", "2"), puncher("2", "1"));
+    let (one, two) = timeout(Duration::from_secs(20), async {
+        tokio::join!(one.punch(), two.punch())
+    })
+    .await
+    .expect("punch timed out");
+    let (mut one, mut two) = (one.unwrap(), two.unwrap());
+    assert!(matches!(one.stream, PunchedStream::Udp(_)));
+    ping(&mut one, &mut two).await;
+}
+
+// whichever side registers first, the other one learns a port it left already
+#[tokio::test]
+async fn builtin_tcp_punch_connects_to_the_listener() {
+    for listener_first in [true, false] {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        tokio::spawn(server::tcp(listener));
+        let mut a = Puncher::new(Strategy::Tcp, server, "a".to_owned(), Some("b".to_owned()));
+        let mut b = Puncher::new(Strategy::Tcp, server, "b".to_owned(), Some("a".to_owned()))
+            .tcp_listener(true);
+        let (a, b) = timeout(Duration::from_secs(20), async {
+            let late = Duration::from_millis(2500);
+            tokio::join!(
+                async {
+                    if listener_first {
+                        tokio::time::sleep(late).await;
+                    }
+                    a.punch().await
+                },
+                async {
+                    if !listener_first {
+                        tokio::time::sleep(late).await;
+                    }
+                    b.punch().await
+                }
+            )
+        })
+        .await
+        .expect("punch timed out");
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        assert!(a.initiator && !b.initiator);
+        ping(&mut a, &mut b).await;
+        ping(&mut b, &mut a).await;
+    }
+}
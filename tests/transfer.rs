@@ -0,0 +1,168 @@
+use anyhow::{anyhow, Result};
+use futures::{future::BoxFuture, FutureExt, SinkExt};
+use punch::transfer::{self, Connect, Message, Offer};
+use std::path::PathBuf;
+use tokio::io::DuplexStream;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+// found while adding the fuzz targets: the hash was sliced by bytes to name
+// the part file and panicked
+#[tokio::test]
+async fn non_ascii_sha256_is_rejected() {
+    let (sender, receiver) = tokio::io::duplex(4096);
+    let dir = std::env::temp_dir();
+    let receive = tokio::spawn(async move { transfer::receive(receiver, &dir).await });
+
+    let offer = Offer {
+        name: "x".to_owned(),
+        size: 1,
+        sha256: format!("a{}", "é".repeat(32)),
+    };
+    let mut frame = vec![0u8];
+    frame.extend(serde_json::to_vec(&Message::offer(offer)).unwrap());
+    let mut framed = Framed::new(sender, LengthDelimitedCodec::new());
+    framed.send(frame.into()).await.unwrap();
+
+    assert!(receive.await.unwrap().is_err());
+}
+
+// in-memory connections, each with a sender at the other end
+struct Senders {
+    file: PathBuf,
+    attempts: usize,
+    // the first ones break before anything is sent
+    broken: usize,
+}
+
+impl Senders {
+    fn new(name: &str, content: &[u8], broken: usize) -> Self {
+        let file = temp_path(name);
+        std::fs::write(&file, content).unwrap();
+        Self {
+            file,
+            attempts: 0,
+            broken,
+        }
+    }
+}
+
+impl Connect for Senders {
+    type Stream = DuplexStream;
+    fn connect(&mut self) -> BoxFuture<'_, Result<DuplexStream>> {
+        self.attempts += 1;
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (file, broken) = (self.file.clone(), self.attempts <= self.broken);
+        async move {
+            if !broken {
+                tokio::spawn(async move {
+                    let offer = Offer::from_file(&file).await.unwrap();
+                    transfer::send(theirs, &file, &offer).await.ok();
+                });
+            }
+            Ok(ours)
+        }
+        .boxed()
+    }
+}
+
+struct Unreachable {
+    attempts: usize,
+}
+
+impl Connect for Unreachable {
+    type Stream = DuplexStream;
+    fn connect(&mut self) -> BoxFuture<'_, Result<DuplexStream>> {
+        self.attempts += 1;
+        async { Err(anyhow!("no route")) }.boxed()
+    }
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("punch-transfer-{}-{}", std::process::id(), name))
+}
+
+#[tokio::test]
+async fn dropped_connections_are_tried_again() {
+    let mut senders = Senders::new("again.bin", &[7u8; 100_000], 1);
+    let dir = temp_path("again");
+    std::fs::create_dir_all(&dir).unwrap();
+    let received = transfer::with_retry(&mut senders, |stream| transfer::receive(stream, &dir))
+        .await
+        .unwrap();
+    assert_eq!(senders.attempts, 2);
+    assert_eq!(std::fs::read(&received).unwrap(), vec![7u8; 100_000]);
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::remove_file(&senders.file).ok();
+}
+
+#[tokio::test]
+async fn local_errors_are_not_tried_again() {
+    let mut senders = Senders::new("missing.bin", b"data", 0);
+    let dir = temp_path("missing-dir");
+    assert!(
+        transfer::with_retry(&mut senders, |stream| transfer::receive(stream, &dir))
+            .await
+            .is_err()
+    );
+    assert_eq!(senders.attempts, 1);
+    std::fs::remove_file(&senders.file).ok();
+}
+
+#[tokio::test(start_paused = true)]
+async fn punching_gives_up_after_max_attempts() {
+    let mut unreachable = Unreachable { attempts: 0 };
+    let result = transfer::with_retry(&mut unreachable, |_stream| async { Ok(()) }).await;
+    assert!(result.is_err());
+    assert_eq!(unreachable.attempts, transfer::MAX_ATTEMPTS);
+}
+
+// receives the file of `senders` into `dir` in one go
+async fn receive_once(senders: &mut Senders, dir: &std::path::Path) -> PathBuf {
+    let stream = senders.connect().await.unwrap();
+    transfer::receive(stream, dir).await.unwrap()
+}
+
+#[tokio::test]
+async fn existing_files_are_not_overwritten() {
+    let mut senders = Senders::new("keep.txt", b"new", 0);
+    let dir = temp_path("keep");
+    std::fs::create_dir_all(&dir).unwrap();
+    let name = senders.file.file_name().unwrap().to_owned();
+    std::fs::write(dir.join(&name), b"old").unwrap();
+
+    let first = receive_once(&mut senders, &dir).await;
+    let second = receive_once(&mut senders, &dir).await;
+    assert_eq!(std::fs::read(dir.join(&name)).unwrap(), b"old");
+    assert_ne!(first, dir.join(&name));
+    assert_ne!(first, second);
+    assert_eq!(std::fs::read(&first).unwrap(), b"new");
+    assert_eq!(std::fs::read(&second).unwrap(), b"new");
+    assert!(first.to_string_lossy().ends_with(".txt"));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::remove_file(&senders.file).ok();
+}
+
+#[tokio::test]
+async fn resumed_parts_are_part_of_the_check() {
+    let content: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    let mut senders = Senders::new("resume.bin", &content, 0);
+    let offer = Offer::from_file(&senders.file).await.unwrap();
+    let dir = temp_path("resume");
+    std::fs::create_dir_all(&dir).unwrap();
+    let part = dir.join(format!("{}.{}.part", offer.name, &offer.sha256[..16]));
+
+    std::fs::write(&part, &content[..50_000]).unwrap();
+    let received = receive_once(&mut senders, &dir).await;
+    assert!(std::fs::read(&received).unwrap() == content);
+    assert!(!part.exists());
+
+    // a damaged part is found out and dropped
+    let mut damaged = content[..50_000].to_vec();
+    damaged[10] ^= 1;
+    std::fs::write(&part, &damaged).unwrap();
+    let stream = senders.connect().await.unwrap();
+    assert!(transfer::receive(stream, &dir).await.is_err());
+    assert!(!part.exists());
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::remove_file(&senders.file).ok();
+}
@@ -0,0 +1,134 @@
+use clap::Parser;
+use punch::{
+    net::Datagram,
+    puncher::{Puncher, Strategy},
+    server::{self, Options},
+    trigger::{Trigger, TriggerArgs},
+};
+use std::net::SocketAddr;
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    time::{sleep, timeout, Duration, Instant},
+};
+
+#[derive(Parser)]
+struct Args {
+    #[clap(flatten)]
+    trigger: TriggerArgs,
+}
+
+fn trigger(flags: &[&str]) -> Result<Trigger, clap::Error> {
+    let args = Args::try_parse_from(std::iter::once("client").chain(flags.iter().copied()))?;
+    Ok(args.trigger.trigger().unwrap())
+}
+
+async fn start_hybrid(options: Options) -> SocketAddr {
+    loop {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        if let Ok(socket) = UdpSocket::bind(addr).await {
+            tokio::spawn(server::hybrid_with(
+                vec![listener],
+                vec![Datagram::Os(socket)],
+                options,
+            ));
+            return addr;
+        }
+    }
+}
+
+#[test]
+fn flags_pick_the_trigger() {
+    assert!(matches!(trigger(&[]).unwrap(), Trigger::Now));
+    assert!(matches!(
+        trigger(&["--punch-after", "5s"]).unwrap(),
+        Trigger::After(delay) if delay == Duration::from_secs(5)
+    ));
+    assert!(matches!(
+        trigger(&["--interactive"]).unwrap(),
+        Trigger::Stdin
+    ));
+    assert!(trigger(&["--punch-after", "5s", "--interactive"]).is_err());
+    assert!(trigger(&["--punch-socket", "/tmp/p.sock", "--interactive"]).is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn delay_holds_the_punch_back() {
+    let started = Instant::now();
+    Trigger::After(Duration::from_secs(5)).wait().await.unwrap();
+    assert!(started.elapsed() >= Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn hybrid_a_punches_when_told() {
+    let options = Options::default();
+    let control = options.control.clone();
+    let server = start_hybrid(options).await;
+    let (trigger, handle) = Trigger::manual();
+    let mut a = Puncher::new(
+        Strategy::Hybrid,
+        server,
+        "a".to_owned(),
+        Some("b".to_owned()),
+    )
+    .trigger(trigger);
+    let mut b = Puncher::new(Strategy::Hybrid, server, "b".to_owned(), None);
+    b.register().await.unwrap();
+    let b = tokio::spawn(async move { b.punch().await.map(|_| ()) });
+    let a = tokio::spawn(async move { a.punch().await.map(|_| ()) });
+
+    // registered, but nothing asked of the server yet
+    sleep(Duration::from_secs(1)).await;
+    assert!(!a.is_finished());
+    assert!(control.trails().list(Some("b")).is_empty());
+
+    handle.punch();
+    let (a, b) = timeout(Duration::from_secs(20), async { tokio::join!(a, b) })
+        .await
+        .expect("punch timed out");
+    a.unwrap().unwrap();
+    b.unwrap().unwrap();
+    assert_eq!(control.trails().list(Some("b")).len(), 1);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn a_line_on_the_socket_starts_the_punch() {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixStream,
+    };
+
+    let path = std::env::temp_dir().join(format!("punch-{}.sock", std::process::id()));
+    // a stale socket from an earlier run is replaced
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+    let waiting = tokio::spawn(Trigger::Socket(path.clone()).wait());
+    let mut stream = loop {
+        match UnixStream::connect(&path).await {
+            Ok(stream) => break stream,
+            Err(_) => sleep(Duration::from_millis(50)).await,
+        }
+    };
+    assert!(!waiting.is_finished());
+    stream.write_all(b"punch\n").await.unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await.unwrap();
+    assert_eq!(reply, "punching\n");
+    timeout(Duration::from_secs(5), waiting)
+        .await
+        .expect("trigger did not fire")
+        .unwrap()
+        .unwrap();
+    assert!(!path.exists());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn the_socket_never_replaces_other_files() {
+    let path = std::env::temp_dir().join(format!("punch-{}.not-a-sock", std::process::id()));
+    std::fs::write(&path, b"keep me").unwrap();
+    assert!(Trigger::Socket(path.clone()).wait().await.is_err());
+    assert_eq!(std::fs::read(&path).unwrap(), b"keep me");
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,182 @@
+use punch::{
+    net::Datagram,
+    rudp::RudpStream,
+    tunnel::{self, ForwardArgs},
+};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::{sleep, timeout, Duration},
+};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+// the two kinds of punched connection
+async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (client, server)
+}
+
+async fn udp_pair() -> (RudpStream, RudpStream) {
+    let one = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let two = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    one.connect(two.local_addr().unwrap()).await.unwrap();
+    two.connect(one.local_addr().unwrap()).await.unwrap();
+    (
+        RudpStream::new(Datagram::Os(one)).unwrap(),
+        RudpStream::new(Datagram::Os(two)).unwrap(),
+    )
+}
+
+async fn start_echo() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut rd, mut wr) = stream.split();
+                tokio::io::copy(&mut rd, &mut wr).await.ok();
+            });
+        }
+    });
+    addr
+}
+
+async fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+// the listener is up once the tunnel got going
+async fn connect(addr: &str) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(addr).await {
+            return stream;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    panic!("nobody listens on {}", addr);
+}
+
+async fn echoes(addr: &str) {
+    let mut stream = connect(addr).await;
+    let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+    let (mut rd, mut wr) = stream.split();
+    let (_, echoed) = timeout(WAIT, async {
+        tokio::join!(
+            async {
+                wr.write_all(&data).await.unwrap();
+                wr.shutdown().await.unwrap();
+            },
+            async {
+                let mut echoed = Vec::new();
+                rd.read_to_end(&mut echoed).await.unwrap();
+                echoed
+            }
+        )
+    })
+    .await
+    .expect("echo timed out");
+    assert!(echoed == data);
+}
+
+async fn local_forward<S>(ours: S, theirs: S)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let echo = start_echo().await;
+    let local = free_addr().await;
+    let args = ForwardArgs {
+        local: Some(local.clone()),
+        remote_target: Some(echo.to_string()),
+        ..ForwardArgs::default()
+    };
+    let allowed = ForwardArgs {
+        allow_target: vec![echo.to_string()],
+        ..ForwardArgs::default()
+    };
+    tokio::spawn(async move { tunnel::forward(ours, true, &args).await });
+    tokio::spawn(async move { tunnel::forward(theirs, false, &allowed).await });
+    // two connections at once share the punched one
+    tokio::join!(echoes(&local), echoes(&local));
+}
+
+async fn remote_forward<S>(ours: S, theirs: S)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let echo = start_echo().await;
+    let remote = free_addr().await;
+    let args = ForwardArgs {
+        remote: Some(remote.clone()),
+        local_target: Some(echo.to_string()),
+        ..ForwardArgs::default()
+    };
+    let allowed = ForwardArgs {
+        allow_listen: vec![remote.clone()],
+        ..ForwardArgs::default()
+    };
+    tokio::spawn(async move { tunnel::forward(ours, true, &args).await });
+    tokio::spawn(async move { tunnel::forward(theirs, false, &allowed).await });
+    echoes(&remote).await;
+}
+
+// the peer refuses what it was not told to allow
+#[tokio::test]
+async fn unlisted_targets_and_listen_addrs_are_refused() {
+    let echo = start_echo().await;
+    let (local, remote) = (free_addr().await, free_addr().await);
+    let args = ForwardArgs {
+        local: Some(local.clone()),
+        remote_target: Some(echo.to_string()),
+        remote: Some(remote.clone()),
+        local_target: Some(echo.to_string()),
+        ..ForwardArgs::default()
+    };
+    let allowed = ForwardArgs {
+        allow_target: vec![free_addr().await],
+        allow_listen: vec![free_addr().await],
+        ..ForwardArgs::default()
+    };
+    let (ours, theirs) = tcp_pair().await;
+    tokio::spawn(async move { tunnel::forward(ours, true, &args).await });
+    tokio::spawn(async move { tunnel::forward(theirs, false, &allowed).await });
+    let mut stream = connect(&local).await;
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 8];
+    let read = timeout(WAIT, stream.read(&mut buf))
+        .await
+        .expect("not closed");
+    assert!(matches!(read, Ok(0) | Err(_)));
+    sleep(Duration::from_millis(300)).await;
+    assert!(TcpStream::connect(&remote).await.is_err());
+}
+
+#[tokio::test]
+async fn local_forward_over_tcp() {
+    let (ours, theirs) = tcp_pair().await;
+    local_forward(ours, theirs).await;
+}
+
+#[tokio::test]
+async fn local_forward_over_udp() {
+    let (ours, theirs) = udp_pair().await;
+    local_forward(ours, theirs).await;
+}
+
+#[tokio::test]
+async fn remote_forward_over_tcp() {
+    let (ours, theirs) = tcp_pair().await;
+    remote_forward(ours, theirs).await;
+}
+
+#[tokio::test]
+async fn remote_forward_over_udp() {
+    let (ours, theirs) = udp_pair().await;
+    remote_forward(ours, theirs).await;
+}
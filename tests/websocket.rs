@@ -0,0 +1,344 @@
+use futures::{SinkExt, StreamExt};
+use punch::{
+    hybrid::{Answer, Message},
+    metrics::Metrics,
+    net::Datagram,
+    pad_request,
+    server::{self, Options},
+    signaling::{ServerSignaling, Signal, Signaling, WebSocketSignaling},
+};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::{sleep, timeout, Duration},
+};
+use tokio_tungstenite::{client_async, tungstenite, WebSocketStream};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+type WebSocket = WebSocketStream<TcpStream>;
+
+// tcp and udp on one addr, WebSocket on another
+async fn start(options: Options) -> (SocketAddr, SocketAddr) {
+    let websocket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let websocket_addr = websocket.local_addr().unwrap();
+    loop {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        if let Ok(socket) = UdpSocket::bind(addr).await {
+            tokio::spawn(server::hybrid_with_websocket(
+                vec![listener],
+                vec![Datagram::Os(socket)],
+                vec![websocket],
+                options,
+            ));
+            return (addr, websocket_addr);
+        }
+    }
+}
+
+async fn connect(addr: SocketAddr) -> WebSocket {
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (websocket, _) = client_async(format!("ws://{}/", addr), stream)
+        .await
+        .unwrap();
+    websocket
+}
+
+async fn send(websocket: &mut WebSocket, msg: Message) {
+    let text = String::from_utf8(msg.encode()).unwrap();
+    websocket
+        .send(tungstenite::Message::Text(text))
+        .await
+        .unwrap();
+}
+
+async fn recv(websocket: &mut WebSocket) -> Message {
+    loop {
+        let frame = timeout(WAIT, websocket.next())
+            .await
+            .expect("nothing received")
+            .expect("closed")
+            .unwrap();
+        if let tungstenite::Message::Text(text) = frame {
+            return Message::decode(text.as_bytes()).unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn websocket_client_registers_and_answers_punches() {
+    let metrics = Arc::new(Metrics::default());
+    let (server, websocket) = start(Options {
+        metrics: metrics.clone(),
+        ..Options::default()
+    })
+    .await;
+    let mut b = connect(websocket).await;
+    let b_addr = b.get_ref().local_addr().unwrap();
+    send(&mut b, Message::register_request("B".to_owned())).await;
+    assert_eq!(recv(&mut b).await, Message::register_response(0));
+    send(&mut b, Message::addr_request("B".to_owned())).await;
+    assert_eq!(recv(&mut b).await, Message::addr_response(b_addr));
+
+    // A asks over plain tcp, B is told on its WebSocket
+    let mut a = TcpStream::connect(server).await.unwrap();
+    let a_addr = a.local_addr().unwrap();
+    a.write_all(&Message::punchA2S("B".to_owned()).encode())
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut b).await, Message::punchS2B(a_addr));
+    // the addr of the WebSocket is not where B punches from
+    send(&mut b, Message::punchB2S(Answer::A(a_addr))).await;
+    let mut b_punch = TcpStream::connect(server).await.unwrap();
+    let b_punch_addr = b_punch.local_addr().unwrap();
+    b_punch
+        .write_all(&Message::punchB2S(Answer::A(a_addr)).encode())
+        .await
+        .unwrap();
+    let mut buf = vec![0u8; 1024];
+    let n = timeout(WAIT, a.read(&mut buf)).await.unwrap().unwrap();
+    assert_eq!(
+        Message::decode(&buf[..n]).unwrap(),
+        Message::punchS2A(Some(b_punch_addr))
+    );
+    // register, addr and the ignored punch answer in, two answers and the punch out
+    let body = metrics.render();
+    assert!(body.contains("punch_received_messages_total{protocol=\"websocket\"} 3"));
+    assert!(body.contains("punch_sent_messages_total{protocol=\"websocket\"} 3"));
+}
+
+#[tokio::test]
+async fn websocket_client_asks_once_registered() {
+    let (server, websocket) = start(Options::default()).await;
+    // B registered over udp
+    let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    b.connect(server).await.unwrap();
+    b.send(&pad_request(
+        Message::register_request("B".to_owned()).encode(),
+    ))
+    .await
+    .unwrap();
+    let mut buf = vec![0u8; 1024];
+    let n = timeout(WAIT, b.recv(&mut buf)).await.unwrap().unwrap();
+    assert_eq!(
+        Message::decode(&buf[..n]).unwrap(),
+        Message::register_response(0)
+    );
+
+    // plain tcp has no registration to ask from
+    let mut tcp = TcpStream::connect(server).await.unwrap();
+    let lookup = Message::lookup_request("A".to_owned(), "B".to_owned());
+    tcp.write_all(&lookup.encode()).await.unwrap();
+    let mut answer = Vec::new();
+    timeout(WAIT, tcp.read_to_end(&mut answer))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(answer.is_empty());
+
+    // only registered clients are answered, for the id they registered
+    let mut a = connect(websocket).await;
+    let lookup = || Message::lookup_request("A".to_owned(), "B".to_owned());
+    send(&mut a, lookup()).await;
+    assert_eq!(recv(&mut a).await, Message::lookup_response(None));
+    send(&mut a, Message::register_request("A".to_owned())).await;
+    assert_eq!(recv(&mut a).await, Message::register_response(0));
+    send(&mut a, lookup()).await;
+    assert_eq!(
+        recv(&mut a).await,
+        Message::lookup_response(Some(b.local_addr().unwrap()))
+    );
+    let forged = Signal {
+        from: "C".to_owned(),
+        to: "B".to_owned(),
+        candidates: Vec::new(),
+    };
+    send(&mut a, Message::signal(forged)).await;
+    assert_eq!(recv(&mut a).await, Message::signal_sent(false));
+    let signal = Signal {
+        from: "A".to_owned(),
+        to: "B".to_owned(),
+        candidates: Vec::new(),
+    };
+    send(&mut a, Message::signal(signal.clone())).await;
+    assert_eq!(recv(&mut a).await, Message::signal_sent(true));
+    let n = timeout(WAIT, b.recv(&mut buf)).await.unwrap().unwrap();
+    assert_eq!(Message::decode(&buf[..n]).unwrap(), Message::signal(signal));
+}
+
+#[tokio::test]
+async fn punch_requests_over_a_websocket_are_ignored() {
+    let (_, websocket) = start(Options::default()).await;
+    let mut b = connect(websocket).await;
+    send(&mut b, Message::register_request("B".to_owned())).await;
+    assert_eq!(recv(&mut b).await, Message::register_response(0));
+    let mut a = connect(websocket).await;
+    send(&mut a, Message::register_request("A".to_owned())).await;
+    assert_eq!(recv(&mut a).await, Message::register_response(0));
+
+    // the addr of a WebSocket is no use to punch to, nobody is told
+    send(&mut a, Message::punchA2S("B".to_owned())).await;
+    send(&mut a, Message::punchA2S("C".to_owned())).await;
+    send(&mut a, Message::addr_request("A".to_owned())).await;
+    assert!(matches!(recv(&mut a).await, Message::addr_response(_)));
+    send(&mut b, Message::addr_request("B".to_owned())).await;
+    assert!(matches!(recv(&mut b).await, Message::addr_response(_)));
+}
+
+#[tokio::test]
+async fn pipelined_requests_are_all_answered() {
+    let (_, websocket) = start(Options::default()).await;
+    let mut b = connect(websocket).await;
+    send(&mut b, Message::register_request("B".to_owned())).await;
+    assert_eq!(recv(&mut b).await, Message::register_response(0));
+    // many more than queue for a connection, sent before reading any answer,
+    // and fewer than the rate limit lets through at once
+    for _ in 0..60 {
+        send(&mut b, Message::addr_request("B".to_owned())).await;
+    }
+    let b_addr = b.get_ref().local_addr().unwrap();
+    for _ in 0..60 {
+        assert_eq!(recv(&mut b).await, Message::addr_response(b_addr));
+    }
+    send(&mut b, Message::register_request("B".to_owned())).await;
+    assert_eq!(recv(&mut b).await, Message::register_response(0));
+}
+
+#[tokio::test]
+async fn websocket_registrations_end_with_the_connection() {
+    let options = Options::default();
+    let control = options.control.clone();
+    let (_, websocket) = start(options).await;
+    let mut b = connect(websocket).await;
+    send(&mut b, Message::register_request("B".to_owned())).await;
+    assert_eq!(recv(&mut b).await, Message::register_response(0));
+    assert_eq!(control.registrations(Some("B")).len(), 1);
+
+    b.close(None).await.unwrap();
+    drop(b);
+    for _ in 0..50 {
+        if control.registrations(Some("B")).is_empty() {
+            return;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    panic!("B is still registered");
+}
+
+// the server closed the connection
+async fn closed(websocket: &mut WebSocket, within: Duration) {
+    loop {
+        match timeout(within, websocket.next()).await {
+            Ok(Some(Ok(tungstenite::Message::Close(_))) | Some(Err(_)) | None) => return,
+            Ok(Some(Ok(_))) => continue,
+            Err(_) => panic!("still open"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn websockets_that_do_not_register_or_go_quiet_are_closed() {
+    let (_, websocket) = start(Options {
+        registration_ttl: Duration::from_millis(300),
+        ..Options::default()
+    })
+    .await;
+    let mut quiet = connect(websocket).await;
+    send(&mut quiet, Message::register_request("B".to_owned())).await;
+    assert_eq!(recv(&mut quiet).await, Message::register_response(0));
+    closed(&mut quiet, WAIT).await;
+
+    let mut silent = connect(websocket).await;
+    send(&mut silent, Message::addr_request("C".to_owned())).await;
+    assert!(matches!(recv(&mut silent).await, Message::addr_response(_)));
+    closed(&mut silent, Duration::from_secs(15)).await;
+}
+
+#[tokio::test]
+async fn websockets_have_their_own_connection_limit() {
+    let (server, websocket) = start(Options {
+        max_websockets: 1,
+        ..Options::default()
+    })
+    .await;
+    let mut first = connect(websocket).await;
+    send(&mut first, Message::register_request("B".to_owned())).await;
+    assert_eq!(recv(&mut first).await, Message::register_response(0));
+
+    let stream = TcpStream::connect(websocket).await.unwrap();
+    let handshake = client_async(format!("ws://{}/", websocket), stream);
+    assert!(timeout(Duration::from_millis(500), handshake)
+        .await
+        .is_err());
+    // tcp clients are still served
+    let mut a = TcpStream::connect(server).await.unwrap();
+    a.write_all(&Message::punchA2S("C".to_owned()).encode())
+        .await
+        .unwrap();
+    let mut buf = vec![0u8; 1024];
+    let n = timeout(WAIT, a.read(&mut buf)).await.unwrap().unwrap();
+    assert_eq!(Message::decode(&buf[..n]).unwrap(), Message::punchS2A(None));
+}
+
+#[tokio::test]
+async fn websocket_registrations_are_not_stored_and_leave_udp_ones_alone() {
+    let options = Options::default();
+    let control = options.control.clone();
+    let (server, websocket) = start(options).await;
+    let mut b = connect(websocket).await;
+    send(&mut b, Message::register_request("B".to_owned())).await;
+    assert_eq!(recv(&mut b).await, Message::register_response(0));
+    assert_eq!(control.registrations(Some("B")).len(), 1);
+    assert!(control.store().get("B").unwrap().is_none());
+
+    // the same id over udp takes over
+    let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    udp.connect(server).await.unwrap();
+    udp.send(&pad_request(
+        Message::register_request("B".to_owned()).encode(),
+    ))
+    .await
+    .unwrap();
+    let mut buf = vec![0u8; 1024];
+    timeout(WAIT, udp.recv(&mut buf)).await.unwrap().unwrap();
+    b.close(None).await.unwrap();
+    drop(b);
+    sleep(Duration::from_millis(300)).await;
+    let registrations = control.registrations(Some("B"));
+    assert_eq!(registrations.len(), 1);
+    assert_eq!(registrations[0].addr, udp.local_addr().unwrap());
+    assert!(control.store().get("B").unwrap().is_some());
+}
+
+#[tokio::test]
+async fn websocket_signaling_trades_with_udp_signaling() {
+    let (server, websocket) = start(Options::default()).await;
+    let (a, b) = (
+        WebSocketSignaling::new(websocket),
+        ServerSignaling::new(server),
+    );
+    assert!(a.lookup("b").await.is_err());
+    a.register("a").await.unwrap();
+    b.register("b").await.unwrap();
+    assert!(a.lookup("b").await.unwrap().is_some());
+    assert!(b.lookup("a").await.unwrap().is_some());
+
+    let signal = |from: &str, to: &str| Signal {
+        from: from.to_owned(),
+        to: to.to_owned(),
+        candidates: Vec::new(),
+    };
+    a.send(signal("a", "b")).await.unwrap();
+    assert_eq!(
+        timeout(WAIT, b.receive()).await.unwrap().unwrap(),
+        signal("a", "b")
+    );
+    b.send(signal("b", "a")).await.unwrap();
+    assert_eq!(
+        timeout(WAIT, a.receive()).await.unwrap().unwrap(),
+        signal("b", "a")
+    );
+    assert!(a.send(signal("c", "b")).await.is_err());
+}